@@ -0,0 +1,654 @@
+use crate::builder::{Client, ClientBuilder};
+use crate::compat::HostVersion;
+use crate::encrypter::{Decrypter, Encrypter};
+use crate::protocol::{
+    Auth, AuthOutput, Channel, ChannelOutput, Kex, KexOutput, Pty, gen_window_change,
+};
+use crate::ssh_stream::SshStream;
+use crate::terminal::NO_TERMINAL_MODES;
+use crate::{
+    CLIENT_VERSION, Error, MAX_RETRY_DELAY, RETRY_DELAY, SSH_MSG_CHANNEL_DATA, SSH_MSG_KEXINIT,
+    connection_reply, debug, gen_version_hash_prefix, last_sequence_number, resolve,
+    verify_host_key,
+};
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// An SSH connection that preforms all network io asynchronously using tokio.
+/// The protocol itself is shared with the blocking client so only the reads and
+/// writes differ.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), tssh::Error> {
+/// let mut client = tssh::AsyncClient::connect("example.com", 22).await?;
+/// client.authenticate_password("user", "password").await?;
+/// let output = client.exec("uname -a").await?;
+/// println!("{}", String::from_utf8_lossy(&output.stdout));
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncClient {
+    /// The buffered connection to the host
+    stream: BufReader<TcpStream>,
+    /// Encrypter for all packets sent after key exchange
+    encrypter: Encrypter,
+    /// Decrypter for all packets recieved after key exchange
+    decrypter: Decrypter,
+    /// The client channel number to use for the next opened channel
+    next_channel: u32,
+    /// The version information the host identified itself with
+    host_version: HostVersion,
+    /// The version information needed for the exchange hash of key re-exchanges
+    hash_prefix: Vec<u8>,
+    /// The host key accepted in the first key exchange, which every re-exchange must match
+    host_key: Vec<u8>,
+    /// The encryption algorithms offered in every key exchange in order of preference
+    ciphers: Vec<&'static str>,
+    /// Packets that arrived during a key re-exchange and still need handling
+    deferred: VecDeque<(u8, Vec<u8>)>,
+    /// The largest packet in bytes accepted from the host
+    max_packet: usize,
+    /// The sequence number of the last packet read, which is None if it was deferred
+    sequence_number: Option<u32>,
+    /// How much debugging output to print to stderr
+    verbosity: u8,
+}
+
+/// The output collected from running a command on the host
+#[derive(Debug, Default)]
+pub struct ExecOutput {
+    /// Everything the command wrote to stdout
+    pub stdout: Vec<u8>,
+    /// Everything the command wrote to stderr
+    pub stderr: Vec<u8>,
+    /// The exit status of the command if the host reported one
+    pub exit_status: Option<u32>,
+}
+
+/// Events that can be recieved over an open channel
+#[derive(Debug)]
+pub enum ChannelEvent {
+    /// Data written to the channel's normal stream
+    Stdout(Vec<u8>),
+    /// Data written to the channel's stderr stream
+    Stderr(Vec<u8>),
+    /// The exit status of the remote program
+    ExitStatus(u32),
+    /// The host will not send any more data over the channel
+    Eof,
+}
+
+/// A session channel open on the host such as an interactive shell
+pub struct AsyncChannel<'a> {
+    /// The connection the channel is open on
+    client: &'a mut AsyncClient,
+    /// The state of the channel
+    channel: Channel,
+    /// The number of bytes that can still be sent to the host
+    remote_window: u64,
+    /// Events recieved while waiting on something else
+    pending: VecDeque<ChannelEvent>,
+    /// Whether or not the shell or command has started
+    ready: bool,
+    /// Whether or not the host has closed the channel
+    closed: bool,
+}
+
+impl AsyncClient {
+    /// Establishes a connection to the host and runs the version exchange and key exchange.
+    /// The connection fails unless the host key is already in known_hosts.
+    /// Everything else uses the defaults of ClientBuilder.
+    pub async fn connect(host: &str, port: u16) -> Result<AsyncClient, Error> {
+        // The username is only needed to authenticate, which is done separately
+        let client = ClientBuilder::new()
+            .host(host)
+            .port(port)
+            .username("")
+            .build()?;
+        AsyncClient::connect_client(&client).await
+    }
+
+    /// Establishes a connection like connect using the address family, source ports,
+    /// connection attempts, socket options, maximum packet size, ciphers and pinned host key
+    /// fingerprints configured in the client
+    pub(crate) async fn connect_client(client: &Client) -> Result<AsyncClient, Error> {
+        let stream = open_tcp(client).await?;
+        configure_socket(&stream, client)?;
+        debug(client.verbosity, 1, || {
+            format!("Connected to {} port {}", client.host, client.port)
+        });
+        let mut stream = BufReader::new(stream);
+
+        // Send version info to host
+        stream.get_mut().write_all(CLIENT_VERSION).await?;
+        stream.get_mut().write_all(b"\r\n").await?;
+
+        // Recieve version info from host and ignore header information
+        let mut host_version = String::new();
+        while !host_version.starts_with("SSH-") {
+            host_version = String::new();
+            if stream.read_line(&mut host_version).await? == 0 {
+                return Err(Error::Other("Did not recieve version info from host"));
+            }
+        }
+        let (hash_prefix, host_version) = gen_version_hash_prefix(host_version)?;
+
+        // Send key negotiation information and run the exchange
+        let (mut kex, payload) = Kex::new(hash_prefix.clone(), &client.ciphers);
+        let mut accepted_key = Vec::new();
+        send_packet(&mut stream, &payload, None).await?;
+
+        loop {
+            let (packet_type, packet) = read_packet(&mut stream, None, client.max_packet).await?;
+            match kex.handle(packet_type, &packet)? {
+                KexOutput::None => (),
+                KexOutput::Send(payload) => send_packet(&mut stream, &payload, None).await?,
+                KexOutput::VerifyHostKey(host_key) => {
+                    // There is no user to ask about unknown hosts so they are rejected
+                    verify_host_key(client, &host_key, false)?;
+                    accepted_key = host_key;
+                    let payload = match kex.accept_host_key()? {
+                        KexOutput::Send(payload) => payload,
+                        _ => return Err(Error::Other("Key exchange did not send new keys")),
+                    };
+                    send_packet(&mut stream, &payload, None).await?;
+                }
+                KexOutput::Done => break,
+            }
+        }
+        let (encrypter, decrypter) = kex.keys(None, None)?;
+
+        Ok(AsyncClient {
+            stream,
+            encrypter,
+            decrypter,
+            next_channel: 0,
+            host_version,
+            hash_prefix,
+            host_key: accepted_key,
+            ciphers: client.ciphers.clone(),
+            deferred: VecDeque::new(),
+            max_packet: client.max_packet,
+            sequence_number: None,
+            verbosity: client.verbosity,
+        })
+    }
+
+    /// Returns the version information the host identified itself with, such as its
+    /// software version, for diagnosing interoperability problems
+    pub fn host_version(&self) -> &HostVersion {
+        &self.host_version
+    }
+
+    /// Authenticates as the given user with a password
+    pub async fn authenticate_password(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> Result<(), Error> {
+        // Request user authentication
+        let (mut auth, request) = Auth::new(username.to_string(), false, false);
+        self.send(&request).await?;
+
+        let mut password_sent = false;
+        loop {
+            let (code, packet) = self.read().await?;
+            match auth.handle(code, &packet)? {
+                AuthOutput::None
+                | AuthOutput::Banner(_)
+                | AuthOutput::PublicKey
+                | AuthOutput::HostBased => (),
+                AuthOutput::Send(payload) => self.send(&payload).await?,
+                AuthOutput::Password if password_sent => {
+                    return Err(Error::Other("Host rejected password authentication"));
+                }
+                AuthOutput::Password => {
+                    self.send(&auth.password_request(password)).await?;
+                    password_sent = true;
+                }
+                AuthOutput::InfoRequest(_) => {
+                    return Err(Error::Other(
+                        "Host requires keyboard-interactive authentication",
+                    ));
+                }
+                AuthOutput::Success => {
+                    self.encrypter.start_delayed_compression();
+                    self.decrypter.start_delayed_compression();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Runs a command on the host without a pseudo-terminal and collects all of its output
+    pub async fn exec(&mut self, command: &str) -> Result<ExecOutput, Error> {
+        let mut channel = self.open_session(None, Some(command.to_string())).await?;
+
+        let mut output = ExecOutput::default();
+        while let Some(event) = channel.read().await? {
+            match event {
+                ChannelEvent::Stdout(data) => output.stdout.extend(data),
+                ChannelEvent::Stderr(data) => output.stderr.extend(data),
+                ChannelEvent::ExitStatus(status) => output.exit_status = Some(status),
+                ChannelEvent::Eof => (),
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Starts an interactive shell in a pseudo-terminal of the given width and height
+    /// in characters. The pseudo-terminal is left with the host's default settings.
+    pub async fn shell(&mut self, width: u32, height: u32) -> Result<AsyncChannel<'_>, Error> {
+        let pty = Pty {
+            width,
+            height,
+            modes: NO_TERMINAL_MODES.to_vec(),
+        };
+        self.open_session(Some(pty), None).await
+    }
+
+    /// Opens a new channel of type session and waits for the shell or command to start
+    async fn open_session(
+        &mut self,
+        pty: Option<Pty>,
+        command: Option<String>,
+    ) -> Result<AsyncChannel<'_>, Error> {
+        let client_channel = self.next_channel;
+        self.next_channel += 1;
+
+        let (channel, payload) = Channel::open(client_channel, pty, Vec::new(), command);
+        self.send(&payload).await?;
+
+        let mut channel = AsyncChannel {
+            client: self,
+            channel,
+            remote_window: 0,
+            pending: VecDeque::new(),
+            ready: false,
+            closed: false,
+        };
+
+        while !channel.ready {
+            if channel.closed {
+                return Err(Error::Other(
+                    "Host closed channel before the session started",
+                ));
+            }
+            channel.process_packet().await?;
+        }
+
+        Ok(channel)
+    }
+
+    /// Sends a single encrypted packet with the given payload
+    async fn send(&mut self, payload: &[u8]) -> Result<(), Error> {
+        send_packet(&mut self.stream, payload, Some(&mut self.encrypter)).await
+    }
+
+    /// Reads the next encrypted packet and returns its type and payload. Key re-exchanges
+    /// are run as they come up, whether the host starts one or the keys have been used for
+    /// too long or for too much data, so their packets are never returned.
+    async fn read(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        loop {
+            if let Some(packet) = self.deferred.pop_front() {
+                self.sequence_number = None;
+                return Ok(packet);
+            }
+            if self.encrypter.needs_rekey() || self.decrypter.needs_rekey() {
+                self.rexchange(None).await?;
+                continue;
+            }
+
+            let (packet_type, data) =
+                read_packet(&mut self.stream, Some(&mut self.decrypter), self.max_packet).await?;
+            self.sequence_number = Some(last_sequence_number(&self.decrypter));
+            if packet_type != SSH_MSG_KEXINIT {
+                return Ok((packet_type, data));
+            }
+            self.rexchange(Some(data)).await?;
+        }
+    }
+
+    /// Runs a key re-exchange, handing it the host's SSH_MSG_KEXINIT if the host started it.
+    /// Nothing else is sent until it is done since reads and writes are never interleaved.
+    async fn rexchange(&mut self, host_kexinit: Option<Vec<u8>>) -> Result<(), Error> {
+        let (mut kex, payload) = Kex::new(self.hash_prefix.clone(), &self.ciphers);
+        self.send(&payload).await?;
+
+        let mut output = match host_kexinit {
+            Some(data) => kex.handle(SSH_MSG_KEXINIT, &data)?,
+            None => KexOutput::None,
+        };
+        loop {
+            match output {
+                KexOutput::None => (),
+                KexOutput::Send(payload) => self.send(&payload).await?,
+                KexOutput::VerifyHostKey(host_key) => {
+                    if host_key != self.host_key {
+                        return Err(Error::Other("Host key changed during key re-exchange"));
+                    }
+                    output = kex.accept_host_key()?;
+                    continue;
+                }
+                KexOutput::Done => break,
+            }
+            let (packet_type, packet) =
+                read_packet(&mut self.stream, Some(&mut self.decrypter), self.max_packet).await?;
+            output = kex.handle(packet_type, &packet)?;
+        }
+
+        // The old keys are still needed to carry the session over to the new ones
+        self.deferred.extend(kex.take_deferred());
+        let (encrypter, decrypter) =
+            kex.keys(Some(&mut self.encrypter), Some(&mut self.decrypter))?;
+        self.encrypter = encrypter;
+        self.decrypter = decrypter;
+        Ok(())
+    }
+}
+
+impl AsyncChannel<'_> {
+    /// Returns the next event recieved on the channel or None once the host has closed it
+    pub async fn read(&mut self) -> Result<Option<ChannelEvent>, Error> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+
+            if self.closed {
+                return Ok(None);
+            }
+
+            self.process_packet().await?;
+        }
+    }
+
+    /// Sends data to the channel, waiting for the host to adjust the window when it is full
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        while !data.is_empty() {
+            // Wait until we can send data
+            while self.remote_window == 0 {
+                if self.closed {
+                    return Err(Error::Other("Tried to write to a closed channel"));
+                }
+                self.process_packet().await?;
+            }
+
+            let len = data
+                .len()
+                .min(self.remote_window as usize)
+                .min(self.channel.packet_max() as usize);
+            let (chunk, rest) = data.split_at(len);
+
+            let mut packet = vec![SSH_MSG_CHANNEL_DATA];
+            packet.extend(self.channel.server_channel().to_be_bytes());
+            SshStream::append_string(&mut packet, chunk);
+            self.client.send(&packet).await?;
+
+            self.remote_window -= len as u64;
+            data = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Notifies the host that the terminal was resized to the given width and height in characters
+    pub async fn resize(&mut self, width: u32, height: u32) -> Result<(), Error> {
+        let request = gen_window_change(self.channel.server_channel(), width, height);
+        self.client.send(&request).await
+    }
+
+    /// Notifies the host that no more data will be sent over the channel
+    pub async fn eof(&mut self) -> Result<(), Error> {
+        let message = self.channel.eof_message();
+        self.client.send(&message).await
+    }
+
+    /// Closes the channel and waits for the host to confirm
+    pub async fn close(mut self) -> Result<(), Error> {
+        if let Some(message) = self.channel.close_message() {
+            self.client.send(&message).await?;
+        }
+        while !self.closed {
+            self.process_packet().await?;
+        }
+        Ok(())
+    }
+
+    /// Reads a single packet from the host and hands it to the channel. Any channel events
+    /// are queued to be returned by read.
+    async fn process_packet(&mut self) -> Result<(), Error> {
+        let (packet_type, data) = self.client.read().await?;
+
+        for output in self.channel.handle(packet_type, &data)? {
+            match output {
+                ChannelOutput::Send(payload) => self.client.send(&payload).await?,
+                ChannelOutput::WindowAdjust(amount) => self.remote_window += amount,
+                ChannelOutput::Ready => self.ready = true,
+                ChannelOutput::Stdout(data) => self.pending.push_back(ChannelEvent::Stdout(data)),
+                ChannelOutput::Stderr(data) => self.pending.push_back(ChannelEvent::Stderr(data)),
+                ChannelOutput::ExitStatus(status) => {
+                    self.pending.push_back(ChannelEvent::ExitStatus(status))
+                }
+                ChannelOutput::Eof => self.pending.push_back(ChannelEvent::Eof),
+                ChannelOutput::Closed => self.closed = true,
+                ChannelOutput::OpenFailed(..) => {
+                    return Err(Error::Other("Host failed to open session channel"));
+                }
+                output @ (ChannelOutput::GlobalRequest(..) | ChannelOutput::Unknown(_)) => {
+                    let sequence_number = self.client.sequence_number;
+                    let verbosity = self.client.verbosity;
+                    if let Some(reply) = connection_reply(&output, sequence_number, verbosity) {
+                        self.client.send(&reply).await?;
+                    }
+                }
+                ChannelOutput::ForwardDenied(_)
+                | ChannelOutput::Warning(_)
+                | ChannelOutput::Debug(..) => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Opens the client's TCP connection to one of the host's addresses of its address family,
+/// from a port in its source port range if it has one. Connections that time out or are
+/// refused are tried again like the blocking client does.
+async fn open_tcp(client: &Client) -> Result<TcpStream, Error> {
+    let addrs = resolve(&client.host, client.port, client.address_family)?;
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let connected = match &client.source_ports {
+            Some(ports) => connect_tcp_from(&addrs, ports).await,
+            None => TcpStream::connect(&addrs[..]).await,
+        };
+        let err = match connected {
+            Ok(stream) => return Ok(stream),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Error::ConnectTimeout,
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Error::ConnectionRefused,
+            Err(e) => return Err(Error::ConnectFailed(addrs, Box::new(e.into()))),
+        };
+        if attempt >= client.connection_attempts {
+            return Err(Error::ConnectFailed(addrs, Box::new(err)));
+        }
+
+        debug(client.verbosity, 1, || {
+            format!("Connection attempt {attempt} failed: {err}")
+        });
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+        attempt += 1;
+    }
+}
+
+/// Applies the client's socket options to a connected TCP stream
+fn configure_socket(stream: &TcpStream, client: &Client) -> io::Result<()> {
+    stream.set_nodelay(client.nodelay)?;
+    if let Some(idle) = client.keepalive {
+        let keepalive = TcpKeepalive::new().with_time(idle);
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// Opens a TCP connection to one of the host's addresses from the first free local port in
+/// the range
+async fn connect_tcp_from(
+    addrs: &[SocketAddr],
+    ports: &RangeInclusive<u16>,
+) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "Host has no addresses");
+    for &addr in addrs {
+        let unspecified: IpAddr = match addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+
+        last_err = io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "No port in the source port range is available",
+        );
+        for source_port in ports.clone() {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            match socket.bind(SocketAddr::new(unspecified, source_port)) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e),
+            }
+
+            match socket.connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                // The port is free locally but already connected to this address
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+                    ) => {}
+                Err(e) => {
+                    last_err = e;
+                    break;
+                }
+            }
+        }
+    }
+    Err(last_err)
+}
+
+/// Sends a single SSH packet with the given payload
+async fn send_packet(
+    stream: &mut BufReader<TcpStream>,
+    payload: &[u8],
+    encrypter: Option<&mut Encrypter>,
+) -> Result<(), Error> {
+    let packet = SshStream::seal(payload, encrypter)?;
+    stream.get_mut().write_all(&packet).await?;
+    Ok(())
+}
+
+/// Returns the packet type and payload of the next SSH packet, rejecting packets longer than
+/// max_packet bytes
+async fn read_packet(
+    stream: &mut BufReader<TcpStream>,
+    mut decrypter: Option<&mut Decrypter>,
+    max_packet: usize,
+) -> Result<(u8, Vec<u8>), Error> {
+    // Get the first block of the packet
+    let mut block: Vec<u8> = vec![0; SshStream::first_block_size(decrypter.as_deref())];
+    stream.read_exact(&mut block).await?;
+    let (packet, rest_length) =
+        SshStream::open_first_block(block, decrypter.as_deref_mut(), max_packet)?;
+
+    // Get rest of packet and its mac
+    let mut rest: Vec<u8> = vec![0; rest_length];
+    stream.read_exact(&mut rest).await?;
+
+    SshStream::open_rest(packet, rest, decrypter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SSH_MSG_REQUEST_FAILURE;
+    use crate::encrypter::loopback_pair;
+    use crate::protocol::{gen_keepalive, gen_unimplemented};
+    use crate::ssh_stream::DEFAULT_MAX_PACKET;
+
+    /// Runs a future to completion on a runtime of its own
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// Returns a client already past key exchange on one end of a loopback connection along
+    /// with the host's end and the keys the host uses. Must be called within a runtime.
+    fn loopback_client() -> (AsyncClient, SshStream, Encrypter, Decrypter) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (host, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        let (host_encrypter, decrypter) = loopback_pair("aes128-ctr", "hmac-sha2-256", "none");
+        let (encrypter, host_decrypter) = loopback_pair("aes128-ctr", "hmac-sha2-256", "none");
+        let client = AsyncClient {
+            stream: BufReader::new(TcpStream::from_std(stream).unwrap()),
+            encrypter,
+            decrypter,
+            next_channel: 0,
+            host_version: HostVersion::parse("SSH-2.0-OpenSSH_9.6").unwrap(),
+            hash_prefix: Vec::new(),
+            host_key: Vec::new(),
+            ciphers: Vec::new(),
+            deferred: VecDeque::new(),
+            max_packet: DEFAULT_MAX_PACKET,
+            sequence_number: None,
+            verbosity: 0,
+        };
+        (client, SshStream::new(host), host_encrypter, host_decrypter)
+    }
+
+    #[test]
+    fn unknown_packets_and_global_requests_are_answered() {
+        block_on(async {
+            let (mut client, mut host, mut host_encrypter, mut host_decrypter) = loopback_client();
+
+            // A deferred packet's sequence number is no longer known so it goes unanswered
+            client.deferred.push_back((201, Vec::new()));
+            host.send(&[200], Some(&mut host_encrypter)).unwrap();
+            host.send(&gen_keepalive(), Some(&mut host_encrypter))
+                .unwrap();
+
+            let (channel, _) = Channel::open(0, None, Vec::new(), None);
+            let mut channel = AsyncChannel {
+                client: &mut client,
+                channel,
+                remote_window: 0,
+                pending: VecDeque::new(),
+                ready: false,
+                closed: false,
+            };
+            for _ in 0..3 {
+                channel.process_packet().await.unwrap();
+            }
+
+            // The first packet read from the stream has sequence number 0
+            let (packet_type, data) = host.read(Some(&mut host_decrypter)).unwrap();
+            assert_eq!([vec![packet_type], data].concat(), gen_unimplemented(0));
+            let (packet_type, data) = host.read(Some(&mut host_decrypter)).unwrap();
+            assert_eq!((packet_type, data), (SSH_MSG_REQUEST_FAILURE, Vec::new()));
+        });
+    }
+}
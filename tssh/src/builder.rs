@@ -0,0 +1,577 @@
+#[cfg(feature = "async")]
+use crate::AsyncClient;
+use crate::encrypter::is_implemented;
+use crate::ssh_stream::{DEFAULT_MAX_PACKET, SshStream};
+use crate::{
+    AddressFamily, CBC_ALGS, Connection, DEFAULT_ENCRYPT_ALGS, ENCRYPT_ALGS, Error, OutputSink,
+    QUERY_CATEGORIES, StdinEofAction, StdioSink, connect,
+};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The port SSH servers listen on by default
+const DEFAULT_PORT: u16 = 22;
+
+/// The number of times to try connecting by default
+const DEFAULT_CONNECTION_ATTEMPTS: u32 = 1;
+
+/// The environment variables sent to the host by default
+const DEFAULT_SEND_ENV: [&str; 3] = ["TERM", "LANG", "LC_*"];
+
+/// The number of unanswered server alive checks before the host is considered dead by default
+const DEFAULT_SERVER_ALIVE_COUNT_MAX: u32 = 3;
+
+/// Configures a connection to a host one option at a time. Only the host and username
+/// are required.
+///
+/// ```no_run
+/// # fn example() -> Result<(), tssh::Error> {
+/// let client = tssh::ClientBuilder::new()
+///     .host("example.com")
+///     .port(2222)
+///     .username("user")
+///     .timeout(std::time::Duration::from_secs(10))
+///     .build()?;
+///
+/// // Opens an interactive shell in the local terminal
+/// client.run()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ClientBuilder {
+    /// The name or ip address of the host
+    host: Option<String>,
+    /// The port the host is listening on
+    port: Option<u16>,
+    /// The username to sign in as
+    username: Option<String>,
+    /// The password to try before prompting the user for one
+    password: Option<String>,
+    /// How long to wait for the TCP connection to be established
+    timeout: Option<Duration>,
+    /// How many times to try connecting when the host times out or refuses
+    connection_attempts: Option<u32>,
+    /// Which kinds of addresses the host may be connected to at
+    address_family: AddressFamily,
+    /// The encryption algorithms to offer in order of preference
+    ciphers: Option<Vec<String>>,
+    /// Whether or not CBC ciphers may be offered
+    allow_cbc: bool,
+    /// The file to write exchange hash inputs to for debugging
+    dump_exchange_hash: Option<PathBuf>,
+    /// The file to record the session's output in
+    log_file: Option<PathBuf>,
+    /// The file to record the timing of the session's output in
+    log_timing: Option<PathBuf>,
+    /// The file to record the session's input in
+    log_input: Option<PathBuf>,
+    /// The largest packet accepted from the host in bytes
+    max_packet: Option<usize>,
+    /// Whether or not to send small packets right away instead of waiting to batch them
+    nodelay: Option<bool>,
+    /// How long the connection may sit idle before TCP keepalive probes are sent
+    keepalive: Option<Duration>,
+    /// The private key file to authenticate with
+    identity_file: Option<PathBuf>,
+    /// Fingerprints the host key must match instead of being checked against known_hosts
+    host_key_fingerprints: Vec<String>,
+    /// The patterns of the environment variables to send to the host
+    send_env: Option<Vec<String>>,
+    /// Whether or not to try hostbased authentication with this machine's host key
+    hostbased: bool,
+    /// The local ports the connection may be made from
+    source_ports: Option<RangeInclusive<u16>>,
+    /// How long to go without hearing from the host before checking that it is alive
+    server_alive_interval: Option<Duration>,
+    /// How many checks can go unanswered before the host is considered dead
+    server_alive_count_max: Option<u32>,
+    /// How long a session may run before it is ended
+    session_timeout: Option<Duration>,
+    /// How much debugging output to print
+    verbosity: u8,
+    /// What to do once piped input ends
+    stdin_eof_action: StdinEofAction,
+    /// Whether or not to request a pseudo-terminal for sessions
+    request_pty: Option<bool>,
+    /// The character that starts escape sequences, which may be set to none
+    escape_char: Option<Option<u8>>,
+}
+
+/// A configured connection to a host created by ClientBuilder
+pub struct Client {
+    /// The name or ip address of the host
+    pub(crate) host: String,
+    /// The port the host is listening on
+    pub(crate) port: u16,
+    /// The username to sign in as
+    pub(crate) username: String,
+    /// The password to try before prompting the user for one
+    pub(crate) password: Option<String>,
+    /// How long to wait for the TCP connection to be established
+    pub(crate) timeout: Option<Duration>,
+    /// How many times to try connecting when the host times out or refuses
+    pub(crate) connection_attempts: u32,
+    /// Which kinds of addresses the host may be connected to at
+    pub(crate) address_family: AddressFamily,
+    /// The encryption algorithms to offer in order of preference
+    pub(crate) ciphers: Vec<&'static str>,
+    /// The file to write exchange hash inputs to for debugging
+    pub(crate) dump_exchange_hash: Option<PathBuf>,
+    /// The file to record the session's output in
+    pub(crate) log_file: Option<PathBuf>,
+    /// The file to record the timing of the session's output in
+    pub(crate) log_timing: Option<PathBuf>,
+    /// The file to record the session's input in
+    pub(crate) log_input: Option<PathBuf>,
+    /// The largest packet accepted from the host in bytes
+    pub(crate) max_packet: usize,
+    /// Whether or not to send small packets right away instead of waiting to batch them
+    pub(crate) nodelay: bool,
+    /// How long the connection may sit idle before TCP keepalive probes are sent
+    pub(crate) keepalive: Option<Duration>,
+    /// The private key file to authenticate with
+    pub(crate) identity_file: Option<PathBuf>,
+    /// Fingerprints the host key must match instead of being checked against known_hosts
+    pub(crate) host_key_fingerprints: Vec<String>,
+    /// The patterns of the environment variables to send to the host
+    pub(crate) send_env: Vec<String>,
+    /// Whether or not to try hostbased authentication with this machine's host key
+    pub(crate) hostbased: bool,
+    /// The local ports the connection may be made from
+    pub(crate) source_ports: Option<RangeInclusive<u16>>,
+    /// How long to go without hearing from the host before checking that it is alive
+    pub(crate) server_alive_interval: Option<Duration>,
+    /// How many checks can go unanswered before the host is considered dead
+    pub(crate) server_alive_count_max: u32,
+    /// How long a session may run before it is ended
+    pub(crate) session_timeout: Option<Duration>,
+    /// How much debugging output to print
+    pub(crate) verbosity: u8,
+    /// What to do once piped input ends
+    pub(crate) stdin_eof_action: StdinEofAction,
+    /// Whether or not to request a pseudo-terminal for sessions, or None to decide from the
+    /// command and stdin
+    pub(crate) request_pty: Option<bool>,
+    /// The character that starts escape sequences in the interactive session if any
+    pub(crate) escape_char: Option<u8>,
+}
+
+impl ClientBuilder {
+    /// Creates a builder with nothing configured
+    pub fn new() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// Sets the name or ip address of the host to connect to
+    pub fn host(mut self, host: impl Into<String>) -> ClientBuilder {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Sets the port the host is listening on. Defaults to 22.
+    pub fn port(mut self, port: u16) -> ClientBuilder {
+        self.port = Some(port);
+        self
+    }
+
+    /// Sets the username to sign in as
+    pub fn username(mut self, username: impl Into<String>) -> ClientBuilder {
+        self.username = Some(username.into());
+        self
+    }
+
+    /// Sets a password to try first. If the host rejects it the user is prompted instead.
+    pub fn password(mut self, password: impl Into<String>) -> ClientBuilder {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Sets how long to wait for the TCP connection before giving up. Waits for as long as
+    /// the operating system allows by default.
+    pub fn timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how many times to try connecting when the host times out or refuses the
+    /// connection, waiting longer between each attempt. Only tries once by default.
+    pub fn connection_attempts(mut self, attempts: u32) -> ClientBuilder {
+        self.connection_attempts = Some(attempts);
+        self
+    }
+
+    /// Only connects to the host's IPv4 or IPv6 addresses. Defaults to any address, trying
+    /// each in the order they resolved.
+    pub fn address_family(mut self, family: AddressFamily) -> ClientBuilder {
+        self.address_family = family;
+        self
+    }
+
+    /// Limits the encryption algorithms offered to the host to the given ones in order of
+    /// preference. Defaults to every supported algorithm except CBC ciphers, which can only
+    /// be given once allowed with allow_cbc.
+    pub fn ciphers<S: AsRef<str>>(mut self, ciphers: &[S]) -> ClientBuilder {
+        self.ciphers = Some(ciphers.iter().map(|s| s.as_ref().to_string()).collect());
+        self
+    }
+
+    /// Offers aes256-cbc after every other cipher for legacy hosts that support nothing
+    /// better. CBC has known weaknesses in SSH so it is never offered unless allowed here.
+    pub fn allow_cbc(mut self, allow: bool) -> ClientBuilder {
+        self.allow_cbc = allow;
+        self
+    }
+
+    /// Appends every value fed into the exchange hash of each key exchange, and the hash
+    /// itself, to the given file for debugging signature failures. The file will contain
+    /// the session's shared secret so this is off by default.
+    pub fn dump_exchange_hash(mut self, path: impl Into<PathBuf>) -> ClientBuilder {
+        self.dump_exchange_hash = Some(path.into());
+        self
+    }
+
+    /// Sets the largest packet in bytes accepted from the host. Defaults to 35000, the smallest
+    /// the spec allows, and may be raised up to 262144 for bulk transfers.
+    pub fn max_packet(mut self, max_packet: usize) -> ClientBuilder {
+        self.max_packet = Some(max_packet);
+        self
+    }
+
+    /// Sets whether or not TCP_NODELAY is set on the socket so that keystrokes are sent
+    /// right away instead of being batched. Defaults to true.
+    pub fn nodelay(mut self, nodelay: bool) -> ClientBuilder {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Turns on TCP keepalive probes after the connection has been idle for the given time so
+    /// that a dead connection is noticed. Off by default.
+    pub fn keepalive(mut self, idle: Duration) -> ClientBuilder {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Sets the unencrypted OpenSSH private key file to offer before asking for a password.
+    /// Defaults to ~/.ssh/id_rsa if it exists.
+    pub fn identity_file(mut self, path: impl Into<PathBuf>) -> ClientBuilder {
+        self.identity_file = Some(path.into());
+        self
+    }
+
+    /// Records everything the host sends during a session to the given file, replacing it if
+    /// it exists. The raw bytes are written like the script command does so that the log
+    /// replays escape sequences faithfully. Off by default.
+    pub fn log_file(mut self, path: impl Into<PathBuf>) -> ClientBuilder {
+        self.log_file = Some(path.into());
+        self
+    }
+
+    /// Records the delay before and length of each chunk of the log file to the given file in
+    /// the format of script -t, so the session can be replayed with scriptreplay
+    pub fn log_timing(mut self, path: impl Into<PathBuf>) -> ClientBuilder {
+        self.log_timing = Some(path.into());
+        self
+    }
+
+    /// Records everything sent to the host during a session to the given file, separately
+    /// from the log file since the host echoes typed input back into it. Off by default.
+    pub fn log_input(mut self, path: impl Into<PathBuf>) -> ClientBuilder {
+        self.log_input = Some(path.into());
+        self
+    }
+
+    /// Pins a SHA256:... fingerprint the host key must match, in which case known_hosts is
+    /// neither checked nor updated. Can be given more than once to allow any of several keys
+    /// such as while the host's key is being rotated.
+    pub fn host_key_fingerprint(mut self, fingerprint: impl Into<String>) -> ClientBuilder {
+        self.host_key_fingerprints.push(fingerprint.into());
+        self
+    }
+
+    /// Sets which local environment variables are sent to the host before the interactive
+    /// shell or command starts, replacing the default of TERM, LANG and LC_*. Patterns may use
+    /// * and ? and an empty list sends nothing. The host ignores any it doesn't accept.
+    pub fn send_env<S: AsRef<str>>(mut self, patterns: &[S]) -> ClientBuilder {
+        self.send_env = Some(patterns.iter().map(|s| s.as_ref().to_string()).collect());
+        self
+    }
+
+    /// Tries hostbased authentication after any private key, signing the request with this
+    /// machine's host key from /etc/ssh/ssh_host_rsa_key. Reading the host key requires
+    /// running as root. Off by default.
+    pub fn hostbased(mut self, enabled: bool) -> ClientBuilder {
+        self.hostbased = enabled;
+        self
+    }
+
+    /// Makes the connection from a local port within the range, for firewalls that only let
+    /// certain source ports out. Ports that are in use are skipped. Any port by default.
+    pub fn source_port_range(mut self, ports: RangeInclusive<u16>) -> ClientBuilder {
+        self.source_ports = Some(ports);
+        self
+    }
+
+    /// Asks the host to reply whenever the interactive session has gone the given time without
+    /// hearing from it, ending the session once too many go unanswered. Off by default.
+    pub fn server_alive_interval(mut self, interval: Duration) -> ClientBuilder {
+        self.server_alive_interval = Some(interval);
+        self
+    }
+
+    /// Sets how many checks from server_alive_interval can go unanswered before the host is
+    /// considered dead. Anything recieved from the host counts as an answer. Defaults to 3.
+    pub fn server_alive_count_max(mut self, count: u32) -> ClientBuilder {
+        self.server_alive_count_max = Some(count);
+        self
+    }
+
+    /// Ends the interactive session or command with Error::SessionTimeout once it has run for
+    /// the given time, however active it is. Off by default.
+    pub fn session_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.session_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets what the interactive session does once local input ends when it is piped in
+    /// instead of typed in a terminal. Defaults to sending an end of transmission character
+    /// like Ctrl-D.
+    pub fn stdin_eof_action(mut self, action: StdinEofAction) -> ClientBuilder {
+        self.stdin_eof_action = action;
+        self
+    }
+
+    /// Sets whether or not the interactive session or command gets a pseudo-terminal. Without
+    /// one output is passed through untouched by terminal control codes or echo, which suits
+    /// piping. Defaults to requesting one only for an interactive shell when stdin is a
+    /// terminal, so commands get none unless this is set.
+    pub fn request_pty(mut self, request_pty: bool) -> ClientBuilder {
+        self.request_pty = Some(request_pty);
+        self
+    }
+
+    /// Sets the character that starts escape sequences such as ~. at the start of a line in the
+    /// interactive session, or None to send every key to the host. Defaults to ~.
+    pub fn escape_char(mut self, escape_char: Option<u8>) -> ClientBuilder {
+        self.escape_char = Some(escape_char);
+        self
+    }
+
+    /// Prints debugging output to stderr. At 1 the connection's progress, the negotiated
+    /// algorithms, and the type of every packet are printed and at 2 packet payloads are
+    /// printed as well. Defaults to 0, which prints nothing.
+    pub fn verbose(mut self, verbosity: u8) -> ClientBuilder {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Creates the client. Returns an error if the host or username were not set or if an
+    /// unsupported or disallowed cipher, out of bounds maximum packet size, or empty source
+    /// port range was given.
+    pub fn build(self) -> Result<Client, Error> {
+        let host = match self.host {
+            Some(host) => host,
+            None => return Err(Error::Other("No host was given to connect to")),
+        };
+        let username = match self.username {
+            Some(username) => username,
+            None => return Err(Error::Other("No username was given to sign in as")),
+        };
+
+        let ciphers = match self.ciphers {
+            Some(names) => names
+                .iter()
+                .map(
+                    |name| match ENCRYPT_ALGS.iter().find(|alg| **alg == name.as_str()) {
+                        Some(alg) => Ok(*alg),
+                        None => Err(Error::Other("Unsupported cipher: See tssh -Q cipher")),
+                    },
+                )
+                .collect::<Result<Vec<_>, _>>()?,
+            None if self.allow_cbc => ENCRYPT_ALGS.to_vec(),
+            None => DEFAULT_ENCRYPT_ALGS.to_vec(),
+        };
+        if !self.allow_cbc && ciphers.iter().any(|cipher| CBC_ALGS.contains(cipher)) {
+            return Err(Error::Other(
+                "CBC ciphers are insecure: They must be allowed with --allow-cbc",
+            ));
+        }
+        if ciphers.is_empty() {
+            return Err(Error::Other("No ciphers were given to offer the host"));
+        }
+
+        // The host can pick any advertised algorithm so each must be usable
+        let implemented = QUERY_CATEGORIES
+            .iter()
+            .all(|(category, names)| names.iter().all(|name| is_implemented(category, name)));
+        if !implemented {
+            return Err(Error::Other(
+                "An advertised algorithm has no implementation",
+            ));
+        }
+
+        // Only SHA-256 fingerprints are computed so anything else could never match
+        if !self
+            .host_key_fingerprints
+            .iter()
+            .all(|fingerprint| fingerprint.starts_with("SHA256:"))
+        {
+            return Err(Error::Other(
+                "Invalid host key fingerprint: Expected SHA256:...",
+            ));
+        }
+
+        if self.connection_attempts == Some(0) {
+            return Err(Error::Other("At least one connection attempt is needed"));
+        }
+
+        if self.log_timing.is_some() && self.log_file.is_none() {
+            return Err(Error::Other("A timing file needs a log file to go with it"));
+        }
+
+        let max_packet = self.max_packet.unwrap_or(DEFAULT_MAX_PACKET);
+        SshStream::check_max_packet(max_packet)?;
+
+        if let Some(ports) = &self.source_ports
+            && (ports.is_empty() || *ports.start() == 0)
+        {
+            return Err(Error::Other("Invalid source port range"));
+        }
+
+        Ok(Client {
+            host,
+            port: self.port.unwrap_or(DEFAULT_PORT),
+            username,
+            password: self.password,
+            timeout: self.timeout,
+            connection_attempts: self
+                .connection_attempts
+                .unwrap_or(DEFAULT_CONNECTION_ATTEMPTS),
+            address_family: self.address_family,
+            ciphers,
+            dump_exchange_hash: self.dump_exchange_hash,
+            log_file: self.log_file,
+            log_timing: self.log_timing,
+            log_input: self.log_input,
+            max_packet,
+            nodelay: self.nodelay.unwrap_or(true),
+            keepalive: self.keepalive,
+            identity_file: self.identity_file,
+            host_key_fingerprints: self.host_key_fingerprints,
+            send_env: self
+                .send_env
+                .unwrap_or_else(|| DEFAULT_SEND_ENV.map(str::to_string).to_vec()),
+            hostbased: self.hostbased,
+            source_ports: self.source_ports,
+            server_alive_interval: self.server_alive_interval,
+            session_timeout: self.session_timeout,
+            verbosity: self.verbosity,
+            stdin_eof_action: self.stdin_eof_action,
+            request_pty: self.request_pty,
+            escape_char: self.escape_char.unwrap_or(Some(b'~')),
+            server_alive_count_max: self
+                .server_alive_count_max
+                .unwrap_or(DEFAULT_SERVER_ALIVE_COUNT_MAX),
+        })
+    }
+}
+
+impl Client {
+    /// Connects to the host, authenticates, and runs an interactive shell in the local
+    /// terminal until the host closes the session
+    pub fn run(&self) -> Result<(), Error> {
+        connect(self)?.open_session(&mut StdioSink)
+    }
+
+    /// Runs an interactive shell like run but hands everything the host sends to the given
+    /// sink instead of writing it to the local terminal
+    ///
+    /// ```no_run
+    /// # fn example(client: tssh::Client) -> Result<(), tssh::Error> {
+    /// let mut output = tssh::BufferSink::default();
+    /// client.run_with_sink(&mut output)?;
+    /// println!("{}", String::from_utf8_lossy(&output.stdout));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn run_with_sink(&self, sink: &mut dyn OutputSink) -> Result<(), Error> {
+        connect(self)?.open_session(sink)
+    }
+
+    /// Connects to the host, authenticates, and runs a command in the local terminal instead
+    /// of a shell. Results in the command's exit status if the host reported it.
+    pub fn exec(&self, command: &str) -> Result<Option<u32>, Error> {
+        connect(self)?.exec(command, &mut StdioSink)
+    }
+
+    /// Runs each command in order in a single shell and returns the standard output of each
+    /// one. Useful for driving tools that only work from an interactive shell.
+    ///
+    /// ```no_run
+    /// # fn example(client: tssh::Client) -> Result<(), tssh::Error> {
+    /// let outputs = client.run_commands(&["cd /tmp", "pwd"])?;
+    /// assert_eq!(outputs[1], "/tmp\n");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn run_commands(&self, commands: &[&str]) -> Result<Vec<String>, Error> {
+        connect(self)?.run_commands(commands)
+    }
+
+    /// Connects to the host, authenticates, and starts the sftp subsystem, running get, put
+    /// and ls commands read from standard input until exit is entered
+    pub fn sftp(&self) -> Result<(), Error> {
+        connect(self)?.sftp()
+    }
+
+    /// Connects to the host and authenticates without opening any channels so that a
+    /// session can be opened later, or never for a connection that is only kept open
+    pub fn connect(&self) -> Result<Connection<'_>, Error> {
+        connect(self)
+    }
+
+    /// Connects to the host with the async client and authenticates with the configured
+    /// password, which is required since the async client never prompts for one.
+    ///
+    /// ```no_run
+    /// # async fn example() -> Result<(), tssh::Error> {
+    /// let mut client = tssh::ClientBuilder::new()
+    ///     .host("example.com")
+    ///     .username("user")
+    ///     .password("password")
+    ///     .build()?
+    ///     .connect_async()
+    ///     .await?;
+    ///
+    /// let output = client.exec("uname -a").await?;
+    /// println!("{}", String::from_utf8_lossy(&output.stdout));
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "async")]
+    pub async fn connect_async(&self) -> Result<AsyncClient, Error> {
+        let password = match &self.password {
+            Some(password) => password,
+            None => {
+                return Err(Error::Other(
+                    "A password is required to authenticate the async client",
+                ));
+            }
+        };
+
+        let connect = AsyncClient::connect_client(self);
+        let mut client = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, connect).await {
+                Ok(client) => client?,
+                Err(_) => return Err(Error::ConnectTimeout),
+            },
+            None => connect.await?,
+        };
+
+        client
+            .authenticate_password(&self.username, password)
+            .await?;
+        Ok(client)
+    }
+}
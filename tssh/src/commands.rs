@@ -0,0 +1,190 @@
+use crate::connection::Connection;
+use crate::encrypter::Encrypter;
+use crate::protocol::{Channel, ChannelOutput, Channels};
+use crate::ssh_stream::SshStream;
+use crate::terminal::release_interrupt;
+use crate::{
+    Error, SSH_MSG_CHANNEL_DATA, SSH_MSG_KEXINIT, connection_reply, key_rexchange,
+    last_sequence_number, rekey,
+};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+/// Runs each command in order in a single shell without a pseudo-terminal and returns the
+/// standard output of each one. The end of each command's output is found by echoing a
+/// sentinel after it, which is stripped from the output. Standard error is written to the
+/// local standard error as it arrives.
+pub fn run_commands(connection: Connection, commands: &[&str]) -> Result<Vec<String>, Error> {
+    let Connection {
+        client,
+        mut stream,
+        mut encrypter,
+        mut decrypter,
+        hash_prefix,
+    } = connection;
+    release_interrupt();
+
+    // Without a pseudo-terminal the shell does not echo the commands back
+    let mut channels = Channels::new();
+    let (shell, payload) = channels.open_session(None, Vec::new(), None);
+    stream.send(&payload, Some(&mut encrypter))?;
+
+    // The sentinels are made unique to this run so that command output can't match them
+    let nonce: u32 = rand::random();
+    let sentinels: Vec<String> = (0..commands.len())
+        .map(|n| format!("__TSSH_DONE_{nonce:08x}_{n}__\n"))
+        .collect();
+
+    let mut input = Vec::new();
+    for (command, sentinel) in commands.iter().zip(&sentinels) {
+        input.extend(format!("{command}\necho {sentinel}").as_bytes());
+    }
+
+    let mut remote_window: u64 = 0;
+    let mut output = Vec::new();
+    let mut outputs = Vec::new();
+    let mut ready = false;
+    let mut closing = false;
+    let mut deferred = VecDeque::new();
+
+    // Keep reading until the host closes the channel so that nothing it queued is lost
+    loop {
+        let rekey_due = encrypter.needs_rekey() || decrypter.needs_rekey();
+        if rekey_due && deferred.is_empty() {
+            let (new_enc, new_dec, packets) = rekey(
+                &mut stream,
+                hash_prefix.clone(),
+                client,
+                &mut encrypter,
+                &mut decrypter,
+            )?;
+            encrypter = new_enc;
+            decrypter = new_dec;
+            deferred.extend(packets);
+        }
+
+        let from_stream = deferred.is_empty();
+        let (packet_type, data) = match deferred.pop_front() {
+            Some(packet) => packet,
+            None => stream.read(Some(&mut decrypter))?,
+        };
+        let sequence_number = from_stream.then(|| last_sequence_number(&decrypter));
+        if packet_type == SSH_MSG_KEXINIT {
+            let (new_enc, new_dec) = key_rexchange(
+                data,
+                &mut stream,
+                hash_prefix.clone(),
+                client,
+                &mut encrypter,
+                &mut decrypter,
+            )?;
+            encrypter = new_enc;
+            decrypter = new_dec;
+            continue;
+        }
+
+        let (_, outs) = channels.handle(packet_type, &data)?;
+        for out in outs {
+            match out {
+                ChannelOutput::Send(payload) => stream.send(&payload, Some(&mut encrypter))?,
+                output @ (ChannelOutput::GlobalRequest(..) | ChannelOutput::Unknown(_)) => {
+                    if let Some(reply) =
+                        connection_reply(&output, sequence_number, client.verbosity)
+                    {
+                        stream.send(&reply, Some(&mut encrypter))?;
+                    }
+                }
+                ChannelOutput::WindowAdjust(amount) => remote_window += amount,
+                ChannelOutput::Ready => ready = true,
+                ChannelOutput::Stdout(data) => output.extend(data),
+                ChannelOutput::Stderr(data) => {
+                    io::stderr().write_all(&data)?;
+                    io::stderr().flush()?;
+                }
+                ChannelOutput::Eof if closing => (),
+                ChannelOutput::Closed if closing => {
+                    io::stderr().flush()?;
+                    stream.disconnect(b"Done with commands", Some(&mut encrypter))?;
+                    return Ok(outputs);
+                }
+                ChannelOutput::Eof | ChannelOutput::Closed => {
+                    return Err(Error::Other("Shell exited before every command finished"));
+                }
+                ChannelOutput::OpenFailed(..) => {
+                    return Err(Error::Other("Host failed to open the shell channel"));
+                }
+                ChannelOutput::ExitStatus(_)
+                | ChannelOutput::ForwardDenied(_)
+                | ChannelOutput::Warning(_)
+                | ChannelOutput::Debug(..) => (),
+            }
+        }
+
+        // Split off the output of every command whose sentinel has arrived, which may have
+        // taken more than one packet
+        while let Some(sentinel) = sentinels.get(outputs.len()) {
+            let end = match find(&output, sentinel.as_bytes()) {
+                Some(end) => end,
+                None => break,
+            };
+            let rest = output.split_off(end + sentinel.len());
+            output.truncate(end);
+            outputs.push(String::from_utf8_lossy(&output).to_string());
+            output = rest;
+        }
+
+        if ready {
+            send_input(
+                &mut stream,
+                &mut encrypter,
+                channels.get(shell)?,
+                &mut remote_window,
+                &mut input,
+            )?;
+        }
+
+        // Every command is done so the channel can be closed once the host closes its side
+        if outputs.len() == commands.len() && !closing {
+            closing = true;
+            let channel = channels.get_mut(shell)?;
+            stream.send(&channel.eof_message(), Some(&mut encrypter))?;
+            if let Some(message) = channel.close_message() {
+                stream.send(&message, Some(&mut encrypter))?;
+            }
+        }
+    }
+}
+
+/// Sends as much of the pending input as fits in the host's window and removes what was sent
+fn send_input(
+    stream: &mut SshStream,
+    encrypter: &mut Encrypter,
+    channel: &Channel,
+    window: &mut u64,
+    input: &mut Vec<u8>,
+) -> Result<(), Error> {
+    while !input.is_empty() && *window != 0 {
+        let len = input
+            .len()
+            .min(channel.packet_max() as usize)
+            .min(*window as usize);
+        let rest = input.split_off(len);
+
+        let mut packet = vec![SSH_MSG_CHANNEL_DATA];
+        packet.extend(channel.server_channel().to_be_bytes());
+        SshStream::append_string(&mut packet, input);
+        stream.send(&packet, Some(&mut *encrypter))?;
+
+        *window -= len as u64;
+        *input = rest;
+    }
+
+    Ok(())
+}
+
+/// Returns the index of the first occurrence of needle in haystack
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
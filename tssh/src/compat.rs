@@ -0,0 +1,90 @@
+use crate::Error;
+
+/// Host software with known interoperability problems keyed on the start of its software version
+const KNOWN_QUIRKS: [(&str, Quirk); 5] = [
+    ("OpenSSH_4.", Quirk::NoRsaSha2),
+    ("OpenSSH_5.", Quirk::NoRsaSha2),
+    ("OpenSSH_6.", Quirk::NoRsaSha2),
+    ("OpenSSH_7.0", Quirk::NoRsaSha2),
+    ("OpenSSH_7.1", Quirk::NoRsaSha2),
+];
+
+/// Interoperability problems of specific host software that tssh has to work around
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quirk {
+    /// The host predates the rsa-sha2-512 host key algorithm so a key exchange can never succeed
+    NoRsaSha2,
+}
+
+/// The identification line sent by the host during version exchange split into its parts
+#[derive(Clone, Debug)]
+pub struct HostVersion {
+    /// The protocol version which is 1.99 for hosts that also accept SSH-1 clients
+    pub proto_version: String,
+    /// The name and version of the host's SSH software such as OpenSSH_9.6
+    pub software_version: String,
+    /// The free form comments following the software version if there are any
+    pub comments: Option<String>,
+}
+
+impl HostVersion {
+    /// Splits a version line with its return characters removed into its parts.
+    /// Returns an error if the host is not using SSH-2.0.
+    pub fn parse(line: &str) -> Result<HostVersion, Error> {
+        let line = match line.strip_prefix("SSH-") {
+            Some(line) => line,
+            None => {
+                return Err(Error::Other(
+                    "Recieved invalid version info: Expected SSH- prefix",
+                ));
+            }
+        };
+
+        let (proto_version, rest) = match line.split_once('-') {
+            Some(parts) => parts,
+            None => {
+                return Err(Error::Other(
+                    "Recieved invalid version info: Expected software version",
+                ));
+            }
+        };
+
+        // Hosts that report 1.99 still speak SSH-2.0
+        if !proto_version.starts_with("2.") && proto_version != "1.99" {
+            return Err(Error::Other(
+                "Incompatible version: Host is not using SSH-2.0 but TSSH is",
+            ));
+        }
+
+        let (software_version, comments) = match rest.split_once(' ') {
+            Some((software_version, comments)) => (software_version, Some(comments.to_string())),
+            None => (rest, None),
+        };
+
+        Ok(HostVersion {
+            proto_version: proto_version.to_string(),
+            software_version: software_version.to_string(),
+            comments,
+        })
+    }
+
+    /// Returns every known quirk of the host's software
+    pub fn quirks(&self) -> Vec<Quirk> {
+        KNOWN_QUIRKS
+            .iter()
+            .filter(|(prefix, _)| self.software_version.starts_with(*prefix))
+            .map(|(_, quirk)| *quirk)
+            .collect()
+    }
+
+    /// Returns an error if the host's software is known to be unable to finish a key
+    /// exchange with tssh so that it is reported clearly instead of as a failed negotiation
+    pub fn check_compatible(&self) -> Result<(), Error> {
+        if self.quirks().contains(&Quirk::NoRsaSha2) {
+            return Err(Error::Other(
+                "Incompatible host: Host software predates the rsa-sha2-512 host keys TSSH requires",
+            ));
+        }
+        Ok(())
+    }
+}
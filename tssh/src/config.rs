@@ -0,0 +1,222 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// The options of an OpenSSH client config file such as ~/.ssh/config. Only HostName, User,
+/// Port, IdentityFile, SendEnv, ServerAliveInterval and ServerAliveCountMax are used and
+/// Match blocks are skipped.
+#[derive(Default)]
+pub struct SshConfig {
+    /// Each Host block in order, starting with the options before the first one which apply
+    /// to every host
+    blocks: Vec<HostBlock>,
+}
+
+/// A Host line and the options that follow it
+struct HostBlock {
+    /// The host patterns, any of which can match unless a negated one does
+    patterns: Vec<String>,
+    /// The keyword of each option in lowercase along with its value
+    options: Vec<(String, String)>,
+}
+
+/// The options a config file gives for a host. Command line options take precedence.
+#[derive(Default, Debug, PartialEq)]
+pub struct HostConfig<'a> {
+    /// The real hostname to connect to
+    pub hostname: Option<&'a str>,
+    /// The username to sign in as
+    pub user: Option<&'a str>,
+    /// The port the host is listening on
+    pub port: Option<u16>,
+    /// The private key to authenticate with
+    pub identity_file: Option<&'a str>,
+    /// The patterns of the environment variables to send, from every matching block
+    pub send_env: Vec<&'a str>,
+    /// The seconds without hearing from the host before checking it is alive
+    pub server_alive_interval: Option<u64>,
+    /// The number of unanswered checks before the host is considered dead
+    pub server_alive_count_max: Option<u32>,
+}
+
+impl SshConfig {
+    /// Loads ~/.ssh/config, which is treated as empty if it is missing or unreadable
+    pub fn load() -> SshConfig {
+        match path().and_then(|path| fs::read_to_string(path).ok()) {
+            Some(text) => SshConfig::parse(&text),
+            None => SshConfig::default(),
+        }
+    }
+
+    /// Parses the text of a config file. Lines that can't be understood are ignored.
+    pub fn parse(text: &str) -> SshConfig {
+        let mut blocks = vec![HostBlock {
+            patterns: vec!["*".to_string()],
+            options: Vec::new(),
+        }];
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // Keywords are separated from their values by whitespace or an equals sign
+            let (keyword, value) = match line.split_once(|c: char| c.is_whitespace() || c == '=') {
+                Some((keyword, value)) => (keyword, value.trim_start_matches([' ', '\t', '='])),
+                None => continue,
+            };
+            let keyword = keyword.to_ascii_lowercase();
+            let value = value.trim().trim_matches('"');
+
+            match keyword.as_str() {
+                "host" => blocks.push(HostBlock {
+                    patterns: value.split_whitespace().map(str::to_string).collect(),
+                    options: Vec::new(),
+                }),
+                // Match blocks take conditions tssh doesn't evaluate so they never apply
+                "match" => blocks.push(HostBlock {
+                    patterns: Vec::new(),
+                    options: Vec::new(),
+                }),
+                "identityfile" => {
+                    let last = blocks.len() - 1;
+                    blocks[last].options.push((keyword, expand_home(value)));
+                }
+                _ => {
+                    let last = blocks.len() - 1;
+                    blocks[last].options.push((keyword, value.to_string()));
+                }
+            }
+        }
+
+        SshConfig { blocks }
+    }
+
+    /// Returns the options for a host as given on the command line. Like OpenSSH the first
+    /// value found for each option in any matching block is the one used, except for
+    /// SendEnv whose patterns all add up.
+    pub fn lookup(&self, host: &str) -> HostConfig<'_> {
+        let mut config = HostConfig::default();
+        let options = self
+            .blocks
+            .iter()
+            .filter(|block| block.matches(host))
+            .flat_map(|block| &block.options);
+        for (keyword, value) in options {
+            match keyword.as_str() {
+                "hostname" if config.hostname.is_none() => config.hostname = Some(value),
+                "user" if config.user.is_none() => config.user = Some(value),
+                "port" if config.port.is_none() => config.port = value.parse().ok(),
+                "identityfile" if config.identity_file.is_none() => {
+                    config.identity_file = Some(value)
+                }
+                "sendenv" => config.send_env.extend(value.split_whitespace()),
+                "serveraliveinterval" if config.server_alive_interval.is_none() => {
+                    config.server_alive_interval = value.parse().ok()
+                }
+                "serveralivecountmax" if config.server_alive_count_max.is_none() => {
+                    config.server_alive_count_max = value.parse().ok()
+                }
+                _ => (),
+            }
+        }
+        config
+    }
+}
+
+impl HostBlock {
+    /// Returns whether or not the block applies to a host, which is when one of its patterns
+    /// matches and none of its negated patterns do
+    fn matches(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let mut matched = false;
+        for pattern in &self.patterns {
+            let pattern = pattern.to_ascii_lowercase();
+            match pattern.strip_prefix('!') {
+                Some(negated) if glob_match(negated.as_bytes(), host.as_bytes()) => return false,
+                Some(_) => (),
+                None => matched |= glob_match(pattern.as_bytes(), host.as_bytes()),
+            }
+        }
+        matched
+    }
+}
+
+/// Matches text against a pattern where * matches any run of characters and ? matches any
+/// single character
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// Replaces a leading ~/ with the home directory if it is known
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{home}/{rest}"),
+        _ => path.to_string(),
+    }
+}
+
+/// Returns the path of the config file if the home directory is known
+fn path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".ssh").join("config"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+# Defaults before any Host line apply everywhere
+User everyone
+
+Host work
+    HostName work.example.com
+    Port=2222
+    IdentityFile \"/keys/work key\"
+    SendEnv LANG LC_*
+
+Host *.example.com !secret.example.com
+    User example
+
+Match exec \"true\"
+    Port 2
+
+Host *
+    Port 22
+    User ignored
+    ServerAliveInterval 15
+";
+
+    #[test]
+    fn first_value_from_any_matching_block_wins() {
+        let config = SshConfig::parse(CONFIG);
+        assert_eq!(
+            config.lookup("work"),
+            HostConfig {
+                hostname: Some("work.example.com"),
+                user: Some("everyone"),
+                port: Some(2222),
+                identity_file: Some("/keys/work key"),
+                send_env: vec!["LANG", "LC_*"],
+                server_alive_interval: Some(15),
+                server_alive_count_max: None,
+            }
+        );
+        assert_eq!(config.lookup("other").port, Some(22));
+    }
+
+    #[test]
+    fn host_patterns_support_globs_and_negation() {
+        let config = SshConfig::parse("Host web?.Example.com !web9.example.com\n User web\n");
+        assert_eq!(config.lookup("web1.example.com").user, Some("web"));
+        assert_eq!(config.lookup("WEB2.EXAMPLE.COM").user, Some("web"));
+        assert_eq!(config.lookup("web10.example.com").user, None);
+        assert_eq!(config.lookup("web9.example.com").user, None);
+    }
+}
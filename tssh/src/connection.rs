@@ -0,0 +1,111 @@
+use crate::builder::Client;
+use crate::encrypter::{Decrypter, Encrypter};
+use crate::ssh_stream::SshStream;
+use crate::{
+    Error, OutputSink, SSH_MSG_CHANNEL_OPEN, SSH_MSG_DISCONNECT, SSH_MSG_GLOBAL_REQUEST,
+    SSH_MSG_KEXINIT, SSH_MSG_REQUEST_FAILURE, commands, key_rexchange, protocol, rekey,
+    run_session, sftp, socks,
+};
+use std::collections::VecDeque;
+
+/// An authenticated connection to a host that only opens session channels when asked to
+pub struct Connection<'a> {
+    /// The configuration the connection was made with
+    pub(crate) client: &'a Client,
+    /// The stream to the host
+    pub(crate) stream: SshStream,
+    /// Encrypts packets sent to the host
+    pub(crate) encrypter: Encrypter,
+    /// Decrypts packets recieved from the host
+    pub(crate) decrypter: Decrypter,
+    /// The version information needed for the exchange hash of key re-exchanges
+    pub(crate) hash_prefix: Vec<u8>,
+}
+
+impl Connection<'_> {
+    /// Opens a session channel with a pseudo-terminal, if the client requests one, and runs an
+    /// interactive shell in the local terminal until the host closes it, handing the host's
+    /// output to the sink
+    pub fn open_session(self, sink: &mut dyn OutputSink) -> Result<(), Error> {
+        run_session(self, None, sink).map(|_| ())
+    }
+
+    /// Opens a session channel like open_session and runs a command instead of a shell,
+    /// handing the host's output to the sink. Results in the command's exit status if the
+    /// host reported it.
+    pub fn exec(self, command: &str, sink: &mut dyn OutputSink) -> Result<Option<u32>, Error> {
+        run_session(self, Some(command), sink)
+    }
+
+    /// Opens a session channel and runs each command in order in a single shell, returning
+    /// the standard output of each one
+    pub fn run_commands(self, commands: &[&str]) -> Result<Vec<String>, Error> {
+        commands::run_commands(self, commands)
+    }
+
+    /// Starts the sftp subsystem and runs get, put and ls commands read from standard input
+    /// until exit is entered or standard input ends
+    pub fn sftp(self) -> Result<(), Error> {
+        sftp::run_sftp(self)
+    }
+
+    /// Serves a SOCKS5 proxy on the bind address and port that tunnels each connection made
+    /// through it over a direct-tcpip channel, until the host disconnects
+    pub fn dynamic_forward(self, bind_address: &str, port: u16) -> Result<(), Error> {
+        socks::run_socks(self, bind_address, port)
+    }
+
+    /// Keeps the connection open without opening any channels until the host disconnects.
+    /// Key re-exchanges are run and every request from the host is refused.
+    pub fn wait(mut self) -> Result<(), Error> {
+        let mut deferred = VecDeque::new();
+        loop {
+            let rekey_due = self.encrypter.needs_rekey() || self.decrypter.needs_rekey();
+            if rekey_due && deferred.is_empty() {
+                let (encrypter, decrypter, packets) = rekey(
+                    &mut self.stream,
+                    self.hash_prefix.clone(),
+                    self.client,
+                    &mut self.encrypter,
+                    &mut self.decrypter,
+                )?;
+                self.encrypter = encrypter;
+                self.decrypter = decrypter;
+                deferred.extend(packets);
+            }
+
+            let (packet_type, data) = match deferred.pop_front() {
+                Some(packet) => packet,
+                None => self.stream.read(Some(&mut self.decrypter))?,
+            };
+            match packet_type {
+                SSH_MSG_DISCONNECT => return Ok(()),
+                SSH_MSG_KEXINIT => {
+                    let (encrypter, decrypter) = key_rexchange(
+                        data,
+                        &mut self.stream,
+                        self.hash_prefix.clone(),
+                        self.client,
+                        &mut self.encrypter,
+                        &mut self.decrypter,
+                    )?;
+                    self.encrypter = encrypter;
+                    self.decrypter = decrypter;
+                }
+                SSH_MSG_GLOBAL_REQUEST => {
+                    // Only reply if the host wants one
+                    let (_, rest) = SshStream::extract_string(&data)?;
+                    if rest.first().is_some_and(|want_reply| *want_reply != 0) {
+                        self.stream
+                            .send(&[SSH_MSG_REQUEST_FAILURE], Some(&mut self.encrypter))?;
+                    }
+                }
+                SSH_MSG_CHANNEL_OPEN => {
+                    let denial = protocol::gen_channel_open_denial(&data)?;
+                    self.stream.send(&denial, Some(&mut self.encrypter))?;
+                }
+                _ => (),
+            }
+        }
+    }
+}
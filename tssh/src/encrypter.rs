@@ -1,25 +1,29 @@
 use crate::Error;
+use crate::ssh_stream::MAX_PACKET_LIMIT;
 use crate::ssh_stream::SshStream;
 use aes::{
-    Aes256,
-    cipher::{
-        BlockEncrypt, KeyInit,
-        consts::{U16, U32},
-        generic_array::GenericArray,
-    },
+    Aes128, Aes192, Aes256,
+    cipher::{BlockDecrypt, BlockEncrypt, KeyInit, consts::U16, generic_array::GenericArray},
 };
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
 use hmac::{Hmac, Mac};
-use p256::{NistP256, ecdh::EphemeralSecret, elliptic_curve::PublicKey};
-use rand_core::OsRng;
+use p256::{
+    NistP256,
+    ecdh::{EphemeralSecret, diffie_hellman},
+    elliptic_curve::{PublicKey, SecretKey},
+};
+use rand_core::{OsRng, RngCore};
 use rsa::{
-    RsaPublicKey,
+    BigUint, RsaPublicKey,
     pkcs1v15::{Signature, VerifyingKey},
+    pkcs8::AssociatedOid,
     signature::Verifier,
 };
 use sha2::{Digest, Sha256, Sha512};
+use std::time::{Duration, Instant};
 
 /// Indicates successfule key exchange
-const SSH_MSG_NEWKEYS: u8 = 21;
+pub const SSH_MSG_NEWKEYS: u8 = 21;
 
 /// Indicates start of ecdh key exchange
 const SSH_MSG_KEX_ECDH_INIT: u8 = 30;
@@ -27,6 +31,36 @@ const SSH_MSG_KEX_ECDH_INIT: u8 = 30;
 /// Indicates end of ecdh key exchange
 const SSH_MSG_KEX_ECDH_REPLY: u8 = 31;
 
+/// Indicates start of diffie-hellman key exchange
+const SSH_MSG_KEXDH_INIT: u8 = 30;
+
+/// Indicates end of diffie-hellman key exchange
+const SSH_MSG_KEXDH_REPLY: u8 = 31;
+
+/// The 2048-bit MODP group prime from RFC 3526 used by diffie-hellman-group14
+const DH_GROUP14_PRIME: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AACAA68FFFFFFFFFFFFFFFF",
+);
+
+/// The generator of the diffie-hellman-group14 group
+const DH_GROUP14_GENERATOR: u32 = 2;
+
+/// The number of random bytes in a diffie-hellman private exponent, which is well over twice
+/// the 112 bits of security group14 provides
+const DH_SECRET_LEN: usize = 64;
+
+/// How much data and how much time keys are used for before they are replaced, following the
+/// recommendation of RFC 4253
+const REKEY_BYTES: u64 = 1 << 30;
+const REKEY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 /// A struct containing all information neccessary to encrypt, mac, and compress
 /// messages sent and recieved over an SSHStream.
 pub struct Encrypter {
@@ -36,14 +70,22 @@ pub struct Encrypter {
     mac: MacAlg,
     /// Algorithm used for compressing messages
     compress: CompressAlg,
+    /// The deflate stream that every compressed message continues once compression starts
+    compressor: Option<Compress>,
+    /// Whether or not the user has authenticated, which starts delayed compression
+    authenticated: bool,
     /// Initial vector for encrypting messages
     iv: Vec<u8>,
-    /// Key for encrypting messages
-    key: Vec<u8>,
+    /// Cipher for encrypting messages, keyed once so it can be reused for every packet
+    cipher: Cipher,
     /// Key for macing messages
     mac_key: Vec<u8>,
     /// Number of packets sent (after initial key exchange)
     pub packet_num: u32,
+    /// Number of bytes sent with these keys
+    bytes: u64,
+    /// When these keys came into use
+    keyed_at: Instant,
     /// Unique identifier for the ssh session
     session_id: Vec<u8>,
 }
@@ -55,95 +97,397 @@ pub struct Decrypter {
     verify: MacAlg,
     /// Algorithm used for decompressing messages
     decompress: CompressAlg,
+    /// The inflate stream that every compressed message continues once compression starts
+    decompressor: Option<Decompress>,
+    /// Whether or not the user has authenticated, which starts delayed compression
+    authenticated: bool,
     /// Initial vector for decrypting messages
     iv: Vec<u8>,
-    /// Key for decrypting messages
-    key: Vec<u8>,
+    /// Cipher for decrypting messages, keyed once so it can be reused for every packet
+    cipher: Cipher,
     /// Key for verifying messages
     verify_key: Vec<u8>,
     /// Number of packets recieved (after initial key exchange)
     pub packet_num: u32,
+    /// Number of bytes recieved with these keys
+    bytes: u64,
+    /// When these keys came into use
+    keyed_at: Instant,
 }
 
 /// Enum representing all supported encryption algorithm types
+#[derive(Clone, Copy)]
 enum EncryptAlg {
+    /// Represents aes128-ctr algorithm
+    Aes128Ctr,
+    /// Represents aes192-ctr algorithm
+    Aes192Ctr,
     /// Represents aes256-ctr algorithm
     Aes256Ctr,
+    /// Represents aes256-cbc algorithm, which is only offered when explicitly allowed
+    Aes256Cbc,
+}
+
+impl EncryptAlg {
+    /// Returns the algorithm with the given name if it is implemented
+    fn from_name(name: &str) -> Option<EncryptAlg> {
+        match name {
+            "aes256-ctr" => Some(EncryptAlg::Aes256Ctr),
+            "aes192-ctr" => Some(EncryptAlg::Aes192Ctr),
+            "aes128-ctr" => Some(EncryptAlg::Aes128Ctr),
+            "aes256-cbc" => Some(EncryptAlg::Aes256Cbc),
+            _ => None,
+        }
+    }
+
+    /// Returns the length in bytes of the algorithm's initial vector
+    fn iv_len(&self) -> usize {
+        match self {
+            EncryptAlg::Aes128Ctr
+            | EncryptAlg::Aes192Ctr
+            | EncryptAlg::Aes256Ctr
+            | EncryptAlg::Aes256Cbc => 16,
+        }
+    }
+
+    /// Returns the length in bytes of the algorithm's key
+    fn key_len(&self) -> usize {
+        match self {
+            EncryptAlg::Aes128Ctr => 16,
+            EncryptAlg::Aes192Ctr => 24,
+            EncryptAlg::Aes256Ctr | EncryptAlg::Aes256Cbc => 32,
+        }
+    }
+}
+
+/// A block cipher whose key schedule has already been computed
+enum Cipher {
+    /// Keyed aes with a 128 bit key
+    Aes128(Aes128),
+    /// Keyed aes with a 192 bit key
+    Aes192(Aes192),
+    /// Keyed aes with a 256 bit key
+    Aes256(Aes256),
+}
+
+impl Cipher {
+    /// Computes the key schedule of an encryption algorithm's cipher. The key must be the
+    /// algorithm's key length.
+    fn new(alg: EncryptAlg, key: &[u8]) -> Result<Cipher, Error> {
+        if key.len() != alg.key_len() {
+            return Err(Error::Other(
+                "Tried to create cipher with invalid key length: Expected the algorithm's key length",
+            ));
+        }
+
+        Ok(match alg {
+            EncryptAlg::Aes128Ctr => Cipher::Aes128(Aes128::new(GenericArray::from_slice(key))),
+            EncryptAlg::Aes192Ctr => Cipher::Aes192(Aes192::new(GenericArray::from_slice(key))),
+            EncryptAlg::Aes256Ctr | EncryptAlg::Aes256Cbc => {
+                Cipher::Aes256(Aes256::new(GenericArray::from_slice(key)))
+            }
+        })
+    }
 }
 
 /// Enum representing all supported mac algorithm types
 enum MacAlg {
     /// Represents hmac-sha2-s56 algorithm
     HmacSha256,
+    /// Represents hmac-sha2-256-etm@openssh.com, which macs the encrypted packet instead
+    HmacSha256Etm,
+}
+
+impl MacAlg {
+    /// Returns the algorithm with the given name if it is implemented
+    fn from_name(name: &str) -> Option<MacAlg> {
+        match name {
+            "hmac-sha2-256" => Some(MacAlg::HmacSha256),
+            "hmac-sha2-256-etm@openssh.com" => Some(MacAlg::HmacSha256Etm),
+            _ => None,
+        }
+    }
+
+    /// Returns the length in bytes of the algorithm's key
+    fn key_len(&self) -> usize {
+        match self {
+            MacAlg::HmacSha256 | MacAlg::HmacSha256Etm => 32,
+        }
+    }
+
+    /// Returns whether or not the mac is taken over the encrypted packet, in which case the
+    /// packet length is sent unencrypted
+    fn is_etm(&self) -> bool {
+        match self {
+            MacAlg::HmacSha256 => false,
+            MacAlg::HmacSha256Etm => true,
+        }
+    }
 }
 
 /// Enum representing all supported compression algorithm types
+#[derive(Clone, Copy)]
 enum CompressAlg {
     /// Represents no compression
     None,
+    /// Represents zlib compression starting as soon as the keys are in use
+    Zlib,
+    /// Represents zlib@openssh.com compression starting once the user has authenticated
+    ZlibDelayed,
 }
 
-/// Preforms a key exchange using the provided key exchange algorithm and dirives enryption and mac
-/// keys. Returns a new Encrypter that can be used to encrypt, mac, and compress packets as needed.
-pub fn generate(
-    stream: &mut SshStream,
-    key_exchange_alg: &'static str,
-    host_key_alg: &'static str,
-    encrypt_alg: &'static str,
-    decrypt_alg: &'static str,
-    mac_alg: &'static str,
-    verify_alg: &'static str,
-    compress_alg: &'static str,
-    decompress_alg: &'static str,
-    hash_prefix: Vec<u8>,
-    mut num_read: u32,
-    mut old_enc: Option<Encrypter>,
-    old_dec: Option<Decrypter>,
+impl CompressAlg {
+    /// Returns the algorithm with the given name if it is implemented
+    fn from_name(name: &str) -> Option<CompressAlg> {
+        match name {
+            "none" => Some(CompressAlg::None),
+            "zlib" => Some(CompressAlg::Zlib),
+            "zlib@openssh.com" => Some(CompressAlg::ZlibDelayed),
+            _ => None,
+        }
+    }
+
+    /// Returns whether or not messages are compressed given whether the user has authenticated
+    fn is_active(&self, authenticated: bool) -> bool {
+        match self {
+            CompressAlg::None => false,
+            CompressAlg::Zlib => true,
+            CompressAlg::ZlibDelayed => authenticated,
+        }
+    }
+}
+
+/// Enum representing all supported key exchange algorithm types
+enum KexAlg {
+    /// Represents ecdh-sha2-nistp256 algorithm
+    EcdhSha2Nistp256,
+    /// Represents diffie-hellman-group14-sha256 algorithm
+    DhGroup14Sha256,
+}
+
+impl KexAlg {
+    /// Returns the algorithm with the given name if it is implemented
+    fn from_name(name: &str) -> Option<KexAlg> {
+        match name {
+            "ecdh-sha2-nistp256" => Some(KexAlg::EcdhSha2Nistp256),
+            "diffie-hellman-group14-sha256" => Some(KexAlg::DhGroup14Sha256),
+            _ => None,
+        }
+    }
+}
+
+/// Enum representing all supported host key algorithm types
+enum HostKeyAlg {
+    /// Represents rsa-sha2-512 algorithm
+    RsaSha2_512,
+    /// Represents rsa-sha2-256 algorithm
+    RsaSha2_256,
+}
+
+impl HostKeyAlg {
+    /// Returns the algorithm with the given name if it is implemented
+    fn from_name(name: &str) -> Option<HostKeyAlg> {
+        match name {
+            "rsa-sha2-512" => Some(HostKeyAlg::RsaSha2_512),
+            "rsa-sha2-256" => Some(HostKeyAlg::RsaSha2_256),
+            _ => None,
+        }
+    }
+}
+
+/// Returns whether or not an algorithm has a complete implementation given the category it
+/// is listed under by tssh -Q. Every advertised algorithm must have one since negotiation
+/// can pick any of them.
+pub fn is_implemented(category: &str, name: &str) -> bool {
+    match category {
+        "cipher" => EncryptAlg::from_name(name).is_some(),
+        "mac" => MacAlg::from_name(name).is_some(),
+        "kex" => KexAlg::from_name(name).is_some(),
+        "key" => HostKeyAlg::from_name(name).is_some(),
+        "compression" => CompressAlg::from_name(name).is_some(),
+        _ => false,
+    }
+}
+
+/// The names of the algorithms agreed upon during key exchange negotiation
+#[derive(Clone, Copy)]
+pub struct Algorithms {
+    /// Algorithm used to exchange the shared secret
+    pub key_exchange: &'static str,
+    /// Algorithm used to verify the host's signature on the exchange hash
+    pub host_key: &'static str,
+    /// Algorithm used to encrypt sent messages
+    pub encrypt: &'static str,
+    /// Algorithm used to decrypt recieved messages
+    pub decrypt: &'static str,
+    /// Algorithm used to mac sent messages
+    pub mac: &'static str,
+    /// Algorithm used to verify macs on recieved messages
+    pub verify: &'static str,
+    /// Algorithm used to compress sent messages
+    pub compress: &'static str,
+    /// Algorithm used to decompress recieved messages
+    pub decompress: &'static str,
+}
+
+/// The hash function used for deriving keys from a shared secret
+type HashFn = fn(&[u8]) -> Vec<u8>;
+
+/// The result of a completed key exchange that encryption and mac keys are derived from
+pub struct SharedSecret {
+    /// The shared secret key
+    key: Vec<u8>,
+    /// The exchange hash signed by the host
+    exchange_hash: Vec<u8>,
+    /// The hash function of the key exchange algorithm
+    hash_fn: HashFn,
+    /// The host key the exchange hash was signed with
+    host_key: Vec<u8>,
+}
+
+impl SharedSecret {
+    /// Returns the host key the exchange hash was signed with, which must be checked against
+    /// the keys known for the host
+    pub fn host_key(&self) -> &[u8] {
+        &self.host_key
+    }
+}
+
+/// Every value fed into an exchange hash along with the resulting hash, recorded for
+/// debugging exchange hash signature failures
+#[derive(Default)]
+pub struct ExchangeHashInputs {
+    /// The exact bytes that were hashed
+    input: Vec<u8>,
+    /// The resulting exchange hash
+    hash: Vec<u8>,
+}
+
+impl ExchangeHashInputs {
+    /// Returns whether or not nothing has been recorded yet
+    pub fn is_empty(&self) -> bool {
+        self.input.is_empty()
+    }
+
+    /// Formats each value that was hashed in hex with a label followed by the hash itself.
+    /// This includes the shared secret so it should only ever be written when asked for.
+    pub fn dump(&self) -> Result<String, Error> {
+        let labels = [
+            "V_C (client version)",
+            "V_S (host version)",
+            "I_C (client SSH_MSG_KEXINIT)",
+            "I_S (host SSH_MSG_KEXINIT)",
+            "K_S (host key)",
+            "Q_C or e (client public key)",
+            "Q_S or f (host public key)",
+            "K (shared secret mpint)",
+        ];
+
+        // Every value is length prefixed the same way whether it is a string or an mpint
+        let mut dump = String::new();
+        let mut input = self.input.as_slice();
+        for label in labels {
+            let (value, rest) = SshStream::extract_string(input)?;
+            dump.push_str(&format!(
+                "{label}, {} bytes:\n{}",
+                value.len(),
+                hex_lines(&value)
+            ));
+            input = rest;
+        }
+        dump.push_str(&format!("H (exchange hash):\n{}", hex_lines(&self.hash)));
+
+        Ok(dump)
+    }
+}
+
+/// A key exchange that has sent its initial message and is waiting on a reply from the host.
+/// Holds no reference to the underlying stream so that both blocking and non-blocking
+/// clients can drive the exchange.
+pub enum KeyExchange {
+    /// An ecdh-sha2-nistp256 exchange
+    EcdhSha2Nistp256 {
+        /// The client's ephemeral secret
+        secret: EphemeralSecret,
+        /// The client's public key in sec1 format
+        public: Box<[u8]>,
+        /// The host key algorithm used to validate the exchange hash
+        host_key_alg: &'static str,
+        /// All values of the exchange hash that are known before the exchange
+        hash_prefix: Vec<u8>,
+    },
+    /// A diffie-hellman-group14-sha256 exchange
+    DhGroup14Sha256 {
+        /// The client's private exponent x
+        secret: BigUint,
+        /// The client's public value e = g^x mod p
+        public: BigUint,
+        /// The host key algorithm used to validate the exchange hash
+        host_key_alg: &'static str,
+        /// All values of the exchange hash that are known before the exchange
+        hash_prefix: Vec<u8>,
+    },
+}
+
+/// Dirives the encryption and mac keys from the result of a key exchange once SSH_MSG_NEWKEYS
+/// has been sent and recieved. The number of packets read should include every packet
+/// recieved before the new keys take effect. On a key re-exchange the old encrypter and
+/// decrypter must be given so that the session id and sequence numbers carry over.
+pub fn derive_keys(
+    algs: &Algorithms,
+    secret: SharedSecret,
+    num_read: u32,
+    old_enc: Option<&mut Encrypter>,
+    old_dec: Option<&mut Decrypter>,
 ) -> Result<(Encrypter, Decrypter), Error> {
     // Determine encryption information
-    let (iv_encrypt_len, encrypt_key_len, encrypt_alg) = match encrypt_alg {
-        "aes256-ctr" => (16usize, 32usize, EncryptAlg::Aes256Ctr),
-        _ => {
+    let encrypt_alg = match EncryptAlg::from_name(algs.encrypt) {
+        Some(alg) => alg,
+        None => {
             return Err(Error::Other(
                 "Made new encrypter for incompattible encryption algorithm",
             ));
         }
     };
+    let (iv_encrypt_len, encrypt_key_len) = (encrypt_alg.iv_len(), encrypt_alg.key_len());
 
     // Determine decryption information
-    let (iv_decrypt_len, decrypt_key_len, decrypt_alg) = match decrypt_alg {
-        "aes256-ctr" => (16usize, 32usize, EncryptAlg::Aes256Ctr),
-        _ => {
+    let decrypt_alg = match EncryptAlg::from_name(algs.decrypt) {
+        Some(alg) => alg,
+        None => {
             return Err(Error::Other(
                 "Made new encrypter for incompattible encryption algorithm",
             ));
         }
     };
+    let (iv_decrypt_len, decrypt_key_len) = (decrypt_alg.iv_len(), decrypt_alg.key_len());
 
     // Determine mac send information
-    let (mac_key_len, mac_alg) = match mac_alg {
-        "hmac-sha2-256" => (32usize, MacAlg::HmacSha256),
-        _ => {
+    let mac_alg = match MacAlg::from_name(algs.mac) {
+        Some(alg) => alg,
+        None => {
             return Err(Error::Other(
                 "Made new encrypter for incompattible mac send algorithm",
             ));
         }
     };
+    let mac_key_len = mac_alg.key_len();
 
     // Determine mac recieve information
-    let (verify_key_len, verify_alg) = match verify_alg {
-        "hmac-sha2-256" => (32usize, MacAlg::HmacSha256),
-        _ => {
+    let verify_alg = match MacAlg::from_name(algs.verify) {
+        Some(alg) => alg,
+        None => {
             return Err(Error::Other(
                 "Made new encrypter for incompattible mac recieve algorithm",
             ));
         }
     };
+    let verify_key_len = verify_alg.key_len();
 
     // Determine compression sending information
-    let compress_alg = match compress_alg {
-        "none" => CompressAlg::None,
-        _ => {
+    let compress_alg = match CompressAlg::from_name(algs.compress) {
+        Some(alg) => alg,
+        None => {
             return Err(Error::Other(
                 "Made new encrypter for incompattible compression send algorithm",
             ));
@@ -151,57 +495,46 @@ pub fn generate(
     };
 
     // Determine compression sending information
-    let decompress_alg = match decompress_alg {
-        "none" => CompressAlg::None,
-        _ => {
+    let decompress_alg = match CompressAlg::from_name(algs.decompress) {
+        Some(alg) => alg,
+        None => {
             return Err(Error::Other(
                 "Made new encrypter for incompattible compression recieve algorithm",
             ));
         }
     };
 
-    // Exchange secret keys
-    let (key, exchange_hash, hash_fn) = match key_exchange_alg {
-        "ecdh-sha2-nistp256" => ecdh_sha2_nistp256_exchange(
-            stream,
-            host_key_alg,
-            hash_prefix,
-            &mut num_read,
-            &mut old_enc,
-        )?,
-        _ => {
-            return Err(Error::Other(
-                "Made new encrypter for incompattible key exchange algorithm",
-            ));
-        }
+    let SharedSecret {
+        key,
+        exchange_hash,
+        hash_fn,
+        ..
+    } = secret;
+
+    // Extract info if key re-exchange. Compression streams carry on across key exchanges
+    // as long as compression stays on.
+    let (packet_num_send, session_id, compressor, authenticated) = match old_enc {
+        Some(encrypter) => (
+            encrypter.packet_num,
+            encrypter.session_id.clone(),
+            encrypter.compressor.take(),
+            encrypter.authenticated,
+        ),
+        None => (3, exchange_hash.clone(), None, false),
     };
 
-    // Send and recieve the SSH_MSG_NEWKEYS message to validate successfule key exchange
-    stream.send(&[SSH_MSG_NEWKEYS], old_enc.as_mut())?;
-    let (recieved, new_read) = stream.read_until_no_decrypter(SSH_MSG_NEWKEYS)?;
-    num_read += new_read;
-    if recieved.len() > 0 {
-        return Err(Error::Other("Recieved invalid SSH_MSG_NEWKEYS message"));
-    }
-
-    // Extract info if key re-exchange
-    let (packet_num_send, session_id) = match old_enc {
-        Some(encrypter) => (encrypter.packet_num, encrypter.session_id),
-        None => (3, exchange_hash.clone()),
-    };
-
-    let packet_num_recieve = match old_dec {
-        Some(decrypter) => decrypter.packet_num,
-        None => num_read,
+    let (packet_num_recieve, decompressor) = match old_dec {
+        Some(decrypter) => (decrypter.packet_num, decrypter.decompressor.take()),
+        None => (num_read, None),
     };
 
     // Calculate encryption IV
     let iv_encrypt = generate_key(
         &key,
         &exchange_hash,
-        'A' as u8,
+        b'A',
         &session_id,
-        &hash_fn,
+        hash_fn,
         iv_encrypt_len,
     );
 
@@ -209,9 +542,9 @@ pub fn generate(
     let iv_decrypt = generate_key(
         &key,
         &exchange_hash,
-        'B' as u8,
+        b'B',
         &session_id,
-        &hash_fn,
+        hash_fn,
         iv_decrypt_len,
     );
 
@@ -219,9 +552,9 @@ pub fn generate(
     let encrypt_key = generate_key(
         &key,
         &exchange_hash,
-        'C' as u8,
+        b'C',
         &session_id,
-        &hash_fn,
+        hash_fn,
         encrypt_key_len,
     );
 
@@ -229,9 +562,9 @@ pub fn generate(
     let decrypt_key = generate_key(
         &key,
         &exchange_hash,
-        'D' as u8,
+        b'D',
         &session_id,
-        &hash_fn,
+        hash_fn,
         decrypt_key_len,
     );
 
@@ -239,9 +572,9 @@ pub fn generate(
     let mac_key = generate_key(
         &key,
         &exchange_hash,
-        'E' as u8,
+        b'E',
         &session_id,
-        &hash_fn,
+        hash_fn,
         mac_key_len,
     );
 
@@ -249,9 +582,9 @@ pub fn generate(
     let verify_key = generate_key(
         &key,
         &exchange_hash,
-        'F' as u8,
+        b'F',
         &session_id,
-        &hash_fn,
+        hash_fn,
         verify_key_len,
     );
 
@@ -260,76 +593,239 @@ pub fn generate(
             encrypt: encrypt_alg,
             mac: mac_alg,
             compress: compress_alg,
+            compressor: compressor.filter(|_| !matches!(compress_alg, CompressAlg::None)),
+            authenticated,
             iv: iv_encrypt,
-            key: encrypt_key,
+            cipher: Cipher::new(encrypt_alg, &encrypt_key)?,
             mac_key,
             packet_num: packet_num_send,
-            session_id: session_id.clone(),
+            bytes: 0,
+            keyed_at: Instant::now(),
+            session_id,
         },
         Decrypter {
             decrypt: decrypt_alg,
             verify: verify_alg,
             decompress: decompress_alg,
+            decompressor: decompressor.filter(|_| !matches!(decompress_alg, CompressAlg::None)),
+            authenticated,
             iv: iv_decrypt,
-            key: decrypt_key,
+            cipher: Cipher::new(decrypt_alg, &decrypt_key)?,
             verify_key,
             packet_num: packet_num_recieve,
+            bytes: 0,
+            keyed_at: Instant::now(),
         },
     ))
 }
 
-/// Preforms ecdh-sha2-nistp256 key exchange on the given stream and uses the
-/// given host_key_alg to validate signatures on important values produced
-/// during the exchange. The hash_prefix should contain all relavant values
-/// to the exchange hash in the proper format so that newly computed values can
-/// be appended.
+impl KeyExchange {
+    /// Begins a key exchange with the negotiated key exchange algorithm. The hash_prefix should
+    /// contain all relavant values to the exchange hash in the proper format so that newly
+    /// computed values can be appended.
+    ///
+    /// Results in the pending exchange and the payload that must be sent to the host.
+    pub fn start(algs: &Algorithms, hash_prefix: Vec<u8>) -> Result<(Self, Vec<u8>), Error> {
+        match KexAlg::from_name(algs.key_exchange) {
+            Some(KexAlg::EcdhSha2Nistp256) => {
+                let secret = EphemeralSecret::random(&mut OsRng);
+                let public = secret.public_key().to_sec1_bytes();
+
+                let mut ecdh_init = vec![SSH_MSG_KEX_ECDH_INIT];
+                SshStream::append_string(&mut ecdh_init, &public);
+
+                let exchange = KeyExchange::EcdhSha2Nistp256 {
+                    secret,
+                    public,
+                    host_key_alg: algs.host_key,
+                    hash_prefix,
+                };
+                Ok((exchange, ecdh_init))
+            }
+            Some(KexAlg::DhGroup14Sha256) => {
+                let mut secret = [0u8; DH_SECRET_LEN];
+                OsRng.fill_bytes(&mut secret);
+                let secret = BigUint::from_bytes_be(&secret);
+                let public =
+                    BigUint::from(DH_GROUP14_GENERATOR).modpow(&secret, &dh_group14_prime());
+
+                let mut dh_init = vec![SSH_MSG_KEXDH_INIT];
+                SshStream::append_mpint(&mut dh_init, &public.to_bytes_be(), true);
+
+                let exchange = KeyExchange::DhGroup14Sha256 {
+                    secret,
+                    public,
+                    host_key_alg: algs.host_key,
+                    hash_prefix,
+                };
+                Ok((exchange, dh_init))
+            }
+            None => Err(Error::Other(
+                "Made new encrypter for incompattible key exchange algorithm",
+            )),
+        }
+    }
+
+    /// Returns the packet type of the host's reply that must be passed to finish
+    pub fn reply_type(&self) -> u8 {
+        match self {
+            KeyExchange::EcdhSha2Nistp256 { .. } => SSH_MSG_KEX_ECDH_REPLY,
+            KeyExchange::DhGroup14Sha256 { .. } => SSH_MSG_KEXDH_REPLY,
+        }
+    }
+
+    /// Completes the key exchange with the payload of the host's reply and validates the
+    /// host's signature on the exchange hash. If inputs are given the values fed into the
+    /// exchange hash are recorded in them before the signature is checked.
+    pub fn finish(
+        self,
+        reply: &[u8],
+        inputs: Option<&mut ExchangeHashInputs>,
+    ) -> Result<SharedSecret, Error> {
+        match self {
+            KeyExchange::EcdhSha2Nistp256 {
+                secret,
+                public,
+                host_key_alg,
+                hash_prefix,
+            } => {
+                ecdh_sha2_nistp256_finish(secret, &public, host_key_alg, hash_prefix, reply, inputs)
+            }
+            KeyExchange::DhGroup14Sha256 {
+                secret,
+                public,
+                host_key_alg,
+                hash_prefix,
+            } => {
+                dh_group14_sha256_finish(&secret, &public, host_key_alg, hash_prefix, reply, inputs)
+            }
+        }
+    }
+}
+
+/// Completes an ecdh-sha2-nistp256 key exchange using the reply from the host and uses
+/// the given host_key_alg to validate signatures on important values produced
+/// during the exchange. The exchange hash inputs are recorded if inputs are given.
 ///
 /// Results in the shared secret key, the exchange hash, and the hash function to use
 /// for key generation.
-fn ecdh_sha2_nistp256_exchange(
-    stream: &mut SshStream,
+fn ecdh_sha2_nistp256_finish(
+    secret: EphemeralSecret,
+    public: &[u8],
     host_key_alg: &'static str,
     mut hash_prefix: Vec<u8>,
-    num_read: &mut u32,
-    old: &mut Option<Encrypter>,
-) -> Result<(Vec<u8>, Vec<u8>, impl Fn(&[u8]) -> Vec<u8> + use<>), Error> {
-    let secret = EphemeralSecret::random(&mut OsRng);
-    let public = secret.public_key().to_sec1_bytes();
-
-    let mut ecdh_init = vec![SSH_MSG_KEX_ECDH_INIT];
-    SshStream::append_string(&mut ecdh_init, &*public);
-
-    stream.send(&ecdh_init, old.as_mut())?;
-
-    let (reply, new_read) = stream.read_until_no_decrypter(SSH_MSG_KEX_ECDH_REPLY)?;
-    *num_read += new_read;
-
-    let (host_key, reply) = SshStream::extract_string(&reply)?;
-    let (server_public, reply) = SshStream::extract_string(&reply)?;
-    let (signature, _) = SshStream::extract_string(&reply)?;
-
+    reply: &[u8],
+    inputs: Option<&mut ExchangeHashInputs>,
+) -> Result<SharedSecret, Error> {
+    let (host_key, reply) = SshStream::extract_string(reply)?;
+    let (server_public, reply) = SshStream::extract_string(reply)?;
+    let (signature, reply) = SshStream::extract_string(reply)?;
+    SshStream::expect_end(reply)?;
+
+    // Parsing fails for the identity point and for points that are not on the curve, and
+    // nistp256 has a cofactor of 1 so there are no other low order points to check for
     let pub_key = match PublicKey::<NistP256>::from_sec1_bytes(&server_public) {
         Ok(key) => key,
-        Err(_) => return Err(Error::Other("Failed to parse ecdh public key")),
+        Err(_) => {
+            return Err(Error::KeyExchange(
+                "Failed to parse ecdh public key".to_string(),
+            ));
+        }
     };
 
+    // A shared secret of zero would let the host choose the keys
     let key = secret.diffie_hellman(&pub_key).raw_secret_bytes().to_vec();
+    if key.iter().all(|&byte| byte == 0) {
+        return Err(Error::KeyExchange(
+            "Recieved ecdh public key from host that gives a trivial shared secret".to_string(),
+        ));
+    }
 
     // Update exchange hash
     SshStream::append_string(&mut hash_prefix, &host_key);
-    SshStream::append_string(&mut hash_prefix, &*public);
+    SshStream::append_string(&mut hash_prefix, public);
     SshStream::append_string(&mut hash_prefix, &server_public);
     SshStream::append_mpint(&mut hash_prefix, &key, true);
 
     // Compute exchange hash
-    let exchang_hash = Sha256::digest(hash_prefix).to_vec();
+    let exchang_hash = Sha256::digest(&hash_prefix).to_vec();
+
+    // Record the hash inputs before verifying since a failed verification is why they are wanted
+    if let Some(inputs) = inputs {
+        inputs.input = hash_prefix;
+        inputs.hash = exchang_hash.clone();
+    }
 
     // Verify exchange hash
-    verify_hash(host_key_alg, host_key, &exchang_hash, signature)?;
+    verify_hash(host_key_alg, host_key.clone(), &exchang_hash, signature)?;
+
+    Ok(SharedSecret {
+        key,
+        exchange_hash: exchang_hash,
+        hash_fn: |x: &[u8]| Sha256::digest(x).to_vec(),
+        host_key,
+    })
+}
+
+/// Returns the prime modulus p of diffie-hellman-group14
+fn dh_group14_prime() -> BigUint {
+    BigUint::parse_bytes(DH_GROUP14_PRIME.as_bytes(), 16).expect("group14 prime is valid hex")
+}
 
-    let hash_fn = |x: &[u8]| Sha256::digest(x).to_vec();
+/// Completes a diffie-hellman-group14-sha256 key exchange using the reply from the host and
+/// uses the given host_key_alg to validate the host's signature on the exchange hash. The
+/// exchange hash inputs are recorded if inputs are given.
+///
+/// Results in the shared secret key, the exchange hash, and the hash function to use
+/// for key generation.
+fn dh_group14_sha256_finish(
+    secret: &BigUint,
+    public: &BigUint,
+    host_key_alg: &'static str,
+    mut hash_prefix: Vec<u8>,
+    reply: &[u8],
+    inputs: Option<&mut ExchangeHashInputs>,
+) -> Result<SharedSecret, Error> {
+    let (host_key, reply) = SshStream::extract_string(reply)?;
+    let (server_public, reply) = SshStream::extract_mpint_unsigned(reply)?;
+    let (signature, reply) = SshStream::extract_string(reply)?;
+    SshStream::expect_end(reply)?;
+
+    // Values outside of 1 < f < p - 1 would force the shared secret to a known value
+    let prime = dh_group14_prime();
+    let one = BigUint::from(1u32);
+    if server_public <= one || server_public >= &prime - &one {
+        return Err(Error::KeyExchange(
+            "Recieved invalid diffie-hellman public value from host".to_string(),
+        ));
+    }
 
-    Ok((key, exchang_hash, hash_fn))
+    let key = server_public.modpow(secret, &prime).to_bytes_be();
+
+    // Update exchange hash
+    SshStream::append_string(&mut hash_prefix, &host_key);
+    SshStream::append_mpint(&mut hash_prefix, &public.to_bytes_be(), true);
+    SshStream::append_mpint(&mut hash_prefix, &server_public.to_bytes_be(), true);
+    SshStream::append_mpint(&mut hash_prefix, &key, true);
+
+    // Compute exchange hash
+    let exchange_hash = Sha256::digest(&hash_prefix).to_vec();
+
+    // Record the hash inputs before verifying since a failed verification is why they are wanted
+    if let Some(inputs) = inputs {
+        inputs.input = hash_prefix;
+        inputs.hash = exchange_hash.clone();
+    }
+
+    // Verify exchange hash
+    verify_hash(host_key_alg, host_key.clone(), &exchange_hash, signature)?;
+
+    Ok(SharedSecret {
+        key,
+        exchange_hash,
+        hash_fn: |x: &[u8]| Sha256::digest(x).to_vec(),
+        host_key,
+    })
 }
 
 /// Generates a new key based on the shared secret, exchange hash, session id, and byte value given in
@@ -339,7 +835,7 @@ fn generate_key(
     exchange_hash: &[u8],
     char: u8,
     session_id: &[u8],
-    hash_fn: impl Fn(&[u8]) -> Vec<u8>,
+    hash_fn: HashFn,
     output_len: usize,
 ) -> Vec<u8> {
     let mut hash_data = Vec::new();
@@ -381,9 +877,10 @@ fn verify_hash(
     hash: &[u8],
     signature: Vec<u8>,
 ) -> Result<(), Error> {
-    match host_key_alg {
-        "rsa-sha2-512" => rsa_sha2_512_verify(host_key, hash, signature),
-        _ => Err(Error::Other(
+    match HostKeyAlg::from_name(host_key_alg) {
+        Some(HostKeyAlg::RsaSha2_512) => rsa_sha2_512_verify(host_key, hash, signature),
+        Some(HostKeyAlg::RsaSha2_256) => rsa_sha2_256_verify(host_key, hash, signature),
+        None => Err(Error::Other(
             "Made new encrypter with invalid host key algorithm",
         )),
     }
@@ -403,6 +900,23 @@ fn verify_hash(
 ///
 /// The value of hash should simply be the unhashed message that needs to be verified
 fn rsa_sha2_512_verify(host_key: Vec<u8>, hash: &[u8], signature: Vec<u8>) -> Result<(), Error> {
+    rsa_verify::<Sha512>("rsa-sha2-512", host_key, hash, signature)
+}
+
+/// Uses rsa-sha2-256 to verify a signature on a value using the given host key, which is in
+/// the same format as for rsa-sha2-512 with a signature of type rsa-sha2-256
+fn rsa_sha2_256_verify(host_key: Vec<u8>, hash: &[u8], signature: Vec<u8>) -> Result<(), Error> {
+    rsa_verify::<Sha256>("rsa-sha2-256", host_key, hash, signature)
+}
+
+/// Verifies a pkcs1v15 signature of the given type made with the digest D on a value using an
+/// ssh-rsa host key. Only the signature type and digest differ between the RSA algorithms.
+fn rsa_verify<D: Digest + AssociatedOid>(
+    sig_name: &str,
+    host_key: Vec<u8>,
+    hash: &[u8],
+    signature: Vec<u8>,
+) -> Result<(), Error> {
     // Check for valid key type
     let (key_type, host_key) = SshStream::extract_string(&host_key)?;
     if key_type != b"ssh-rsa" {
@@ -411,32 +925,156 @@ fn rsa_sha2_512_verify(host_key: Vec<u8>, hash: &[u8], signature: Vec<u8>) -> Re
 
     // Create rsa verifying key
     let (e, host_key) = SshStream::extract_mpint_unsigned(host_key)?;
-    let (n, _) = SshStream::extract_mpint_unsigned(host_key)?;
+    let (n, host_key) = SshStream::extract_mpint_unsigned(host_key)?;
+    SshStream::expect_end(host_key)?;
     let pub_key = match RsaPublicKey::new(n, e) {
         Ok(key) => key,
         Err(_) => return Err(Error::Other("Invalid RSA host key")),
     };
-    let verifying_key = VerifyingKey::<Sha512>::new(pub_key);
+    let verifying_key = VerifyingKey::<D>::new(pub_key);
 
     // Extract signature
     let (sig_type, signature) = SshStream::extract_string(&signature)?;
-    if sig_type != b"rsa-sha2-512" {
-        return Err(Error::Other(
-            "Invalid signature type: Expected rsa-sha2-512",
-        ));
+    if sig_type != sig_name.as_bytes() {
+        return Err(Error::KeyExchange(format!(
+            "Invalid signature type: Expected {sig_name}"
+        )));
     }
-    let (signature, _) = SshStream::extract_string(signature)?;
+    let (signature, rest) = SshStream::extract_string(signature)?;
+    SshStream::expect_end(rest)?;
     let signature = Signature::try_from(signature.as_slice()).unwrap();
 
     // Verify signature
     match verifying_key.verify(hash, &signature) {
         Ok(()) => Ok(()),
-        Err(_) => Err(Error::Other(
-            "Failed to validate signature of exchange hash",
+        Err(_) => Err(Error::KeyExchange(
+            "Failed to validate signature of exchange hash".to_string(),
         )),
     }
 }
 
+/// Formats bytes as lines of hex with 32 bytes to a line
+pub fn hex_lines(bytes: &[u8]) -> String {
+    let mut lines = String::new();
+    for chunk in bytes.chunks(32) {
+        for byte in chunk {
+            lines.push_str(&format!("{byte:02x}"));
+        }
+        lines.push('\n');
+    }
+    lines
+}
+
+/// Runs data through aes in ctr mode with an already keyed cipher according to ssh
+/// specifications, incrementing the counter in iv for each block. Encrypting and
+/// decrypting are the same operation.
+fn aes_ctr(cipher: &Cipher, iv: &mut [u8], mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    // Check if data is a multiple of the block size
+    if !data.len().is_multiple_of(16) {
+        return Err(Error::Other(
+            "Tried to run aes-ctr on block with bad size: Expected multiple of 16",
+        ));
+    }
+
+    // If data is empty then we are done
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Check for valid iv length
+    if iv.len() != 16 {
+        return Err(Error::Other(
+            "Tried to run aes-ctr with invalid iv length: Expect 16 bytes",
+        ));
+    }
+
+    // Run data through the cypher
+    match cipher {
+        Cipher::Aes128(cypher) => ctr_xor(cypher, iv, &mut data),
+        Cipher::Aes192(cypher) => ctr_xor(cypher, iv, &mut data),
+        Cipher::Aes256(cypher) => ctr_xor(cypher, iv, &mut data),
+    }
+
+    Ok(data)
+}
+
+/// XORs data in place with the keystream made by encrypting each value of the counter
+fn ctr_xor<C: BlockEncrypt<BlockSize = U16>>(cypher: &C, counter: &mut [u8], data: &mut [u8]) {
+    for chunk in data.chunks_mut(16) {
+        let mut block: GenericArray<u8, U16> = GenericArray::clone_from_slice(counter);
+        cypher.encrypt_block(&mut block);
+        for (d, k) in chunk.iter_mut().zip(block.iter()) {
+            *d ^= k;
+        }
+        increment_counter(counter);
+    }
+}
+
+/// Decrypts the private section of an encrypted OpenSSH key file with aes256-ctr
+pub fn aes256_ctr_decrypt(key: &[u8], iv: &[u8], cyphertext: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let cipher = Cipher::new(EncryptAlg::Aes256Ctr, key)?;
+    aes_ctr(&cipher, &mut iv.to_vec(), cyphertext)
+}
+
+/// Runs data through aes in cbc mode with an already keyed cipher. Each block is chained to
+/// the cyphertext block before it, starting with iv, and the last cyphertext block is left in
+/// iv since SSH continues the chain from one packet to the next.
+fn aes_cbc(
+    cipher: &Cipher,
+    iv: &mut [u8],
+    mut data: Vec<u8>,
+    decrypt: bool,
+) -> Result<Vec<u8>, Error> {
+    // Check if data is a multiple of the block size
+    if !data.len().is_multiple_of(16) {
+        return Err(Error::Other(
+            "Tried to run aes-cbc on block with bad size: Expected multiple of 16",
+        ));
+    }
+
+    // Check for valid iv length
+    if iv.len() != 16 {
+        return Err(Error::Other(
+            "Tried to run aes-cbc with invalid iv length: Expect 16 bytes",
+        ));
+    }
+
+    // Run data through the cypher
+    match (cipher, decrypt) {
+        (Cipher::Aes128(cypher), false) => cbc_encrypt(cypher, iv, &mut data),
+        (Cipher::Aes192(cypher), false) => cbc_encrypt(cypher, iv, &mut data),
+        (Cipher::Aes256(cypher), false) => cbc_encrypt(cypher, iv, &mut data),
+        (Cipher::Aes128(cypher), true) => cbc_decrypt(cypher, iv, &mut data),
+        (Cipher::Aes192(cypher), true) => cbc_decrypt(cypher, iv, &mut data),
+        (Cipher::Aes256(cypher), true) => cbc_decrypt(cypher, iv, &mut data),
+    }
+
+    Ok(data)
+}
+
+/// Encrypts data in place, XORing each plaintext block with the previous cyphertext block
+fn cbc_encrypt<C: BlockEncrypt<BlockSize = U16>>(cypher: &C, iv: &mut [u8], data: &mut [u8]) {
+    for chunk in data.chunks_mut(16) {
+        for (d, v) in chunk.iter_mut().zip(iv.iter()) {
+            *d ^= v;
+        }
+        cypher.encrypt_block(GenericArray::from_mut_slice(chunk));
+        iv.copy_from_slice(chunk);
+    }
+}
+
+/// Decrypts data in place, XORing each decrypted block with the previous cyphertext block
+fn cbc_decrypt<C: BlockDecrypt<BlockSize = U16>>(cypher: &C, iv: &mut [u8], data: &mut [u8]) {
+    for chunk in data.chunks_mut(16) {
+        let next_iv: [u8; 16] = chunk.try_into().expect("Chunks are a full block");
+        cypher.decrypt_block(GenericArray::from_mut_slice(chunk));
+        for (d, v) in chunk.iter_mut().zip(iv.iter()) {
+            *d ^= v;
+        }
+        iv.copy_from_slice(&next_iv);
+    }
+}
+
 /// Incraments a counter in the form of an array slice in place
 fn increment_counter(counter: &mut [u8]) {
     for digit in counter.iter_mut().rev() {
@@ -455,73 +1093,78 @@ impl Encrypter {
     /// Encrypts a plaintext vector for sending over ssh
     pub fn encrypt(&mut self, plaintext: Vec<u8>) -> Result<Vec<u8>, Error> {
         match self.encrypt {
-            EncryptAlg::Aes256Ctr => self.aes256_ctr(plaintext),
+            EncryptAlg::Aes128Ctr | EncryptAlg::Aes192Ctr | EncryptAlg::Aes256Ctr => {
+                aes_ctr(&self.cipher, &mut self.iv, plaintext)
+            }
+            EncryptAlg::Aes256Cbc => aes_cbc(&self.cipher, &mut self.iv, plaintext, false),
         }
     }
 
+    /// Returns the session identifier, which is the exchange hash of the first key exchange
+    pub fn session_id(&self) -> &[u8] {
+        &self.session_id
+    }
+
     /// Returns the block size needed for the encryption algorithm
     pub fn block_size(&self) -> u32 {
         match self.encrypt {
-            EncryptAlg::Aes256Ctr => 16,
+            EncryptAlg::Aes128Ctr
+            | EncryptAlg::Aes192Ctr
+            | EncryptAlg::Aes256Ctr
+            | EncryptAlg::Aes256Cbc => 16,
         }
     }
 
-    /// Encrypts a plaintext vector using aes256-ctr according to ssh specifications
-    fn aes256_ctr(&mut self, mut plaintext: Vec<u8>) -> Result<Vec<u8>, Error> {
-        // Check if plaintext is a multiple of the block size
-        if plaintext.len() % 16 != 0 {
-            return Err(Error::Other(
-                "Tried to encrypt block with bad size: Expected multiple of 16",
-            ));
-        }
-
-        // If plaintext is empty then we are done
-        if plaintext.len() == 0 {
-            return Ok(Vec::new());
-        }
-
-        // Check for valid key length
-        if self.key.len() != 32 {
-            return Err(Error::Other(
-                "Tried to encrypt with invalid key length: Expect 32 bytes",
-            ));
-        }
-
-        // Check for valid iv length
-        if self.iv.len() != 16 {
-            return Err(Error::Other(
-                "Tried to encrypt with invalid iv length: Expect 16 bytes",
-            ));
+    /// Returns whether or not the encryption algorithm authenticates packets itself. If it does
+    /// the packet length is sent unencrypted and packets are sealed with encrypt_aead instead
+    /// of being encrypted and mac'd seperately.
+    pub fn is_aead(&self) -> bool {
+        match self.encrypt {
+            EncryptAlg::Aes128Ctr
+            | EncryptAlg::Aes192Ctr
+            | EncryptAlg::Aes256Ctr
+            | EncryptAlg::Aes256Cbc => false,
         }
+    }
 
-        // Create cypher
-        let key: GenericArray<u8, U32> = GenericArray::clone_from_slice(&self.key);
-        let cypher = Aes256::new(&key);
-
-        // Encrypt plaintext
-        for chunk in plaintext.chunks_mut(16) {
-            let mut block: GenericArray<u8, U16> = GenericArray::clone_from_slice(&self.iv);
-            cypher.encrypt_block(&mut block);
-            for (c, k) in chunk.iter_mut().zip(block.iter()) {
-                *c ^= k;
-            }
-            increment_counter(&mut self.iv);
+    /// Encrypts a plaintext vector with an AEAD cipher and appends the authentication tag.
+    /// The additional data is authenticated but not encrypted.
+    pub fn encrypt_aead(&mut self, _aad: &[u8], _plaintext: Vec<u8>) -> Result<Vec<u8>, Error> {
+        match self.encrypt {
+            EncryptAlg::Aes128Ctr
+            | EncryptAlg::Aes192Ctr
+            | EncryptAlg::Aes256Ctr
+            | EncryptAlg::Aes256Cbc => Err(Error::Other(
+                "Tried to encrypt with AEAD using a cipher that is not AEAD",
+            )),
         }
-
-        Ok(plaintext)
     }
 
     // Mac functions
 
+    /// Returns whether or not packets are encrypted before being mac'd, in which case the
+    /// packet length is sent unencrypted and the mac covers the encrypted packet
+    pub fn is_etm(&self) -> bool {
+        self.mac.is_etm()
+    }
+
     /// Generates a mac for a message
     pub fn mac(&mut self, message: &[u8]) -> Vec<u8> {
         let result = match self.mac {
-            MacAlg::HmacSha256 => self.hmac_sha256(message),
+            MacAlg::HmacSha256 | MacAlg::HmacSha256Etm => self.hmac_sha256(message),
         };
-        self.packet_num += 1;
+        // Sequence numbers wrap around rather than ending the session
+        self.packet_num = self.packet_num.wrapping_add(1);
+        self.bytes += message.len() as u64;
         result
     }
 
+    /// Returns whether or not these keys have been used for long enough or for enough data
+    /// that they should be replaced by a key re-exchange
+    pub fn needs_rekey(&self) -> bool {
+        self.bytes >= REKEY_BYTES || self.keyed_at.elapsed() >= REKEY_INTERVAL
+    }
+
     /// Uses hmac-sha2-256 to generate a mac for a message
     fn hmac_sha256(&mut self, message: &[u8]) -> Vec<u8> {
         // Create message to mac
@@ -540,10 +1183,20 @@ impl Encrypter {
     // Compression functions
 
     /// Uses the negotiated compression algorithm to compress a payload
-    pub fn compress(&self, payload: &[u8]) -> Vec<u8> {
-        match self.compress {
-            CompressAlg::None => payload.to_vec(),
+    pub fn compress(&mut self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        if !self.compress.is_active(self.authenticated) {
+            return Ok(payload.to_vec());
         }
+
+        let stream = self
+            .compressor
+            .get_or_insert_with(|| Compress::new(Compression::default(), true));
+        zlib_compress(stream, payload)
+    }
+
+    /// Starts zlib@openssh.com compression for messages sent after user authentication
+    pub fn start_delayed_compression(&mut self) {
+        self.authenticated = true;
     }
 }
 
@@ -553,77 +1206,77 @@ impl Decrypter {
     /// decrypts a cyphertext vector recieved over an ssh stream
     pub fn decrypt(&mut self, cyphertext: Vec<u8>) -> Result<Vec<u8>, Error> {
         match self.decrypt {
-            EncryptAlg::Aes256Ctr => self.aes256_ctr(cyphertext),
+            EncryptAlg::Aes128Ctr | EncryptAlg::Aes192Ctr | EncryptAlg::Aes256Ctr => {
+                aes_ctr(&self.cipher, &mut self.iv, cyphertext)
+            }
+            EncryptAlg::Aes256Cbc => aes_cbc(&self.cipher, &mut self.iv, cyphertext, true),
         }
     }
 
     /// Returns the block size needed for the encryption algorithm
     pub fn block_size(&self) -> u32 {
         match self.decrypt {
-            EncryptAlg::Aes256Ctr => 16,
+            EncryptAlg::Aes128Ctr
+            | EncryptAlg::Aes192Ctr
+            | EncryptAlg::Aes256Ctr
+            | EncryptAlg::Aes256Cbc => 16,
         }
     }
 
-    /// Decrypts a cyphertext vector using aes256-ctr according to ssh specifications
-    fn aes256_ctr(&mut self, mut cyphertext: Vec<u8>) -> Result<Vec<u8>, Error> {
-        // Check if cyphertext is a multiple of the block size
-        if cyphertext.len() % 16 != 0 {
-            return Err(Error::Other(
-                "Tried to decrypt block with bad size: Expected multiple of 16",
-            ));
-        }
-
-        // If cyphertext is empty then we are done
-        if cyphertext.len() == 0 {
-            return Ok(Vec::new());
-        }
-
-        // Check for valid key length
-        if self.key.len() != 32 {
-            return Err(Error::Other(
-                "Tried to decrypt with invalid key length: Expect 32 bytes",
-            ));
-        }
-
-        // Check for valid iv length
-        if self.iv.len() != 16 {
-            return Err(Error::Other(
-                "Tried to decrypt with invalid iv length: Expect 16 bytes",
-            ));
+    /// Returns whether or not the decryption algorithm authenticates packets itself. If it does
+    /// the packet length is recieved unencrypted and packets are opened with decrypt_aead
+    /// instead of being decrypted and verified seperately.
+    pub fn is_aead(&self) -> bool {
+        match self.decrypt {
+            EncryptAlg::Aes128Ctr
+            | EncryptAlg::Aes192Ctr
+            | EncryptAlg::Aes256Ctr
+            | EncryptAlg::Aes256Cbc => false,
         }
+    }
 
-        // Create cypher
-        let key: GenericArray<u8, U32> = GenericArray::clone_from_slice(&self.key);
-        let cypher = Aes256::new(&key);
-
-        // Encrypt plaintext
-        for chunk in cyphertext.chunks_mut(16) {
-            let mut block: GenericArray<u8, U16> = GenericArray::clone_from_slice(&self.iv);
-            cypher.encrypt_block(&mut block);
-            for (p, k) in chunk.iter_mut().zip(block.iter()) {
-                *p ^= k;
-            }
-            increment_counter(&mut self.iv);
+    /// Decrypts a cyphertext vector ending in its authentication tag with an AEAD cipher.
+    /// The additional data is authenticated along with the cyphertext.
+    pub fn decrypt_aead(&mut self, _aad: &[u8], _cyphertext: Vec<u8>) -> Result<Vec<u8>, Error> {
+        match self.decrypt {
+            EncryptAlg::Aes128Ctr
+            | EncryptAlg::Aes192Ctr
+            | EncryptAlg::Aes256Ctr
+            | EncryptAlg::Aes256Cbc => Err(Error::Other(
+                "Tried to decrypt with AEAD using a cipher that is not AEAD",
+            )),
         }
-
-        Ok(cyphertext)
     }
 
     // Mac Verification Functions
 
+    /// Returns whether or not packets were encrypted before being mac'd, in which case the
+    /// packet length is recieved unencrypted and the mac must be verified before decrypting
+    pub fn is_etm(&self) -> bool {
+        self.verify.is_etm()
+    }
+
     /// Verifies a mac for a message
     pub fn verify(&mut self, message: &[u8], mac: &[u8]) -> bool {
         let result = match self.verify {
-            MacAlg::HmacSha256 => self.hmac_sha256(message, mac),
+            MacAlg::HmacSha256 | MacAlg::HmacSha256Etm => self.hmac_sha256(message, mac),
         };
-        self.packet_num += 1;
+        // Sequence numbers wrap around rather than ending the session
+        self.packet_num = self.packet_num.wrapping_add(1);
+        self.bytes += message.len() as u64;
         result
     }
 
-    /// The length of the mac expected by verify
+    /// Returns whether or not these keys have been used for long enough or for enough data
+    /// that they should be replaced by a key re-exchange
+    pub fn needs_rekey(&self) -> bool {
+        self.bytes >= REKEY_BYTES || self.keyed_at.elapsed() >= REKEY_INTERVAL
+    }
+
+    /// The length of the mac expected by verify, or of the authentication tag for AEAD ciphers
     pub fn verify_length(&self) -> usize {
         match self.verify {
-            MacAlg::HmacSha256 => 32,
+            MacAlg::HmacSha256 | MacAlg::HmacSha256Etm => 32,
         }
     }
 
@@ -648,9 +1301,568 @@ impl Decrypter {
     // Decompression Functions
 
     /// Uses the negotiated compression algorithm to decompress a payload
-    pub fn decompress(&self, payload: &[u8]) -> Vec<u8> {
-        match self.decompress {
-            CompressAlg::None => payload.to_vec(),
+    pub fn decompress(&mut self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        if !self.decompress.is_active(self.authenticated) {
+            return Ok(payload.to_vec());
+        }
+
+        let stream = self
+            .decompressor
+            .get_or_insert_with(|| Decompress::new(true));
+        zlib_decompress(stream, payload)
+    }
+
+    /// Starts zlib@openssh.com decompression for messages recieved after user authentication
+    pub fn start_delayed_compression(&mut self) {
+        self.authenticated = true;
+    }
+}
+
+/// Compresses a payload as the next part of a deflate stream. Each payload ends with a
+/// partial flush so that the host can decompress it without waiting on later payloads.
+fn zlib_compress(stream: &mut Compress, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::with_capacity(payload.len() + 64);
+    let mut input = payload;
+    loop {
+        let total_in = stream.total_in();
+        if stream
+            .compress_vec(input, &mut output, FlushCompress::Partial)
+            .is_err()
+        {
+            return Err(Error::Other("Failed to compress packet"));
+        }
+        input = &input[(stream.total_in() - total_in) as usize..];
+
+        // The flush is done once there was room left over for more output
+        if input.is_empty() && output.len() < output.capacity() {
+            return Ok(output);
+        }
+        output.reserve(output.capacity().max(64));
+    }
+}
+
+/// Decompresses a payload as the next part of an inflate stream. Payloads that decompress
+/// to more than the largest allowed packet are rejected.
+fn zlib_decompress(stream: &mut Decompress, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::with_capacity(payload.len() * 2 + 64);
+    let mut input = payload;
+    loop {
+        let total_in = stream.total_in();
+        if stream
+            .decompress_vec(input, &mut output, FlushDecompress::Sync)
+            .is_err()
+        {
+            return Err(Error::Other("Failed to decompress packet"));
+        }
+        input = &input[(stream.total_in() - total_in) as usize..];
+
+        if input.is_empty() && output.len() < output.capacity() {
+            return Ok(output);
         }
+        if output.len() > MAX_PACKET_LIMIT {
+            return Err(Error::Other(
+                "Decompressed packet is too large: Expected at most the maximum packet size",
+            ));
+        }
+        output.reserve(output.capacity().max(64));
+    }
+}
+
+// Self-test functions
+
+/// The message signed by the rsa-sha2-512 self-test key
+const RSA_TEST_MESSAGE: &[u8] = b"tssh self-test";
+
+/// The modulus of the rsa-sha2-512 self-test key whose public exponent is 65537. There is no
+/// published vector in the SSH wire format so the key was generated for tssh.
+const RSA_TEST_MODULUS: &str = "\
+    9fda6f77ecac307906e5c165d6e062963b8b8bc5b3c51ed35898445a84402389\
+    604c36145b6fd6338f168b8d24306c6d3409acbbba3e7d7fc95371f400996ddc\
+    43d8a72d96f0a12b8800cd1fc3c879cb2e3d69763a8859e2044367ffd501a230\
+    1ad9cd220fe90990c267e034425c67e64a5b91b01fbfbcb803bb63cc0e1aafad\
+    7648625ab9e3a5b17d4c8fc006c786c7882313449496385bfd8be349a6ca250c\
+    ca1751e5ca68991c700e1a0b382e3a9b19403d166f30371a336a42ce7c84f736\
+    3f103c6b5cb0ea003084901148a74d895338b6366bd2c9da84a4ffbaa21c5fe2\
+    edd951c183b8d60d66b1e498183e60d29d427fcf4ce9a11771c490f313749fd5";
+
+/// The pkcs1v15 sha512 signature of RSA_TEST_MESSAGE made by the self-test key
+const RSA_TEST_SIGNATURE: &str = "\
+    64a9933d9ed5173046125a8afb27dd88e162c2b8984a33085d057a7db58ba029\
+    34a55c5778c796782b14497dde39c60b30836acd4d2df27994e04271f1e8284d\
+    5a5b99ebbd464e2b0235f0532176d6ddf82baa6585a203e6875c767e786b9e5a\
+    72d191c902ce717b811f3eb6dcf536720e44e821d9bb3180b3884954e24e30a6\
+    5a8ffca73bbd3608e327b5e04ac18008098d1ff5d7fb4c33cfd571514ee78b9f\
+    3b627246c8ecf6bb4c57408b0b8c20ff9f6bd7d3f57041f1d34639018740b6fe\
+    13a6567eac36749547cc1c73a2a9a34edd72905ad669d383a34aef7a08307a97\
+    8f5b1ba57e297d60aeecc957cf00f6ccbe3cd6ea83f217c374c57539df916393";
+
+/// The modulus of the rsa-sha2-256 self-test key whose public exponent is 65537, generated for
+/// tssh like the rsa-sha2-512 one
+const RSA_SHA256_TEST_MODULUS: &str = "\
+    c850de409b2449563c027fa8ae6ba2ce9ade8c9923c0987ec74f58335a789290\
+    1d3c30ec838ad31831f295b71531f2dc5a1bb20281a7c03bf28b845f44491d5c\
+    bda021a2f60e8820023a24790443eba8e5e28e4d2f4932205c50f7400a78e751\
+    1e5c87a5507eb70bf9215d39dfb3f1c14103aa5e861ffc0f6a50c78342f2b372\
+    24a857ecf98c5a070897e680af71f9222eb05bc1d124fe52cf9fe1358065afdc\
+    34bf459243654180a84a191ddc22bec7fd498eed5604d42ea59d30cd502e7c73\
+    96e9d0c240d0ca45a154a696cdb95be4ecbf3b7e3af8d78e5a499784c3de6545\
+    4016588c338c5fc969b8c219a30c37efa533e737cb27ec735f3726c31597eaeb";
+
+/// The pkcs1v15 sha256 signature of RSA_TEST_MESSAGE made by the rsa-sha2-256 self-test key
+const RSA_SHA256_TEST_SIGNATURE: &str = "\
+    9cd4209e754f73faf5712fe2e3b3c93fea33d9c288ee4ee6e3a5d2777fb39c89\
+    faeecc024deec52ed0d6846dea3ccfee5f8f52e17b86e150e1e0141c101b69c4\
+    363bf57e06cc812314eae3c9c9ff16a9244a8c59436b325445d21f7e554726a7\
+    38642f9888c55eddc1ce4645827241a4d70a31195253d1139c7a6b4b5465c4d4\
+    e1d7940d01284a2731b864d468fb9428e6f85d905892017e0d0afbd5cefab486\
+    bee5958f97415ab17e1566d232e7262c4b2ea2c4397e2f9e183d0ec52b6a59f1\
+    ccc1d9014fac49f68e77e48f23f09ba72e73181e6e1ec9516045eb0980bbbed4\
+    6cbb94cdb061de8d91354205e4f71a38cdcc5f639f17fb2d6ee3d08ecb1dca97";
+
+/// Checks aes128-ctr in both directions against the CTR-AES128 vector from NIST SP 800-38A
+pub fn aes128_ctr_self_test() -> bool {
+    aes_ctr_self_test(
+        EncryptAlg::Aes128Ctr,
+        "2b7e151628aed2a6abf7158809cf4f3c",
+        "874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff\
+        5ae4df3edbd5d35e5b4f09020db03eab1e031dda2fbe03d1792170a0f3009cee",
+    )
+}
+
+/// Checks aes192-ctr in both directions against the CTR-AES192 vector from NIST SP 800-38A
+pub fn aes192_ctr_self_test() -> bool {
+    aes_ctr_self_test(
+        EncryptAlg::Aes192Ctr,
+        "8e73b0f7da0e6452c810f32b809079e562f8ead2522c6b7b",
+        "1abc932417521ca24f2b0459fe7e6e0b090339ec0aa6faefd5ccc2c6f4ce8e94\
+        1e36b26bd1ebc670d1bd1d665620abf74f78a7f6d29809585a97daec58c6b050",
+    )
+}
+
+/// Checks aes256-ctr in both directions against the CTR-AES256 vector from NIST SP 800-38A
+pub fn aes256_ctr_self_test() -> bool {
+    aes_ctr_self_test(
+        EncryptAlg::Aes256Ctr,
+        "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+        "601ec313775789a5b7a7f504bbf3d228f443e3ca4d62b59aca84e990cacaf5c5\
+        2b0930daa23de94ce87017ba2d84988ddfc9c58db67aada613c2dd08457941a6",
+    )
+}
+
+/// Checks aes256-cbc in both directions against the CBC-AES256 vector from NIST SP 800-38A
+pub fn aes256_cbc_self_test() -> bool {
+    let (mut encrypter, mut decrypter) = test_keys(
+        EncryptAlg::Aes256Cbc,
+        "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+        "000102030405060708090a0b0c0d0e0f",
+        b"",
+        0,
+    );
+    let plaintext = decode_hex(
+        "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+        30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710",
+    );
+    let cyphertext = decode_hex(
+        "f58c4c04d6e5f1ba779eabfb5f7bfbd69cfc4e967edb808d679f777bc6702c7d\
+        39f23369a9d9bacfa530e26304231461b2eb05e2c39be9fcda6c19078c6a9d1b",
+    );
+
+    matches!(encrypter.encrypt(plaintext.clone()), Ok(result) if result == cyphertext)
+        && matches!(decrypter.decrypt(cyphertext), Ok(result) if result == plaintext)
+}
+
+/// Checks an aes-ctr algorithm in both directions with the given hex encoded key against the
+/// expected cyphertext of the multi-block NIST SP 800-38A plaintext
+fn aes_ctr_self_test(alg: EncryptAlg, key: &str, cyphertext: &str) -> bool {
+    let (mut encrypter, mut decrypter) =
+        test_keys(alg, key, "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff", b"", 0);
+    let plaintext = decode_hex(
+        "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+        30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710",
+    );
+    let cyphertext = decode_hex(cyphertext);
+
+    matches!(encrypter.encrypt(plaintext.clone()), Ok(result) if result == cyphertext)
+        && matches!(decrypter.decrypt(cyphertext), Ok(result) if result == plaintext)
+}
+
+/// Checks hmac-sha2-256 in both directions against test case 2 from RFC 4231. The first four
+/// bytes of the message double as the packet number that is prepended to every mac.
+pub fn hmac_sha256_self_test() -> bool {
+    let packet_num = u32::from_be_bytes(*b"what");
+    let (mut encrypter, mut decrypter) = test_keys(
+        EncryptAlg::Aes128Ctr,
+        &"00".repeat(16),
+        "",
+        b"Jefe",
+        packet_num,
+    );
+    let message = b" do ya want for nothing?";
+    let mac = decode_hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+
+    encrypter.mac(message) == mac && decrypter.verify(message, &mac)
+}
+
+/// Checks ecdh over nistp256 against the 256-bit random ECP group vector from RFC 5903
+pub fn ecdh_nistp256_self_test() -> bool {
+    let secret = match SecretKey::<NistP256>::from_slice(&decode_hex(
+        "c88f01f510d9ac3f70a292daa2316de544e9aab8afe84049c62a9c57862d1433",
+    )) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let public = decode_hex(
+        "04dad0b65394221cf9b051e1feca5787d098dfe637fc90b9ef945d0c3772581180\
+        5271a0461cdb8252d61f1c456fa3e59ab1f45b33accf5f58389e0577b8990bb3",
+    );
+    let peer = match PublicKey::<NistP256>::from_sec1_bytes(&decode_hex(
+        "04d12dfb5289c8d4f81208b70270398c342296970a0bccb74c736fc7554494bf63\
+        56fbf3ca366cc23e8157854c13c58d6aac23f046ada30f8353e74f33039872ab",
+    )) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let shared = decode_hex("d6840f6b42f6edafd13116e0e12565202fef8e9ece7dce03812464d04b9442de");
+
+    let result = diffie_hellman(secret.to_nonzero_scalar(), peer.as_affine());
+    secret.public_key().to_sec1_bytes()[..] == public[..]
+        && result.raw_secret_bytes().as_slice() == shared.as_slice()
+}
+
+/// Checks that rsa-sha2-512 accepts a valid signature and rejects it for a different message
+pub fn rsa_sha2_512_self_test() -> bool {
+    let (host_key, signature) =
+        rsa_test_vector(RSA_TEST_MODULUS, "rsa-sha2-512", RSA_TEST_SIGNATURE);
+    let valid = rsa_sha2_512_verify(host_key.clone(), RSA_TEST_MESSAGE, signature.clone());
+    let forged = rsa_sha2_512_verify(host_key, b"tssh self-tesT", signature);
+    valid.is_ok() && forged.is_err()
+}
+
+/// Checks that rsa-sha2-256 accepts a valid signature and rejects it for a different message
+pub fn rsa_sha2_256_self_test() -> bool {
+    let (host_key, signature) = rsa_test_vector(
+        RSA_SHA256_TEST_MODULUS,
+        "rsa-sha2-256",
+        RSA_SHA256_TEST_SIGNATURE,
+    );
+    let valid = rsa_sha2_256_verify(host_key.clone(), RSA_TEST_MESSAGE, signature.clone());
+    let forged = rsa_sha2_256_verify(host_key, b"tssh self-tesT", signature);
+    valid.is_ok() && forged.is_err()
+}
+
+/// Encodes a self-test key with the hex encoded modulus and an exponent of 65537 along with
+/// its hex encoded signature of the given type in the SSH wire format
+fn rsa_test_vector(modulus: &str, sig_name: &str, signature: &str) -> (Vec<u8>, Vec<u8>) {
+    let mut host_key = Vec::new();
+    SshStream::append_string(&mut host_key, b"ssh-rsa");
+    SshStream::append_mpint(&mut host_key, &[0x01, 0x00, 0x01], true);
+    SshStream::append_mpint(&mut host_key, &decode_hex(modulus), true);
+
+    let mut sig = Vec::new();
+    SshStream::append_string(&mut sig, sig_name.as_bytes());
+    SshStream::append_string(&mut sig, &decode_hex(signature));
+    (host_key, sig)
+}
+
+/// Creates an encrypter and decrypter for the given algorithm that share the given hex encoded
+/// key and iv along with the given mac key and packet number
+fn test_keys(
+    alg: EncryptAlg,
+    key: &str,
+    iv: &str,
+    mac_key: &[u8],
+    packet_num: u32,
+) -> (Encrypter, Decrypter) {
+    let encrypter = Encrypter {
+        encrypt: alg,
+        mac: MacAlg::HmacSha256,
+        compress: CompressAlg::None,
+        compressor: None,
+        authenticated: false,
+        iv: decode_hex(iv),
+        cipher: Cipher::new(alg, &decode_hex(key)).expect("Test keys have a valid length"),
+        mac_key: mac_key.to_vec(),
+        packet_num,
+        bytes: 0,
+        keyed_at: Instant::now(),
+        session_id: Vec::new(),
+    };
+    let decrypter = Decrypter {
+        decrypt: alg,
+        verify: MacAlg::HmacSha256,
+        decompress: CompressAlg::None,
+        decompressor: None,
+        authenticated: false,
+        iv: decode_hex(iv),
+        cipher: Cipher::new(alg, &decode_hex(key)).expect("Test keys have a valid length"),
+        verify_key: mac_key.to_vec(),
+        packet_num,
+        bytes: 0,
+        keyed_at: Instant::now(),
+    };
+    (encrypter, decrypter)
+}
+
+/// Decodes a string of hex digits into bytes
+fn decode_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("Test vectors are valid hex"))
+        .collect()
+}
+
+/// Creates an encrypter and a decrypter that share their keys so that every packet sealed by
+/// one can be opened by the other
+#[cfg(test)]
+pub fn loopback_pair(cipher: &str, mac: &str, compress: &str) -> (Encrypter, Decrypter) {
+    let encrypt = EncryptAlg::from_name(cipher).unwrap();
+    let compress = CompressAlg::from_name(compress).unwrap();
+    let mac_alg = || MacAlg::from_name(mac).unwrap();
+    let key = vec![7; encrypt.key_len()];
+    let iv = vec![3; encrypt.iv_len()];
+    let mac_key = vec![5; mac_alg().key_len()];
+
+    let encrypter = Encrypter {
+        encrypt,
+        mac: mac_alg(),
+        compress,
+        compressor: None,
+        authenticated: false,
+        iv: iv.clone(),
+        cipher: Cipher::new(encrypt, &key).unwrap(),
+        mac_key: mac_key.clone(),
+        packet_num: 0,
+        bytes: 0,
+        keyed_at: Instant::now(),
+        session_id: Vec::new(),
+    };
+    let decrypter = Decrypter {
+        decrypt: encrypt,
+        verify: mac_alg(),
+        decompress: compress,
+        decompressor: None,
+        authenticated: false,
+        iv,
+        cipher: Cipher::new(encrypt, &key).unwrap(),
+        verify_key: mac_key,
+        packet_num: 0,
+        bytes: 0,
+        keyed_at: Instant::now(),
+    };
+    (encrypter, decrypter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QUERY_CATEGORIES;
+
+    #[test]
+    fn every_advertised_algorithm_is_implemented() {
+        for (category, names) in QUERY_CATEGORIES {
+            for name in names {
+                assert!(
+                    is_implemented(category, name),
+                    "{category} algorithm {name} is advertised without an implementation"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cbc_chains_from_one_packet_to_the_next() {
+        let (mut whole, _) = loopback_pair("aes256-cbc", "hmac-sha2-256", "none");
+        let (mut split, mut decrypter) = loopback_pair("aes256-cbc", "hmac-sha2-256", "none");
+        let plaintext: Vec<u8> = (0..64).collect();
+
+        let cyphertext = whole.encrypt(plaintext.clone()).unwrap();
+        let mut first = split.encrypt(plaintext[..16].to_vec()).unwrap();
+        first.extend(split.encrypt(plaintext[16..].to_vec()).unwrap());
+        assert_eq!(first, cyphertext);
+
+        // Identical plaintext blocks only encrypt the same at the same point in the chain
+        assert_ne!(
+            split.encrypt(plaintext[..16].to_vec()).unwrap(),
+            cyphertext[..16]
+        );
+
+        let mut decrypted = decrypter.decrypt(cyphertext[..32].to_vec()).unwrap();
+        decrypted.extend(decrypter.decrypt(cyphertext[32..].to_vec()).unwrap());
+        assert_eq!(decrypted, plaintext);
+        assert!(aes256_cbc_self_test());
+    }
+
+    /// Starts a diffie-hellman-group14-sha256 exchange and returns it with its init payload
+    fn start_dh_group14() -> (KeyExchange, Vec<u8>) {
+        start_kex("diffie-hellman-group14-sha256")
+    }
+
+    fn start_kex(key_exchange: &'static str) -> (KeyExchange, Vec<u8>) {
+        let algs = Algorithms {
+            key_exchange,
+            host_key: "rsa-sha2-512",
+            encrypt: "aes128-ctr",
+            decrypt: "aes128-ctr",
+            mac: "hmac-sha2-256",
+            verify: "hmac-sha2-256",
+            compress: "none",
+            decompress: "none",
+        };
+        KeyExchange::start(&algs, Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn dh_group14_init_sends_public_value() {
+        let prime = dh_group14_prime();
+        assert_eq!(prime.bits(), 2048);
+
+        let (exchange, init) = start_dh_group14();
+        assert_eq!(init[0], SSH_MSG_KEXDH_INIT);
+        assert_eq!(exchange.reply_type(), SSH_MSG_KEXDH_REPLY);
+        let (e, rest) = SshStream::extract_mpint_unsigned(&init[1..]).unwrap();
+        assert!(rest.is_empty());
+
+        // The host's shared secret f^x matches the client's e^y for any host secret y
+        let KeyExchange::DhGroup14Sha256 { secret, public, .. } = exchange else {
+            panic!("Expected a diffie-hellman exchange");
+        };
+        assert_eq!(e, public);
+        let host_secret = BigUint::from(0x1234_5678_9abc_def0u64);
+        let f = BigUint::from(DH_GROUP14_GENERATOR).modpow(&host_secret, &prime);
+        assert_eq!(f.modpow(&secret, &prime), e.modpow(&host_secret, &prime));
+    }
+
+    #[test]
+    fn dh_group14_rejects_degenerate_public_values() {
+        let prime = dh_group14_prime();
+        for f in [
+            BigUint::from(1u32),
+            &prime - BigUint::from(1u32),
+            prime.clone(),
+        ] {
+            let (exchange, _) = start_dh_group14();
+            let mut reply = Vec::new();
+            SshStream::append_string(&mut reply, b"host key");
+            SshStream::append_mpint(&mut reply, &f.to_bytes_be(), true);
+            SshStream::append_string(&mut reply, b"signature");
+            assert!(matches!(
+                exchange.finish(&reply, None),
+                Err(Error::KeyExchange(e))
+                    if e == "Recieved invalid diffie-hellman public value from host"
+            ));
+        }
+    }
+
+    #[test]
+    fn ecdh_rejects_invalid_public_keys() {
+        // The identity point, a point off the curve, and a truncated point
+        let mut off_curve = vec![0x04];
+        off_curve.extend([0u8; 31]);
+        off_curve.push(1);
+        off_curve.extend([0u8; 31]);
+        off_curve.push(1);
+        for point in [vec![0x00], off_curve.clone(), off_curve[..33].to_vec()] {
+            let (exchange, _) = start_kex("ecdh-sha2-nistp256");
+            let mut reply = Vec::new();
+            SshStream::append_string(&mut reply, b"host key");
+            SshStream::append_string(&mut reply, &point);
+            SshStream::append_string(&mut reply, b"signature");
+            assert!(matches!(
+                exchange.finish(&reply, None),
+                Err(Error::KeyExchange(e)) if e == "Failed to parse ecdh public key"
+            ));
+        }
+    }
+
+    #[test]
+    fn rsa_signatures_verify_with_their_own_digest() {
+        assert!(rsa_sha2_512_self_test());
+        assert!(rsa_sha2_256_self_test());
+
+        // A signature only verifies as the type it was made as
+        let (host_key, signature) = rsa_test_vector(
+            RSA_SHA256_TEST_MODULUS,
+            "rsa-sha2-512",
+            RSA_SHA256_TEST_SIGNATURE,
+        );
+        assert!(
+            rsa_sha2_256_verify(host_key.clone(), RSA_TEST_MESSAGE, signature.clone()).is_err()
+        );
+        assert!(rsa_sha2_512_verify(host_key, RSA_TEST_MESSAGE, signature).is_err());
+    }
+
+    #[test]
+    fn rekeying_keeps_the_session_id_and_resets_usage() {
+        let algs = Algorithms {
+            key_exchange: "ecdh-sha2-nistp256",
+            host_key: "rsa-sha2-512",
+            encrypt: "aes128-ctr",
+            decrypt: "aes128-ctr",
+            mac: "hmac-sha2-256",
+            verify: "hmac-sha2-256",
+            compress: "none",
+            decompress: "none",
+        };
+        let secret = |exchange_hash: u8| SharedSecret {
+            key: vec![1; 32],
+            exchange_hash: vec![exchange_hash; 32],
+            hash_fn: |data| Sha256::digest(data).to_vec(),
+            host_key: Vec::new(),
+        };
+
+        let (mut encrypter, mut decrypter) = derive_keys(&algs, secret(1), 3, None, None).unwrap();
+        encrypter.mac(&[0; 64]);
+        encrypter.bytes = REKEY_BYTES;
+        assert!(encrypter.needs_rekey());
+        assert!(!decrypter.needs_rekey());
+
+        // The first exchange hash stays the session id and the sequence numbers carry on
+        let (rekeyed, _) = derive_keys(
+            &algs,
+            secret(2),
+            0,
+            Some(&mut encrypter),
+            Some(&mut decrypter),
+        )
+        .unwrap();
+        assert_eq!(rekeyed.session_id(), &[1; 32]);
+        assert_eq!(rekeyed.packet_num, 4);
+        assert!(!rekeyed.needs_rekey());
+    }
+
+    #[test]
+    fn delayed_compression_starts_after_authentication() {
+        let (mut encrypter, mut decrypter) =
+            loopback_pair("aes128-ctr", "hmac-sha2-256", "zlib@openssh.com");
+        let payload = b"ls -la /home/user\n".repeat(8);
+
+        // Nothing is compressed while authenticating
+        assert_eq!(encrypter.compress(&payload).unwrap(), payload);
+        assert_eq!(decrypter.decompress(&payload).unwrap(), payload);
+
+        // Every payload after continues the same deflate stream starting with the first
+        encrypter.start_delayed_compression();
+        decrypter.start_delayed_compression();
+        for _ in 0..3 {
+            let compressed = encrypter.compress(&payload).unwrap();
+            assert!(compressed.len() < payload.len());
+            assert_eq!(decrypter.decompress(&compressed).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn zlib_compresses_from_the_start() {
+        let (mut encrypter, mut decrypter) = loopback_pair("aes128-ctr", "hmac-sha2-256", "zlib");
+        let payload = b"ls -la /home/user\n".repeat(8);
+        let compressed = encrypter.compress(&payload).unwrap();
+        assert!(compressed.len() < payload.len());
+        assert_eq!(decrypter.decompress(&compressed).unwrap(), payload);
+    }
+
+    #[test]
+    fn unknown_algorithms_are_not_implemented() {
+        assert!(!is_implemented("cipher", "aes128-cbc"));
+        assert!(!is_implemented("mac", "hmac-sha1"));
+        assert!(!is_implemented("other", "none"));
     }
 }
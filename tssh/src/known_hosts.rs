@@ -0,0 +1,257 @@
+use crate::ssh_stream::SshStream;
+use crate::{Error, debug};
+use base64ct::{Base64, Base64Unpadded, Encoding};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// How a host key compares to the keys stored for its host
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub enum HostKeyStatus {
+    /// The key is stored for the host on the given line
+    Known(usize),
+    /// No key of the same type is stored for the host
+    Unknown,
+    /// A different key of the same type is stored for the host on the given line
+    Changed(usize),
+}
+
+/// Checks a host key against ~/.ssh/known_hosts. Unknown hosts are trusted on first use if
+/// prompt is set and the user accepts the key's fingerprint, after which the key is saved.
+/// A changed key is always rejected.
+pub fn verify(
+    host: &str,
+    port: u16,
+    host_key: &[u8],
+    prompt: bool,
+    verbosity: u8,
+) -> Result<(), Error> {
+    let status = check(host, port, host_key)?;
+    let key_type = key_type(host_key)?;
+    let file = match path() {
+        Some(path) => path.display().to_string(),
+        None => "known_hosts".to_string(),
+    };
+    debug(verbosity, 1, || match status {
+        HostKeyStatus::Known(line) => format!("Matched {key_type} host key at {file}:{line}"),
+        HostKeyStatus::Changed(line) => format!("Different {key_type} host key at {file}:{line}"),
+        HostKeyStatus::Unknown => format!("No {key_type} host key match in {file}"),
+    });
+
+    match status {
+        HostKeyStatus::Known(_) => Ok(()),
+        HostKeyStatus::Changed(line) => {
+            eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+            eprintln!("@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @");
+            eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+            eprintln!("Someone could be eavesdropping on you right now!");
+            eprintln!(
+                "The {} key fingerprint sent by {} is {}",
+                key_type,
+                host_pattern(host, port),
+                fingerprint(host_key)
+            );
+            eprintln!("It does not match the key in {file}:{line}.");
+            Err(Error::Other("Host key verification failed"))
+        }
+        HostKeyStatus::Unknown if !prompt => Err(Error::Other("Host key is not in known_hosts")),
+        HostKeyStatus::Unknown => {
+            eprintln!(
+                "The authenticity of host '{}' can't be established.",
+                host_pattern(host, port)
+            );
+            eprintln!("{} key fingerprint is {}.", key_type, fingerprint(host_key));
+            eprint!("Are you sure you want to continue connecting (yes/no)? ");
+            io::stderr().flush()?;
+
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            if answer.trim() != "yes" {
+                return Err(Error::Other("Host key verification failed"));
+            }
+
+            add(host, port, host_key)?;
+            eprintln!(
+                "Permanently added '{}' to the list of known hosts.",
+                host_pattern(host, port)
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Checks a host key against fingerprints pinned by the user instead of known_hosts, which
+/// is left untouched. Any one of the fingerprints may match so that keys can be rotated.
+pub fn verify_pinned(host_key: &[u8], fingerprints: &[String]) -> Result<(), Error> {
+    // OpenSSH prints fingerprints without base64 padding but accept them either way
+    let actual = fingerprint(host_key);
+    if fingerprints
+        .iter()
+        .any(|pinned| pinned.trim_end_matches('=') == actual)
+    {
+        return Ok(());
+    }
+
+    eprintln!(
+        "The {} key fingerprint sent by the host is {}",
+        key_type(host_key)?,
+        actual
+    );
+    eprintln!("It does not match any pinned host key fingerprint.");
+    Err(Error::Other("Host key verification failed"))
+}
+
+/// Compares a host key to the keys stored for its host in ~/.ssh/known_hosts. Hashed
+/// entries, wildcards and markers such as @revoked are not supported and are skipped.
+pub fn check(host: &str, port: u16, host_key: &[u8]) -> Result<HostKeyStatus, Error> {
+    match path().map(fs::read_to_string) {
+        Some(Ok(contents)) => check_contents(&contents, host, port, host_key),
+        Some(Err(err)) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(HostKeyStatus::Unknown),
+    }
+}
+
+/// Compares a host key to the keys stored for its host in the contents of a known_hosts file,
+/// returning the number of the line that decided it
+fn check_contents(
+    contents: &str,
+    host: &str,
+    port: u16,
+    host_key: &[u8],
+) -> Result<HostKeyStatus, Error> {
+    let pattern = host_pattern(host, port);
+    let key_type = key_type(host_key)?;
+    let mut status = HostKeyStatus::Unknown;
+    for (line_num, line) in (1..).zip(contents.lines().map(str::trim)) {
+        if line.is_empty() || line.starts_with(['#', '@', '|']) {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let (hosts, stored_type, stored_key) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(hosts), Some(stored_type), Some(stored_key)) => (hosts, stored_type, stored_key),
+            _ => continue,
+        };
+        if stored_type != key_type || !hosts.split(',').any(|name| name == pattern) {
+            continue;
+        }
+
+        // A host can have more than one key of a type listed so keep looking on a mismatch,
+        // reporting the first one if none match
+        match Base64::decode_vec(stored_key) {
+            Ok(stored_key) if stored_key == host_key => return Ok(HostKeyStatus::Known(line_num)),
+            _ if matches!(status, HostKeyStatus::Unknown) => {
+                status = HostKeyStatus::Changed(line_num)
+            }
+            _ => (),
+        }
+    }
+
+    Ok(status)
+}
+
+/// Appends a host key to ~/.ssh/known_hosts, creating it if needed
+fn add(host: &str, port: u16, host_key: &[u8]) -> Result<(), Error> {
+    let path = match path() {
+        Some(path) => path,
+        None => {
+            return Err(Error::Other(
+                "Unable to find home directory for known_hosts",
+            ));
+        }
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(
+        file,
+        "{} {} {}",
+        host_pattern(host, port),
+        key_type(host_key)?,
+        Base64::encode_string(host_key)
+    )?;
+    Ok(())
+}
+
+/// Returns the path of the known_hosts file if the home directory is known
+fn path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".ssh").join("known_hosts"))
+}
+
+/// Formats a host the way known_hosts names it, which only includes the port if it is not 22
+fn host_pattern(host: &str, port: u16) -> String {
+    match port {
+        22 => host.to_string(),
+        _ => format!("[{host}]:{port}"),
+    }
+}
+
+/// Returns the type name a host key blob starts with such as ssh-rsa
+fn key_type(host_key: &[u8]) -> Result<String, Error> {
+    let (key_type, _) = SshStream::extract_string(host_key)?;
+    Ok(String::from_utf8_lossy(&key_type).to_string())
+}
+
+/// Returns the SHA-256 fingerprint of a host key in the form OpenSSH prints it
+pub fn fingerprint(host_key: &[u8]) -> String {
+    format!(
+        "SHA256:{}",
+        Base64Unpadded::encode_string(&Sha256::digest(host_key))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned_fingerprints_match_any_listed_key() {
+        let mut host_key = Vec::new();
+        SshStream::append_string(&mut host_key, b"ssh-rsa");
+        SshStream::append_string(&mut host_key, b"not a real key");
+        let actual = fingerprint(&host_key);
+        let other = format!("SHA256:{}", Base64Unpadded::encode_string(&[0; 32]));
+
+        assert!(verify_pinned(&host_key, std::slice::from_ref(&actual)).is_ok());
+        assert!(verify_pinned(&host_key, &[other.clone(), actual.clone()]).is_ok());
+        assert!(verify_pinned(&host_key, &[format!("{actual}=")]).is_ok());
+        assert!(verify_pinned(&host_key, &[other]).is_err());
+    }
+
+    #[test]
+    fn checks_report_the_deciding_line() {
+        let mut host_key = Vec::new();
+        SshStream::append_string(&mut host_key, b"ssh-ed25519");
+        SshStream::append_string(&mut host_key, b"not a real key");
+        let mut other_key = Vec::new();
+        SshStream::append_string(&mut other_key, b"ssh-ed25519");
+        SshStream::append_string(&mut other_key, b"another key");
+        let stored = Base64::encode_string(&host_key);
+        let other = Base64::encode_string(&other_key);
+
+        let contents = format!(
+            "# comment\nother.example ssh-ed25519 {stored}\n\nexample.com ssh-ed25519 {other}\n\
+             [example.com]:2222,example.com ssh-ed25519 {stored}\n"
+        );
+        assert_eq!(
+            check_contents(&contents, "example.com", 22, &host_key).unwrap(),
+            HostKeyStatus::Known(5)
+        );
+        assert_eq!(
+            check_contents(&contents, "example.com", 22, &other_key).unwrap(),
+            HostKeyStatus::Known(4)
+        );
+        assert_eq!(
+            check_contents(&contents, "example.com", 2222, &other_key).unwrap(),
+            HostKeyStatus::Changed(5)
+        );
+        assert_eq!(
+            check_contents(&contents, "example.org", 22, &host_key).unwrap(),
+            HostKeyStatus::Unknown
+        );
+    }
+}
@@ -1,25 +1,76 @@
+#[cfg(feature = "async")]
+mod async_client;
+mod builder;
+mod commands;
+mod compat;
+mod config;
+mod connection;
 mod encrypter;
+mod keys;
+mod known_hosts;
+mod protocol;
+mod server_alive;
+mod session;
+mod session_timeout;
+mod sftp;
+mod sink;
+mod socks;
 mod ssh_stream;
+mod terminal;
 mod writer;
 
+#[cfg(feature = "async")]
+pub use async_client::{AsyncChannel, AsyncClient, ChannelEvent, ExecOutput};
+pub use builder::{Client, ClientBuilder};
+pub use compat::{HostVersion, Quirk};
+use config::glob_match;
+pub use config::{HostConfig, SshConfig};
+pub use connection::Connection;
 use crossterm::terminal::size;
-use encrypter::{Decrypter, Encrypter, generate};
-use rand::Rng;
-use rand_core::OsRng;
-use rpassword;
+use encrypter::{
+    Decrypter, Encrypter, aes128_ctr_self_test, aes192_ctr_self_test, aes256_cbc_self_test,
+    aes256_ctr_self_test, ecdh_nistp256_self_test, hmac_sha256_self_test, rsa_sha2_256_self_test,
+    rsa_sha2_512_self_test,
+};
+use keys::prompt_private_key;
+pub use keys::{PrivateKey, load_private_key};
+use protocol::{
+    Auth, AuthOutput, ChannelOutput, Channels, InfoRequest, Kex, KexOutput, Pty, gen_disconnect,
+    gen_unimplemented, gen_userauth_header, gen_window_change,
+};
+pub use session::{Handshake, Session};
+use sink::LogSink;
+pub use sink::{BufferSink, OutputSink, StdioSink};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
 use ssh_stream::SshStream;
 use std::array::TryFromSliceError;
+use std::collections::VecDeque;
+use std::env;
 use std::fmt;
-use std::io::{self, BufRead, BufReader, Write};
-use std::net::TcpStream;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use writer::spawn;
+use terminal::{
+    catch_interrupt, interrupted, local_hostname, local_username, read_password_fd,
+    release_interrupt, restore_terminal, terminal_modes,
+};
+pub use writer::StdinEofAction;
+use writer::{InputOptions, spawn};
 
 // Packet Types
 /// Indicates a packet intends to disconnect
 const SSH_MSG_DISCONNECT: u8 = 1;
+/// Indicates a packet that carries nothing and must be ignored
+const SSH_MSG_IGNORE: u8 = 2;
+/// Indicates that the sender did not recognize a packet it was sent
+const SSH_MSG_UNIMPLEMENTED: u8 = 3;
+/// Indicates a packet carrying a debugging message
+const SSH_MSG_DEBUG: u8 = 4;
 /// Indicates that a pecket contains key exchange negotiation info
 const SSH_MSG_KEXINIT: u8 = 20;
 /// Indicates that a packet is accepting a request for a service
@@ -34,8 +85,16 @@ const SSH_MSG_USERAUTH_SUCCESS: u8 = 52;
 const SSH_MSG_USERAUTH_BANNER: u8 = 53;
 /// Indicates that a user tried to authenticate with an expired passwords and needs to change it
 const SSH_MSG_USERAUTH_PASSWD_CHANGEREQ: u8 = 60;
+/// Indicates that the host is asking keyboard-interactive questions
+const SSH_MSG_USERAUTH_INFO_REQUEST: u8 = 60;
+/// Indicates the answers to keyboard-interactive questions
+const SSH_MSG_USERAUTH_INFO_RESPONSE: u8 = 61;
 /// Indicates that a general ssh request has been made
 const SSH_MSG_GLOBAL_REQUEST: u8 = 80;
+/// Indicates that a general ssh request was fulfilled
+const SSH_MSG_REQUEST_SUCCESS: u8 = 81;
+/// Indicates that a general ssh request could not be fulfilled
+const SSH_MSG_REQUEST_FAILURE: u8 = 82;
 /// Indicates an attempt to open a channel
 const SSH_MSG_CHANNEL_OPEN: u8 = 90;
 /// Confirms the success of an open channel request
@@ -59,29 +118,85 @@ const SSH_MSG_CHANNEL_REQUEST: u8 = 98;
 const SSH_MSG_CHANNEL_SUCCESS: u8 = 99;
 /// Indicates that a channel request failed to be processed
 const SSH_MSG_CHANNEL_FAILURE: u8 = 100;
+/// Indicates the reason for disconnecting is that the key exchange failed
+const SSH_DISCONNECT_KEY_EXCHANGE_FAILED: [u8; 4] = [0, 0, 0, 3];
 /// Indicates the reason for disconnecting is that the application is done
 const SSH_DISCONNECT_BY_APPLICATION: [u8; 4] = [0, 0, 0, 11];
 /// Indicates the reason for a failure to open a channel was because it was unauthorized
 const SSH_OPEN_ADMINISTRATIVELY_PROHIBITED: [u8; 4] = [0, 0, 0, 1];
 
+/// The version string sent to the host during version exchange
+const CLIENT_VERSION: &[u8; 16] = b"SSH-2.0-TSSH_1.0";
+
+/// The window size granted to the host for each opened channel
+const CLIENT_WINDOW_SIZE: u32 = 2097152;
+
+/// The maximum packet size the host may send over an opened channel
+const CLIENT_PACKET_MAX: u32 = 32768;
+
+/// This machine's host key, which signs hostbased authentication requests
+const HOST_KEY_PATH: &str = "/etc/ssh/ssh_host_rsa_key";
+
+/// How long to wait before the first retry of a connection that timed out or was refused,
+/// doubling after each further attempt up to the maximum
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(8);
+
+/// The longest the host may take to send a packet during a key re-exchange
+const REKEY_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// List of supported key exchange algorithms
-const KEX_ALGS: [&'static str; 1] = ["ecdh-sha2-nistp256"];
+const KEX_ALGS: [&str; 2] = ["ecdh-sha2-nistp256", "diffie-hellman-group14-sha256"];
 
 /// List of supported host key varification algorithms
 /// these must be compatible with all kex algorithms for now
-const HOST_KEY_ALGS: [&'static str; 1] = ["rsa-sha2-512"];
+const HOST_KEY_ALGS: [&str; 2] = ["rsa-sha2-512", "rsa-sha2-256"];
 
 /// List of all supported encryption algorithms
 /// (both server to client and client to server)
-const ENCRYPT_ALGS: [&'static str; 1] = ["aes256-ctr"];
+const ENCRYPT_ALGS: [&str; 4] = ["aes256-ctr", "aes192-ctr", "aes128-ctr", "aes256-cbc"];
+
+/// The encryption algorithms offered unless others are given. CBC has known weaknesses in
+/// SSH so it is only offered for legacy hosts when explicitly allowed.
+const DEFAULT_ENCRYPT_ALGS: [&str; 3] = ["aes256-ctr", "aes192-ctr", "aes128-ctr"];
+
+/// The encryption algorithms that must be explicitly allowed to be offered
+const CBC_ALGS: [&str; 1] = ["aes256-cbc"];
 
 /// List of all supported mac algorithms
 /// (both server to client and client to server)
-const MAC_ALGS: [&'static str; 1] = ["hmac-sha2-256"];
+const MAC_ALGS: [&str; 2] = ["hmac-sha2-256-etm@openssh.com", "hmac-sha2-256"];
 
 /// List of all supported compression algorithms
 /// (both server to client and client to server)
-const COMPRESS_ALGS: [&'static str; 1] = ["none"];
+const COMPRESS_ALGS: [&str; 3] = ["none", "zlib@openssh.com", "zlib"];
+
+/// The algorithm categories that can be queried with the supported algorithms of each
+const QUERY_CATEGORIES: [(&str, &[&str]); 5] = [
+    ("cipher", &ENCRYPT_ALGS),
+    ("mac", &MAC_ALGS),
+    ("kex", &KEX_ALGS),
+    ("key", &HOST_KEY_ALGS),
+    ("compression", &COMPRESS_ALGS),
+];
+
+/// A named check of a cryptographic primitive against known test vectors
+type SelfTest = (&'static str, fn() -> bool);
+
+/// The checks run by self_test
+const SELF_TESTS: [SelfTest; 8] = [
+    ("aes128-ctr", aes128_ctr_self_test),
+    ("aes192-ctr", aes192_ctr_self_test),
+    ("aes256-ctr", aes256_ctr_self_test),
+    ("aes256-cbc", aes256_cbc_self_test),
+    ("hmac-sha2-256", hmac_sha256_self_test),
+    ("ecdh-sha2-nistp256", ecdh_nistp256_self_test),
+    ("rsa-sha2-512", rsa_sha2_512_self_test),
+    ("rsa-sha2-256", rsa_sha2_256_self_test),
+];
+
+/// The keys from a re-exchange along with the connection packets deferred during it
+type Rekeyed = (Encrypter, Decrypter, Vec<(u8, Vec<u8>)>);
 
 /// The arguments used when first run
 pub struct Args<'a> {
@@ -90,6 +205,66 @@ pub struct Args<'a> {
     /// The name or ip adress of the host server for
     /// establishing TCP/IP connection
     pub hostname: &'a str,
+    /// The port the host is listening on
+    pub port: u16,
+    /// The encryption algorithms to offer instead of the defaults
+    pub ciphers: Option<Vec<String>>,
+    /// The file to write exchange hash inputs to for debugging if any
+    pub dump_exchange_hash: Option<&'a str>,
+    /// The number of idle seconds before sending TCP keepalive probes if any
+    pub keepalive: Option<u64>,
+    /// Whether or not to send keystrokes right away instead of letting TCP batch them
+    pub nodelay: bool,
+    /// Whether or not to offer CBC ciphers to legacy hosts
+    pub allow_cbc: bool,
+    /// The private key file to authenticate with if any
+    pub identity_file: Option<&'a str>,
+    /// Whether or not to try hostbased authentication with this machine's host key
+    pub hostbased: bool,
+    /// The fingerprints the host key must match instead of checking known_hosts if any
+    pub host_key_fingerprints: Vec<&'a str>,
+    /// The patterns of the environment variables to send instead of the defaults if any
+    pub send_env: Option<Vec<&'a str>>,
+    /// The file to record the session's output in if any
+    pub log_file: Option<&'a str>,
+    /// The file to record the timing of the session's output in if any
+    pub log_timing: Option<&'a str>,
+    /// The file to record the session's input in if any
+    pub log_input: Option<&'a str>,
+    /// Whether or not to skip opening a session and just keep the connection open
+    pub no_session: bool,
+    /// Whether or not to start the sftp subsystem instead of a shell
+    pub sftp: bool,
+    /// Whether or not to skip requesting a pseudo-terminal even when stdin is a terminal
+    pub no_pty: bool,
+    /// Whether or not to request a pseudo-terminal even for a command, unless no_pty is set
+    pub force_pty: bool,
+    /// The address and port to serve a SOCKS proxy on instead of opening a session if any
+    pub dynamic_forward: Option<(&'a str, u16)>,
+    /// The command to run instead of an interactive shell if any
+    pub command: Option<String>,
+    /// The seconds to wait for the TCP connection before giving up if any
+    pub connect_timeout: Option<u64>,
+    /// How many times to try connecting to a host that times out or refuses if any
+    pub connection_attempts: Option<u32>,
+    /// The local ports the connection must be made from if any
+    pub source_ports: Option<RangeInclusive<u16>>,
+    /// The file descriptor to read the password from if any
+    pub password_fd: Option<i32>,
+    /// The number of seconds without hearing from the host before checking it is alive if any
+    pub server_alive_interval: Option<u64>,
+    /// The number of unanswered checks before the host is considered dead if not the default
+    pub server_alive_count_max: Option<u32>,
+    /// The number of seconds the session may run before it is ended if any
+    pub session_timeout: Option<u64>,
+    /// How much debugging output to print, from 0 for none to 2 for packet payloads
+    pub verbosity: u8,
+    /// What to do once piped input ends
+    pub stdin_eof_action: StdinEofAction,
+    /// The character that starts escape sequences, or None to disable them
+    pub escape_char: Option<u8>,
+    /// Which kinds of addresses the host may be connected to at
+    pub address_family: AddressFamily,
 }
 
 /// The types of errors that can be returned by running tssh
@@ -97,6 +272,32 @@ pub struct Args<'a> {
 pub enum Error {
     Io(io::Error),
     TryFromSliceError(TryFromSliceError),
+    KeyRexchange(Box<Error>),
+    /// The connection dropped part way through a packet after reading this many of its bytes
+    TruncatedPacket(usize, usize),
+    /// The session ran for longer than its session timeout and was ended
+    SessionTimeout,
+    /// The host did not accept the TCP connection before the connect timeout
+    ConnectTimeout,
+    /// The host refused the TCP connection
+    ConnectionRefused,
+    /// The hostname did not resolve to any address
+    HostNotFound(String),
+    /// Every address the host resolved to was tried and failed for the given reason
+    ConnectFailed(Vec<SocketAddr>, Box<Error>),
+    /// The host sent something the SSH protocol does not allow
+    Protocol(String),
+    /// Every authentication method the client could try was rejected or unsupported
+    AuthFailed(String),
+    /// The host ended the connection with a disconnect message
+    Disconnect {
+        code: u32,
+        description: String,
+    },
+    /// The client and host have no algorithm of some category in common
+    Negotiation(String),
+    /// The key exchange could not produce keys the client can trust
+    KeyExchange(String),
     Other(&'static str),
 }
 
@@ -117,879 +318,1302 @@ impl fmt::Display for Error {
         match self {
             Error::Io(e) => write!(f, "{e}"),
             Error::TryFromSliceError(e) => write!(f, "{e}"),
+            Error::KeyRexchange(e) => write!(f, "Key re-exchange failed: {e}"),
+            Error::TruncatedPacket(read, expected) => write!(
+                f,
+                "Connection dropped mid-packet (read {read} of {expected} bytes)"
+            ),
+            Error::SessionTimeout => write!(f, "Session timed out"),
+            Error::ConnectTimeout => write!(f, "Timed out connecting to host"),
+            Error::ConnectionRefused => write!(f, "Connection refused by host"),
+            Error::HostNotFound(host) => write!(f, "Could not resolve hostname {host}"),
+            Error::ConnectFailed(addrs, e) => {
+                let addrs: Vec<String> = addrs.iter().map(SocketAddr::to_string).collect();
+                write!(f, "Failed to connect to {}: {e}", addrs.join(", "))
+            }
+            Error::Protocol(e) => write!(f, "Protocol error: {e}"),
+            Error::AuthFailed(e) => write!(f, "Authentication failed: {e}"),
+            Error::Disconnect { code, description } => {
+                write!(f, "Disconnected ({code}): {description}")
+            }
+            Error::Negotiation(e) => write!(f, "Negotiation failed: {e}"),
+            Error::KeyExchange(e) => write!(f, "Key exchange failed: {e}"),
             Error::Other(e) => write!(f, "Custom error: {e}"),
         }
     }
 }
 
+/// A channel request sent with want reply set that the host has not replied to yet
 #[derive(Clone, Copy)]
 pub enum WaitingFor {
-    None,
+    /// The pseudo-terminal request
     Pty,
+    /// The shell or exec request
     Shell,
 }
 
-/// Establishes a connection to a given host and procedes with SSH authentication and connection
-pub fn run(args: Args) -> Result<(), Error> {
-    // Establish connection
-    let mut stream = TcpStream::connect(format!("{}:22", args.hostname))?;
-
-    // Runs the SSH version exchange protocol and saves version info for exchange hash
-    let hash_prefix = exchange_versions(&mut stream)?;
-
-    // Set up SSH stream
-    let mut stream = SshStream::new(stream);
-
-    // Exchange key information
-    let (mut encrypter, mut decrypter) = exchange_keys(&mut stream, hash_prefix.clone())?;
-
-    // Begin authentication stage
-    authenticate(&mut stream, &mut encrypter, &mut decrypter, args.username)?;
+/// Which kinds of addresses a host may be connected to at
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AddressFamily {
+    /// Any address the host resolves to
+    #[default]
+    Any,
+    /// Only IPv4 addresses
+    Inet,
+    /// Only IPv6 addresses
+    Inet6,
+}
 
-    // Start a session window
-    let mut local_window = open_channel(&mut stream, &mut encrypter)?;
+/// Establishes a connection to a given host and procedes with SSH authentication and connection.
+/// Results in the exit status of the remote command if one was run and the host reported it.
+pub fn run(args: Args) -> Result<Option<u32>, Error> {
+    let mut builder = ClientBuilder::new()
+        .host(args.hostname)
+        .port(args.port)
+        .username(args.username)
+        .nodelay(args.nodelay)
+        .allow_cbc(args.allow_cbc)
+        .address_family(args.address_family);
+    if let Some(ciphers) = args.ciphers {
+        builder = builder.ciphers(&ciphers);
+    }
+    if let Some(path) = args.dump_exchange_hash {
+        builder = builder.dump_exchange_hash(path);
+    }
+    if let Some(seconds) = args.keepalive {
+        builder = builder.keepalive(Duration::from_secs(seconds));
+    }
+    if let Some(path) = args.identity_file {
+        builder = builder.identity_file(path);
+    }
+    if let Some(seconds) = args.connect_timeout {
+        builder = builder.timeout(Duration::from_secs(seconds));
+    }
+    if let Some(attempts) = args.connection_attempts {
+        builder = builder.connection_attempts(attempts);
+    }
+    for fingerprint in args.host_key_fingerprints {
+        builder = builder.host_key_fingerprint(fingerprint);
+    }
+    if let Some(patterns) = args.send_env {
+        builder = builder.send_env(&patterns);
+    }
+    if let Some(path) = args.log_file {
+        builder = builder.log_file(path);
+    }
+    if let Some(path) = args.log_timing {
+        builder = builder.log_timing(path);
+    }
+    if let Some(path) = args.log_input {
+        builder = builder.log_input(path);
+    }
+    if let Some(ports) = args.source_ports {
+        builder = builder.source_port_range(ports);
+    }
+    if let Some(fd) = args.password_fd {
+        builder = builder.password(read_password_fd(fd)?);
+    }
+    if let Some(seconds) = args.server_alive_interval {
+        builder = builder.server_alive_interval(Duration::from_secs(seconds));
+    }
+    if let Some(count) = args.server_alive_count_max {
+        builder = builder.server_alive_count_max(count);
+    }
+    if let Some(seconds) = args.session_timeout {
+        builder = builder.session_timeout(Duration::from_secs(seconds));
+    }
+    // -T always wins over -t
+    if args.no_pty {
+        builder = builder.request_pty(false);
+    } else if args.force_pty {
+        builder = builder.request_pty(true);
+    }
+    builder = builder
+        .hostbased(args.hostbased)
+        .stdin_eof_action(args.stdin_eof_action)
+        .escape_char(args.escape_char)
+        .verbose(args.verbosity);
+    let client = builder.build()?;
+    if let Some((bind_address, port)) = args.dynamic_forward {
+        return client
+            .connect()?
+            .dynamic_forward(bind_address, port)
+            .map(|()| None);
+    }
+    if args.no_session {
+        return client.connect()?.wait().map(|()| None);
+    }
+    if args.sftp {
+        return client.sftp().map(|()| None);
+    }
+    match &args.command {
+        Some(command) => client.exec(command),
+        None => client.run().map(|()| None),
+    }
+}
 
-    let mut server_channel: u32 = 0u32;
-    let mut state: WaitingFor = WaitingFor::None;
+/// Opens a session on an authenticated connection and procedes with the command or an
+/// interactive shell if there is no command, handing the host's output to the sink. Results
+/// in the exit status the host reported if any.
+fn run_session(
+    connection: Connection,
+    command: Option<&str>,
+    sink: &mut dyn OutputSink,
+) -> Result<Option<u32>, Error> {
+    let Connection {
+        client,
+        mut stream,
+        mut encrypter,
+        mut decrypter,
+        hash_prefix,
+    } = connection;
+
+    // Start a session window with a pseudo-terminal the size of the local terminal in characters
+    // unless the session is being piped through
+    let request_pty = pty_wanted(
+        client.request_pty,
+        command.is_some(),
+        io::stdin().is_terminal(),
+    );
+    let (width, height) = if request_pty { size()? } else { (0, 0) };
+    let pty = request_pty.then(|| Pty {
+        width: width as u32,
+        height: height as u32,
+        modes: terminal_modes(),
+    });
+    let env = env_vars(&client.send_env);
+    let mut channels = Channels::new();
+    let (session, payload) = channels.open_session(pty, env, command.map(str::to_string));
+    stream.send(&payload, Some(&mut encrypter))?;
+
+    // Record the session if asked to, creating every log file before the session starts
+    let mut log_sink;
+    let sink: &mut dyn OutputSink = match &client.log_file {
+        Some(path) => {
+            let timing = client.log_timing.as_ref().map(File::create).transpose()?;
+            log_sink = LogSink::new(sink, File::create(path)?, timing);
+            &mut log_sink
+        }
+        None => sink,
+    };
+    let mut input_log = client.log_input.as_ref().map(File::create).transpose()?;
 
     // Shared state with reading and writing thread
-    let mut server_packet_max: u32 = 0;
     let remote_window: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
-    let mut encrypter = Arc::new(Mutex::new(encrypter));
+    let local_window = Arc::new(AtomicU64::new(channels.get(session)?.local_window()));
+    let encrypter = Arc::new(Mutex::new(encrypter));
     let stop_flag = Arc::new(AtomicBool::new(false));
+    let missed_checks = Arc::new(AtomicU32::new(0));
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let mut exit_status = None;
+    let mut deferred = VecDeque::new();
+
+    // The deadline covers the whole session however active it is
+    if let Some(timeout) = client.session_timeout {
+        session_timeout::spawn(
+            stream.try_clone()?,
+            encrypter.clone(),
+            timeout,
+            timed_out.clone(),
+            stop_flag.clone(),
+        );
+    }
 
     loop {
-        let (packet_type, data) = stream.read(Some(&mut decrypter))?;
-        match packet_type {
-            SSH_MSG_DISCONNECT => return Err(Error::Other("Host sent ssh disconnect message")),
-            SSH_MSG_KEXINIT => {
-                stop_flag.store(true, Ordering::Relaxed);
-                std::thread::sleep(Duration::from_millis(300));
-                if let Ok(mutex) = Arc::try_unwrap(encrypter) {
-                    let enc = mutex.into_inner().unwrap();
-                    let (enc, dec) =
-                        key_rexchange(data, &mut stream, hash_prefix.clone(), enc, decrypter)?;
-                    encrypter = Arc::new(Mutex::new(enc));
-                    decrypter = dec;
-                    stop_flag.store(false, Ordering::Relaxed);
+        // Replace keys that have been used for too long or for too much data. Whatever the
+        // host sent before it saw the re-exchange is handled once the new keys are in use.
+        let rekey_due = decrypter.needs_rekey() || encrypter.lock().unwrap().needs_rekey();
+        if rekey_due && deferred.is_empty() {
+            let mut enc = encrypter.lock().unwrap();
+            let rekeyed = rekey(
+                &mut stream,
+                hash_prefix.clone(),
+                client,
+                &mut enc,
+                &mut decrypter,
+            );
+            let (new_enc, new_dec, packets) = match rekeyed {
+                Ok(rekeyed) => rekeyed,
+                Err(err) => {
+                    abandon_session(&stop_flag);
+                    return Err(err);
+                }
+            };
+            *enc = new_enc;
+            decrypter = new_dec;
+            deferred.extend(packets);
+        }
+
+        // The sequence number of a packet deferred during a key re-exchange is no longer known
+        let from_stream = deferred.is_empty();
+        let read = match deferred.pop_front() {
+            Some(packet) => Ok(packet),
+            None => stream.read(Some(&mut decrypter)),
+        };
+        let (packet_type, data) = match read {
+            Ok(packet) => packet,
+            Err(err) => {
+                // Keep whatever output did arrive before the connection failed
+                let _ = sink.flush();
+                let stopped = stop_flag.load(Ordering::Relaxed);
+                abandon_session(&stop_flag);
+
+                if timed_out.load(Ordering::Relaxed) {
+                    return Err(Error::SessionTimeout);
+                }
+
+                // The connection is expected to end once the session is stopping
+                if stopped {
+                    return Ok(exit_status);
+                }
+                if server_alive::gave_up(&missed_checks, client.server_alive_count_max) {
+                    return Err(Error::Other("Host stopped responding to keepalives"));
+                }
+                return Err(err);
+            }
+        };
+        let sequence_number = from_stream.then(|| last_sequence_number(&decrypter));
+
+        // Any packet at all shows the host is still alive
+        missed_checks.store(0, Ordering::Relaxed);
+        if packet_type == SSH_MSG_KEXINIT {
+            // Holding the encrypter pauses the writing thread until the new keys are in use
+            let mut enc = encrypter.lock().unwrap();
+            let rekeyed = key_rexchange(
+                data,
+                &mut stream,
+                hash_prefix.clone(),
+                client,
+                &mut enc,
+                &mut decrypter,
+            );
+            let (new_enc, new_dec) = match rekeyed {
+                Ok(keys) => keys,
+                Err(err) => {
+                    abandon_session(&stop_flag);
+                    return Err(err);
+                }
+            };
+            *enc = new_enc;
+            decrypter = new_dec;
+            continue;
+        }
+
+        let (_, outputs) = channels.handle(packet_type, &data)?;
+        for output in outputs {
+            match output {
+                ChannelOutput::Send(payload) => send(&mut stream, &encrypter, &payload)?,
+                ChannelOutput::WindowAdjust(amount) => {
+                    let mut size = remote_window.lock().unwrap();
+                    *size += amount;
+                }
+                ChannelOutput::Ready => {
+                    // Ctrl-C is sent to the host once the session starts
+                    release_interrupt();
+
+                    // The writing thread only sees resizes once it starts so catch any that
+                    // happened since the pseudo-terminal was requested
+                    let channel = channels.get(session)?;
+                    let (new_width, new_height) = if request_pty {
+                        size()?
+                    } else {
+                        (width, height)
+                    };
+                    if (new_width, new_height) != (width, height) {
+                        let request = gen_window_change(
+                            channel.server_channel(),
+                            new_width as u32,
+                            new_height as u32,
+                        );
+                        send(&mut stream, &encrypter, &request)?;
+                    }
+
                     spawn(
                         stream.try_clone()?,
                         encrypter.clone(),
                         remote_window.clone(),
-                        server_packet_max,
-                        server_channel,
+                        local_window.clone(),
+                        channel,
                         stop_flag.clone(),
+                        InputOptions {
+                            eof_action: client.stdin_eof_action,
+                            log: input_log.take(),
+                            pty: request_pty,
+                            escape_char: client.escape_char,
+                        },
                     )?;
-                } else {
-                    return Err(Error::Other(
-                        "Failed to stop writing thread for key rexchange",
-                    ));
+
+                    if let Some(interval) = client.server_alive_interval {
+                        server_alive::spawn(
+                            stream.try_clone()?,
+                            encrypter.clone(),
+                            interval,
+                            client.server_alive_count_max,
+                            missed_checks.clone(),
+                            stop_flag.clone(),
+                        );
+                    }
+                }
+                ChannelOutput::Stdout(data) => sink.stdout(&data)?,
+                ChannelOutput::Stderr(data) => sink.stderr(&data)?,
+                ChannelOutput::ExitStatus(status) => exit_status = Some(status),
+                ChannelOutput::Eof => {
+                    // Terminate the writing thread and close the channel
+                    stop_flag.store(true, Ordering::Relaxed);
+                    let channel = channels.get_mut(session)?;
+                    send(&mut stream, &encrypter, &channel.eof_message())?;
+                    if let Some(message) = channel.close_message() {
+                        send(&mut stream, &encrypter, &message)?;
+                    }
+                }
+                ChannelOutput::Closed => {
+                    // Everything the host sent has been read by now since it closes last
+                    stop_flag.store(true, Ordering::Relaxed);
+                    restore_terminal();
+                    sink.flush()?;
+                    let mut enc = encrypter.lock().unwrap();
+                    stream.disconnect(b"Done with session", Some(&mut enc))?;
+                    return Ok(exit_status);
+                }
+                ChannelOutput::OpenFailed(reason_code, description) => {
+                    eprintln!(
+                        "Failed to open channel with reason code {reason_code}: {description}"
+                    );
+                }
+                output @ (ChannelOutput::GlobalRequest(..) | ChannelOutput::Unknown(_)) => {
+                    if let Some(reply) =
+                        connection_reply(&output, sequence_number, client.verbosity)
+                    {
+                        send(&mut stream, &encrypter, &reply)?;
+                    }
+                }
+                ChannelOutput::ForwardDenied(origin) => {
+                    writeln!(
+                        io::stderr(),
+                        "Denied connection from {}:{} on forwarded port {}",
+                        origin.originator_address,
+                        origin.originator_port,
+                        origin.connected_port
+                    )?;
+                    io::stderr().flush()?;
+                }
+                ChannelOutput::Warning(message) => {
+                    writeln!(io::stderr(), "{message}")?;
+                    io::stderr().flush()?;
+                }
+                ChannelOutput::Debug(message, always_display) => {
+                    print_host_debug(&message, always_display, client.verbosity)
                 }
             }
-            SSH_MSG_GLOBAL_REQUEST => process_global_request(data)?,
-            SSH_MSG_CHANNEL_OPEN => deny_channel_open(data, &mut stream, &encrypter)?,
-            SSH_MSG_CHANNEL_OPEN_CONFIRMATION => {
-                let (channel, window, packet_max) =
-                    confirm_channel_open(data, &mut stream, &encrypter)?;
-                server_channel = channel;
-                server_packet_max = packet_max;
-                state = WaitingFor::Pty;
-                let mut size = remote_window.lock().unwrap();
-                *size = window;
-            }
-            SSH_MSG_CHANNEL_OPEN_FAILURE => handle_channel_open_fail(data)?,
-            SSH_MSG_CHANNEL_WINDOW_ADJUST => {
-                let add_amount = adjust_window(data)?;
-                let mut size = remote_window.lock().unwrap();
-                *size += add_amount;
-            }
-            SSH_MSG_CHANNEL_DATA => {
-                local_window = process_channel_data(
-                    data,
-                    &mut stream,
-                    &encrypter,
-                    server_channel,
-                    local_window,
-                )?
-            }
-            SSH_MSG_CHANNEL_EXTENDED_DATA => {
-                local_window = process_extended_channel_data(
-                    data,
-                    &mut stream,
-                    &encrypter,
-                    server_channel,
-                    local_window,
-                )?
-            }
-            SSH_MSG_CHANNEL_EOF => {
-                send_channel_close(&mut stream, &encrypter, server_channel, &stop_flag)?
-            }
-            SSH_MSG_CHANNEL_CLOSE => {
-                disconnect(&mut stream, &encrypter, server_channel, &stop_flag)?;
-                return Ok(());
-            }
-            SSH_MSG_CHANNEL_REQUEST => {
-                process_channel_request(data, server_channel, &mut stream, &encrypter)?
+        }
+
+        // The session can't go on once its channel failed to open
+        let channel = match channels.get(session) {
+            Ok(channel) => channel,
+            Err(_) => return Err(Error::Other("Failed to open session channel")),
+        };
+        local_window.store(channel.local_window(), Ordering::Relaxed);
+    }
+}
+
+/// Decides whether or not a session gets a pseudo-terminal the way OpenSSH does. Forcing one
+/// on or off always wins, and otherwise only an interactive shell started from a terminal
+/// gets one.
+fn pty_wanted(request_pty: Option<bool>, has_command: bool, stdin_is_terminal: bool) -> bool {
+    request_pty.unwrap_or(!has_command && stdin_is_terminal)
+}
+
+/// Returns the local environment variables whose names match any of the patterns, sorted by
+/// name. Variables that aren't valid unicode are skipped.
+fn env_vars(patterns: &[String]) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(name, _)| {
+            patterns
+                .iter()
+                .any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes()))
+        })
+        .collect();
+    vars.sort();
+    vars
+}
+
+/// Connects to the host configured in a client and runs the transport and authentication
+/// layers. Returns the connection ready for opening channels.
+fn connect(client: &Client) -> Result<Connection<'_>, Error> {
+    // Let Ctrl-C abort the connection until the session starts
+    catch_interrupt();
+
+    let (mut stream, mut encrypter, mut decrypter, hash_prefix) = handshake(client, true)?;
+
+    // Begin authentication stage
+    let key = load_identity(client)?;
+    let host_key = load_host_key(client)?;
+    authenticate(
+        &mut stream,
+        &mut encrypter,
+        &mut decrypter,
+        client,
+        key.as_ref(),
+        host_key.as_ref(),
+    )?;
+
+    Ok(Connection {
+        client,
+        stream,
+        encrypter,
+        decrypter,
+        hash_prefix,
+    })
+}
+
+/// Connects to the host configured in a client and runs the version exchange and key
+/// exchange, asking the user about unknown host keys if prompt is set. Results in the stream,
+/// the keys, and the version information needed for the exchange hash of key re-exchanges.
+fn handshake(
+    client: &Client,
+    prompt: bool,
+) -> Result<(SshStream, Encrypter, Decrypter, Vec<u8>), Error> {
+    // Establish connection
+    let stream = open_tcp(client)?;
+    configure_socket(&stream, client)?;
+    debug(client.verbosity, 1, || {
+        format!("Connected to {} port {}", client.host, client.port)
+    });
+
+    // Set up SSH stream before anything is read so that no bytes are lost to another buffer
+    let mut stream = SshStream::new(stream);
+    stream.set_max_packet(client.max_packet);
+    stream.set_verbosity(client.verbosity);
+
+    // Runs the SSH version exchange protocol and saves version info for exchange hash
+    let hash_prefix = exchange_versions(&mut stream, client.verbosity)?;
+
+    // Exchange key information
+    let (encrypter, decrypter) = exchange_keys(&mut stream, hash_prefix.clone(), client, prompt)?;
+
+    Ok((stream, encrypter, decrypter, hash_prefix))
+}
+
+/// Runs each cryptographic primitive against known test vectors, prints whether or not it
+/// passed, and returns whether or not they all passed
+pub fn self_test() -> bool {
+    let mut passed = true;
+    for (name, test) in SELF_TESTS {
+        let result = test();
+        println!("{name}: {}", if result { "pass" } else { "FAIL" });
+        passed &= result;
+    }
+    passed
+}
+
+/// Returns the algorithms tssh supports for a category (cipher, mac, kex, key, or compression)
+/// or the names of the categories themselves for help. Returns None for any other category.
+pub fn supported_algorithms(category: &str) -> Option<Vec<&'static str>> {
+    if category == "help" {
+        return Some(QUERY_CATEGORIES.iter().map(|(name, _)| *name).collect());
+    }
+
+    QUERY_CATEGORIES
+        .iter()
+        .find(|(name, _)| *name == category)
+        .map(|(_, algs)| algs.to_vec())
+}
+
+/// Opens the client's TCP connection to the host. Connections that time out or are refused
+/// are tried again up to the client's number of connection attempts, waiting longer before
+/// each one. Each attempt tries every address in the order they resolved.
+fn open_tcp(client: &Client) -> Result<TcpStream, Error> {
+    let addrs = resolve(&client.host, client.port, client.address_family)?;
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let connected = match &client.source_ports {
+            Some(ports) => connect_tcp_from(&addrs, client.timeout, ports),
+            None => connect_tcp(&addrs, client.timeout),
+        };
+        let err = match connected {
+            Ok(stream) => return Ok(stream),
+            Err(_) if interrupted() => return Err(Error::Other("Connection aborted by user")),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Error::ConnectTimeout,
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Error::ConnectionRefused,
+            Err(e) => return Err(Error::ConnectFailed(addrs, Box::new(e.into()))),
+        };
+        if attempt >= client.connection_attempts {
+            return Err(Error::ConnectFailed(addrs, Box::new(err)));
+        }
+
+        debug(client.verbosity, 1, || {
+            format!("Connection attempt {attempt} failed: {err}")
+        });
+        std::thread::sleep(delay);
+        if interrupted() {
+            return Err(Error::Other("Connection aborted by user"));
+        }
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+        attempt += 1;
+    }
+}
+
+/// Looks up the addresses of the host of the given family, failing if there are none. IPv6
+/// addresses may be given in brackets as in [::1].
+fn resolve(host: &str, port: u16, family: AddressFamily) -> Result<Vec<SocketAddr>, Error> {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    match (unbracketed, port).to_socket_addrs() {
+        Ok(addrs) => {
+            let addrs: Vec<SocketAddr> = addrs
+                .filter(|addr| match family {
+                    AddressFamily::Any => true,
+                    AddressFamily::Inet => addr.is_ipv4(),
+                    AddressFamily::Inet6 => addr.is_ipv6(),
+                })
+                .collect();
+            if addrs.is_empty() {
+                return Err(Error::HostNotFound(host.to_string()));
             }
-            SSH_MSG_CHANNEL_SUCCESS => {
-                state = handle_request_success(
-                    data,
-                    server_channel,
-                    state,
-                    &mut stream,
-                    &encrypter,
-                    &remote_window,
-                    server_packet_max,
-                    &stop_flag,
-                )?
+            Ok(addrs)
+        }
+        Err(_) => Err(Error::HostNotFound(host.to_string())),
+    }
+}
+
+/// Opens a TCP connection to one of the host's addresses, giving up on each after the
+/// timeout if there is one
+fn connect_tcp(addrs: &[SocketAddr], timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return TcpStream::connect(addrs),
+    };
+
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "Host has no addresses");
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Opens a TCP connection to the host from a local port within the given range like
+/// connect_tcp. Ports that are already in use are skipped.
+fn connect_tcp_from(
+    addrs: &[SocketAddr],
+    timeout: Option<Duration>,
+    ports: &RangeInclusive<u16>,
+) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "Host has no addresses");
+    for &addr in addrs {
+        let unspecified: IpAddr = match addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+
+        last_err = io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "No port in the source port range is available",
+        );
+        for source_port in ports.clone() {
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+            match socket.bind(&SocketAddr::new(unspecified, source_port).into()) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e),
             }
-            SSH_MSG_CHANNEL_FAILURE => handle_request_fail(data, state)?,
-            _ => {
-                write!(io::stdout(), "Recieved packet of type {packet_type}\n")?;
-                io::stdout().flush()?;
+
+            let connected = match timeout {
+                Some(timeout) => socket.connect_timeout(&addr.into(), timeout),
+                None => socket.connect(&addr.into()),
+            };
+            match connected {
+                Ok(()) => return Ok(socket.into()),
+                // The port is free locally but already connected to this address
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+                    ) => {}
+                Err(e) => {
+                    last_err = e;
+                    break;
+                }
             }
         }
     }
+    Err(last_err)
 }
 
-/// Exchanges version information via the SSH-2.0 version exchange protocol over the given TCP stream
-fn exchange_versions(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
-    const CLIENT_VERSION: &[u8; 16] = b"SSH-2.0-TSSH_1.0";
+/// Applies the client's socket options to a connected TCP stream
+fn configure_socket(stream: &TcpStream, client: &Client) -> io::Result<()> {
+    stream.set_nodelay(client.nodelay)?;
+    if let Some(idle) = client.keepalive {
+        let keepalive = TcpKeepalive::new().with_time(idle);
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
 
+/// Exchanges version information via the SSH-2.0 version exchange protocol over the given stream
+fn exchange_versions(stream: &mut SshStream, verbosity: u8) -> Result<Vec<u8>, Error> {
     // Send version info to host
-    stream.write_all(CLIENT_VERSION)?;
-    stream.write(b"\r\n")?;
+    stream.send_line(CLIENT_VERSION)?;
 
     // Recieve version info from host
-    let mut reader = BufReader::new(stream);
-
     let mut host_version = String::new();
-    let mut num_read = reader.read_line(&mut host_version)?;
+    let mut num_read = stream.read_line(&mut host_version)?;
 
     // Ignore header information
     while !host_version.starts_with("SSH-") {
         // Check if reached end of data stream
         if num_read == 0 {
-            return Err(Error::Other("Did not recieve version info from host"));
+            return Err(Error::Protocol(
+                "Did not recieve version info from host".to_string(),
+            ));
         }
 
         host_version = String::new();
-        num_read = reader.read_line(&mut host_version)?;
+        num_read = stream.read_line(&mut host_version)?;
     }
+    debug(verbosity, 1, || {
+        format!("Remote version: {}", host_version.trim_end())
+    });
 
+    let (hash_prefix, _) = gen_version_hash_prefix(host_version)?;
+    Ok(hash_prefix)
+}
+
+/// Validates the version line sent by the host (including its trailing return characters)
+/// and returns the version info formatted as the start of the exchange hash along with
+/// the parsed host version
+fn gen_version_hash_prefix(mut host_version: String) -> Result<(Vec<u8>, HostVersion), Error> {
     // Validate host version format
-    if !host_version.ends_with("\r\n") || num_read > 255 {
+    if !host_version.ends_with("\r\n") || host_version.len() > 255 {
         eprintln!("{host_version}");
-        return Err(Error::Other(
-            "Recieved invalid version info: Host did not follow SSH version exchange protocol",
-        ));
-    }
-
-    // Ensure host is using SSH-2.0 or greater
-    if !host_version.starts_with("SSH-2.") {
-        return Err(Error::Other(
-            "Incompatible version: Host is not using SSH-2.0 but TSSH is",
+        return Err(Error::Protocol(
+            "Recieved invalid version info: Host did not follow SSH version exchange protocol"
+                .to_string(),
         ));
     }
 
     // Remove return characters from host_version string
     host_version.truncate(host_version.len() - 2);
 
+    // Ensure host is using SSH-2.0 and is not known to be incompatible
+    let version = HostVersion::parse(&host_version)?;
+    version.check_compatible()?;
+
     // Save version info for exchange hash
     let mut hash_prefix = Vec::new();
     SshStream::append_string(&mut hash_prefix, CLIENT_VERSION);
     SshStream::append_string(&mut hash_prefix, host_version.as_bytes());
 
-    Ok(hash_prefix)
+    Ok((hash_prefix, version))
 }
 
-/// Runs the secret key exchange portion of the SSH transport layer
+/// Runs the secret key exchange portion of the SSH transport layer offering the client's
+/// ciphers, asking the user about an unknown host key if prompt is set
 fn exchange_keys(
     stream: &mut SshStream,
-    mut hash_prefix: Vec<u8>,
+    hash_prefix: Vec<u8>,
+    client: &Client,
+    prompt: bool,
 ) -> Result<(Encrypter, Decrypter), Error> {
-    // Generate kexinit payload and add it to exchange hash prefix
-    let payload = gen_kexinit_payload();
-    SshStream::append_string(&mut hash_prefix, &payload);
-
     // Send key negotiation information
-    stream.send(&payload, None)?;
-
-    // Wait until recieved key exchange packet each packet
-    let (mut packet, num_read) = stream.read_until_no_decrypter(SSH_MSG_KEXINIT)?;
-
-    // Ensure packet can be a key exchange packet
-    if packet.len() < 61 {
-        return Err(Error::Other(
-            "Key exchange packet is not large enough to contain all key exchange info",
-        ));
+    let (mut kex, payload) = Kex::new(hash_prefix, &client.ciphers);
+    if client.dump_exchange_hash.is_some() {
+        kex.record_exchange_hash();
     }
+    stream.send(&payload, None)?;
 
-    // Add packet to exchange hash prefix
-    packet.insert(0, SSH_MSG_KEXINIT);
-    SshStream::append_string(&mut hash_prefix, &packet);
-    packet.remove(0);
-
-    // Extract packet information
-
-    // Don't need cookie but here incase needed later
-    // let cookie = &packet[..16];
-
-    let (key_exchange_algs, packet) = SshStream::extract_name_list(&packet[16..])?;
-
-    let (host_key_algs, packet) = SshStream::extract_name_list(packet)?;
-
-    let (encrypt_algs_cts, packet) = SshStream::extract_name_list(packet)?;
-    let (encrypt_algs_stc, packet) = SshStream::extract_name_list(packet)?;
-
-    let (mac_algs_cts, packet) = SshStream::extract_name_list(packet)?;
-    let (mac_algs_stc, packet) = SshStream::extract_name_list(packet)?;
-
-    let (compress_algs_cts, packet) = SshStream::extract_name_list(packet)?;
-    let (compress_algs_stc, packet) = SshStream::extract_name_list(packet)?;
-
-    // Disregard language information
-    let (_, packet) = SshStream::extract_name_list(packet)?;
-    let (_, _) = SshStream::extract_name_list(packet)?;
-
-    // Only valid guess requires client send first so for now this is irrelevant
-    // let server_guess: bool = packet[0] != 0;
-
-    // Begin negotiating shared algorithm
-    let key_exchange_alg = negotiate_alg(&KEX_ALGS, &key_exchange_algs)?;
-
-    let host_key_alg = negotiate_alg(&HOST_KEY_ALGS, &host_key_algs)?;
-
-    let encrypt_alg = negotiate_alg(&ENCRYPT_ALGS, &encrypt_algs_cts)?;
-    let decrypt_alg = negotiate_alg(&ENCRYPT_ALGS, &encrypt_algs_stc)?;
-
-    let mac_alg_send = negotiate_alg(&MAC_ALGS, &mac_algs_cts)?;
-    let verify_alg = negotiate_alg(&MAC_ALGS, &mac_algs_stc)?;
-
-    let compress_alg = negotiate_alg(&COMPRESS_ALGS, &compress_algs_cts)?;
-    let decompress_alg = negotiate_alg(&COMPRESS_ALGS, &compress_algs_stc)?;
-
-    // Normally you check for incorrect kex guesses here but the only implemented algorithm requires client to move first
-
-    generate(
+    // Dump the exchange hash even if the exchange failed since that is when it is needed
+    let exchanged = finish_kex(
         stream,
-        key_exchange_alg,
-        host_key_alg,
-        encrypt_alg,
-        decrypt_alg,
-        mac_alg_send,
-        verify_alg,
-        compress_alg,
-        decompress_alg,
-        hash_prefix,
-        num_read,
+        &mut kex,
+        KexOutput::None,
+        client,
+        prompt,
         None,
         None,
-    )
+    );
+    dump_exchange_hash(&kex, client)?;
+    debug_algorithms(&kex, client.verbosity);
+    exchanged?;
+
+    kex.keys(None, None)
 }
 
-fn authenticate(
+/// Runs a key re-exchange started by the host's SSH_MSG_KEXINIT. If the exchange fails while
+/// the old keys are still in use the host is told why before the session is ended, since
+/// anything sent after that could not be decrypted by either side.
+fn key_rexchange(
+    data: Vec<u8>,
     stream: &mut SshStream,
-    encrypter: &mut Encrypter,
-    decrypter: &mut Decrypter,
-    username: String,
-) -> Result<(), Error> {
-    // Request user authentication
-    stream.send(b"\x05\x00\x00\x00\x0cssh-userauth", Some(encrypter))?;
-    let payload = stream.read_until(SSH_MSG_SERVICE_ACCEPT, decrypter)?;
-    let (service, _) = SshStream::extract_string(&payload)?;
-    if service != b"ssh-userauth" {
-        return Err(Error::Other(
-            "Invalid service accept message: Expected ssh-userauth",
-        ));
-    }
-
-    // Send initial request to get authentication methods
-    let mut initial_request = gen_userauth_header(&username);
-    SshStream::append_string(&mut initial_request, b"none");
-    stream.send(&initial_request, Some(encrypter))?;
-
-    // Get response from host
-    let mut attempt_counter: u8 = 0;
-    loop {
-        let (code, response) = stream.read(Some(decrypter))?;
-        match code {
-            SSH_MSG_DISCONNECT => return Err(Error::Other("Host sent ssh disconnect message")),
-            SSH_MSG_USERAUTH_SUCCESS => return Ok(()),
-            SSH_MSG_USERAUTH_FAILURE => {
-                // Check that password is a valid authentication method
-                let (methods, _) = SshStream::extract_name_list(&response)?;
-                if !methods.contains(&"password".to_string()) {
-                    return Err(Error::Other(
-                        "Host does not support password authentication",
-                    ));
-                }
-
-                if attempt_counter == 3 {
-                    return Err(Error::Other("Too many failed login attempts"));
-                }
-                attempt_counter += 1;
-
-                // Prompt user for password
-                let password =
-                    rpassword::prompt_password("Password: ").expect("Unable to parse password");
+    hash_prefix: Vec<u8>,
+    client: &Client,
+    old_enc: &mut Encrypter,
+    old_dec: &mut Decrypter,
+) -> Result<(Encrypter, Decrypter), Error> {
+    // The host sends nothing else after its SSH_MSG_KEXINIT so nothing can have been deferred
+    rexchange(Some(data), stream, hash_prefix, client, old_enc, old_dec)
+        .map(|(encrypter, decrypter, _)| (encrypter, decrypter))
+}
 
-                // Send authentication request
-                let mut request = gen_userauth_header(&username);
-                SshStream::append_string(&mut request, b"password");
-                request.push(0); // false boolean field
-                SshStream::append_string(&mut request, password.as_bytes());
-                stream.send(&request, Some(encrypter))?;
-            }
-            SSH_MSG_USERAUTH_BANNER => {
-                let (banner, _) = SshStream::extract_string(&response)?;
-                let banner = String::from_utf8_lossy(&banner);
-                println!("{banner}");
-            }
-            SSH_MSG_USERAUTH_PASSWD_CHANGEREQ => {
-                return Err(Error::Other(
-                    "Password expired and tssh does not support password changes",
-                ));
-            }
-            _ => (),
-        }
-    }
+/// Starts a key re-exchange from the client's side once the current keys have been used for
+/// too long or for too much data, failing like key_rexchange does. Results in the new keys
+/// along with the connection packets the host sent before it saw the client's SSH_MSG_KEXINIT,
+/// which must be handled before anything else is read.
+fn rekey(
+    stream: &mut SshStream,
+    hash_prefix: Vec<u8>,
+    client: &Client,
+    old_enc: &mut Encrypter,
+    old_dec: &mut Decrypter,
+) -> Result<Rekeyed, Error> {
+    debug(client.verbosity, 1, || "Renewing session keys");
+    rexchange(None, stream, hash_prefix, client, old_enc, old_dec)
 }
 
-fn key_rexchange(
-    mut data: Vec<u8>,
+/// Runs a key re-exchange, handing the exchange the host's SSH_MSG_KEXINIT if the host
+/// started it. Results in the new keys and any connection packets deferred during it.
+fn rexchange(
+    host_kexinit: Option<Vec<u8>>,
     stream: &mut SshStream,
-    mut hash_prefix: Vec<u8>,
-    mut old_enc: Encrypter,
-    old_dec: Decrypter,
-) -> Result<(Encrypter, Decrypter), Error> {
-    // Generate kexinit payload and add it to exchange hash prefix
-    let payload = gen_kexinit_payload();
-    SshStream::append_string(&mut hash_prefix, &payload);
+    hash_prefix: Vec<u8>,
+    client: &Client,
+    old_enc: &mut Encrypter,
+    old_dec: &mut Decrypter,
+) -> Result<Rekeyed, Error> {
+    // Don't wait forever on a host that stops responding part way through
+    stream.set_read_timeout(Some(REKEY_TIMEOUT))?;
 
     // Send key negotiation information
-    stream.send(&payload, Some(&mut old_enc))?;
-
-    // Ensure packet can be a key exchange packet
-    if data.len() < 61 {
-        return Err(Error::Other(
-            "Key exchange packet is not large enough to contain all key exchange info",
-        ));
+    let (mut kex, payload) = Kex::new(hash_prefix, &client.ciphers);
+    if client.dump_exchange_hash.is_some() {
+        kex.record_exchange_hash();
+    }
+    let exchanged = stream
+        .send(&payload, Some(old_enc))
+        .and_then(|()| match &host_kexinit {
+            Some(data) => kex.handle(SSH_MSG_KEXINIT, data),
+            None => Ok(KexOutput::None),
+        })
+        .and_then(|output| {
+            finish_kex(
+                stream,
+                &mut kex,
+                output,
+                client,
+                false,
+                Some(old_enc),
+                Some(old_dec),
+            )
+        })
+        .and(dump_exchange_hash(&kex, client));
+    debug_algorithms(&kex, client.verbosity);
+
+    if let Err(err) = exchanged {
+        if !kex.keys_sent() {
+            let message = gen_disconnect(
+                SSH_DISCONNECT_KEY_EXCHANGE_FAILED,
+                b"Key re-exchange failed",
+            );
+            let _ = stream.send(&message, Some(old_enc));
+        }
+        return Err(Error::KeyRexchange(Box::new(err)));
     }
 
-    // Add packet to exchange hash prefix
-    data.insert(0, SSH_MSG_KEXINIT);
-    SshStream::append_string(&mut hash_prefix, &data);
-    data.remove(0);
-
-    // Extract packet information
-
-    // Don't need cookie but here incase needed later
-    // let cookie = &data[..16];
-
-    let (key_exchange_algs, data) = SshStream::extract_name_list(&data[16..])?;
-
-    let (host_key_algs, data) = SshStream::extract_name_list(data)?;
-
-    let (encrypt_algs_cts, data) = SshStream::extract_name_list(data)?;
-    let (encrypt_algs_stc, data) = SshStream::extract_name_list(data)?;
-
-    let (mac_algs_cts, data) = SshStream::extract_name_list(data)?;
-    let (mac_algs_stc, data) = SshStream::extract_name_list(data)?;
-
-    let (compress_algs_cts, data) = SshStream::extract_name_list(data)?;
-    let (compress_algs_stc, data) = SshStream::extract_name_list(data)?;
-
-    // Disregard language information
-    let (_, data) = SshStream::extract_name_list(data)?;
-    let (_, _) = SshStream::extract_name_list(data)?;
-
-    // Only valid guess requires client send first so for now this is irrelevant
-    // let server_guess: bool = packet[0] != 0;
-
-    // Begin negotiating shared algorithm
-    let key_exchange_alg = negotiate_alg(&KEX_ALGS, &key_exchange_algs)?;
-
-    let host_key_alg = negotiate_alg(&HOST_KEY_ALGS, &host_key_algs)?;
-
-    let encrypt_alg = negotiate_alg(&ENCRYPT_ALGS, &encrypt_algs_cts)?;
-    let decrypt_alg = negotiate_alg(&ENCRYPT_ALGS, &encrypt_algs_stc)?;
-
-    let mac_alg_send = negotiate_alg(&MAC_ALGS, &mac_algs_cts)?;
-    let verify_alg = negotiate_alg(&MAC_ALGS, &mac_algs_stc)?;
+    stream.set_read_timeout(None)?;
+    let deferred = kex.take_deferred();
+    let (encrypter, decrypter) = kex
+        .keys(Some(old_enc), Some(old_dec))
+        .map_err(|err| Error::KeyRexchange(Box::new(err)))?;
+    Ok((encrypter, decrypter, deferred))
+}
 
-    let compress_alg = negotiate_alg(&COMPRESS_ALGS, &compress_algs_cts)?;
-    let decompress_alg = negotiate_alg(&COMPRESS_ALGS, &compress_algs_stc)?;
+/// Appends every value fed into the exchange hash of a key exchange to the client's dump file
+/// if it asked for one and the exchange got far enough to compute the hash
+fn dump_exchange_hash(kex: &Kex, client: &Client) -> Result<(), Error> {
+    let (path, inputs) = match (&client.dump_exchange_hash, kex.exchange_hash_inputs()) {
+        (Some(path), Some(inputs)) => (path, inputs),
+        _ => return Ok(()),
+    };
 
-    // Normally you check for incorrect kex guesses here but the only implemented algorithm requires client to move first
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", inputs.dump()?)?;
+    Ok(())
+}
 
-    generate(
-        stream,
-        key_exchange_alg,
-        host_key_alg,
-        encrypt_alg,
-        decrypt_alg,
-        mac_alg_send,
-        verify_alg,
-        compress_alg,
-        decompress_alg,
-        hash_prefix,
-        0,
-        Some(old_enc),
-        Some(old_dec),
-    )
-}
-
-/// Processes an ssh global request
-fn process_global_request(data: Vec<u8>) -> Result<(), Error> {
-    let (request, data) = SshStream::extract_string(&data)?;
-    write!(
-        io::stdout(),
-        "Global Request: {}\n",
-        String::from_utf8_lossy(&request)
-    )?;
+/// Logs the algorithms a key exchange negotiated if it got far enough to negotiate them
+fn debug_algorithms(kex: &Kex, verbosity: u8) {
+    if let Some(algs) = kex.algorithms() {
+        debug(verbosity, 1, || {
+            format!("kex: {}, host key: {}", algs.key_exchange, algs.host_key)
+        });
+        debug(verbosity, 1, || {
+            format!(
+                "cipher: {} sending, {} recieving",
+                algs.encrypt, algs.decrypt
+            )
+        });
+        debug(verbosity, 1, || {
+            format!("mac: {} sending, {} recieving", algs.mac, algs.verify)
+        });
+        debug(verbosity, 1, || {
+            format!(
+                "compression: {} sending, {} recieving",
+                algs.compress, algs.decompress
+            )
+        });
+    }
+}
 
-    if let Some(want_reply) = data.get(0) {
-        write!(io::stdout(), "Want Reply: {}\n", *want_reply != 0)?;
+/// Parses the reason code and description of a disconnect message from the host into an
+/// error. A truncated message still results in a disconnect error with whatever could be read.
+fn parse_disconnect(data: &[u8]) -> Error {
+    let code = match data.get(0..4) {
+        Some(code) => u32::from_be_bytes([code[0], code[1], code[2], code[3]]),
+        None => {
+            return Error::Disconnect {
+                code: 0,
+                description: "Host sent a truncated disconnect message".to_string(),
+            };
+        }
+    };
+
+    // The language tag is ignored and control characters are dropped so the host can't
+    // mess with the local terminal
+    let description = match SshStream::extract_string(&data[4..]) {
+        Ok((description, _)) => String::from_utf8_lossy(&description)
+            .chars()
+            .filter(|c| !c.is_control())
+            .collect(),
+        Err(_) => String::new(),
+    };
+    if description.is_empty() {
+        return Error::Disconnect {
+            code,
+            description: "Host sent ssh disconnect message".to_string(),
+        };
     }
+    Error::Disconnect { code, description }
+}
 
-    io::stdout().flush()?;
-    Ok(())
+/// Prints a debugging message sent by the host to stderr if the host wants it always shown or
+/// debugging output is on
+fn print_host_debug(message: &str, always_display: bool, verbosity: u8) {
+    if always_display || verbosity > 0 {
+        eprint!("{message}\r\n");
+    }
 }
 
-/// Opens a new channel of type session
-fn open_channel(stream: &mut SshStream, encrypter: &mut Encrypter) -> Result<u64, Error> {
-    let mut payload = vec![SSH_MSG_CHANNEL_OPEN];
-    SshStream::append_string(&mut payload, b"session");
-    payload.extend(0u32.to_be_bytes()); // session id
-    payload.extend(2097152u32.to_be_bytes()); // client window size
-    payload.extend(32768u32.to_be_bytes()); // max packet size
+/// Prints a debugging message to stderr if the verbosity is at least the message's level. The
+/// message is only built when it is printed. Lines end with a carriage return as well since
+/// the local terminal may be in raw mode.
+fn debug<S: AsRef<str>>(verbosity: u8, level: u8, message: impl FnOnce() -> S) {
+    if verbosity < level {
+        return;
+    }
+    for line in message().as_ref().lines() {
+        eprint!("debug{level}: {line}\r\n");
+    }
+}
 
-    stream.send(&payload, Some(encrypter))?;
+/// Returns the sequence number of the last packet the decrypter opened
+fn last_sequence_number(decrypter: &Decrypter) -> u32 {
+    decrypter.packet_num.wrapping_sub(1)
+}
 
-    Ok(2097152u64)
+/// Results in the reply owed to the host for a global request or a packet of an unknown type,
+/// which every packet loop must send so that the host doesn't give up on the connection.
+/// Requests that want a reply, such as keepalive@openssh.com, are refused and unknown packets
+/// are named by their sequence number. That is None for packets deferred during a key
+/// re-exchange since theirs is no longer known.
+fn connection_reply(
+    output: &ChannelOutput,
+    sequence_number: Option<u32>,
+    verbosity: u8,
+) -> Option<Vec<u8>> {
+    match output {
+        ChannelOutput::GlobalRequest(request, want_reply) => {
+            debug(verbosity, 1, || {
+                format!("Recieved unsupported global request {request}")
+            });
+            (*want_reply == Some(true)).then(|| vec![SSH_MSG_REQUEST_FAILURE])
+        }
+        ChannelOutput::Unknown(packet_type) => {
+            debug(verbosity, 1, || {
+                format!("Recieved unknown packet of type {packet_type}")
+            });
+            sequence_number.map(gen_unimplemented)
+        }
+        _ => None,
+    }
 }
 
-/// Responds to any channel open request with a fail response
-fn deny_channel_open(
-    data: Vec<u8>,
+/// Feeds packets from the host to a key exchange until it is done starting with the given output.
+/// On a key re-exchange everything is sent and read with the old keys until the exchange is done.
+/// The host key is checked against the pinned fingerprints if there are any and otherwise against
+/// known_hosts, asking the user about unknown hosts if prompt is set.
+fn finish_kex(
     stream: &mut SshStream,
-    encrypter: &Arc<Mutex<Encrypter>>,
+    kex: &mut Kex,
+    mut output: KexOutput,
+    client: &Client,
+    prompt: bool,
+    mut old_enc: Option<&mut Encrypter>,
+    mut old_dec: Option<&mut Decrypter>,
 ) -> Result<(), Error> {
-    let (_, data) = SshStream::extract_string(&data)?;
-    if data.len() < 4 {
-        return Err(Error::Other(
-            "Recieved corrupt channel open packet: Expected channel number",
-        ));
-    }
+    loop {
+        match output {
+            KexOutput::None => (),
+            KexOutput::Send(payload) => stream.send(&payload, old_enc.as_deref_mut())?,
+            KexOutput::VerifyHostKey(host_key) => {
+                verify_host_key(client, &host_key, prompt)?;
+                output = kex.accept_host_key()?;
+                continue;
+            }
+            KexOutput::Done => return Ok(()),
+        }
 
-    let mut response = vec![SSH_MSG_CHANNEL_OPEN_FAILURE];
-    response.extend(&data[0..4]);
-    response.extend(SSH_OPEN_ADMINISTRATIVELY_PROHIBITED);
-    SshStream::append_string(
-        &mut response,
-        b"Client does not permit host to open channels",
-    );
-    SshStream::append_string(&mut response, b"");
+        let (packet_type, packet) = stream.read(old_dec.as_deref_mut())?;
+        output = kex.handle(packet_type, &packet)?;
+    }
+}
 
-    let mut encrypter = encrypter.lock().unwrap();
-    stream.send(&response, Some(&mut encrypter))
+/// Checks a host key against the client's pinned fingerprints if it has any and otherwise
+/// against known_hosts, asking the user about an unknown host if prompt is set
+fn verify_host_key(client: &Client, host_key: &[u8], prompt: bool) -> Result<(), Error> {
+    if client.host_key_fingerprints.is_empty() {
+        known_hosts::verify(
+            &client.host,
+            client.port,
+            host_key,
+            prompt,
+            client.verbosity,
+        )
+    } else {
+        known_hosts::verify_pinned(host_key, &client.host_key_fingerprints)
+    }
 }
 
-/// Verifies that the confirmed channel was the one requested and proceeds with requesting a psudo terminal session
-fn confirm_channel_open(
-    data: Vec<u8>,
+fn authenticate(
     stream: &mut SshStream,
-    encrypter: &Arc<Mutex<Encrypter>>,
-) -> Result<(u32, u64, u32), Error> {
-    if data.len() < 16 {
-        return Err(Error::Other(
-            "Recieved corrupt channel open failure packet: Expected length of at least 16 bytes",
-        ));
-    }
-    let client_channel = u32::from_be_bytes(data[0..4].try_into()?);
-    if client_channel != 0 {
-        return Err(Error::Other(
-            "Recieved confirmation for openning of unrequested channel",
-        ));
-    }
+    encrypter: &mut Encrypter,
+    decrypter: &mut Decrypter,
+    client: &Client,
+    key: Option<&PrivateKey>,
+    host_key: Option<&PrivateKey>,
+) -> Result<(), Error> {
+    let username = &client.username;
+    let mut password = client.password.as_deref();
+    let verbosity = client.verbosity;
 
-    let server_channel = u32::from_be_bytes(data[4..8].try_into()?);
-    let window_size = u32::from_be_bytes(data[8..12].try_into()?);
-    let packet_max = u32::from_be_bytes(data[12..16].try_into()?);
-
-    // Request a pseudo-terminal
-    let mut request = vec![SSH_MSG_CHANNEL_REQUEST];
-    request.extend(server_channel.to_be_bytes()); // server channel num
-    SshStream::append_string(&mut request, b"pty-req"); // request pty
-    request.push(1); // want_reply = true
-    SshStream::append_string(&mut request, b"xterm-256color"); // terminal type is xterm 
-
-    // Get terminal width and height in characters
-    let (width, height) = size()?;
-    request.extend((width as u32).to_be_bytes());
-    request.extend((height as u32).to_be_bytes());
-    request.extend([0; 8]); // ignore pixel measurement parameters
-    SshStream::append_string(
-        &mut request,
-        &[
-            1, 0, 0, 0, 3, 3, 0, 0, 0, 127, 4, 0, 0, 0, 21, 5, 0, 0, 0, 4, 9, 0, 0, 0, 19, 10, 0,
-            0, 0, 26, 35, 0, 0, 0, 1, 50, 0, 0, 0, 1, 51, 0, 0, 0, 1, 53, 0, 0, 0, 1, 0,
-        ],
-    ); // add terminal settings
+    // Request user authentication
+    let (mut auth, request) = Auth::new(username.clone(), key.is_some(), host_key.is_some());
+    stream.send(&request, Some(encrypter))?;
 
-    let mut encrypter = encrypter.lock().unwrap();
-    stream.send(&request, Some(&mut encrypter))?;
+    loop {
+        let (code, response) = stream.read(Some(decrypter))?;
+        match auth.handle(code, &response)? {
+            AuthOutput::None => (),
+            AuthOutput::Send(payload) => stream.send(&payload, Some(encrypter))?,
+            AuthOutput::Password => {
+                debug(verbosity, 1, || "Trying password authentication");
+
+                // Try the given password first and prompt the user for any others
+                let password = match password.take() {
+                    Some(password) => password.to_string(),
+                    None => {
+                        rpassword::prompt_password("Password: ").expect("Unable to parse password")
+                    }
+                };
 
-    Ok((server_channel, window_size as u64, packet_max))
-}
+                // Send authentication request
+                stream.send(&auth.password_request(&password), Some(encrypter))?;
+            }
+            AuthOutput::PublicKey => {
+                if let Some(key) = key {
+                    debug(verbosity, 1, || {
+                        format!("Trying public key authentication with {}", key.algorithm())
+                    });
+                    let data = gen_publickey_sign_data(
+                        encrypter.session_id(),
+                        username,
+                        key.algorithm(),
+                        key.public_key(),
+                    );
+                    let request = auth.publickey_request(
+                        key.algorithm(),
+                        key.public_key(),
+                        &key.sign(&data)?,
+                    );
+                    stream.send(&request, Some(encrypter))?;
+                }
+            }
+            AuthOutput::HostBased => {
+                if let Some(host_key) = host_key {
+                    let client_host = local_hostname()?;
+                    let local_user = local_username()?;
+                    debug(verbosity, 1, || {
+                        format!("Trying hostbased authentication as {local_user}@{client_host}")
+                    });
+                    let data = gen_hostbased_sign_data(
+                        encrypter.session_id(),
+                        username,
+                        host_key.algorithm(),
+                        host_key.public_key(),
+                        &client_host,
+                        &local_user,
+                    );
+                    let request = auth.hostbased_request(
+                        host_key.algorithm(),
+                        host_key.public_key(),
+                        &client_host,
+                        &local_user,
+                        &host_key.sign(&data)?,
+                    );
+                    stream.send(&request, Some(encrypter))?;
+                }
+            }
+            AuthOutput::InfoRequest(request) => {
+                debug(
+                    verbosity,
+                    1,
+                    || "Trying keyboard-interactive authentication",
+                );
+                let answers = ask_info_request(&request)?;
+                stream.send(&auth.info_response(&answers), Some(encrypter))?;
+            }
+            AuthOutput::Banner(banner) => println!("{banner}"),
+            AuthOutput::Success => {
+                debug(verbosity, 1, || format!("Authenticated as {username}"));
 
-/// Handles the case when a channel has failed to open and prints the error to user
-fn handle_channel_open_fail(data: Vec<u8>) -> Result<(), Error> {
-    if data.len() < 16 {
-        return Err(Error::Other(
-            "Recieved corrupt channel open failure packet: Expected length of at least 16 bytes",
-        ));
+                // zlib@openssh.com only compresses what is sent after authentication
+                encrypter.start_delayed_compression();
+                decrypter.start_delayed_compression();
+                return Ok(());
+            }
+        }
     }
-
-    let reason_code = u32::from_be_bytes(data[4..8].try_into()?);
-    let (bytes, _) = SshStream::extract_string(&data[8..])?;
-    let description = String::from_utf8_lossy(&bytes);
-
-    eprintln!("Failed to open channel with reason code {reason_code}: {description}");
-
-    Ok(())
 }
 
-/// Processes the amount to adjust a window by if the adjustment is for a valid channel
-/// and ignores the packet otherwise. If the packet is malformed (not big enough) it
-/// returns an error.
-fn adjust_window(data: Vec<u8>) -> Result<u64, Error> {
-    if data.len() < 8 {
-        return Err(Error::Other(
-            "Recieved corrupt window adjust packet: Expected length of at least 8 bytes",
-        ));
+/// Asks the user each keyboard-interactive question, hiding the answers that should not be
+/// shown as they are typed, and returns the answers in order
+fn ask_info_request(request: &InfoRequest) -> Result<Vec<String>, Error> {
+    if !request.name.is_empty() {
+        println!("{}", request.name);
+    }
+    if !request.instruction.is_empty() {
+        println!("{}", request.instruction);
     }
 
-    let channel = u32::from_be_bytes(data[0..4].try_into()?);
-    if channel != 0 {
-        write!(
-            io::stderr(),
-            "Recieved window adjustment for unopened channel\n"
-        )?;
-        io::stderr().flush()?;
-        return Ok(0);
+    let mut answers = Vec::new();
+    for prompt in &request.prompts {
+        let answer = if prompt.echo {
+            print!("{}", prompt.text);
+            io::stdout().flush()?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            answer.trim_end_matches(['\r', '\n']).to_string()
+        } else {
+            rpassword::prompt_password(&prompt.text)?
+        };
+        answers.push(answer);
     }
+    Ok(answers)
+}
 
-    let amount = u32::from_be_bytes(data[4..8].try_into()?);
-    Ok(amount as u64)
+/// Builds the data signed for publickey authentication, which is the session id followed by
+/// the authentication request up to and including the public key
+fn gen_publickey_sign_data(
+    session_id: &[u8],
+    username: &str,
+    algorithm: &str,
+    public_key: &[u8],
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    SshStream::append_string(&mut data, session_id);
+    data.extend(gen_userauth_header(username));
+    SshStream::append_string(&mut data, b"publickey");
+    data.push(1); // true boolean field
+    SshStream::append_string(&mut data, algorithm.as_bytes());
+    SshStream::append_string(&mut data, public_key);
+    data
 }
 
-/// Prints channel data to stdout and updates the client window apropriatly
-fn process_channel_data(
-    data: Vec<u8>,
-    stream: &mut SshStream,
-    encrypter: &Arc<Mutex<Encrypter>>,
-    server_channel: u32,
-    window_size: u64,
-) -> Result<u64, Error> {
-    if data.len() < 8 {
-        return Err(Error::Other(
-            "Recieved corrupt channel data packet: Expected length of at least 8 bytes",
-        ));
-    }
+/// Builds the data signed for hostbased authentication, which is the session id followed by
+/// the whole authentication request except for the signature
+fn gen_hostbased_sign_data(
+    session_id: &[u8],
+    username: &str,
+    algorithm: &str,
+    host_key: &[u8],
+    client_host: &str,
+    local_user: &str,
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    SshStream::append_string(&mut data, session_id);
+    data.extend(gen_userauth_header(username));
+    SshStream::append_string(&mut data, b"hostbased");
+    SshStream::append_string(&mut data, algorithm.as_bytes());
+    SshStream::append_string(&mut data, host_key);
+    SshStream::append_string(&mut data, client_host.as_bytes());
+    SshStream::append_string(&mut data, local_user.as_bytes());
+    data
+}
 
-    let channel = u32::from_be_bytes(data[0..4].try_into()?);
-    if channel != 0 {
-        write!(
-            io::stderr(),
-            "Recieved channel data packet for unopened channel\n"
-        )?;
-        io::stderr().flush()?;
-        return Ok(window_size);
+/// Loads this machine's host key when hostbased authentication is enabled. Fails if it can't
+/// be read rather than silently skipping the method the user asked for.
+fn load_host_key(client: &Client) -> Result<Option<PrivateKey>, Error> {
+    if !client.hostbased {
+        return Ok(None);
     }
 
-    let (data, _) = SshStream::extract_string(&data[4..])?;
-    write!(io::stdout(), "{}", String::from_utf8_lossy(&data))?;
-    io::stdout().flush()?;
-
-    // update window
-    let len = data.len() as u64;
-    // If window is too small after processing data adjust window size
-    if len > window_size || window_size - len < 100 {
-        let mut request = vec![SSH_MSG_CHANNEL_WINDOW_ADJUST];
-        request.extend(server_channel.to_be_bytes());
-        request.extend(2097152u32.to_be_bytes());
-
-        let mut encrypter = encrypter.lock().unwrap();
-        stream.send(&request, Some(&mut encrypter))?;
-        return Ok(window_size + 2097152u64 - len);
+    match load_private_key(HOST_KEY_PATH, None) {
+        Ok(key) => Ok(Some(key)),
+        Err(Error::Io(err)) if err.kind() == io::ErrorKind::PermissionDenied => Err(Error::Other(
+            "Hostbased authentication requires root to read the host key",
+        )),
+        Err(err) => Err(err),
     }
-
-    Ok(window_size - len)
 }
 
-/// Prints extended channel data to the appropiate channel (stderr for error data
-/// and stdout for all other data) and updates the client window accordingly
-fn process_extended_channel_data(
-    data: Vec<u8>,
-    stream: &mut SshStream,
-    encrypter: &Arc<Mutex<Encrypter>>,
-    server_channel: u32,
-    window_size: u64,
-) -> Result<u64, Error> {
-    if data.len() < 12 {
-        return Err(Error::Other(
-            "Recieved corrupt extended channel data packet: Expected length of at least 12 bytes",
-        ));
-    }
-
-    let channel = u32::from_be_bytes(data[0..4].try_into()?);
-    if channel != 0 {
-        write!(
-            io::stderr(),
-            "Recieved extended channel data packet for unopened channel\n"
-        )?;
-        io::stderr().flush()?;
-        return Ok(window_size);
+/// Loads the client's private key. If none was given ~/.ssh/id_rsa is used when it exists,
+/// but is skipped with a warning rather than failing if it can't be used.
+fn load_identity(client: &Client) -> Result<Option<PrivateKey>, Error> {
+    if let Some(path) = &client.identity_file {
+        return prompt_private_key(path).map(Some);
     }
 
-    let data_type = u32::from_be_bytes(data[4..8].try_into()?);
-    let (data, _) = SshStream::extract_string(&data[8..])?;
-
-    // If data type is stderr then print to stderr
-    if data_type == 1 {
-        write!(io::stderr(), "{}", String::from_utf8_lossy(&data))?;
-        io::stderr().flush()?;
-    } else {
-        write!(io::stdout(), "{}", String::from_utf8_lossy(&data))?;
-        io::stdout().flush()?;
+    let path = match env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".ssh").join("id_rsa"),
+        None => return Ok(None),
+    };
+    if !path.exists() {
+        return Ok(None);
     }
 
-    // update window
-    let len = data.len() as u64;
-    // If window is too small after processing data adjust window size
-    if len > window_size || window_size - len < 100 {
-        let mut request = vec![SSH_MSG_CHANNEL_WINDOW_ADJUST];
-        request.extend(server_channel.to_be_bytes());
-        request.extend(2097152u32.to_be_bytes());
-
-        let mut encrypter = encrypter.lock().unwrap();
-        stream.send(&request, Some(&mut encrypter))?;
-        return Ok(window_size + 2097152u64 - len);
+    match prompt_private_key(&path) {
+        Ok(key) => Ok(Some(key)),
+        Err(err) => {
+            eprintln!("Skipping {}: {err}", path.display());
+            Ok(None)
+        }
     }
-
-    Ok(window_size - len)
 }
 
-/// Sends the EOF and channel close packets and closes the writing thread
-fn send_channel_close(
-    stream: &mut SshStream,
-    encrypter: &Arc<Mutex<Encrypter>>,
-    server_channel: u32,
-    stop_flag: &Arc<AtomicBool>,
-) -> Result<(), Error> {
-    // Terminate the writing thread
+/// Stops the writing thread and leaves raw mode when a session ends with an error, since the
+/// writing thread is still running and can not be left to leave raw mode before tssh exits
+fn abandon_session(stop_flag: &AtomicBool) {
     stop_flag.store(true, Ordering::Relaxed);
-
-    let mut message = vec![SSH_MSG_CHANNEL_EOF];
-    message.extend(server_channel.to_be_bytes());
-
-    // Send eof message
-    let mut encrypter = encrypter.lock().unwrap();
-    stream.send(&message, Some(&mut encrypter))?;
-
-    // Sends channel close message
-    message[0] = SSH_MSG_CHANNEL_CLOSE;
-    stream.send(&message, Some(&mut encrypter))
+    restore_terminal();
 }
 
-fn disconnect(
+/// Sends a payload using the encrypter shared with the writing thread
+fn send(
     stream: &mut SshStream,
     encrypter: &Arc<Mutex<Encrypter>>,
-    server_channel: u32,
-    stop_flag: &Arc<AtomicBool>,
+    payload: &[u8],
 ) -> Result<(), Error> {
-    // Terminate writing thread if eof not recieved
-    if !stop_flag.load(Ordering::Relaxed) {
-        send_channel_close(stream, encrypter, server_channel, stop_flag)?;
-    }
-
-    // Assemble disconnect message
-    let mut message = vec![SSH_MSG_DISCONNECT];
-    message.extend(SSH_DISCONNECT_BY_APPLICATION);
-    SshStream::append_string(&mut message, b"Done with session");
-    SshStream::append_string(&mut message, b"");
-
     let mut encrypter = encrypter.lock().unwrap();
-    stream.send(&message, Some(&mut encrypter))
+    stream.send(payload, Some(&mut encrypter))
 }
 
-/// Handles channel specific requests
-fn process_channel_request(
-    data: Vec<u8>,
-    server_channel: u32,
-    stream: &mut SshStream,
-    encrypter: &Arc<Mutex<Encrypter>>,
-) -> Result<(), Error> {
-    let channel = u32::from_be_bytes(data[0..4].try_into()?);
-    if channel != 0 {
-        return Err(Error::Other("Recieved channel request for invalid channel"));
-    }
-
-    let (_, data) = SshStream::extract_string(&data[4..])?;
-
-    if data[0] != 0 {
-        let mut response = vec![SSH_MSG_CHANNEL_FAILURE];
-        response.extend(server_channel.to_be_bytes());
-
-        let mut encrypter = encrypter.lock().unwrap();
-        return stream.send(&response, Some(&mut encrypter));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_host_keys_are_rejected_without_a_prompt() {
+        let mut host_key = Vec::new();
+        SshStream::append_string(&mut host_key, b"ssh-rsa");
+        SshStream::append_string(&mut host_key, b"not in any known_hosts");
+        let client = ClientBuilder::new()
+            .host("unknown.invalid")
+            .username("user")
+            .build()
+            .unwrap();
+        assert!(verify_host_key(&client, &host_key, false).is_err());
+
+        // A pinned fingerprint stands in for known_hosts
+        let client = ClientBuilder::new()
+            .host("unknown.invalid")
+            .username("user")
+            .host_key_fingerprint(known_hosts::fingerprint(&host_key))
+            .build()
+            .unwrap();
+        assert!(verify_host_key(&client, &host_key, false).is_ok());
     }
 
-    Ok(())
-}
-
-/// Hadles success responsed to channel requests. If the success is in response to a terminal request, it requests a shell and updates state.
-/// Ignores responses to unsent messages and unopened channels
-fn handle_request_success(
-    data: Vec<u8>,
-    server_channel: u32,
-    state: WaitingFor,
-    stream: &mut SshStream,
-    encrypter: &Arc<Mutex<Encrypter>>,
-    remote_window: &Arc<Mutex<u64>>,
-    server_packet_max: u32,
-    stop_flag: &Arc<AtomicBool>,
-) -> Result<WaitingFor, Error> {
-    if data.len() < 4 {
-        return Err(Error::Other(
-            "Recieved corrupt channel request failure packet: Expected length of at least 4 bytes",
-        ));
+    #[test]
+    fn unknown_packets_are_answered_with_their_sequence_number() {
+        let (mut host, mut client) = ssh_stream::loopback_streams();
+        let (mut encrypter, mut decrypter) =
+            encrypter::loopback_pair("aes128-ctr", "hmac-sha2-256", "none");
+        let mut channels = Channels::new();
+
+        // The first packet has sequence number 0
+        for (sequence_number, packet_type) in [200u8, 201, 202].into_iter().enumerate() {
+            host.send(&[packet_type, 1, 2, 3], Some(&mut encrypter))
+                .unwrap();
+            let (packet_type, data) = client.read(Some(&mut decrypter)).unwrap();
+            let (_, outputs) = channels.handle(packet_type, &data).unwrap();
+            let [output @ ChannelOutput::Unknown(unknown)] = &outputs[..] else {
+                panic!("Expected packet {packet_type} to be unknown");
+            };
+            assert_eq!(*unknown, packet_type);
+            assert_eq!(
+                connection_reply(output, Some(last_sequence_number(&decrypter)), 0),
+                Some(gen_unimplemented(sequence_number as u32))
+            );
+
+            // A deferred packet's sequence number is no longer known
+            assert_eq!(connection_reply(output, None, 0), None);
+        }
     }
 
-    let channel = u32::from_be_bytes(data[0..4].try_into()?);
-    if channel != 0 {
-        write!(
-            io::stderr(),
-            "Recieved channel request success packet for unopened channel\n"
-        )?;
-        io::stderr().flush()?;
-        return Ok(state);
+    #[test]
+    fn global_requests_that_want_a_reply_are_refused() {
+        let keepalive = |want_reply| {
+            ChannelOutput::GlobalRequest("keepalive@openssh.com".to_string(), want_reply)
+        };
+        assert_eq!(
+            connection_reply(&keepalive(Some(true)), Some(4), 0),
+            Some(vec![SSH_MSG_REQUEST_FAILURE])
+        );
+        assert_eq!(connection_reply(&keepalive(Some(false)), Some(4), 0), None);
+        assert_eq!(connection_reply(&keepalive(None), None, 0), None);
+
+        // Everything else is left to the channels
+        assert_eq!(connection_reply(&ChannelOutput::Eof, Some(4), 0), None);
     }
 
-    match state {
-        WaitingFor::Pty => {
-            let mut request = vec![SSH_MSG_CHANNEL_REQUEST];
-            request.extend(server_channel.to_be_bytes());
-            SshStream::append_string(&mut request, b"shell");
-            request.push(1); // want_reply = true
-
-            let mut encrypter = encrypter.lock().unwrap();
-            stream.send(&request, Some(&mut encrypter))?;
-
-            return Ok(WaitingFor::Shell);
-        }
-        WaitingFor::Shell => {
-            spawn(
-                stream.try_clone()?,
-                encrypter.clone(),
-                remote_window.clone(),
-                server_packet_max,
-                server_channel,
-                stop_flag.clone(),
-            )?;
-            return Ok(WaitingFor::None);
+    #[test]
+    fn ptys_are_only_wanted_for_interactive_shells_unless_forced() {
+        // Nothing forced: a shell on a terminal
+        assert!(pty_wanted(None, false, true));
+        assert!(!pty_wanted(None, false, false));
+        assert!(!pty_wanted(None, true, true));
+        assert!(!pty_wanted(None, true, false));
+
+        // -t
+        for (has_command, stdin_is_terminal) in [(false, false), (true, true), (true, false)] {
+            assert!(pty_wanted(Some(true), has_command, stdin_is_terminal));
         }
-        WaitingFor::None => {
-            write!(
-                io::stderr(),
-                "Recieved channel request success packet for request that has not been sent\n"
-            )?;
-            io::stderr().flush()?;
-            return Ok(state);
-        }
-    }
-}
 
-/// Hnadles fail responses from channel requests mainly opening a terminal
-fn handle_request_fail(data: Vec<u8>, state: WaitingFor) -> Result<(), Error> {
-    if data.len() < 4 {
-        return Err(Error::Other(
-            "Recieved corrupt channel request failure packet: Expected length of at least 4 bytes",
-        ));
+        // -T
+        for (has_command, stdin_is_terminal) in [(false, true), (false, false), (true, true)] {
+            assert!(!pty_wanted(Some(false), has_command, stdin_is_terminal));
+        }
     }
 
-    let channel = u32::from_be_bytes(data[0..4].try_into()?);
-    if channel == 0 {
-        match state {
-            WaitingFor::Pty => return Err(Error::Other("Failed to open remote terminal")),
-            WaitingFor::Shell => return Err(Error::Other("Failed to open a remote shell")),
-            WaitingFor::None => {
-                write!(
-                    io::stderr(),
-                    "Recieved channel request failure packet for request that has not been sent\n"
-                )?;
-                io::stderr().flush()?;
-                return Ok(());
-            }
+    #[test]
+    fn every_self_test_passes() {
+        for (name, test) in SELF_TESTS {
+            assert!(test(), "{name}");
         }
     }
-
-    write!(
-        io::stderr(),
-        "Recieved channel request failure packet for unopened channel\n"
-    )?;
-    io::stderr().flush()?;
-    Ok(())
-}
-
-/// Generates the payload for the ssh key exchange init packet
-fn gen_kexinit_payload() -> Vec<u8> {
-    // Create initial payload
-    let mut payload = vec![SSH_MSG_KEXINIT];
-
-    // Create and add cookie
-    let mut cookie = [0u8; 16];
-    OsRng.fill(&mut cookie);
-    payload.extend(cookie);
-
-    // Add algorithm name lists
-    SshStream::append_name_list(&mut payload, &KEX_ALGS);
-    SshStream::append_name_list(&mut payload, &HOST_KEY_ALGS);
-    SshStream::append_name_list(&mut payload, &ENCRYPT_ALGS);
-    SshStream::append_name_list(&mut payload, &ENCRYPT_ALGS);
-    SshStream::append_name_list(&mut payload, &MAC_ALGS);
-    SshStream::append_name_list(&mut payload, &MAC_ALGS);
-    SshStream::append_name_list(&mut payload, &COMPRESS_ALGS);
-    SshStream::append_name_list(&mut payload, &COMPRESS_ALGS);
-
-    // Add empty language fields, a false guess byte, and a 0 extention
-    payload.extend([0u8; 13]);
-
-    payload
-}
-
-/// Runs the ssh negotioation algorithm on a list of client algorithms and a vector of server algorithms
-/// and returns the first client algorithm that appears in the server list or throws an error if is found.
-fn negotiate_alg(client: &[&'static str], server: &Vec<String>) -> Result<&'static str, Error> {
-    match client.iter().find(
-        |alg: &&&str| match server.iter().find(|s: &&String| s == alg) {
-            Some(_) => true,
-            None => false,
-        },
-    ) {
-        Some(alg) => Ok(*alg),
-        None => return Err(Error::Other("Could not find compatible algorithms")),
-    }
-}
-
-/// Generates the header for a userauthenctication payload. Expects a method name and
-/// related fields to be appended before being sent.
-fn gen_userauth_header(username: &str) -> Vec<u8> {
-    let mut header = Vec::new();
-    header.push(SSH_USERAUTH_REQUEST);
-    SshStream::append_string(&mut header, username.as_bytes());
-    SshStream::append_string(&mut header, b"ssh-connection");
-    header
 }
@@ -1,33 +1,342 @@
 use std::env;
 use std::io;
 use std::io::Write;
-use tssh::Args;
+use std::process;
+use tssh::{AddressFamily, Args, SshConfig, StdinEofAction};
 
-/// Extracts username and hostname and passes it to tssh for processing and prints any runtime errors
+/// Extracts username and hostname and passes it to tssh for processing and prints any runtime errors.
+/// Exits with the exit status of the remote command if one was run.
 fn main() {
     let cmd_line: Vec<String> = env::args().collect();
-    if let Some(args) = parse_args(&cmd_line) {
-        if let Err(err) = tssh::run(args) {
-            eprintln!("{err}");
+
+    // Hidden option for checking that the cryptographic primitives behave as expected
+    if cmd_line.len() == 2 && cmd_line[1] == "--self-test" {
+        if !tssh::self_test() {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // Print the supported algorithms of a category instead of connecting
+    if cmd_line.len() == 3 && cmd_line[1] == "-Q" {
+        match tssh::supported_algorithms(&cmd_line[2]) {
+            Some(algs) => algs.iter().for_each(|alg| println!("{alg}")),
+            None => {
+                eprintln!("Unsupported query type: {}", cmd_line[2]);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let config = SshConfig::load();
+    if let Some(args) = parse_args(&cmd_line, &config) {
+        match tssh::run(args) {
+            // Exit with the remote command's status like it was run locally
+            Ok(Some(status)) => process::exit(status as i32),
+            Ok(None) => (),
+            // Exit like timeout(1) does so batch jobs can tell a timeout from a failure
+            Err(err @ tssh::Error::SessionTimeout) => {
+                eprintln!("{err}");
+                process::exit(124);
+            }
+            // Any other failure exits like ssh(1) does so scripts can tell it from the
+            // remote command's own status
+            Err(err) => {
+                eprintln!("{err}");
+                process::exit(255);
+            }
         }
     }
 }
 
 /// Reads the command line arguments and parses them into the argument struct
-/// required for tssh to run. If an error occurs when parsing then it returns
-/// None and prints a message to stderr.
-fn parse_args<'a>(cmd_line: &'a Vec<String>) -> Option<Args<'a>> {
-    // Extract argument containing username@hostname or just hostname
-    if cmd_line.len() != 2 {
-        eprintln!(
-            "Invalid number of arguments: expected 1 found {}",
-            cmd_line.len() - 1
-        );
+/// required for tssh to run, filling in anything not given from the config.
+/// If an error occurs when parsing then it returns None and prints a message
+/// to stderr.
+fn parse_args<'a>(cmd_line: &'a [String], config: &'a SshConfig) -> Option<Args<'a>> {
+    // Extract options and the argument containing username@hostname or just hostname
+    let mut ciphers = None;
+    let mut dump_exchange_hash = None;
+    let mut keepalive = None;
+    let mut nodelay = true;
+    let mut allow_cbc = false;
+    let mut identity_file = None;
+    let mut connect_timeout = None;
+    let mut connection_attempts = None;
+    let mut hostbased = false;
+    let mut host_key_fingerprints = Vec::new();
+    let mut send_env = None;
+    let mut log_file = None;
+    let mut log_timing = None;
+    let mut log_input = None;
+    let mut no_session = false;
+    let mut sftp = false;
+    let mut no_pty = false;
+    let mut force_pty = false;
+    let mut dynamic_forward = None;
+    let mut port = None;
+    let mut source_ports = None;
+    let mut password_fd = None;
+    let mut server_alive_interval = None;
+    let mut server_alive_count_max = None;
+    let mut session_timeout = None;
+    let mut verbosity: u8 = 0;
+    let mut stdin_eof_action = StdinEofAction::default();
+    let mut escape_char = Some(b'~');
+    let mut address_family = AddressFamily::default();
+    let mut destination = None;
+    let mut cmd_args = cmd_line[1..].iter();
+    while let Some(arg) = cmd_args.next() {
+        match arg.as_str() {
+            "-c" => match cmd_args.next() {
+                Some(list) => ciphers = Some(list.split(",").map(|s| s.to_string()).collect()),
+                None => {
+                    eprintln!("Missing argument for -c: Expected a comma seperated cipher list");
+                    return None;
+                }
+            },
+            "--dump-exchange-hash" => match cmd_args.next() {
+                Some(path) => dump_exchange_hash = Some(path.as_str()),
+                None => {
+                    eprintln!("Missing argument for --dump-exchange-hash: Expected a file");
+                    return None;
+                }
+            },
+            "--tcp-keepalive" => match cmd_args.next().map(|secs| secs.parse()) {
+                Some(Ok(secs)) => keepalive = Some(secs),
+                _ => {
+                    eprintln!("Invalid argument for --tcp-keepalive: Expected idle seconds");
+                    return None;
+                }
+            },
+            "--no-tcp-nodelay" => nodelay = false,
+            "--allow-cbc" => allow_cbc = true,
+            "--hostbased" => hostbased = true,
+            // May be repeated or comma seperated to allow any of several host keys
+            "--host-key-fingerprint" => match cmd_args.next() {
+                Some(list) => host_key_fingerprints.extend(list.split(",")),
+                None => {
+                    eprintln!("Missing argument for --host-key-fingerprint: Expected SHA256:...");
+                    return None;
+                }
+            },
+            "--log-file" => match cmd_args.next() {
+                Some(path) => log_file = Some(path.as_str()),
+                None => {
+                    eprintln!("Missing argument for --log-file: Expected a file");
+                    return None;
+                }
+            },
+            "--log-timing" => match cmd_args.next() {
+                Some(path) => log_timing = Some(path.as_str()),
+                None => {
+                    eprintln!("Missing argument for --log-timing: Expected a file");
+                    return None;
+                }
+            },
+            "--log-input" => match cmd_args.next() {
+                Some(path) => log_input = Some(path.as_str()),
+                None => {
+                    eprintln!("Missing argument for --log-input: Expected a file");
+                    return None;
+                }
+            },
+            "-w" => match cmd_args.next().map(|secs| secs.parse()) {
+                Some(Ok(secs)) if secs > 0 => connect_timeout = Some(secs),
+                _ => {
+                    eprintln!("Invalid argument for -w: Expected seconds");
+                    return None;
+                }
+            },
+            // Only the options that have no flag of their own are supported as -o Name=value
+            "-o" => match cmd_args.next().and_then(|option| option.split_once("=")) {
+                Some((name, secs)) if name.eq_ignore_ascii_case("ConnectTimeout") => {
+                    match secs.parse() {
+                        Ok(secs) if secs > 0 => connect_timeout = Some(secs),
+                        _ => {
+                            eprintln!("Invalid value for ConnectTimeout: Expected seconds");
+                            return None;
+                        }
+                    }
+                }
+                // Like ssh(1) the patterns are whitespace seperated and may be given more than once
+                Some((name, patterns)) if name.eq_ignore_ascii_case("SendEnv") => send_env
+                    .get_or_insert_with(Vec::new)
+                    .extend(patterns.split_whitespace()),
+                Some((name, secs)) if name.eq_ignore_ascii_case("ServerAliveInterval") => {
+                    match secs.parse() {
+                        Ok(secs) if secs > 0 => server_alive_interval = Some(secs),
+                        _ => {
+                            eprintln!("Invalid value for ServerAliveInterval: Expected seconds");
+                            return None;
+                        }
+                    }
+                }
+                Some((name, count)) if name.eq_ignore_ascii_case("ServerAliveCountMax") => {
+                    match count.parse() {
+                        Ok(count) => server_alive_count_max = Some(count),
+                        _ => {
+                            eprintln!("Invalid value for ServerAliveCountMax: Expected a count");
+                            return None;
+                        }
+                    }
+                }
+                Some((name, count)) if name.eq_ignore_ascii_case("ConnectionAttempts") => {
+                    match count.parse() {
+                        Ok(count) if count > 0 => connection_attempts = Some(count),
+                        _ => {
+                            eprintln!("Invalid value for ConnectionAttempts: Expected a count");
+                            return None;
+                        }
+                    }
+                }
+                _ => {
+                    eprintln!(
+                        "Invalid argument for -o: Expected ConnectTimeout, ConnectionAttempts, SendEnv, ServerAliveInterval or ServerAliveCountMax=value"
+                    );
+                    return None;
+                }
+            },
+            "-4" => address_family = AddressFamily::Inet,
+            "-6" => address_family = AddressFamily::Inet6,
+            "-N" => no_session = true,
+            "-s" => sftp = true,
+            "-T" => no_pty = true,
+            "-t" => force_pty = true,
+            // Like ssh(1) the proxy only listens on the loopback address unless told otherwise
+            "-D" => match cmd_args
+                .next()
+                .and_then(|spec| split_port(spec))
+                .and_then(|split| match split {
+                    (bind_address, Some(port)) => Some((bind_address, port.parse().ok()?)),
+                    (port, None) => Some(("localhost", port.parse().ok()?)),
+                }) {
+                Some(forward) => dynamic_forward = Some(forward),
+                None => {
+                    eprintln!("Invalid argument for -D: Expected [bind_address:]port");
+                    return None;
+                }
+            },
+            "-p" => match cmd_args.next().map(|port| port.parse()) {
+                Some(Ok(number)) => port = Some(number),
+                _ => {
+                    eprintln!("Invalid argument for -p: Expected a port number");
+                    return None;
+                }
+            },
+            "--source-port-range" => match cmd_args
+                .next()
+                .and_then(|range| range.split_once("-"))
+                .map(|(low, high)| (low.parse::<u16>(), high.parse::<u16>()))
+            {
+                Some((Ok(low), Ok(high))) if low != 0 && low <= high => {
+                    source_ports = Some(low..=high)
+                }
+                _ => {
+                    eprintln!("Invalid argument for --source-port-range: Expected low-high ports");
+                    return None;
+                }
+            },
+            "--password-fd" => match cmd_args.next().map(|fd| fd.parse::<i32>()) {
+                Some(Ok(fd)) if fd >= 0 => password_fd = Some(fd),
+                _ => {
+                    eprintln!("Invalid argument for --password-fd: Expected a file descriptor");
+                    return None;
+                }
+            },
+            "--server-alive-interval" => match cmd_args.next().map(|secs| secs.parse()) {
+                Some(Ok(secs)) if secs > 0 => server_alive_interval = Some(secs),
+                _ => {
+                    eprintln!("Invalid argument for --server-alive-interval: Expected seconds");
+                    return None;
+                }
+            },
+            "--server-alive-count-max" => match cmd_args.next().map(|count| count.parse()) {
+                Some(Ok(count)) => server_alive_count_max = Some(count),
+                _ => {
+                    eprintln!("Invalid argument for --server-alive-count-max: Expected a count");
+                    return None;
+                }
+            },
+            "--session-timeout" => match cmd_args.next().map(|secs| secs.parse()) {
+                Some(Ok(secs)) if secs > 0 => session_timeout = Some(secs),
+                _ => {
+                    eprintln!("Invalid argument for --session-timeout: Expected seconds");
+                    return None;
+                }
+            },
+            "--stdin-eof-action" => match cmd_args.next().map(|action| action.as_str()) {
+                Some("forward-eof") => stdin_eof_action = StdinEofAction::ForwardEof,
+                Some("disconnect") => stdin_eof_action = StdinEofAction::Disconnect,
+                Some("ignore") => stdin_eof_action = StdinEofAction::Ignore,
+                _ => {
+                    eprintln!(
+                        "Invalid argument for --stdin-eof-action: Expected forward-eof, disconnect, or ignore"
+                    );
+                    return None;
+                }
+            },
+            "-e" => match cmd_args.next().and_then(|arg| parse_escape_char(arg)) {
+                Some(c) => escape_char = c,
+                None => {
+                    eprintln!(
+                        "Invalid argument for -e: Expected a single character, ^ followed by a character, or none"
+                    );
+                    return None;
+                }
+            },
+            "-i" => match cmd_args.next() {
+                Some(path) => identity_file = Some(path.as_str()),
+                None => {
+                    eprintln!("Missing argument for -i: Expected a private key file");
+                    return None;
+                }
+            },
+            // Each v adds a level of debugging output as in -v or -vv
+            flag if flag.len() > 1
+                && flag.starts_with('-')
+                && flag[1..].bytes().all(|b| b == b'v') =>
+            {
+                verbosity = verbosity.saturating_add((flag.len() - 1) as u8);
+            }
+            _ => {
+                destination = Some(arg);
+                break;
+            }
+        }
+    }
+
+    // Everything after the destination is the command to run
+    let command: Vec<&str> = cmd_args.map(String::as_str).collect();
+    let command = (!command.is_empty()).then(|| command.join(" "));
+    if no_session && command.is_some() {
+        eprintln!("Too many arguments: -N does not run a command");
+        return None;
+    }
+    if sftp && command.is_some() {
+        eprintln!("Too many arguments: -s does not run a command");
+        return None;
+    }
+    if sftp && no_session {
+        eprintln!("Conflicting options: -s opens a session but -N does not");
+        return None;
+    }
+    if dynamic_forward.is_some() && (sftp || command.is_some()) {
+        eprintln!("Conflicting options: -D serves a SOCKS proxy instead of opening a session");
         return None;
     }
 
+    let destination = match destination {
+        Some(destination) => destination,
+        None => {
+            eprintln!("Missing argument: Expected [username@]hostname[:port]");
+            return None;
+        }
+    };
+
     // Ensure username and hostname are in proper format
-    let args: Vec<&str> = cmd_line[1].split("@").collect();
+    let mut args: Vec<&str> = destination.split("@").collect();
 
     // Too many @ symbols in argument
     if args.len() > 2 {
@@ -35,26 +344,239 @@ fn parse_args<'a>(cmd_line: &'a Vec<String>) -> Option<Args<'a>> {
         return None;
     }
 
+    // A port can follow the hostname as hostname:port
+    let last = args.len() - 1;
+    let (hostname, shorthand) = match split_port(args[last]) {
+        Some(split) => split,
+        None => {
+            eprintln!("Invalid IPv6 address: Expected [address] or [address]:port");
+            return None;
+        }
+    };
+    args[last] = hostname;
+    if let Some(shorthand) = shorthand {
+        if port.is_some() {
+            eprintln!("Port specified twice: Use either -p or hostname:port");
+            return None;
+        }
+        match shorthand.parse() {
+            Ok(number) => port = Some(number),
+            Err(_) => {
+                eprintln!("Invalid port in hostname:port: Expected a port number");
+                return None;
+            }
+        }
+    }
+
+    if args[args.len() - 1].is_empty() {
+        eprintln!("Missing hostname: Expected [username@]hostname[:port]");
+        return None;
+    }
+
+    // The hostname may be an alias from the config, whose options only fill in what the
+    // command line left out
+    let host_config = config.lookup(args[args.len() - 1]);
+    let hostname = host_config.hostname.unwrap_or(args[args.len() - 1]);
+    let port = port.or(host_config.port).unwrap_or(22);
+    let identity_file = identity_file.or(host_config.identity_file);
+    // An interval of 0 in the config turns the checks off like it does for ssh(1)
+    let server_alive_interval = server_alive_interval
+        .or(host_config.server_alive_interval)
+        .filter(|secs| *secs > 0);
+    let server_alive_count_max = server_alive_count_max.or(host_config.server_alive_count_max);
+    if send_env.is_none() && !host_config.send_env.is_empty() {
+        send_env = Some(host_config.send_env);
+    }
+
     // username and hostname were specified
     if args.len() == 2 {
         return Some(Args {
             username: args[0].to_string(),
-            hostname: args[1],
+            hostname,
+            port,
+            ciphers,
+            dump_exchange_hash,
+            keepalive,
+            nodelay,
+            allow_cbc,
+            identity_file,
+            hostbased,
+            host_key_fingerprints,
+            send_env,
+            log_file,
+            log_timing,
+            log_input,
+            no_session,
+            sftp,
+            no_pty,
+            force_pty,
+            dynamic_forward,
+            command,
+            connect_timeout,
+            connection_attempts,
+            source_ports,
+            password_fd,
+            server_alive_interval,
+            server_alive_count_max,
+            session_timeout,
+            stdin_eof_action,
+            escape_char,
+            address_family,
+            verbosity,
         });
     }
 
-    // username was not specified
-    print!("Username: ");
-    io::stdout()
-        .flush()
-        .expect("Failed to print username prompt");
-    let mut username = String::new();
-    io::stdin()
-        .read_line(&mut username)
-        .expect("Failed to read line");
+    // username was not specified so it comes from the config or the user
+    let username = match host_config.user {
+        Some(user) => user.to_string(),
+        None => {
+            print!("Username: ");
+            io::stdout()
+                .flush()
+                .expect("Failed to print username prompt");
+            let mut username = String::new();
+            io::stdin()
+                .read_line(&mut username)
+                .expect("Failed to read line");
+            username.trim().to_string()
+        }
+    };
 
     Some(Args {
-        username: username.trim().to_string(),
-        hostname: args[0],
+        username,
+        hostname,
+        port,
+        ciphers,
+        dump_exchange_hash,
+        keepalive,
+        nodelay,
+        allow_cbc,
+        identity_file,
+        hostbased,
+        host_key_fingerprints,
+        send_env,
+        log_file,
+        log_timing,
+        log_input,
+        no_session,
+        sftp,
+        no_pty,
+        force_pty,
+        dynamic_forward,
+        command,
+        connect_timeout,
+        connection_attempts,
+        source_ports,
+        password_fd,
+        server_alive_interval,
+        server_alive_count_max,
+        session_timeout,
+        stdin_eof_action,
+        escape_char,
+        address_family,
+        verbosity,
     })
 }
+
+/// Parses the escape character given to -e, which is a single character, ^ followed by a
+/// character for a control character, or none to disable escapes
+fn parse_escape_char(arg: &str) -> Option<Option<u8>> {
+    match arg.as_bytes() {
+        b"none" => Some(None),
+        [c] => Some(Some(*c)),
+        [b'^', c] => Some(Some(c & 0x1F)),
+        _ => None,
+    }
+}
+
+/// Splits the port off of a hostname:port destination. IPv6 addresses must be bracketed as
+/// [address]:port to be given a port, and the brackets are removed. An unbracketed IPv6
+/// address is left whole. Returns None if a bracketed address is malformed.
+fn split_port(destination: &str) -> Option<(&str, Option<&str>)> {
+    if let Some(rest) = destination.strip_prefix('[') {
+        let (address, after) = rest.split_once(']')?;
+        if after.is_empty() {
+            return Some((address, None));
+        }
+        return Some((address, Some(after.strip_prefix(':')?)));
+    }
+
+    match destination.split_once(':') {
+        Some((_, rest)) if rest.contains(':') => Some((destination, None)),
+        Some((hostname, port)) => Some((hostname, Some(port))),
+        None => Some((destination, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a command line without a config and returns the username, hostname and port
+    fn parse(args: &[&str]) -> Option<(String, String, u16)> {
+        let cmd_line: Vec<String> = ["tssh"].iter().chain(args).map(|s| s.to_string()).collect();
+        let config = SshConfig::default();
+        parse_args(&cmd_line, &config)
+            .map(|args| (args.username, args.hostname.to_string(), args.port))
+    }
+
+    #[test]
+    fn destinations_give_the_user_host_and_port() {
+        let expected = |host: &str, port: u16| Some(("user".to_string(), host.to_string(), port));
+        assert_eq!(parse(&["user@example.com"]), expected("example.com", 22));
+        assert_eq!(
+            parse(&["user@example.com:2222"]),
+            expected("example.com", 2222)
+        );
+        assert_eq!(
+            parse(&["-p", "2222", "user@example.com"]),
+            expected("example.com", 2222)
+        );
+        assert_eq!(parse(&["user@[::1]:2222"]), expected("::1", 2222));
+        assert_eq!(parse(&["user@[::1]"]), expected("::1", 22));
+
+        // An unbracketed IPv6 address can't have a port so its colons are left alone
+        assert_eq!(parse(&["user@::1"]), expected("::1", 22));
+        assert_eq!(parse(&["user@fe80::1:22"]), expected("fe80::1:22", 22));
+    }
+
+    #[test]
+    fn invalid_destinations_are_rejected() {
+        // The port may only be given once
+        assert_eq!(parse(&["-p", "22", "user@example.com:2222"]), None);
+        assert_eq!(parse(&["-p", "22", "user@[::1]:2222"]), None);
+
+        assert_eq!(parse(&["user@example.com:ssh"]), None);
+        assert_eq!(parse(&["user@example.com:70000"]), None);
+        assert_eq!(parse(&["user@[::1"]), None);
+        assert_eq!(parse(&["user@[::1]2222"]), None);
+        assert_eq!(parse(&["user@:2222"]), None);
+        assert_eq!(parse(&["user@"]), None);
+        assert_eq!(parse(&["a@b@example.com"]), None);
+        assert_eq!(parse(&["-p", "port", "user@example.com"]), None);
+        assert_eq!(parse(&[]), None);
+    }
+
+    #[test]
+    fn ports_are_split_from_hostnames() {
+        assert_eq!(split_port("example.com"), Some(("example.com", None)));
+        assert_eq!(
+            split_port("example.com:22"),
+            Some(("example.com", Some("22")))
+        );
+        assert_eq!(split_port("[::1]:22"), Some(("::1", Some("22"))));
+        assert_eq!(split_port("[::1]"), Some(("::1", None)));
+        assert_eq!(split_port("::1"), Some(("::1", None)));
+        assert_eq!(split_port("[::1"), None);
+        assert_eq!(split_port("[::1]x22"), None);
+    }
+
+    #[test]
+    fn escape_chars_can_be_changed_or_disabled() {
+        assert_eq!(parse_escape_char("~"), Some(Some(b'~')));
+        assert_eq!(parse_escape_char("^]"), Some(Some(0x1D)));
+        assert_eq!(parse_escape_char("none"), Some(None));
+        assert_eq!(parse_escape_char("ab"), None);
+        assert_eq!(parse_escape_char(""), None);
+    }
+}
@@ -0,0 +1,1939 @@
+use crate::encrypter::{
+    Algorithms, Decrypter, Encrypter, ExchangeHashInputs, KeyExchange, SSH_MSG_NEWKEYS,
+    SharedSecret, derive_keys,
+};
+use crate::ssh_stream::SshStream;
+use crate::{
+    CLIENT_PACKET_MAX, CLIENT_WINDOW_SIZE, COMPRESS_ALGS, Error, HOST_KEY_ALGS, KEX_ALGS, MAC_ALGS,
+    SSH_MSG_CHANNEL_CLOSE, SSH_MSG_CHANNEL_DATA, SSH_MSG_CHANNEL_EOF,
+    SSH_MSG_CHANNEL_EXTENDED_DATA, SSH_MSG_CHANNEL_FAILURE, SSH_MSG_CHANNEL_OPEN,
+    SSH_MSG_CHANNEL_OPEN_CONFIRMATION, SSH_MSG_CHANNEL_OPEN_FAILURE, SSH_MSG_CHANNEL_REQUEST,
+    SSH_MSG_CHANNEL_SUCCESS, SSH_MSG_CHANNEL_WINDOW_ADJUST, SSH_MSG_DEBUG, SSH_MSG_DISCONNECT,
+    SSH_MSG_GLOBAL_REQUEST, SSH_MSG_IGNORE, SSH_MSG_KEXINIT, SSH_MSG_REQUEST_FAILURE,
+    SSH_MSG_REQUEST_SUCCESS, SSH_MSG_SERVICE_ACCEPT, SSH_MSG_UNIMPLEMENTED,
+    SSH_MSG_USERAUTH_BANNER, SSH_MSG_USERAUTH_FAILURE, SSH_MSG_USERAUTH_INFO_REQUEST,
+    SSH_MSG_USERAUTH_INFO_RESPONSE, SSH_MSG_USERAUTH_PASSWD_CHANGEREQ, SSH_MSG_USERAUTH_SUCCESS,
+    SSH_OPEN_ADMINISTRATIVELY_PROHIBITED, SSH_USERAUTH_REQUEST, WaitingFor, parse_disconnect,
+};
+use rand::Rng;
+use rand_core::OsRng;
+use std::collections::{HashMap, VecDeque};
+
+/// The extended data type of a channel's stderr stream
+const SSH_EXTENDED_DATA_STDERR: u32 = 1;
+
+/// The number of bytes left in the client window below which the host is granted more
+const WINDOW_ADJUST_THRESHOLD: u64 = 100;
+
+/// The signal names defined by RFC 4254 that can be sent with a signal request
+const SIGNALS: [&str; 13] = [
+    "ABRT", "ALRM", "FPE", "HUP", "ILL", "INT", "KILL", "PIPE", "QUIT", "SEGV", "TERM", "USR1",
+    "USR2",
+];
+
+/// The result of handing a packet to a key exchange
+pub enum KexOutput {
+    /// Nothing needs to be done
+    None,
+    /// A payload that must be sent to the host
+    Send(Vec<u8>),
+    /// The host key that signed the exchange, which must be checked before continuing with
+    /// Kex::accept_host_key
+    VerifyHostKey(Vec<u8>),
+    /// Both sides have sent SSH_MSG_NEWKEYS and the new keys can be taken with Kex::keys
+    Done,
+}
+
+/// The stages of a key exchange
+enum KexState {
+    /// Waiting on the host's SSH_MSG_KEXINIT with the exchange hash prefix so far
+    Init(Vec<u8>),
+    /// Waiting on the host's reply to the key exchange
+    Reply(Algorithms, KeyExchange),
+    /// Waiting on the host key to be accepted
+    Verify(Algorithms, SharedSecret),
+    /// Waiting on the host's SSH_MSG_NEWKEYS
+    NewKeys(Algorithms, SharedSecret),
+    /// The exchange is complete
+    Done(Algorithms, SharedSecret),
+    /// A packet caused the exchange to fail
+    Failed,
+}
+
+/// A key exchange from the client's SSH_MSG_KEXINIT until both sides have sent SSH_MSG_NEWKEYS.
+/// Used for both the initial exchange and any re-exchanges.
+pub struct Kex {
+    /// The current stage of the exchange
+    state: KexState,
+    /// The encryption algorithms offered to the host in order of preference
+    ciphers: Vec<&'static str>,
+    /// The number of packets handed to the exchange
+    num_read: u32,
+    /// Whether or not the client's SSH_MSG_NEWKEYS has been handed out to send
+    keys_sent: bool,
+    /// The values fed into the exchange hash if they are being recorded
+    hash_inputs: Option<ExchangeHashInputs>,
+    /// Connection packets the host sent before it saw the client's SSH_MSG_KEXINIT
+    deferred: Vec<(u8, Vec<u8>)>,
+}
+
+impl Kex {
+    /// Begins a key exchange offering the given encryption algorithms in order of preference.
+    /// The hash_prefix should contain the version information of both sides formatted for the
+    /// exchange hash.
+    ///
+    /// Results in the exchange and the SSH_MSG_KEXINIT payload that must be sent to the host.
+    pub fn new(mut hash_prefix: Vec<u8>, ciphers: &[&'static str]) -> (Kex, Vec<u8>) {
+        // Generate kexinit payload and add it to exchange hash prefix
+        let payload = gen_kexinit_payload(ciphers);
+        SshStream::append_string(&mut hash_prefix, &payload);
+
+        let kex = Kex {
+            state: KexState::Init(hash_prefix),
+            ciphers: ciphers.to_vec(),
+            num_read: 0,
+            keys_sent: false,
+            hash_inputs: None,
+            deferred: Vec::new(),
+        };
+        (kex, payload)
+    }
+
+    /// Handles a packet recieved from the host during the exchange. Connection packets are
+    /// kept to be handled once the exchange is done, since the host can send them until it
+    /// sees a re-exchange the client started, and any other packets are ignored.
+    pub fn handle(&mut self, packet_type: u8, packet: &[u8]) -> Result<KexOutput, Error> {
+        self.num_read += 1;
+
+        if packet_type == SSH_MSG_DISCONNECT {
+            return Err(parse_disconnect(packet));
+        }
+
+        let (state, output) = match std::mem::replace(&mut self.state, KexState::Failed) {
+            KexState::Init(mut hash_prefix) if packet_type == SSH_MSG_KEXINIT => {
+                let algs = negotiate_kexinit(packet, &mut hash_prefix, &self.ciphers)?;
+
+                // Normally you check for incorrect kex guesses here but the only implemented algorithm requires client to move first
+
+                let (exchange, init) = KeyExchange::start(&algs, hash_prefix)?;
+                (KexState::Reply(algs, exchange), KexOutput::Send(init))
+            }
+            KexState::Reply(algs, exchange) if packet_type == exchange.reply_type() => {
+                let secret = exchange.finish(packet, self.hash_inputs.as_mut())?;
+                let output = KexOutput::VerifyHostKey(secret.host_key().to_vec());
+                (KexState::Verify(algs, secret), output)
+            }
+            KexState::NewKeys(algs, secret) if packet_type == SSH_MSG_NEWKEYS => {
+                if !packet.is_empty() {
+                    return Err(Error::Protocol(
+                        "Recieved invalid SSH_MSG_NEWKEYS message".to_string(),
+                    ));
+                }
+                (KexState::Done(algs, secret), KexOutput::Done)
+            }
+            state => {
+                if packet_type >= SSH_USERAUTH_REQUEST {
+                    self.deferred.push((packet_type, packet.to_vec()));
+                }
+                (state, KexOutput::None)
+            }
+        };
+
+        self.state = state;
+        Ok(output)
+    }
+
+    /// Takes the connection packets that arrived during the exchange, oldest first
+    pub fn take_deferred(&mut self) -> Vec<(u8, Vec<u8>)> {
+        std::mem::take(&mut self.deferred)
+    }
+
+    /// Continues the exchange once the host key it handed out has been checked. Results in
+    /// the SSH_MSG_NEWKEYS payload that must be sent to the host.
+    pub fn accept_host_key(&mut self) -> Result<KexOutput, Error> {
+        match std::mem::replace(&mut self.state, KexState::Failed) {
+            KexState::Verify(algs, secret) => {
+                // Send the SSH_MSG_NEWKEYS message to validate successfule key exchange
+                self.keys_sent = true;
+                self.state = KexState::NewKeys(algs, secret);
+                Ok(KexOutput::Send(vec![SSH_MSG_NEWKEYS]))
+            }
+            _ => Err(Error::Other("Key exchange is not waiting on a host key")),
+        }
+    }
+
+    /// Records the values fed into the exchange hash so that they can be dumped for debugging.
+    /// Must be called before the host's reply is handled.
+    pub fn record_exchange_hash(&mut self) {
+        self.hash_inputs = Some(ExchangeHashInputs::default());
+    }
+
+    /// Returns the values fed into the exchange hash if they were recorded and the exchange
+    /// got far enough to compute it
+    pub fn exchange_hash_inputs(&self) -> Option<&ExchangeHashInputs> {
+        self.hash_inputs
+            .as_ref()
+            .filter(|inputs| !inputs.is_empty())
+    }
+
+    /// Returns the negotiated algorithms once both sides' SSH_MSG_KEXINIT have been handled
+    pub fn algorithms(&self) -> Option<&Algorithms> {
+        match &self.state {
+            KexState::Reply(algs, _)
+            | KexState::Verify(algs, _)
+            | KexState::NewKeys(algs, _)
+            | KexState::Done(algs, _) => Some(algs),
+            KexState::Init(_) | KexState::Failed => None,
+        }
+    }
+
+    /// Returns whether or not the client has switched to the new keys for sending, after which
+    /// the old encrypter can no longer be used
+    pub fn keys_sent(&self) -> bool {
+        self.keys_sent
+    }
+
+    /// Dirives the new encrypter and decrypter once the exchange is done. On a key re-exchange
+    /// the old encrypter and decrypter must be given so that the session carries over.
+    pub fn keys(
+        self,
+        old_enc: Option<&mut Encrypter>,
+        old_dec: Option<&mut Decrypter>,
+    ) -> Result<(Encrypter, Decrypter), Error> {
+        match self.state {
+            KexState::Done(algs, secret) => {
+                derive_keys(&algs, secret, self.num_read, old_enc, old_dec)
+            }
+            _ => Err(Error::Other("Key exchange has not finished")),
+        }
+    }
+}
+
+/// The result of handing a packet to user authentication
+pub enum AuthOutput {
+    /// Nothing needs to be done
+    None,
+    /// A payload that must be sent to the host
+    Send(Vec<u8>),
+    /// The host wants a password which must be sent with Auth::password_request
+    Password,
+    /// The host accepts public keys so a signed request must be sent with
+    /// Auth::publickey_request
+    PublicKey,
+    /// The host accepts hostbased authentication so a request signed with the host key must
+    /// be sent with Auth::hostbased_request
+    HostBased,
+    /// The host wants keyboard-interactive answers which must be sent with
+    /// Auth::info_response
+    InfoRequest(InfoRequest),
+    /// A banner that should be displayed to the user
+    Banner(String),
+    /// The user has been authenticated
+    Success,
+}
+
+/// The questions the host asks during keyboard-interactive authentication
+pub struct InfoRequest {
+    /// A title for the questions which may be empty
+    pub name: String,
+    /// Instructions to show the user which may be empty
+    pub instruction: String,
+    /// The questions to ask the user in order, which may be none at all
+    pub prompts: Vec<Prompt>,
+}
+
+/// A single question asked during keyboard-interactive authentication
+pub struct Prompt {
+    /// The question to show the user
+    pub text: String,
+    /// Whether or not the user's answer should be shown as it is typed
+    pub echo: bool,
+}
+
+/// User authentication from the service request until the host accepts the user
+pub struct Auth {
+    /// The username to sign in as
+    username: String,
+    /// The number of passwords the host has asked for
+    attempts: u8,
+    /// Whether or not there is a private key to offer that has not been offered yet
+    key_unoffered: bool,
+    /// Whether or not there is a host key to offer that has not been offered yet
+    host_key_unoffered: bool,
+    /// The method of the last request sent to the host
+    last_method: Option<&'static str>,
+    /// The methods the host accepted with partial success when it requires more than one
+    satisfied: Vec<&'static str>,
+}
+
+impl Auth {
+    /// Begins user authentication as the given user. If there is a private key it is offered
+    /// before asking for a password when the host accepts public keys, followed by the host
+    /// key if there is one and the host accepts hostbased authentication.
+    ///
+    /// Results in the authentication and the service request that must be sent to the host.
+    pub fn new(username: String, has_key: bool, has_host_key: bool) -> (Auth, Vec<u8>) {
+        let auth = Auth {
+            username,
+            attempts: 0,
+            key_unoffered: has_key,
+            host_key_unoffered: has_host_key,
+            last_method: None,
+            satisfied: Vec::new(),
+        };
+        (auth, b"\x05\x00\x00\x00\x0cssh-userauth".to_vec())
+    }
+
+    /// Handles a packet recieved from the host during authentication
+    pub fn handle(&mut self, packet_type: u8, packet: &[u8]) -> Result<AuthOutput, Error> {
+        match packet_type {
+            SSH_MSG_DISCONNECT => Err(parse_disconnect(packet)),
+            SSH_MSG_SERVICE_ACCEPT => {
+                let (service, packet) = SshStream::extract_string(packet)?;
+                SshStream::expect_end(packet)?;
+                if service != b"ssh-userauth" {
+                    return Err(Error::Other(
+                        "Invalid service accept message: Expected ssh-userauth",
+                    ));
+                }
+
+                // Send initial request to get authentication methods
+                let mut initial_request = gen_userauth_header(&self.username);
+                SshStream::append_string(&mut initial_request, b"none");
+                Ok(AuthOutput::Send(initial_request))
+            }
+            SSH_MSG_USERAUTH_SUCCESS => Ok(AuthOutput::Success),
+            SSH_MSG_USERAUTH_FAILURE => {
+                let (methods, packet) = SshStream::extract_name_list(packet)?;
+                let partial_success = match packet.split_first() {
+                    Some((partial_success, rest)) => {
+                        SshStream::expect_end(rest)?;
+                        *partial_success != 0
+                    }
+                    None => {
+                        return Err(Error::Protocol(
+                            "Invalid userauth failure message".to_string(),
+                        ));
+                    }
+                };
+
+                // The last method worked but the host requires another one before succeeding,
+                // so the remaining methods get a fresh set of attempts
+                if partial_success && let Some(method) = self.last_method {
+                    self.satisfied.push(method);
+                    self.attempts = 0;
+                }
+                let allowed = |method: &'static str| {
+                    methods.iter().any(|name| name == method) && !self.satisfied.contains(&method)
+                };
+
+                // Offer the private key once before falling back to passwords
+                if self.key_unoffered && allowed("publickey") {
+                    self.key_unoffered = false;
+                    self.last_method = Some("publickey");
+                    return Ok(AuthOutput::PublicKey);
+                }
+
+                // Likewise the host key is offered once since the host either trusts this
+                // machine or it doesn't
+                if self.host_key_unoffered && allowed("hostbased") {
+                    self.host_key_unoffered = false;
+                    self.last_method = Some("hostbased");
+                    return Ok(AuthOutput::HostBased);
+                }
+
+                // Prefer passwords over keyboard-interactive when the host takes both
+                let method = if allowed("password") {
+                    "password"
+                } else if allowed("keyboard-interactive") {
+                    "keyboard-interactive"
+                } else if !self.satisfied.is_empty() {
+                    return Err(Error::AuthFailed(
+                        "Host requires another authentication method tssh does not support"
+                            .to_string(),
+                    ));
+                } else {
+                    return Err(Error::AuthFailed(
+                        "Host does not support password or keyboard-interactive authentication"
+                            .to_string(),
+                    ));
+                };
+
+                if self.attempts == 3 {
+                    return Err(Error::AuthFailed(
+                        "Too many failed login attempts".to_string(),
+                    ));
+                }
+                self.attempts += 1;
+                self.last_method = Some(method);
+
+                if method == "password" {
+                    return Ok(AuthOutput::Password);
+                }
+                let mut request = gen_userauth_header(&self.username);
+                SshStream::append_string(&mut request, b"keyboard-interactive");
+                SshStream::append_string(&mut request, b""); // deprecated language tag
+                SshStream::append_string(&mut request, b""); // let the host pick submethods
+                Ok(AuthOutput::Send(request))
+            }
+            SSH_MSG_USERAUTH_BANNER => {
+                let (banner, packet) = SshStream::extract_string(packet)?;
+                let (_language, packet) = SshStream::extract_string(packet)?;
+                SshStream::expect_end(packet)?;
+                Ok(AuthOutput::Banner(
+                    String::from_utf8_lossy(&banner).into_owned(),
+                ))
+            }
+            // Shares a message number with SSH_MSG_USERAUTH_PASSWD_CHANGEREQ
+            SSH_MSG_USERAUTH_INFO_REQUEST if self.last_method == Some("keyboard-interactive") => {
+                Ok(AuthOutput::InfoRequest(parse_info_request(packet)?))
+            }
+            SSH_MSG_USERAUTH_PASSWD_CHANGEREQ => Err(Error::Other(
+                "Password expired and tssh does not support password changes",
+            )),
+            _ => Ok(AuthOutput::None),
+        }
+    }
+
+    /// Generates the reply to a keyboard-interactive info request with an answer for each
+    /// of its prompts in order
+    pub fn info_response(&self, answers: &[String]) -> Vec<u8> {
+        let mut response = vec![SSH_MSG_USERAUTH_INFO_RESPONSE];
+        response.extend((answers.len() as u32).to_be_bytes());
+        for answer in answers {
+            SshStream::append_string(&mut response, answer.as_bytes());
+        }
+        response
+    }
+
+    /// Generates the authentication request for the given password
+    pub fn password_request(&self, password: &str) -> Vec<u8> {
+        let mut request = gen_userauth_header(&self.username);
+        SshStream::append_string(&mut request, b"password");
+        request.push(0); // false boolean field
+        SshStream::append_string(&mut request, password.as_bytes());
+        request
+    }
+
+    /// Generates the authentication request for a public key along with its signature of
+    /// the data built by gen_publickey_sign_data
+    pub fn publickey_request(
+        &self,
+        algorithm: &str,
+        public_key: &[u8],
+        signature: &[u8],
+    ) -> Vec<u8> {
+        let mut signature_blob = Vec::new();
+        SshStream::append_string(&mut signature_blob, algorithm.as_bytes());
+        SshStream::append_string(&mut signature_blob, signature);
+
+        let mut request = gen_userauth_header(&self.username);
+        SshStream::append_string(&mut request, b"publickey");
+        request.push(1); // true boolean field
+        SshStream::append_string(&mut request, algorithm.as_bytes());
+        SshStream::append_string(&mut request, public_key);
+        SshStream::append_string(&mut request, &signature_blob);
+        request
+    }
+
+    /// Generates the hostbased authentication request for the host key of the machine named
+    /// client_host, signed by it over the data built by gen_hostbased_sign_data. local_user is
+    /// the user running tssh, which the host checks may sign in as the requested user.
+    pub fn hostbased_request(
+        &self,
+        algorithm: &str,
+        host_key: &[u8],
+        client_host: &str,
+        local_user: &str,
+        signature: &[u8],
+    ) -> Vec<u8> {
+        let mut signature_blob = Vec::new();
+        SshStream::append_string(&mut signature_blob, algorithm.as_bytes());
+        SshStream::append_string(&mut signature_blob, signature);
+
+        let mut request = gen_userauth_header(&self.username);
+        SshStream::append_string(&mut request, b"hostbased");
+        SshStream::append_string(&mut request, algorithm.as_bytes());
+        SshStream::append_string(&mut request, host_key);
+        SshStream::append_string(&mut request, client_host.as_bytes());
+        SshStream::append_string(&mut request, local_user.as_bytes());
+        SshStream::append_string(&mut request, &signature_blob);
+        request
+    }
+}
+
+/// Parses the questions of a keyboard-interactive SSH_MSG_USERAUTH_INFO_REQUEST
+fn parse_info_request(packet: &[u8]) -> Result<InfoRequest, Error> {
+    let (name, packet) = SshStream::extract_string(packet)?;
+    let (instruction, packet) = SshStream::extract_string(packet)?;
+    let (_language, packet) = SshStream::extract_string(packet)?;
+    if packet.len() < 4 {
+        return Err(Error::Other(
+            "Recieved corrupt info request packet: Expected number of prompts",
+        ));
+    }
+    let num_prompts = u32::from_be_bytes(packet[0..4].try_into()?);
+
+    let mut prompts = Vec::new();
+    let mut packet = &packet[4..];
+    for _ in 0..num_prompts {
+        let (text, rest) = SshStream::extract_string(packet)?;
+        let echo = match rest.first() {
+            Some(echo) => *echo != 0,
+            None => {
+                return Err(Error::Other(
+                    "Recieved corrupt info request packet: Expected echo field",
+                ));
+            }
+        };
+        prompts.push(Prompt {
+            text: String::from_utf8_lossy(&text).into_owned(),
+            echo,
+        });
+        packet = &rest[1..];
+    }
+    SshStream::expect_end(packet)?;
+
+    Ok(InfoRequest {
+        name: String::from_utf8_lossy(&name).into_owned(),
+        instruction: String::from_utf8_lossy(&instruction).into_owned(),
+        prompts,
+    })
+}
+
+/// The result of handing a packet to an open channel
+pub enum ChannelOutput {
+    /// A payload that must be sent to the host
+    Send(Vec<u8>),
+    /// The host can recieve the given number of additional bytes
+    WindowAdjust(u64),
+    /// The shell or command has started and can be sent data
+    Ready,
+    /// Data written to the channel's normal stream
+    Stdout(Vec<u8>),
+    /// Data written to the channel's stderr stream
+    Stderr(Vec<u8>),
+    /// The exit status of the remote program
+    ExitStatus(u32),
+    /// The host will not send any more data over the channel
+    Eof,
+    /// The host has closed the channel
+    Closed,
+    /// The host failed to open the channel
+    OpenFailed(u32, String),
+    /// The host made a global request
+    GlobalRequest(String, Option<bool>),
+    /// The host tried to open a forwarded-tcpip channel which was denied
+    ForwardDenied(TcpipOrigin),
+    /// Something unexpected but harmless happened that the user may want to know about
+    Warning(&'static str),
+    /// A debugging message from the host and whether or not the host wants it always shown
+    Debug(String, bool),
+    /// A packet was recieved that the channel does not handle
+    Unknown(u8),
+}
+
+/// Where a forwarded connection was made to and where it came from, sent by the host
+/// when opening a forwarded-tcpip or direct-tcpip channel
+pub struct TcpipOrigin {
+    /// The address that was connected to
+    pub connected_address: String,
+    /// The port that was connected to
+    pub connected_port: u32,
+    /// The address the connection came from
+    pub originator_address: String,
+    /// The port the connection came from
+    pub originator_port: u32,
+}
+
+impl TcpipOrigin {
+    /// Parses the channel specific data at the end of a forwarded-tcpip or direct-tcpip
+    /// channel open request
+    pub fn parse(data: &[u8]) -> Result<TcpipOrigin, Error> {
+        let (connected_address, data) = SshStream::extract_string(data)?;
+        if data.len() < 4 {
+            return Err(Error::Other(
+                "Recieved corrupt channel open packet: Expected connected port",
+            ));
+        }
+        let connected_port = u32::from_be_bytes(data[0..4].try_into()?);
+
+        let (originator_address, data) = SshStream::extract_string(&data[4..])?;
+        if data.len() < 4 {
+            return Err(Error::Other(
+                "Recieved corrupt channel open packet: Expected originator port",
+            ));
+        }
+        let originator_port = u32::from_be_bytes(data[0..4].try_into()?);
+
+        Ok(TcpipOrigin {
+            connected_address: String::from_utf8_lossy(&connected_address).to_string(),
+            connected_port,
+            originator_address: String::from_utf8_lossy(&originator_address).to_string(),
+            originator_port,
+        })
+    }
+}
+
+/// The pseudo-terminal to request for a session channel
+pub struct Pty {
+    /// The width of the terminal in characters
+    pub width: u32,
+    /// The height of the terminal in characters
+    pub height: u32,
+    /// The encoded terminal modes ending with TTY_OP_END
+    pub modes: Vec<u8>,
+}
+
+/// A session or direct-tcpip channel from the open request until it is closed
+pub struct Channel {
+    /// The channel number used by the client
+    client_channel: u32,
+    /// The channel number used by the host
+    server_channel: u32,
+    /// The maximum amount of data the host accepts in a single packet
+    packet_max: u32,
+    /// The number of bytes the host can still send
+    local_window: u64,
+    /// The channel requests waiting on a reply, oldest first since the host replies in order
+    pending: VecDeque<WaitingFor>,
+    /// The pseudo-terminal to request if any
+    pty: Option<Pty>,
+    /// The environment variables to set before the shell or command starts
+    env: Vec<(String, String)>,
+    /// The command to run instead of a shell if any
+    command: Option<String>,
+    /// The subsystem to start instead of a shell or command if any
+    subsystem: Option<String>,
+    /// Whether or not this is a direct-tcpip channel, which is ready as soon as it is open
+    direct_tcpip: bool,
+    /// Whether or not the client has sent a channel close message
+    close_sent: bool,
+}
+
+impl Channel {
+    /// Begins opening a session channel with the given client channel number. Once open the
+    /// pseudo-terminal is requested if one is given, then the environment variables are set,
+    /// followed by the command or a shell if there is no command.
+    ///
+    /// Results in the channel and the channel open payload that must be sent to the host.
+    pub fn open(
+        client_channel: u32,
+        pty: Option<Pty>,
+        env: Vec<(String, String)>,
+        command: Option<String>,
+    ) -> (Channel, Vec<u8>) {
+        let channel = Channel {
+            client_channel,
+            server_channel: 0,
+            packet_max: 0,
+            local_window: CLIENT_WINDOW_SIZE as u64,
+            pending: VecDeque::new(),
+            pty,
+            env,
+            command,
+            subsystem: None,
+            direct_tcpip: false,
+            close_sent: false,
+        };
+        (
+            channel,
+            gen_channel_open_payload(client_channel, b"session"),
+        )
+    }
+
+    /// Begins opening a session channel that starts the named subsystem, such as sftp,
+    /// without a pseudo-terminal once open.
+    ///
+    /// Results in the channel and the channel open payload that must be sent to the host.
+    pub fn open_subsystem(client_channel: u32, subsystem: String) -> (Channel, Vec<u8>) {
+        let (mut channel, payload) = Channel::open(client_channel, None, Vec::new(), None);
+        channel.subsystem = Some(subsystem);
+        (channel, payload)
+    }
+
+    /// Begins opening a direct-tcpip channel that has the host connect to the target's
+    /// connected address and port on behalf of its originator. Data can be sent as soon as
+    /// the host confirms the channel.
+    ///
+    /// Results in the channel and the channel open payload that must be sent to the host.
+    pub fn open_direct_tcpip(client_channel: u32, target: &TcpipOrigin) -> (Channel, Vec<u8>) {
+        let (mut channel, _) = Channel::open(client_channel, None, Vec::new(), None);
+        channel.direct_tcpip = true;
+
+        let mut payload = gen_channel_open_payload(client_channel, b"direct-tcpip");
+        SshStream::append_string(&mut payload, target.connected_address.as_bytes());
+        payload.extend(target.connected_port.to_be_bytes());
+        SshStream::append_string(&mut payload, target.originator_address.as_bytes());
+        payload.extend(target.originator_port.to_be_bytes());
+        (channel, payload)
+    }
+
+    /// Returns the channel number used by the host
+    pub fn server_channel(&self) -> u32 {
+        self.server_channel
+    }
+
+    /// Returns the maximum amount of data the host accepts in a single packet
+    pub fn packet_max(&self) -> u32 {
+        self.packet_max
+    }
+
+    /// Returns how much more data the host may send before it needs a window adjustment
+    pub fn local_window(&self) -> u64 {
+        self.local_window
+    }
+
+    /// Generates the message telling the host that no more data will be sent
+    pub fn eof_message(&self) -> Vec<u8> {
+        let mut message = vec![SSH_MSG_CHANNEL_EOF];
+        message.extend(self.server_channel.to_be_bytes());
+        message
+    }
+
+    /// Generates the channel close message if one has not already been sent
+    pub fn close_message(&mut self) -> Option<Vec<u8>> {
+        if self.close_sent {
+            return None;
+        }
+        self.close_sent = true;
+
+        let mut message = vec![SSH_MSG_CHANNEL_CLOSE];
+        message.extend(self.server_channel.to_be_bytes());
+        Some(message)
+    }
+
+    /// Handles a packet recieved from the host after authentication. Packets for the
+    /// connection as a whole are handled as well so that a lone channel can be driven on its
+    /// own, but with more than one channel open packets should go through Channels instead.
+    pub fn handle(&mut self, packet_type: u8, data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+        match packet_type {
+            SSH_MSG_CHANNEL_OPEN_CONFIRMATION => self.confirm_open(data),
+            SSH_MSG_CHANNEL_OPEN_FAILURE => handle_channel_open_fail(data),
+            SSH_MSG_CHANNEL_WINDOW_ADJUST => self.adjust_window(data),
+            SSH_MSG_CHANNEL_DATA => self.process_data(data),
+            SSH_MSG_CHANNEL_EXTENDED_DATA => self.process_extended_data(data),
+            SSH_MSG_CHANNEL_EOF => Ok(vec![ChannelOutput::Eof]),
+            SSH_MSG_CHANNEL_CLOSE => {
+                let mut outputs: Vec<ChannelOutput> = self
+                    .close_message()
+                    .map(ChannelOutput::Send)
+                    .into_iter()
+                    .collect();
+                outputs.push(ChannelOutput::Closed);
+                Ok(outputs)
+            }
+            SSH_MSG_CHANNEL_REQUEST => self.process_request(data),
+            SSH_MSG_CHANNEL_SUCCESS => self.handle_request_success(data),
+            SSH_MSG_CHANNEL_FAILURE => self.handle_request_fail(data),
+            _ => handle_connection_packet(packet_type, data),
+        }
+    }
+
+    /// Generates the request that starts the subsystem, the command or the shell if there is
+    /// neither
+    fn gen_start_request(&self) -> Vec<u8> {
+        if let Some(subsystem) = &self.subsystem {
+            let mut request = gen_channel_request(self.server_channel, b"subsystem", true);
+            SshStream::append_string(&mut request, subsystem.as_bytes());
+            return request;
+        }
+        match &self.command {
+            Some(command) => {
+                let mut request = gen_channel_request(self.server_channel, b"exec", true);
+                SshStream::append_string(&mut request, command.as_bytes());
+                request
+            }
+            None => gen_channel_request(self.server_channel, b"shell", true),
+        }
+    }
+
+    /// Verifies that the confirmed channel was the one requested and proceeds with requesting
+    /// a psudo terminal or starting the session
+    fn confirm_open(&mut self, data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+        if data.len() < 16 {
+            return Err(Error::Other(
+                "Recieved corrupt channel open confirmation packet: Expected length of at least 16 bytes",
+            ));
+        }
+        let client_channel = u32::from_be_bytes(data[0..4].try_into()?);
+        if client_channel != self.client_channel {
+            return Err(Error::Other(
+                "Recieved confirmation for openning of unrequested channel",
+            ));
+        }
+
+        self.server_channel = u32::from_be_bytes(data[4..8].try_into()?);
+        let window_size = u32::from_be_bytes(data[8..12].try_into()?);
+        self.packet_max = u32::from_be_bytes(data[12..16].try_into()?);
+
+        // Nothing needs to be started on a forwarded connection
+        if self.direct_tcpip {
+            return Ok(vec![
+                ChannelOutput::WindowAdjust(window_size as u64),
+                ChannelOutput::Ready,
+            ]);
+        }
+
+        let mut outputs = vec![ChannelOutput::WindowAdjust(window_size as u64)];
+        let pty_request = self
+            .pty
+            .as_ref()
+            .map(|pty| gen_pty_request(self.server_channel, pty));
+        match pty_request {
+            Some(request) => {
+                self.pending.push_back(WaitingFor::Pty);
+                outputs.push(ChannelOutput::Send(request));
+            }
+            None => outputs.extend(self.start()),
+        }
+
+        Ok(outputs)
+    }
+
+    /// Processes the amount to adjust a window by if the adjustment is for a valid channel
+    /// and ignores the packet otherwise. If the packet is malformed (not big enough) it
+    /// returns an error.
+    fn adjust_window(&self, data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+        if data.len() < 8 {
+            return Err(Error::Other(
+                "Recieved corrupt window adjust packet: Expected length of at least 8 bytes",
+            ));
+        }
+
+        let channel = u32::from_be_bytes(data[0..4].try_into()?);
+        if channel != self.client_channel {
+            return Ok(vec![ChannelOutput::Warning(
+                "Recieved window adjustment for unopened channel",
+            )]);
+        }
+
+        let amount = u32::from_be_bytes(data[4..8].try_into()?);
+        Ok(vec![ChannelOutput::WindowAdjust(amount as u64)])
+    }
+
+    /// Reports channel data and updates the client window apropriatly
+    fn process_data(&mut self, data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+        if data.len() < 8 {
+            return Err(Error::Other(
+                "Recieved corrupt channel data packet: Expected length of at least 8 bytes",
+            ));
+        }
+
+        let channel = u32::from_be_bytes(data[0..4].try_into()?);
+        if channel != self.client_channel {
+            return Ok(vec![ChannelOutput::Warning(
+                "Recieved channel data packet for unopened channel",
+            )]);
+        }
+
+        let (data, _) = SshStream::extract_string(&data[4..])?;
+
+        let len = data.len() as u64;
+        let mut outputs = self.skip_missing_pty_reply();
+        outputs.push(ChannelOutput::Stdout(data));
+        self.consume_window(len, &mut outputs);
+
+        Ok(outputs)
+    }
+
+    /// Reports extended channel data as stderr and updates the client window accordingly.
+    /// Data of any type other than stderr is dropped with a warning but still counts against
+    /// the window.
+    fn process_extended_data(&mut self, data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+        if data.len() < 12 {
+            return Err(Error::Other(
+                "Recieved corrupt extended channel data packet: Expected length of at least 12 bytes",
+            ));
+        }
+
+        let channel = u32::from_be_bytes(data[0..4].try_into()?);
+        if channel != self.client_channel {
+            return Ok(vec![ChannelOutput::Warning(
+                "Recieved extended channel data packet for unopened channel",
+            )]);
+        }
+
+        let data_type = u32::from_be_bytes(data[4..8].try_into()?);
+        let (data, _) = SshStream::extract_string(&data[8..])?;
+
+        let len = data.len() as u64;
+        let mut outputs = self.skip_missing_pty_reply();
+        if data_type == SSH_EXTENDED_DATA_STDERR {
+            outputs.push(ChannelOutput::Stderr(data));
+        } else {
+            outputs.push(ChannelOutput::Warning(
+                "Recieved extended channel data of unknown type",
+            ));
+        }
+        self.consume_window(len, &mut outputs);
+
+        Ok(outputs)
+    }
+
+    /// Some minimal hosts never reply to the pseudo-terminal request and start sending data
+    /// instead. If data arrives while still waiting on that reply the pseudo-terminal is
+    /// assumed to be granted and the shell or command is requested. This only ever moves the
+    /// channel on to the start request so a host that fails that request is still reported.
+    fn skip_missing_pty_reply(&mut self) -> Vec<ChannelOutput> {
+        if !matches!(self.pending.front(), Some(WaitingFor::Pty)) {
+            return Vec::new();
+        }
+
+        self.pending.pop_front();
+        let mut outputs = vec![ChannelOutput::Warning(
+            "Host sent data without replying to the pseudo-terminal request",
+        )];
+        outputs.extend(self.start());
+        outputs
+    }
+
+    /// Requests the environment variables followed by the shell, command or subsystem. The
+    /// host doesn't reply to env requests so the start request can follow right away.
+    fn start(&mut self) -> Vec<ChannelOutput> {
+        let mut outputs: Vec<ChannelOutput> = self
+            .env
+            .iter()
+            .map(|(name, value)| {
+                ChannelOutput::Send(gen_env_request(self.server_channel, name, value))
+            })
+            .collect();
+        self.pending.push_back(WaitingFor::Shell);
+        outputs.push(ChannelOutput::Send(self.gen_start_request()));
+        outputs
+    }
+
+    /// Removes the length of recieved data from the client window, which normal and extended
+    /// data share, and grants the host more window if it is nearly used up
+    fn consume_window(&mut self, len: u64, outputs: &mut Vec<ChannelOutput>) {
+        if len > self.local_window || self.local_window - len < WINDOW_ADJUST_THRESHOLD {
+            let request = gen_window_adjust(self.server_channel, CLIENT_WINDOW_SIZE);
+            outputs.push(ChannelOutput::Send(request));
+            self.local_window += CLIENT_WINDOW_SIZE as u64;
+        }
+        self.local_window = self.local_window.saturating_sub(len);
+    }
+
+    /// Handles channel specific requests by reporting exit statuses and refusing anything
+    /// that wants a reply
+    fn process_request(&self, data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+        if data.len() < 4 {
+            return Err(Error::Other(
+                "Recieved corrupt channel request packet: Expected channel number",
+            ));
+        }
+
+        let channel = u32::from_be_bytes(data[0..4].try_into()?);
+        if channel != self.client_channel {
+            return Err(Error::Other("Recieved channel request for invalid channel"));
+        }
+
+        let (request_type, data) = SshStream::extract_string(&data[4..])?;
+        let want_reply = match data.first() {
+            Some(byte) => *byte != 0,
+            None => {
+                return Err(Error::Other(
+                    "Recieved corrupt channel request packet: Expected want reply field",
+                ));
+            }
+        };
+
+        let mut outputs = Vec::new();
+        if request_type == b"exit-status" && data.len() >= 5 {
+            let status = u32::from_be_bytes(data[1..5].try_into()?);
+            outputs.push(ChannelOutput::ExitStatus(status));
+        }
+
+        if want_reply {
+            let mut response = vec![SSH_MSG_CHANNEL_FAILURE];
+            response.extend(self.server_channel.to_be_bytes());
+            outputs.push(ChannelOutput::Send(response));
+        }
+
+        Ok(outputs)
+    }
+
+    /// Hadles success responsed to channel requests by matching them to the oldest request
+    /// waiting on a reply. If the success is in response to a terminal request, it starts the
+    /// session.
+    /// Ignores responses to unsent messages and unopened channels
+    fn handle_request_success(&mut self, data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+        if data.len() < 4 {
+            return Err(Error::Other(
+                "Recieved corrupt channel request success packet: Expected length of at least 4 bytes",
+            ));
+        }
+
+        let channel = u32::from_be_bytes(data[0..4].try_into()?);
+        if channel != self.client_channel {
+            return Ok(vec![ChannelOutput::Warning(
+                "Recieved channel request success packet for unopened channel",
+            )]);
+        }
+
+        match self.pending.pop_front() {
+            Some(WaitingFor::Pty) => Ok(self.start()),
+            Some(WaitingFor::Shell) => Ok(vec![ChannelOutput::Ready]),
+            None => Ok(vec![ChannelOutput::Warning(
+                "Recieved channel request success packet for request that has not been sent",
+            )]),
+        }
+    }
+
+    /// Hnadles fail responses from channel requests mainly opening a terminal
+    fn handle_request_fail(&mut self, data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+        if data.len() < 4 {
+            return Err(Error::Other(
+                "Recieved corrupt channel request failure packet: Expected length of at least 4 bytes",
+            ));
+        }
+
+        let channel = u32::from_be_bytes(data[0..4].try_into()?);
+        if channel != self.client_channel {
+            return Ok(vec![ChannelOutput::Warning(
+                "Recieved channel request failure packet for unopened channel",
+            )]);
+        }
+
+        match self.pending.pop_front() {
+            Some(WaitingFor::Pty) => Err(Error::Other("Failed to open remote terminal")),
+            Some(WaitingFor::Shell) if self.subsystem.is_some() => {
+                Err(Error::Other("Failed to start remote subsystem"))
+            }
+            Some(WaitingFor::Shell) if self.command.is_some() => {
+                Err(Error::Other("Failed to run remote command"))
+            }
+            Some(WaitingFor::Shell) => Err(Error::Other("Failed to open a remote shell")),
+            None => Ok(vec![ChannelOutput::Warning(
+                "Recieved channel request failure packet for request that has not been sent",
+            )]),
+        }
+    }
+}
+
+/// Every channel open on a connection keyed by the client channel number. Packets from the host
+/// are routed to the channel named by their recipient channel field so that any number of
+/// channels can be open at once.
+#[derive(Default)]
+pub struct Channels {
+    /// The open channels and those waiting on the host to confirm them
+    channels: HashMap<u32, Channel>,
+    /// The client channel number to give the next opened channel
+    next_channel: u32,
+}
+
+impl Channels {
+    /// Creates an empty set of channels
+    pub fn new() -> Channels {
+        Channels::default()
+    }
+
+    /// Begins opening a session channel with the next unused client channel number as
+    /// Channel::open does.
+    ///
+    /// Results in the client channel number and the channel open payload that must be sent
+    /// to the host.
+    pub fn open_session(
+        &mut self,
+        pty: Option<Pty>,
+        env: Vec<(String, String)>,
+        command: Option<String>,
+    ) -> (u32, Vec<u8>) {
+        let client_channel = self.next_channel;
+        self.next_channel += 1;
+
+        let (channel, payload) = Channel::open(client_channel, pty, env, command);
+        self.channels.insert(client_channel, channel);
+        (client_channel, payload)
+    }
+
+    /// Begins opening a session channel that starts a subsystem with the next unused client
+    /// channel number as Channel::open_subsystem does.
+    ///
+    /// Results in the client channel number and the channel open payload that must be sent
+    /// to the host.
+    pub fn open_subsystem(&mut self, subsystem: String) -> (u32, Vec<u8>) {
+        let client_channel = self.next_channel;
+        self.next_channel += 1;
+
+        let (channel, payload) = Channel::open_subsystem(client_channel, subsystem);
+        self.channels.insert(client_channel, channel);
+        (client_channel, payload)
+    }
+
+    /// Begins opening a direct-tcpip channel to the target with the next unused client
+    /// channel number as Channel::open_direct_tcpip does.
+    ///
+    /// Results in the client channel number and the channel open payload that must be sent
+    /// to the host.
+    pub fn open_direct_tcpip(&mut self, target: &TcpipOrigin) -> (u32, Vec<u8>) {
+        let client_channel = self.next_channel;
+        self.next_channel += 1;
+
+        let (channel, payload) = Channel::open_direct_tcpip(client_channel, target);
+        self.channels.insert(client_channel, channel);
+        (client_channel, payload)
+    }
+
+    /// Returns the channel with the given client channel number, which fails once it has
+    /// closed or failed to open
+    pub fn get(&self, client_channel: u32) -> Result<&Channel, Error> {
+        self.channels
+            .get(&client_channel)
+            .ok_or(Error::Other("Channel is not open"))
+    }
+
+    /// Returns the channel with the given client channel number, which fails once it has
+    /// closed or failed to open
+    pub fn get_mut(&mut self, client_channel: u32) -> Result<&mut Channel, Error> {
+        self.channels
+            .get_mut(&client_channel)
+            .ok_or(Error::Other("Channel is not open"))
+    }
+
+    /// Handles a packet recieved from the host after authentication by passing it to the
+    /// channel it is for. Channels are forgotten once they close or fail to open.
+    ///
+    /// Results in the client channel number the packet was routed to, which is None for
+    /// packets about the whole connection, and the outputs of handling it.
+    pub fn handle(
+        &mut self,
+        packet_type: u8,
+        data: &[u8],
+    ) -> Result<(Option<u32>, Vec<ChannelOutput>), Error> {
+        // Every channel specific message starts with the recipient channel number
+        if !(SSH_MSG_CHANNEL_OPEN_CONFIRMATION..=SSH_MSG_CHANNEL_FAILURE).contains(&packet_type) {
+            return Ok((None, handle_connection_packet(packet_type, data)?));
+        }
+        if data.len() < 4 {
+            return Err(Error::Other(
+                "Recieved corrupt channel packet: Expected recipient channel",
+            ));
+        }
+        let client_channel = u32::from_be_bytes(data[0..4].try_into()?);
+
+        let channel = match self.channels.get_mut(&client_channel) {
+            Some(channel) => channel,
+            None => {
+                return Ok((
+                    None,
+                    vec![ChannelOutput::Warning(
+                        "Recieved channel packet for unopened channel",
+                    )],
+                ));
+            }
+        };
+
+        let outputs = channel.handle(packet_type, data)?;
+        let finished = outputs.iter().any(|output| {
+            matches!(
+                output,
+                ChannelOutput::Closed | ChannelOutput::OpenFailed(..)
+            )
+        });
+        if finished {
+            self.channels.remove(&client_channel);
+        }
+        Ok((Some(client_channel), outputs))
+    }
+}
+
+/// Handles a packet about the connection as a whole rather than any one channel
+fn handle_connection_packet(packet_type: u8, data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+    match packet_type {
+        SSH_MSG_DISCONNECT => Err(parse_disconnect(data)),
+        SSH_MSG_GLOBAL_REQUEST => process_global_request(data),
+        SSH_MSG_CHANNEL_OPEN => deny_channel_open(data),
+        // Replies to keepalives only matter in that they arrived
+        SSH_MSG_REQUEST_SUCCESS | SSH_MSG_REQUEST_FAILURE => Ok(Vec::new()),
+        SSH_MSG_IGNORE => Ok(Vec::new()),
+        SSH_MSG_DEBUG => process_debug(data),
+        SSH_MSG_UNIMPLEMENTED => Ok(vec![ChannelOutput::Warning(
+            "Host did not recognize a packet tssh sent",
+        )]),
+        _ => Ok(vec![ChannelOutput::Unknown(packet_type)]),
+    }
+}
+
+/// Parses the payload of the host's SSH_MSG_KEXINIT packet, adds it to the exchange hash
+/// prefix, and negotiates the algorithms to use for the key exchange with the given ciphers
+fn negotiate_kexinit(
+    packet: &[u8],
+    hash_prefix: &mut Vec<u8>,
+    ciphers: &[&'static str],
+) -> Result<Algorithms, Error> {
+    // Ensure packet can be a key exchange packet
+    if packet.len() < 61 {
+        return Err(Error::Other(
+            "Key exchange packet is not large enough to contain all key exchange info",
+        ));
+    }
+
+    // Add packet to exchange hash prefix
+    let mut kexinit = vec![SSH_MSG_KEXINIT];
+    kexinit.extend(packet);
+    SshStream::append_string(hash_prefix, &kexinit);
+
+    // Extract packet information
+
+    // Don't need cookie but here incase needed later
+    // let cookie = &packet[..16];
+
+    let (key_exchange_algs, packet) = SshStream::extract_name_list(&packet[16..])?;
+
+    let (host_key_algs, packet) = SshStream::extract_name_list(packet)?;
+
+    let (encrypt_algs_cts, packet) = SshStream::extract_name_list(packet)?;
+    let (encrypt_algs_stc, packet) = SshStream::extract_name_list(packet)?;
+
+    let (mac_algs_cts, packet) = SshStream::extract_name_list(packet)?;
+    let (mac_algs_stc, packet) = SshStream::extract_name_list(packet)?;
+
+    let (compress_algs_cts, packet) = SshStream::extract_name_list(packet)?;
+    let (compress_algs_stc, packet) = SshStream::extract_name_list(packet)?;
+
+    // Disregard language information
+    let (_, packet) = SshStream::extract_name_list(packet)?;
+    let (_, packet) = SshStream::extract_name_list(packet)?;
+
+    // Only valid guess requires client send first so for now this is irrelevant
+    // let server_guess: bool = packet[0] != 0;
+
+    // Only the guess flag and the reserved uint32 are left
+    if packet.len() != 5 {
+        return Err(Error::Other(
+            "Recieved corrupt kexinit packet: Expected a guess flag and a reserved field last",
+        ));
+    }
+
+    // Begin negotiating shared algorithm
+    Ok(Algorithms {
+        key_exchange: negotiate_alg("key exchange", &KEX_ALGS, &key_exchange_algs)?,
+        host_key: negotiate_alg("host key", &HOST_KEY_ALGS, &host_key_algs)?,
+        encrypt: negotiate_alg("cipher", ciphers, &encrypt_algs_cts)?,
+        decrypt: negotiate_alg("cipher", ciphers, &encrypt_algs_stc)?,
+        mac: negotiate_alg("mac", &MAC_ALGS, &mac_algs_cts)?,
+        verify: negotiate_alg("mac", &MAC_ALGS, &mac_algs_stc)?,
+        compress: negotiate_alg("compression", &COMPRESS_ALGS, &compress_algs_cts)?,
+        decompress: negotiate_alg("compression", &COMPRESS_ALGS, &compress_algs_stc)?,
+    })
+}
+
+/// Reports an ssh global request
+fn process_global_request(data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+    let (request, data) = SshStream::extract_string(data)?;
+    let request = String::from_utf8_lossy(&request).into_owned();
+    let want_reply = data.first().map(|want_reply| *want_reply != 0);
+    Ok(vec![ChannelOutput::GlobalRequest(request, want_reply)])
+}
+
+/// Reports a debugging message from the host. Control characters are dropped so the host
+/// can't mess with the local terminal.
+fn process_debug(data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+    let (always_display, data) = match data.split_first() {
+        Some((always_display, data)) => (*always_display != 0, data),
+        None => {
+            return Err(Error::Protocol(
+                "Recieved corrupt debug message: Expected always display field".to_string(),
+            ));
+        }
+    };
+    let (message, _) = SshStream::extract_string(data)?;
+    let message = String::from_utf8_lossy(&message)
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    Ok(vec![ChannelOutput::Debug(message, always_display)])
+}
+
+/// Reports the reason a channel failed to open
+fn handle_channel_open_fail(data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+    if data.len() < 16 {
+        return Err(Error::Other(
+            "Recieved corrupt channel open failure packet: Expected length of at least 16 bytes",
+        ));
+    }
+
+    let reason_code = u32::from_be_bytes(data[4..8].try_into()?);
+    let (bytes, _) = SshStream::extract_string(&data[8..])?;
+    let description = String::from_utf8_lossy(&bytes).into_owned();
+
+    Ok(vec![ChannelOutput::OpenFailed(reason_code, description)])
+}
+
+/// Denies a channel open request sent by the host, reporting where the connection came from
+/// if it was for a forwarded port
+fn deny_channel_open(data: &[u8]) -> Result<Vec<ChannelOutput>, Error> {
+    let mut outputs = vec![ChannelOutput::Send(gen_channel_open_denial(data)?)];
+
+    // Skip the channel type, sender channel, initial window size, and maximum packet size
+    let (channel_type, rest) = SshStream::extract_string(data)?;
+    if channel_type == b"forwarded-tcpip" && rest.len() >= 12 {
+        let origin = TcpipOrigin::parse(&rest[12..])?;
+        outputs.push(ChannelOutput::ForwardDenied(origin));
+    }
+
+    Ok(outputs)
+}
+
+/// Generates the failure response to a channel open request sent by the host
+pub fn gen_channel_open_denial(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let (_, data) = SshStream::extract_string(data)?;
+    if data.len() < 4 {
+        return Err(Error::Other(
+            "Recieved corrupt channel open packet: Expected channel number",
+        ));
+    }
+
+    let mut response = vec![SSH_MSG_CHANNEL_OPEN_FAILURE];
+    response.extend(&data[0..4]);
+    response.extend(SSH_OPEN_ADMINISTRATIVELY_PROHIBITED);
+    SshStream::append_string(
+        &mut response,
+        b"Client does not permit host to open channels",
+    );
+    SshStream::append_string(&mut response, b"");
+    Ok(response)
+}
+
+/// Generates the payload for the ssh key exchange init packet offering the given ciphers
+fn gen_kexinit_payload(ciphers: &[&'static str]) -> Vec<u8> {
+    // Create initial payload
+    let mut payload = vec![SSH_MSG_KEXINIT];
+
+    // Create and add cookie
+    let mut cookie = [0u8; 16];
+    OsRng.fill(&mut cookie);
+    payload.extend(cookie);
+
+    // Add algorithm name lists
+    SshStream::append_name_list(&mut payload, &KEX_ALGS);
+    SshStream::append_name_list(&mut payload, &HOST_KEY_ALGS);
+    SshStream::append_name_list(&mut payload, ciphers);
+    SshStream::append_name_list(&mut payload, ciphers);
+    SshStream::append_name_list(&mut payload, &MAC_ALGS);
+    SshStream::append_name_list(&mut payload, &MAC_ALGS);
+    SshStream::append_name_list(&mut payload, &COMPRESS_ALGS);
+    SshStream::append_name_list(&mut payload, &COMPRESS_ALGS);
+
+    // Add empty language fields, a false guess byte, and a 0 extention
+    payload.extend([0u8; 13]);
+
+    payload
+}
+
+/// Runs the ssh negotioation algorithm on a list of client algorithms and a vector of server algorithms
+/// and returns the first client algorithm that appears in the server list or throws an error naming
+/// the category and what the host offered if none is found.
+fn negotiate_alg(
+    category: &str,
+    client: &[&'static str],
+    server: &[String],
+) -> Result<&'static str, Error> {
+    match client.iter().find(|alg| server.iter().any(|s| s == *alg)) {
+        Some(alg) => Ok(*alg),
+        None => Err(Error::Negotiation(format!(
+            "No compatible {category} algorithm: Host offers {}",
+            server.join(",")
+        ))),
+    }
+}
+
+/// Generates the header for a userauthenctication payload. Expects a method name and
+/// related fields to be appended before being sent.
+pub fn gen_userauth_header(username: &str) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.push(SSH_USERAUTH_REQUEST);
+    SshStream::append_string(&mut header, username.as_bytes());
+    SshStream::append_string(&mut header, b"ssh-connection");
+    header
+}
+
+/// Generates the payload for opening a channel of the given type with the given client channel
+/// number. Any type specific fields need to be appended before being sent.
+fn gen_channel_open_payload(client_channel: u32, channel_type: &[u8]) -> Vec<u8> {
+    let mut payload = vec![SSH_MSG_CHANNEL_OPEN];
+    SshStream::append_string(&mut payload, channel_type);
+    payload.extend(client_channel.to_be_bytes());
+    payload.extend(CLIENT_WINDOW_SIZE.to_be_bytes()); // client window size
+    payload.extend(CLIENT_PACKET_MAX.to_be_bytes()); // max packet size
+    payload
+}
+
+/// Generates the header of a channel request. Any request specific fields need to be
+/// appended before being sent.
+pub fn gen_channel_request(server_channel: u32, request_type: &[u8], want_reply: bool) -> Vec<u8> {
+    let mut request = vec![SSH_MSG_CHANNEL_REQUEST];
+    request.extend(server_channel.to_be_bytes());
+    SshStream::append_string(&mut request, request_type);
+    request.push(want_reply as u8);
+    request
+}
+
+/// Generates a request for a pseudo-terminal
+fn gen_pty_request(server_channel: u32, pty: &Pty) -> Vec<u8> {
+    let mut request = gen_channel_request(server_channel, b"pty-req", true);
+    SshStream::append_string(&mut request, b"xterm-256color"); // terminal type is xterm
+    request.extend(pty.width.to_be_bytes());
+    request.extend(pty.height.to_be_bytes());
+    request.extend([0; 8]); // ignore pixel measurement parameters
+    SshStream::append_string(&mut request, &pty.modes); // add terminal settings
+    request
+}
+
+/// Generates a request to set an environment variable, which hosts only accept for the names
+/// they allow such as with AcceptEnv in sshd_config
+fn gen_env_request(server_channel: u32, name: &str, value: &str) -> Vec<u8> {
+    let mut request = gen_channel_request(server_channel, b"env", false);
+    SshStream::append_string(&mut request, name.as_bytes());
+    SshStream::append_string(&mut request, value.as_bytes());
+    request
+}
+
+/// Generates a request telling the host that the terminal was resized to the given width and
+/// height in characters
+pub fn gen_window_change(server_channel: u32, width: u32, height: u32) -> Vec<u8> {
+    let mut request = gen_channel_request(server_channel, b"window-change", false);
+    request.extend(width.to_be_bytes());
+    request.extend(height.to_be_bytes());
+    request.extend([0; 8]); // ignore pixel measurement parameters
+    request
+}
+
+/// Generates a request to deliver a signal to the remote process. The name may be given in
+/// any case with or without a SIG prefix. Returns None if it is not a signal SSH defines.
+pub fn gen_signal(server_channel: u32, name: &str) -> Option<Vec<u8>> {
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    let name = SIGNALS.iter().find(|signal| **signal == name)?;
+
+    let mut request = gen_channel_request(server_channel, b"signal", false);
+    SshStream::append_string(&mut request, name.as_bytes());
+    Some(request)
+}
+
+/// Generates a global request asking the host to reply so that it is known to still be alive
+pub fn gen_keepalive() -> Vec<u8> {
+    let mut request = vec![SSH_MSG_GLOBAL_REQUEST];
+    SshStream::append_string(&mut request, b"keepalive@openssh.com");
+    request.push(1); // true want reply field
+    request
+}
+
+/// Generates the reply to a packet of a type that is not recognized, naming it by the
+/// sequence number it was recieved with
+pub fn gen_unimplemented(sequence_number: u32) -> Vec<u8> {
+    let mut message = vec![SSH_MSG_UNIMPLEMENTED];
+    message.extend(sequence_number.to_be_bytes());
+    message
+}
+
+/// Generates a window adjust message granting the host the given number of bytes on a channel
+fn gen_window_adjust(server_channel: u32, amount: u32) -> Vec<u8> {
+    let mut request = vec![SSH_MSG_CHANNEL_WINDOW_ADJUST];
+    request.extend(server_channel.to_be_bytes());
+    request.extend(amount.to_be_bytes());
+    request
+}
+
+/// Generates the message telling the host the client is ending the connection for the given
+/// reason code and description
+pub fn gen_disconnect(reason_code: [u8; 4], description: &[u8]) -> Vec<u8> {
+    let mut message = vec![SSH_MSG_DISCONNECT];
+    message.extend(reason_code);
+    SshStream::append_string(&mut message, description);
+    SshStream::append_string(&mut message, b"");
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the host's confirmation of a client channel as the given server channel
+    fn open_confirmation(client_channel: u32, server_channel: u32) -> Vec<u8> {
+        let mut data = client_channel.to_be_bytes().to_vec();
+        data.extend(server_channel.to_be_bytes());
+        data.extend(CLIENT_WINDOW_SIZE.to_be_bytes());
+        data.extend(CLIENT_PACKET_MAX.to_be_bytes());
+        data
+    }
+
+    /// Builds channel data sent by the host to a client channel
+    fn channel_data(client_channel: u32, data: &[u8]) -> Vec<u8> {
+        let mut packet = client_channel.to_be_bytes().to_vec();
+        SshStream::append_string(&mut packet, data);
+        packet
+    }
+
+    #[test]
+    fn channels_route_packets_by_recipient() {
+        let mut channels = Channels::new();
+        let (first, _) = channels.open_session(None, Vec::new(), None);
+        let (second, _) = channels.open_session(None, Vec::new(), Some("true".to_string()));
+        assert_ne!(first, second);
+
+        for (client_channel, server_channel) in [(first, 7), (second, 9)] {
+            let (routed, _) = channels
+                .handle(
+                    SSH_MSG_CHANNEL_OPEN_CONFIRMATION,
+                    &open_confirmation(client_channel, server_channel),
+                )
+                .unwrap();
+            assert_eq!(routed, Some(client_channel));
+        }
+        assert_eq!(channels.get(first).unwrap().server_channel(), 7);
+        assert_eq!(channels.get(second).unwrap().server_channel(), 9);
+
+        let (routed, outputs) = channels
+            .handle(SSH_MSG_CHANNEL_DATA, &channel_data(second, b"hello"))
+            .unwrap();
+        assert_eq!(routed, Some(second));
+        assert!(matches!(&outputs[..], [ChannelOutput::Stdout(data)] if data == b"hello"));
+
+        // Data for a channel that was never opened is only warned about
+        let (routed, outputs) = channels
+            .handle(SSH_MSG_CHANNEL_DATA, &channel_data(5, b"lost"))
+            .unwrap();
+        assert_eq!(routed, None);
+        assert!(matches!(&outputs[..], [ChannelOutput::Warning(_)]));
+    }
+
+    /// Builds the host's refusal of the last authentication request
+    fn userauth_failure(methods: &[&'static str], partial_success: bool) -> Vec<u8> {
+        let mut packet = Vec::new();
+        SshStream::append_name_list(&mut packet, methods);
+        packet.push(partial_success as u8);
+        packet
+    }
+
+    #[test]
+    fn partial_success_moves_on_to_the_next_method() {
+        let (mut auth, _) = Auth::new("user".to_string(), true, false);
+        assert!(matches!(
+            auth.handle(
+                SSH_MSG_USERAUTH_FAILURE,
+                &userauth_failure(&["publickey", "password"], false)
+            ),
+            Ok(AuthOutput::PublicKey)
+        ));
+
+        // The key was accepted but a password is needed as well
+        assert!(matches!(
+            auth.handle(
+                SSH_MSG_USERAUTH_FAILURE,
+                &userauth_failure(&["publickey", "password"], true)
+            ),
+            Ok(AuthOutput::Password)
+        ));
+
+        // Keyboard-interactive is used when the host doesn't take passwords for the second
+        // factor, and the satisfied publickey method is never tried again
+        let (mut auth, _) = Auth::new("user".to_string(), true, false);
+        auth.handle(
+            SSH_MSG_USERAUTH_FAILURE,
+            &userauth_failure(&["publickey"], false),
+        )
+        .unwrap();
+        let mut expected = gen_userauth_header("user");
+        SshStream::append_string(&mut expected, b"keyboard-interactive");
+        SshStream::append_string(&mut expected, b"");
+        SshStream::append_string(&mut expected, b"");
+        assert!(matches!(
+            auth.handle(
+                SSH_MSG_USERAUTH_FAILURE,
+                &userauth_failure(&["publickey", "keyboard-interactive"], true)
+            ),
+            Ok(AuthOutput::Send(request)) if request == expected
+        ));
+        assert!(matches!(
+            auth.handle(
+                SSH_MSG_USERAUTH_FAILURE,
+                &userauth_failure(&["publickey"], true)
+            ),
+            Err(Error::AuthFailed(_))
+        ));
+    }
+
+    #[test]
+    fn disconnect_reports_reason_and_description() {
+        let mut packet = 11u32.to_be_bytes().to_vec();
+        SshStream::append_string(&mut packet, b"Too many authentication failures\x1b[2J");
+        SshStream::append_string(&mut packet, b"en");
+        let (mut auth, _) = Auth::new("user".to_string(), false, false);
+        match auth.handle(SSH_MSG_DISCONNECT, &packet) {
+            Err(err @ Error::Disconnect { code: 11, .. }) => assert_eq!(
+                err.to_string(),
+                "Disconnected (11): Too many authentication failures[2J"
+            ),
+            _ => panic!("Expected a disconnect error"),
+        }
+
+        // Whatever arrived of a truncated message is still reported
+        assert!(matches!(
+            handle_connection_packet(SSH_MSG_DISCONNECT, &[0, 0, 0, 2, 0, 0]),
+            Err(Error::Disconnect { code: 2, .. })
+        ));
+        assert!(matches!(
+            handle_connection_packet(SSH_MSG_DISCONNECT, &[0, 0]),
+            Err(Error::Disconnect { code: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn only_unrecognized_packets_are_unknown() {
+        for packet_type in [SSH_MSG_IGNORE, SSH_MSG_REQUEST_SUCCESS] {
+            assert!(
+                handle_connection_packet(packet_type, b"")
+                    .unwrap()
+                    .is_empty()
+            );
+        }
+        assert!(matches!(
+            &handle_connection_packet(SSH_MSG_UNIMPLEMENTED, &[0, 0, 0, 9]).unwrap()[..],
+            [ChannelOutput::Warning(_)]
+        ));
+        assert!(matches!(
+            &handle_connection_packet(200, b"").unwrap()[..],
+            [ChannelOutput::Unknown(200)]
+        ));
+        assert_eq!(gen_unimplemented(9), [SSH_MSG_UNIMPLEMENTED, 0, 0, 0, 9]);
+    }
+
+    #[test]
+    fn debug_messages_are_reported_without_control_characters() {
+        let mut data = vec![1];
+        SshStream::append_string(&mut data, b"rekeying soon\x1b[2J");
+        SshStream::append_string(&mut data, b"en");
+        let outputs = handle_connection_packet(SSH_MSG_DEBUG, &data).unwrap();
+        assert!(matches!(
+            &outputs[..],
+            [ChannelOutput::Debug(message, true)] if message == "rekeying soon[2J"
+        ));
+
+        // Both may arrive before a session is open and are skipped there
+        let (mut kex, _) = Kex::new(Vec::new(), &["aes128-ctr"]);
+        let (mut auth, _) = Auth::new("user".to_string(), false, false);
+        for packet_type in [SSH_MSG_IGNORE, SSH_MSG_DEBUG] {
+            assert!(matches!(
+                kex.handle(packet_type, &data),
+                Ok(KexOutput::None)
+            ));
+            assert!(matches!(
+                auth.handle(packet_type, &data),
+                Ok(AuthOutput::None)
+            ));
+        }
+    }
+
+    #[test]
+    fn kex_defers_connection_packets_until_done() {
+        let (mut kex, _) = Kex::new(Vec::new(), &["aes128-ctr"]);
+        let data = channel_data(0, b"late");
+        assert!(matches!(
+            kex.handle(SSH_MSG_CHANNEL_DATA, &data),
+            Ok(KexOutput::None)
+        ));
+        // Transport messages such as SSH_MSG_IGNORE are not worth keeping
+        assert!(matches!(kex.handle(2, b""), Ok(KexOutput::None)));
+
+        assert_eq!(kex.take_deferred(), vec![(SSH_MSG_CHANNEL_DATA, data)]);
+        assert!(kex.take_deferred().is_empty());
+    }
+
+    #[test]
+    fn negotiation_failure_names_the_category() {
+        let server = vec!["chacha20-poly1305@openssh.com".to_string()];
+        match negotiate_alg("cipher", &["aes256-ctr"], &server) {
+            Err(Error::Negotiation(e)) => assert_eq!(
+                e,
+                "No compatible cipher algorithm: Host offers chacha20-poly1305@openssh.com"
+            ),
+            _ => panic!("Expected a negotiation error"),
+        }
+    }
+
+    #[test]
+    fn subsystem_channel_requests_subsystem_once_open() {
+        let mut channels = Channels::new();
+        let (sftp, _) = channels.open_subsystem("sftp".to_string());
+        let (_, outputs) = channels
+            .handle(
+                SSH_MSG_CHANNEL_OPEN_CONFIRMATION,
+                &open_confirmation(sftp, 4),
+            )
+            .unwrap();
+
+        let mut expected = gen_channel_request(4, b"subsystem", true);
+        SshStream::append_string(&mut expected, b"sftp");
+        assert!(
+            matches!(&outputs[..], [ChannelOutput::WindowAdjust(_), ChannelOutput::Send(request)] if *request == expected)
+        );
+
+        let (_, outputs) = channels
+            .handle(SSH_MSG_CHANNEL_SUCCESS, &sftp.to_be_bytes())
+            .unwrap();
+        assert!(matches!(&outputs[..], [ChannelOutput::Ready]));
+    }
+
+    #[test]
+    fn direct_tcpip_channel_is_ready_once_open() {
+        let mut channels = Channels::new();
+        let target = TcpipOrigin {
+            connected_address: "example.com".to_string(),
+            connected_port: 80,
+            originator_address: "127.0.0.1".to_string(),
+            originator_port: 51000,
+        };
+        let (channel, payload) = channels.open_direct_tcpip(&target);
+
+        let (channel_type, rest) = SshStream::extract_string(&payload[1..]).unwrap();
+        assert_eq!(channel_type, b"direct-tcpip");
+        let parsed = TcpipOrigin::parse(&rest[12..]).unwrap();
+        assert_eq!(parsed.connected_address, "example.com");
+        assert_eq!(parsed.connected_port, 80);
+        assert_eq!(parsed.originator_address, "127.0.0.1");
+        assert_eq!(parsed.originator_port, 51000);
+
+        let (_, outputs) = channels
+            .handle(
+                SSH_MSG_CHANNEL_OPEN_CONFIRMATION,
+                &open_confirmation(channel, 3),
+            )
+            .unwrap();
+        assert!(matches!(
+            &outputs[..],
+            [ChannelOutput::WindowAdjust(_), ChannelOutput::Ready]
+        ));
+    }
+
+    #[test]
+    fn env_is_sent_between_pty_and_shell() {
+        let pty = Pty {
+            width: 80,
+            height: 24,
+            modes: Vec::new(),
+        };
+        let env = vec![("LANG".to_string(), "en_US.UTF-8".to_string())];
+        let mut channels = Channels::new();
+        let (shell, _) = channels.open_session(Some(pty), env, None);
+        channels
+            .handle(
+                SSH_MSG_CHANNEL_OPEN_CONFIRMATION,
+                &open_confirmation(shell, 6),
+            )
+            .unwrap();
+
+        let (_, outputs) = channels
+            .handle(SSH_MSG_CHANNEL_SUCCESS, &shell.to_be_bytes())
+            .unwrap();
+        let env = gen_env_request(6, "LANG", "en_US.UTF-8");
+        let start = gen_channel_request(6, b"shell", true);
+        assert!(matches!(
+            &outputs[..],
+            [ChannelOutput::Send(first), ChannelOutput::Send(second)]
+                if *first == env && *second == start
+        ));
+
+        // Only the shell request wants a reply
+        let (_, outputs) = channels
+            .handle(SSH_MSG_CHANNEL_SUCCESS, &shell.to_be_bytes())
+            .unwrap();
+        assert!(matches!(&outputs[..], [ChannelOutput::Ready]));
+    }
+
+    #[test]
+    fn env_is_sent_before_a_command_without_a_pty() {
+        let env = vec![
+            ("LANG".to_string(), "C".to_string()),
+            ("TERM".to_string(), "dumb".to_string()),
+        ];
+        let mut channels = Channels::new();
+        let (exec, _) = channels.open_session(None, env, Some("echo hi".to_string()));
+
+        let (_, outputs) = channels
+            .handle(
+                SSH_MSG_CHANNEL_OPEN_CONFIRMATION,
+                &open_confirmation(exec, 4),
+            )
+            .unwrap();
+        let lang = gen_env_request(4, "LANG", "C");
+        let term = gen_env_request(4, "TERM", "dumb");
+        let mut start = gen_channel_request(4, b"exec", true);
+        SshStream::append_string(&mut start, b"echo hi");
+        assert!(matches!(
+            &outputs[..],
+            [
+                ChannelOutput::WindowAdjust(_),
+                ChannelOutput::Send(first),
+                ChannelOutput::Send(second),
+                ChannelOutput::Send(third),
+            ] if *first == lang && *second == term && *third == start
+        ));
+
+        let (_, outputs) = channels
+            .handle(SSH_MSG_CHANNEL_SUCCESS, &exec.to_be_bytes())
+            .unwrap();
+        assert!(matches!(&outputs[..], [ChannelOutput::Ready]));
+    }
+
+    #[test]
+    fn env_is_sent_when_the_pty_reply_is_skipped() {
+        let pty = Pty {
+            width: 80,
+            height: 24,
+            modes: Vec::new(),
+        };
+        let env = vec![("LANG".to_string(), "C".to_string())];
+        let (mut channel, _) = Channel::open(0, Some(pty), env, None);
+        channel
+            .handle(SSH_MSG_CHANNEL_OPEN_CONFIRMATION, &open_confirmation(0, 3))
+            .unwrap();
+
+        let outputs = channel
+            .handle(SSH_MSG_CHANNEL_DATA, &channel_data(0, b"$ "))
+            .unwrap();
+        let env = gen_env_request(3, "LANG", "C");
+        let start = gen_channel_request(3, b"shell", true);
+        assert!(matches!(
+            &outputs[..],
+            [
+                ChannelOutput::Warning(_),
+                ChannelOutput::Send(first),
+                ChannelOutput::Send(second),
+                ChannelOutput::Stdout(data),
+            ] if *first == env && *second == start && data == b"$ "
+        ));
+    }
+
+    #[test]
+    fn window_is_adjusted_as_data_arrives() {
+        let (mut channel, _) = Channel::open(0, None, Vec::new(), None);
+        channel
+            .handle(SSH_MSG_CHANNEL_OPEN_CONFIRMATION, &open_confirmation(0, 2))
+            .unwrap();
+
+        // Just over 5 MiB in full sized packets, which exhausts the window twice
+        let chunk = vec![b'x'; CLIENT_PACKET_MAX as usize];
+        let adjust = gen_window_adjust(2, CLIENT_WINDOW_SIZE);
+        let mut adjusted = Vec::new();
+        for n in 0..161 {
+            let outputs = channel
+                .handle(SSH_MSG_CHANNEL_DATA, &channel_data(0, &chunk))
+                .unwrap();
+            match &outputs[..] {
+                [ChannelOutput::Stdout(_)] => (),
+                [ChannelOutput::Stdout(_), ChannelOutput::Send(request)] if *request == adjust => {
+                    adjusted.push(n)
+                }
+                _ => panic!("Unexpected outputs for packet {n}"),
+            }
+            // The host is never granted more than a window at a time
+            assert!(channel.local_window() <= CLIENT_WINDOW_SIZE as u64);
+        }
+
+        // The window is only topped up once the packet that uses the last of it arrives
+        assert_eq!(adjusted, [63, 127]);
+        assert_eq!(
+            channel.local_window(),
+            3 * CLIENT_WINDOW_SIZE as u64 - 161 * CLIENT_PACKET_MAX as u64
+        );
+    }
+
+    #[test]
+    fn data_and_extended_data_share_a_window() {
+        let (mut channel, _) = Channel::open(0, None, Vec::new(), None);
+        channel
+            .handle(SSH_MSG_CHANNEL_OPEN_CONFIRMATION, &open_confirmation(0, 2))
+            .unwrap();
+
+        let chunk = vec![b'x'; CLIENT_PACKET_MAX as usize];
+        let mut stderr = 0u32.to_be_bytes().to_vec();
+        stderr.extend(SSH_EXTENDED_DATA_STDERR.to_be_bytes());
+        SshStream::append_string(&mut stderr, &chunk);
+
+        // Neither kind of data alone fills the window but together they do
+        let adjust = gen_window_adjust(2, CLIENT_WINDOW_SIZE);
+        for n in 0..64u64 {
+            let outputs = if n % 2 == 0 {
+                channel.handle(SSH_MSG_CHANNEL_DATA, &channel_data(0, &chunk))
+            } else {
+                channel.handle(SSH_MSG_CHANNEL_EXTENDED_DATA, &stderr)
+            }
+            .unwrap();
+
+            let remaining = CLIENT_WINDOW_SIZE as u64 - (n + 1) * CLIENT_PACKET_MAX as u64;
+            if n < 63 {
+                assert!(matches!(
+                    &outputs[..],
+                    [ChannelOutput::Stdout(_) | ChannelOutput::Stderr(_)]
+                ));
+                assert_eq!(channel.local_window(), remaining);
+            } else {
+                assert!(matches!(
+                    &outputs[..],
+                    [ChannelOutput::Stderr(_), ChannelOutput::Send(request)] if *request == adjust
+                ));
+                assert_eq!(channel.local_window(), CLIENT_WINDOW_SIZE as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn channel_output_keeps_the_order_the_host_sent() {
+        let (mut channel, _) = Channel::open(0, None, Vec::new(), None);
+        channel
+            .handle(SSH_MSG_CHANNEL_OPEN_CONFIRMATION, &open_confirmation(0, 8))
+            .unwrap();
+
+        let mut outputs = Vec::new();
+        for (packet_type, data) in [
+            (SSH_MSG_CHANNEL_DATA, channel_data(0, b"bye")),
+            (SSH_MSG_CHANNEL_EOF, 0u32.to_be_bytes().to_vec()),
+            (SSH_MSG_CHANNEL_CLOSE, 0u32.to_be_bytes().to_vec()),
+        ] {
+            outputs.extend(channel.handle(packet_type, &data).unwrap());
+        }
+
+        // Output written before the host closed the channel must be drained before it ends
+        let close = [SSH_MSG_CHANNEL_CLOSE, 0, 0, 0, 8];
+        assert!(matches!(
+            &outputs[..],
+            [
+                ChannelOutput::Stdout(data),
+                ChannelOutput::Eof,
+                ChannelOutput::Send(reply),
+                ChannelOutput::Closed,
+            ] if data == b"bye" && *reply == close
+        ));
+    }
+
+    #[test]
+    fn channels_forget_closed_channels() {
+        let mut channels = Channels::new();
+        let (first, _) = channels.open_session(None, Vec::new(), None);
+        let (second, _) = channels.open_session(None, Vec::new(), None);
+        channels
+            .handle(
+                SSH_MSG_CHANNEL_OPEN_CONFIRMATION,
+                &open_confirmation(first, 3),
+            )
+            .unwrap();
+
+        let (routed, outputs) = channels
+            .handle(SSH_MSG_CHANNEL_CLOSE, &first.to_be_bytes())
+            .unwrap();
+        assert_eq!(routed, Some(first));
+        assert!(matches!(outputs.last(), Some(ChannelOutput::Closed)));
+        assert!(channels.get(first).is_err());
+        assert!(channels.get(second).is_ok());
+
+        // Packets about the whole connection are not routed to any channel
+        let mut request = Vec::new();
+        SshStream::append_string(&mut request, b"hostkeys-00@openssh.com");
+        request.push(0);
+        let (routed, outputs) = channels.handle(SSH_MSG_GLOBAL_REQUEST, &request).unwrap();
+        assert_eq!(routed, None);
+        assert!(matches!(&outputs[..], [ChannelOutput::GlobalRequest(..)]));
+    }
+}
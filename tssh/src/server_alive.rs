@@ -0,0 +1,109 @@
+use crate::encrypter::Encrypter;
+use crate::protocol::gen_keepalive;
+use crate::ssh_stream::SshStream;
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+/// Starts the thread that checks the host is still alive. Every interval it asks the host to
+/// reply and counts the check as missed until the reading thread hears anything from the host
+/// and resets the count. Once more than count_max checks in a row are missed the connection is
+/// shut down so that the reading thread stops waiting on it.
+pub fn spawn(
+    mut stream: SshStream,
+    encrypter: Arc<Mutex<Encrypter>>,
+    interval: Duration,
+    count_max: u32,
+    missed: Arc<AtomicU32>,
+    stop_flag: Arc<AtomicBool>,
+) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            if stop_flag.load(Ordering::Relaxed) {
+                return;
+            }
+
+            if missed.fetch_add(1, Ordering::Relaxed) >= count_max {
+                let _ = stream.shutdown();
+                return;
+            }
+
+            let mut enc = encrypter.lock().unwrap();
+            if stream.send(&gen_keepalive(), Some(&mut enc)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Returns whether or not the connection was shut down because the host stopped responding
+pub fn gave_up(missed: &AtomicU32, count_max: u32) -> bool {
+    missed.load(Ordering::Relaxed) > count_max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypter::{Decrypter, loopback_pair};
+    use crate::ssh_stream::loopback_streams;
+
+    const INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Starts checking on the client end of a loopback connection and returns the host end
+    /// along with the decrypter for what the checks send
+    fn start(
+        count_max: u32,
+        missed: &Arc<AtomicU32>,
+        stop_flag: &Arc<AtomicBool>,
+    ) -> (SshStream, Decrypter) {
+        let (client, host) = loopback_streams();
+        let (encrypter, decrypter) = loopback_pair("aes128-ctr", "hmac-sha2-256", "none");
+        spawn(
+            client,
+            Arc::new(Mutex::new(encrypter)),
+            INTERVAL,
+            count_max,
+            Arc::clone(missed),
+            Arc::clone(stop_flag),
+        );
+        (host, decrypter)
+    }
+
+    #[test]
+    fn unanswered_checks_shut_down_the_connection() {
+        let missed = Arc::new(AtomicU32::new(0));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let (mut host, mut decrypter) = start(3, &missed, &stop_flag);
+
+        // The host hears count_max checks before the client gives up on it
+        for _ in 0..3 {
+            let (packet_type, payload) = host.read(Some(&mut decrypter)).unwrap();
+            assert_eq!([vec![packet_type], payload].concat(), gen_keepalive());
+        }
+        assert!(host.read(Some(&mut decrypter)).is_err());
+        assert!(gave_up(&missed, 3));
+    }
+
+    #[test]
+    fn answered_checks_keep_the_connection_open() {
+        let missed = Arc::new(AtomicU32::new(0));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let (mut host, mut decrypter) = start(2, &missed, &stop_flag);
+
+        // Hearing from the host resets the count the same way the reading thread does, so
+        // more checks get through than count_max
+        for _ in 0..5 {
+            let (packet_type, payload) = host.read(Some(&mut decrypter)).unwrap();
+            assert_eq!([vec![packet_type], payload].concat(), gen_keepalive());
+            assert!(!gave_up(&missed, 2));
+            missed.store(0, Ordering::Relaxed);
+        }
+        stop_flag.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::builder::{Client, ClientBuilder};
+use crate::encrypter::{Decrypter, Encrypter};
+use crate::protocol::{Auth, AuthOutput, ChannelOutput, Channels};
+use crate::ssh_stream::SshStream;
+use crate::{
+    Error, SSH_MSG_KEXINIT, connection_reply, handshake, key_rexchange, last_sequence_number, rekey,
+};
+use std::collections::VecDeque;
+
+/// A connection to a host whose keys have been exchanged but whose user has not signed in
+/// yet, created by Session::connect
+pub struct Handshake {
+    /// The configuration the connection was made with
+    client: Client,
+    /// The stream to the host
+    stream: SshStream,
+    /// Encrypts packets sent to the host
+    encrypter: Encrypter,
+    /// Decrypts packets recieved from the host
+    decrypter: Decrypter,
+    /// The version information needed for the exchange hash of key re-exchanges
+    hash_prefix: Vec<u8>,
+}
+
+/// An authenticated connection for driving a host from code rather than a terminal. Nothing
+/// is ever read from or written to the local terminal, so unknown host keys are rejected
+/// instead of asked about and a rejected password fails instead of prompting for another.
+///
+/// ```no_run
+/// # fn example() -> Result<(), tssh::Error> {
+/// let mut session = tssh::Session::connect("example.com", 22)?
+///     .authenticate_password("user", "password")?;
+///
+/// let (output, status) = session.open_exec("uname -a")?;
+/// println!("{output}exited with {status}");
+/// session.disconnect()
+/// # }
+/// ```
+pub struct Session {
+    /// The configuration the connection was made with
+    client: Client,
+    /// The stream to the host
+    stream: SshStream,
+    /// Encrypts packets sent to the host
+    encrypter: Encrypter,
+    /// Decrypts packets recieved from the host
+    decrypter: Decrypter,
+    /// The version information needed for the exchange hash of key re-exchanges
+    hash_prefix: Vec<u8>,
+    /// The channels opened over the connection
+    channels: Channels,
+    /// Packets that arrived during a key re-exchange and still need handling
+    deferred: VecDeque<(u8, Vec<u8>)>,
+    /// The sequence number of the last packet read, which is None if it was deferred
+    sequence_number: Option<u32>,
+}
+
+impl Session {
+    /// Establishes a connection to the host and runs the version exchange and key exchange.
+    /// The connection fails if the host key is not in known_hosts.
+    pub fn connect(host: &str, port: u16) -> Result<Handshake, Error> {
+        // The username is only known once authentication starts
+        let client = ClientBuilder::new()
+            .host(host)
+            .port(port)
+            .username("")
+            .build()?;
+        let (stream, encrypter, decrypter, hash_prefix) = handshake(&client, false)?;
+
+        Ok(Handshake {
+            client,
+            stream,
+            encrypter,
+            decrypter,
+            hash_prefix,
+        })
+    }
+
+    /// Runs a command on the host without a pseudo-terminal and waits for it to finish.
+    /// Results in everything the command wrote to stdout and stderr in the order it arrived
+    /// along with its exit status, which is -1 if the host did not report one such as when
+    /// the command was killed by a signal.
+    pub fn open_exec(&mut self, command: &str) -> Result<(String, i32), Error> {
+        let (channel, payload) =
+            self.channels
+                .open_session(None, Vec::new(), Some(command.to_string()));
+        self.send(&payload)?;
+
+        let mut output = Vec::new();
+        let mut exit_status = None;
+        loop {
+            let (packet_type, data) = self.read()?;
+            let (client_channel, outs) = self.channels.handle(packet_type, &data)?;
+            for out in outs {
+                match out {
+                    ChannelOutput::Send(payload) => self.send(&payload)?,
+                    output @ (ChannelOutput::GlobalRequest(..) | ChannelOutput::Unknown(_)) => {
+                        let verbosity = self.client.verbosity;
+                        if let Some(reply) =
+                            connection_reply(&output, self.sequence_number, verbosity)
+                        {
+                            self.send(&reply)?;
+                        }
+                    }
+                    ChannelOutput::Stdout(data) | ChannelOutput::Stderr(data) => {
+                        output.extend(data)
+                    }
+                    ChannelOutput::ExitStatus(status) => exit_status = Some(status),
+                    ChannelOutput::Closed if client_channel == Some(channel) => {
+                        let status = exit_status.map_or(-1, |status| status as i32);
+                        return Ok((String::from_utf8_lossy(&output).to_string(), status));
+                    }
+                    ChannelOutput::OpenFailed(..) => {
+                        return Err(Error::Other("Host failed to open the exec channel"));
+                    }
+                    ChannelOutput::WindowAdjust(_)
+                    | ChannelOutput::Ready
+                    | ChannelOutput::Eof
+                    | ChannelOutput::Closed
+                    | ChannelOutput::ForwardDenied(_)
+                    | ChannelOutput::Warning(_)
+                    | ChannelOutput::Debug(..) => (),
+                }
+            }
+        }
+    }
+
+    /// Tells the host the session is over and closes the connection
+    pub fn disconnect(mut self) -> Result<(), Error> {
+        self.stream
+            .disconnect(b"Done with session", Some(&mut self.encrypter))
+    }
+
+    /// Sends a single encrypted packet with the given payload
+    fn send(&mut self, payload: &[u8]) -> Result<(), Error> {
+        self.stream.send(payload, Some(&mut self.encrypter))
+    }
+
+    /// Reads the next encrypted packet and returns its type and payload. Key re-exchanges
+    /// are run as they come up, whether the host starts one or the keys have been used for
+    /// too long or for too much data, so their packets are never returned.
+    fn read(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        loop {
+            if let Some(packet) = self.deferred.pop_front() {
+                self.sequence_number = None;
+                return Ok(packet);
+            }
+            if self.encrypter.needs_rekey() || self.decrypter.needs_rekey() {
+                let (encrypter, decrypter, deferred) = rekey(
+                    &mut self.stream,
+                    self.hash_prefix.clone(),
+                    &self.client,
+                    &mut self.encrypter,
+                    &mut self.decrypter,
+                )?;
+                self.encrypter = encrypter;
+                self.decrypter = decrypter;
+                self.deferred.extend(deferred);
+                continue;
+            }
+
+            let (packet_type, data) = self.stream.read(Some(&mut self.decrypter))?;
+            self.sequence_number = Some(last_sequence_number(&self.decrypter));
+            if packet_type != SSH_MSG_KEXINIT {
+                return Ok((packet_type, data));
+            }
+            let (encrypter, decrypter) = key_rexchange(
+                data,
+                &mut self.stream,
+                self.hash_prefix.clone(),
+                &self.client,
+                &mut self.encrypter,
+                &mut self.decrypter,
+            )?;
+            self.encrypter = encrypter;
+            self.decrypter = decrypter;
+        }
+    }
+}
+
+impl Handshake {
+    /// Authenticates as the given user with a password, which fails if the host rejects it
+    /// or requires another method, and results in the session ready for running commands
+    pub fn authenticate_password(
+        mut self,
+        username: &str,
+        password: &str,
+    ) -> Result<Session, Error> {
+        self.client.username = username.to_string();
+
+        // Request user authentication
+        let (mut auth, request) = Auth::new(username.to_string(), false, false);
+        self.stream.send(&request, Some(&mut self.encrypter))?;
+
+        let mut password_sent = false;
+        loop {
+            let (code, packet) = self.stream.read(Some(&mut self.decrypter))?;
+            match auth.handle(code, &packet)? {
+                AuthOutput::None
+                | AuthOutput::Banner(_)
+                | AuthOutput::PublicKey
+                | AuthOutput::HostBased => (),
+                AuthOutput::Send(payload) => {
+                    self.stream.send(&payload, Some(&mut self.encrypter))?
+                }
+                AuthOutput::Password if password_sent => {
+                    return Err(Error::AuthFailed("Host rejected the password".to_string()));
+                }
+                AuthOutput::Password => {
+                    let request = auth.password_request(password);
+                    self.stream.send(&request, Some(&mut self.encrypter))?;
+                    password_sent = true;
+                }
+                AuthOutput::InfoRequest(_) => {
+                    return Err(Error::AuthFailed(
+                        "Host requires keyboard-interactive authentication".to_string(),
+                    ));
+                }
+                AuthOutput::Success => {
+                    // zlib@openssh.com only compresses what is sent after authentication
+                    self.encrypter.start_delayed_compression();
+                    self.decrypter.start_delayed_compression();
+                    return Ok(Session {
+                        client: self.client,
+                        stream: self.stream,
+                        encrypter: self.encrypter,
+                        decrypter: self.decrypter,
+                        hash_prefix: self.hash_prefix,
+                        channels: Channels::new(),
+                        deferred: VecDeque::new(),
+                        sequence_number: None,
+                    });
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use crate::encrypter::Encrypter;
+use crate::ssh_stream::SshStream;
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often the timer checks whether the session already ended on its own
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Starts the thread that ends the session once it has run for the given time no matter how
+/// active it is. On expiry it sets timed_out and the stop flag, tells the host it is
+/// disconnecting, and shuts the connection down so that the reading thread stops waiting on
+/// it and sees why.
+pub fn spawn(
+    mut stream: SshStream,
+    encrypter: Arc<Mutex<Encrypter>>,
+    timeout: Duration,
+    timed_out: Arc<AtomicBool>,
+    stop_flag: Arc<AtomicBool>,
+) {
+    let deadline = Instant::now() + timeout;
+    thread::spawn(move || {
+        loop {
+            if stop_flag.load(Ordering::Relaxed) {
+                return;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            thread::sleep(CHECK_INTERVAL.min(deadline - now));
+        }
+
+        timed_out.store(true, Ordering::Relaxed);
+        stop_flag.store(true, Ordering::Relaxed);
+        let mut enc = encrypter.lock().unwrap();
+        let _ = stream.disconnect(b"Session timed out", Some(&mut enc));
+        let _ = stream.shutdown();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SSH_MSG_DISCONNECT;
+    use crate::encrypter::loopback_pair;
+    use crate::ssh_stream::loopback_streams;
+
+    #[test]
+    fn session_is_ended_at_the_deadline() {
+        let (client, mut host) = loopback_streams();
+        let (encrypter, mut decrypter) = loopback_pair("aes128-ctr", "hmac-sha2-256", "none");
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let timeout = Duration::from_millis(200);
+
+        let start = Instant::now();
+        spawn(
+            client,
+            Arc::new(Mutex::new(encrypter)),
+            timeout,
+            timed_out.clone(),
+            stop_flag.clone(),
+        );
+
+        // The host is told why before the connection goes away
+        let (packet_type, _) = host.read(Some(&mut decrypter)).unwrap();
+        assert_eq!(packet_type, SSH_MSG_DISCONNECT);
+        assert!(start.elapsed() >= timeout);
+        assert!(timed_out.load(Ordering::Relaxed));
+        assert!(stop_flag.load(Ordering::Relaxed));
+        assert!(host.read(Some(&mut decrypter)).is_err());
+    }
+
+    #[test]
+    fn stopped_sessions_are_left_alone() {
+        let (client, _host) = loopback_streams();
+        let (encrypter, _) = loopback_pair("aes128-ctr", "hmac-sha2-256", "none");
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let stop_flag = Arc::new(AtomicBool::new(true));
+
+        spawn(
+            client,
+            Arc::new(Mutex::new(encrypter)),
+            Duration::from_millis(50),
+            timed_out.clone(),
+            stop_flag,
+        );
+        thread::sleep(Duration::from_millis(300));
+        assert!(!timed_out.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,658 @@
+use crate::builder::Client;
+use crate::connection::Connection;
+use crate::encrypter::{Decrypter, Encrypter};
+use crate::protocol::{ChannelOutput, Channels};
+use crate::ssh_stream::SshStream;
+use crate::terminal::release_interrupt;
+use crate::{
+    Error, SSH_MSG_CHANNEL_DATA, SSH_MSG_KEXINIT, connection_reply, key_rexchange,
+    last_sequence_number, rekey,
+};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+const SFTP_VERSION: u32 = 3;
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+
+const SSH_FXF_READ: u32 = 0x1;
+const SSH_FXF_WRITE: u32 = 0x2;
+const SSH_FXF_CREAT: u32 = 0x8;
+const SSH_FXF_TRUNC: u32 = 0x10;
+
+const SSH_FX_OK: u32 = 0;
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+const SSH_FX_PERMISSION_DENIED: u32 = 3;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x1;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x2;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x4;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x8;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x80000000;
+
+/// The file type bits of the permissions attribute and the type of a directory
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+/// The most file data asked for or sent in a single request
+const CHUNK_SIZE: u32 = 32768;
+/// The largest SFTP packet accepted from the host, which is well over a full chunk of data
+const SFTP_PACKET_MAX: usize = 256 * 1024;
+
+/// The attributes of a remote file that the client makes use of
+#[derive(Default)]
+struct Attrs {
+    permissions: Option<u32>,
+}
+
+impl Attrs {
+    /// Whether or not the attributes belong to a directory
+    fn is_dir(&self) -> bool {
+        self.permissions
+            .is_some_and(|permissions| permissions & S_IFMT == S_IFDIR)
+    }
+}
+
+/// An SFTP version 3 session run over an sftp subsystem channel
+struct Sftp<'a> {
+    /// The configuration the connection was made with
+    client: &'a Client,
+    /// The stream to the host
+    stream: SshStream,
+    /// Encrypts packets sent to the host
+    encrypter: Encrypter,
+    /// Decrypts packets recieved from the host
+    decrypter: Decrypter,
+    /// The version information needed for the exchange hash of key re-exchanges
+    hash_prefix: Vec<u8>,
+    /// The open channels, which is only ever the sftp channel
+    channels: Channels,
+    /// The client channel number of the sftp channel
+    channel: u32,
+    /// The number of bytes the host can still be sent
+    remote_window: u64,
+    /// Channel data recieved from the host that does not yet make up a whole SFTP packet
+    received: Vec<u8>,
+    /// Packets that arrived during a key re-exchange and still need handling
+    deferred: VecDeque<(u8, Vec<u8>)>,
+    /// The id to give the next request
+    next_id: u32,
+    /// Whether or not the subsystem has started
+    ready: bool,
+    /// Whether or not the host has stopped sending on the channel
+    ended: bool,
+}
+
+/// Starts the sftp subsystem and runs get, put and ls commands read from standard input
+/// until it ends or exit is entered. Failed commands are reported without ending the session.
+pub fn run_sftp(connection: Connection) -> Result<(), Error> {
+    let mut sftp = Sftp::start(connection)?;
+    let mut stdin = io::stdin().lines();
+    loop {
+        print!("sftp> ");
+        io::stdout().flush()?;
+        let line = match stdin.next() {
+            Some(line) => line?,
+            None => {
+                println!();
+                break;
+            }
+        };
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words[..] {
+            [] => Ok(()),
+            ["get", remote] => sftp.get(remote, file_name(remote)),
+            ["get", remote, local] => sftp.get(remote, local),
+            ["put", local] => sftp.put(local, file_name(local)),
+            ["put", local, remote] => sftp.put(local, remote),
+            ["ls"] => sftp.ls("."),
+            ["ls", path] => sftp.ls(path),
+            ["exit" | "quit" | "bye"] => break,
+            _ => {
+                eprintln!("Commands: get REMOTE [LOCAL], put LOCAL [REMOTE], ls [PATH], exit");
+                Ok(())
+            }
+        };
+
+        // Nothing more can be done once the host has stopped answering
+        match result {
+            Err(err) if sftp.ended => return Err(err),
+            Err(err) => eprintln!("{err}"),
+            Ok(()) => (),
+        }
+    }
+
+    sftp.finish()
+}
+
+/// Returns the last component of a path, which names a copied file when no name is given
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+impl<'a> Sftp<'a> {
+    /// Opens the sftp subsystem channel and agrees on version 3 of the protocol with the host
+    fn start(connection: Connection<'a>) -> Result<Sftp<'a>, Error> {
+        let Connection {
+            client,
+            mut stream,
+            mut encrypter,
+            decrypter,
+            hash_prefix,
+        } = connection;
+        release_interrupt();
+
+        let mut channels = Channels::new();
+        let (channel, payload) = channels.open_subsystem("sftp".to_string());
+        stream.send(&payload, Some(&mut encrypter))?;
+
+        let mut sftp = Sftp {
+            client,
+            stream,
+            encrypter,
+            decrypter,
+            hash_prefix,
+            channels,
+            channel,
+            remote_window: 0,
+            received: Vec::new(),
+            deferred: VecDeque::new(),
+            next_id: 0,
+            ready: false,
+            ended: false,
+        };
+        while !sftp.ready {
+            if sftp.ended {
+                return Err(Error::Other("Host closed the sftp channel"));
+            }
+            sftp.process_packet()?;
+        }
+
+        // The init packet carries the version where other packets have a request id
+        sftp.send_packet(SSH_FXP_INIT, &SFTP_VERSION.to_be_bytes())?;
+        let (packet_type, data) = sftp.read_packet()?;
+        if packet_type != SSH_FXP_VERSION || data.len() < 4 {
+            return Err(Error::Other(
+                "Host did not reply to sftp init with a version",
+            ));
+        }
+        if u32::from_be_bytes(data[0..4].try_into()?) < SFTP_VERSION {
+            return Err(Error::Other("Host does not support sftp version 3"));
+        }
+
+        Ok(sftp)
+    }
+
+    /// Downloads a remote file to a local file
+    fn get(&mut self, remote: &str, local: &str) -> Result<(), Error> {
+        if self.stat(remote)?.is_dir() {
+            return Err(Error::Other("Can't get a directory"));
+        }
+        let mut file = File::create(local)?;
+
+        // The handle is closed even if the copy fails part way
+        let handle = self.open(remote, SSH_FXF_READ)?;
+        let mut copy = || -> Result<u64, Error> {
+            let mut offset = 0;
+            while let Some(data) = self.read(&handle, offset, CHUNK_SIZE)? {
+                file.write_all(&data)?;
+                offset += data.len() as u64;
+            }
+            Ok(offset)
+        };
+        let copied = copy();
+        self.close(&handle)?;
+
+        println!("Fetched {remote} to {local} ({} bytes)", copied?);
+        Ok(())
+    }
+
+    /// Uploads a local file to a remote file, replacing it if it exists
+    fn put(&mut self, local: &str, remote: &str) -> Result<(), Error> {
+        let mut file = File::open(local)?;
+
+        // The handle is closed even if the copy fails part way
+        let handle = self.open(remote, SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC)?;
+        let mut copy = || -> Result<u64, Error> {
+            let mut offset = 0;
+            let mut buffer = vec![0; CHUNK_SIZE as usize];
+            loop {
+                let len = file.read(&mut buffer)?;
+                if len == 0 {
+                    return Ok(offset);
+                }
+                self.write(&handle, offset, &buffer[..len])?;
+                offset += len as u64;
+            }
+        };
+        let copied = copy();
+        self.close(&handle)?;
+
+        println!("Uploaded {local} to {remote} ({} bytes)", copied?);
+        Ok(())
+    }
+
+    /// Prints the long listing the host gives for each entry of a remote directory
+    fn ls(&mut self, path: &str) -> Result<(), Error> {
+        let mut fields = Vec::new();
+        SshStream::append_string(&mut fields, path.as_bytes());
+        let handle = self.handle_request(SSH_FXP_OPENDIR, &fields)?;
+
+        let mut fields = Vec::new();
+        SshStream::append_string(&mut fields, &handle);
+        let mut list = || -> Result<(), Error> {
+            loop {
+                match self.request(SSH_FXP_READDIR, &fields)? {
+                    (SSH_FXP_NAME, reply) => {
+                        for name in parse_long_names(&reply)? {
+                            println!("{name}");
+                        }
+                    }
+                    (SSH_FXP_STATUS, reply) => {
+                        return match status_code(&reply)? {
+                            SSH_FX_EOF => Ok(()),
+                            code => Err(status_error(code)),
+                        };
+                    }
+                    _ => return Err(Error::Other("Recieved unexpected sftp reply")),
+                }
+            }
+        };
+        let listed = list();
+        self.close(&handle)?;
+        listed
+    }
+
+    /// Opens a remote file with the given SSH_FXF flags and results in its handle
+    fn open(&mut self, path: &str, pflags: u32) -> Result<Vec<u8>, Error> {
+        let mut fields = Vec::new();
+        SshStream::append_string(&mut fields, path.as_bytes());
+        fields.extend(pflags.to_be_bytes());
+        // No attributes are set on created files so the host's defaults are used
+        fields.extend(0u32.to_be_bytes());
+        self.handle_request(SSH_FXP_OPEN, &fields)
+    }
+
+    /// Closes a remote file or directory handle
+    fn close(&mut self, handle: &[u8]) -> Result<(), Error> {
+        let mut fields = Vec::new();
+        SshStream::append_string(&mut fields, handle);
+        self.status_request(SSH_FXP_CLOSE, &fields)
+    }
+
+    /// Reads up to len bytes of a remote file from the offset. Results in None at the end of
+    /// the file.
+    fn read(&mut self, handle: &[u8], offset: u64, len: u32) -> Result<Option<Vec<u8>>, Error> {
+        let mut fields = Vec::new();
+        SshStream::append_string(&mut fields, handle);
+        fields.extend(offset.to_be_bytes());
+        fields.extend(len.to_be_bytes());
+        match self.request(SSH_FXP_READ, &fields)? {
+            (SSH_FXP_DATA, reply) => Ok(Some(SshStream::extract_string(&reply)?.0)),
+            (SSH_FXP_STATUS, reply) => match status_code(&reply)? {
+                SSH_FX_EOF => Ok(None),
+                code => Err(status_error(code)),
+            },
+            _ => Err(Error::Other("Recieved unexpected sftp reply")),
+        }
+    }
+
+    /// Writes data to a remote file at the offset
+    fn write(&mut self, handle: &[u8], offset: u64, data: &[u8]) -> Result<(), Error> {
+        let mut fields = Vec::new();
+        SshStream::append_string(&mut fields, handle);
+        fields.extend(offset.to_be_bytes());
+        SshStream::append_string(&mut fields, data);
+        self.status_request(SSH_FXP_WRITE, &fields)
+    }
+
+    /// Results in the attributes of a remote file, following symbolic links
+    fn stat(&mut self, path: &str) -> Result<Attrs, Error> {
+        let mut fields = Vec::new();
+        SshStream::append_string(&mut fields, path.as_bytes());
+        match self.request(SSH_FXP_STAT, &fields)? {
+            (SSH_FXP_ATTRS, reply) => Ok(parse_attrs(&reply)?.0),
+            (SSH_FXP_STATUS, reply) => Err(status_error(status_code(&reply)?)),
+            _ => Err(Error::Other("Recieved unexpected sftp reply")),
+        }
+    }
+
+    /// Sends a request that the host answers with a handle and results in the handle
+    fn handle_request(&mut self, packet_type: u8, fields: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.request(packet_type, fields)? {
+            (SSH_FXP_HANDLE, reply) => Ok(SshStream::extract_string(&reply)?.0),
+            (SSH_FXP_STATUS, reply) => Err(status_error(status_code(&reply)?)),
+            _ => Err(Error::Other("Recieved unexpected sftp reply")),
+        }
+    }
+
+    /// Sends a request that the host answers with only a status and fails unless it is ok
+    fn status_request(&mut self, packet_type: u8, fields: &[u8]) -> Result<(), Error> {
+        match self.request(packet_type, fields)? {
+            (SSH_FXP_STATUS, reply) => match status_code(&reply)? {
+                SSH_FX_OK => Ok(()),
+                code => Err(status_error(code)),
+            },
+            _ => Err(Error::Other("Recieved unexpected sftp reply")),
+        }
+    }
+
+    /// Sends a request with the next request id and waits for the reply. Results in the type
+    /// of the reply and what follows its request id.
+    fn request(&mut self, packet_type: u8, fields: &[u8]) -> Result<(u8, Vec<u8>), Error> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut body = id.to_be_bytes().to_vec();
+        body.extend(fields);
+        self.send_packet(packet_type, &body)?;
+
+        // Only one request is ever outstanding so the reply must be for it
+        let (reply_type, reply) = self.read_packet()?;
+        if reply.len() < 4 || u32::from_be_bytes(reply[0..4].try_into()?) != id {
+            return Err(Error::Other("Recieved sftp reply for the wrong request"));
+        }
+        Ok((reply_type, reply[4..].to_vec()))
+    }
+
+    /// Sends an SFTP packet as channel data, waiting for the host to grow the window as needed
+    fn send_packet(&mut self, packet_type: u8, body: &[u8]) -> Result<(), Error> {
+        let mut packet = ((body.len() + 1) as u32).to_be_bytes().to_vec();
+        packet.push(packet_type);
+        packet.extend(body);
+
+        let mut data = &packet[..];
+        while !data.is_empty() {
+            while self.remote_window == 0 {
+                if self.ended {
+                    return Err(Error::Other("Host closed the sftp channel"));
+                }
+                self.process_packet()?;
+            }
+
+            let channel = self.channels.get(self.channel)?;
+            let len = data
+                .len()
+                .min(channel.packet_max() as usize)
+                .min(self.remote_window as usize);
+            let (chunk, rest) = data.split_at(len);
+
+            let mut message = vec![SSH_MSG_CHANNEL_DATA];
+            message.extend(channel.server_channel().to_be_bytes());
+            SshStream::append_string(&mut message, chunk);
+            self.stream.send(&message, Some(&mut self.encrypter))?;
+
+            self.remote_window -= len as u64;
+            data = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Reads from the host until a whole SFTP packet has arrived and results in its type and
+    /// body
+    fn read_packet(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        loop {
+            if let Some(packet) = split_packet(&mut self.received)? {
+                return Ok(packet);
+            }
+            if self.ended {
+                return Err(Error::Other("Host closed the sftp channel"));
+            }
+            self.process_packet()?;
+        }
+    }
+
+    /// Reads and handles a single packet from the host, first renewing the keys if they have
+    /// been used for too long or for too much data as large transfers soon do
+    fn process_packet(&mut self) -> Result<(), Error> {
+        let rekey_due = self.encrypter.needs_rekey() || self.decrypter.needs_rekey();
+        if rekey_due && self.deferred.is_empty() {
+            let (encrypter, decrypter, deferred) = rekey(
+                &mut self.stream,
+                self.hash_prefix.clone(),
+                self.client,
+                &mut self.encrypter,
+                &mut self.decrypter,
+            )?;
+            self.encrypter = encrypter;
+            self.decrypter = decrypter;
+            self.deferred.extend(deferred);
+        }
+
+        let from_stream = self.deferred.is_empty();
+        let (packet_type, data) = match self.deferred.pop_front() {
+            Some(packet) => packet,
+            None => self.stream.read(Some(&mut self.decrypter))?,
+        };
+        let sequence_number = from_stream.then(|| last_sequence_number(&self.decrypter));
+        if packet_type == SSH_MSG_KEXINIT {
+            let (encrypter, decrypter) = key_rexchange(
+                data,
+                &mut self.stream,
+                self.hash_prefix.clone(),
+                self.client,
+                &mut self.encrypter,
+                &mut self.decrypter,
+            )?;
+            self.encrypter = encrypter;
+            self.decrypter = decrypter;
+            return Ok(());
+        }
+
+        let (_, outputs) = self.channels.handle(packet_type, &data)?;
+        for output in outputs {
+            match output {
+                ChannelOutput::Send(payload) => {
+                    self.stream.send(&payload, Some(&mut self.encrypter))?
+                }
+                output @ (ChannelOutput::GlobalRequest(..) | ChannelOutput::Unknown(_)) => {
+                    if let Some(reply) =
+                        connection_reply(&output, sequence_number, self.client.verbosity)
+                    {
+                        self.stream.send(&reply, Some(&mut self.encrypter))?;
+                    }
+                }
+                ChannelOutput::WindowAdjust(amount) => self.remote_window += amount,
+                ChannelOutput::Ready => self.ready = true,
+                ChannelOutput::Stdout(data) => self.received.extend(data),
+                ChannelOutput::Stderr(data) => {
+                    io::stderr().write_all(&data)?;
+                    io::stderr().flush()?;
+                }
+                ChannelOutput::Eof | ChannelOutput::Closed => self.ended = true,
+                ChannelOutput::OpenFailed(..) => {
+                    return Err(Error::Other("Host failed to open the sftp channel"));
+                }
+                ChannelOutput::ExitStatus(_)
+                | ChannelOutput::ForwardDenied(_)
+                | ChannelOutput::Warning(_)
+                | ChannelOutput::Debug(..) => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Closes the sftp channel and disconnects once the host has closed its side
+    fn finish(mut self) -> Result<(), Error> {
+        if let Ok(channel) = self.channels.get_mut(self.channel) {
+            self.stream
+                .send(&channel.eof_message(), Some(&mut self.encrypter))?;
+            if let Some(message) = channel.close_message() {
+                self.stream.send(&message, Some(&mut self.encrypter))?;
+            }
+        }
+        while self.channels.get(self.channel).is_ok() {
+            self.process_packet()?;
+        }
+        self.stream
+            .disconnect(b"Done with sftp", Some(&mut self.encrypter))
+    }
+}
+
+/// Removes the first SFTP packet from the recieved data if all of it has arrived and results
+/// in its type and body
+fn split_packet(received: &mut Vec<u8>) -> Result<Option<(u8, Vec<u8>)>, Error> {
+    if received.len() < 4 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(received[0..4].try_into()?) as usize;
+    if len == 0 || len > SFTP_PACKET_MAX {
+        return Err(Error::Other("Recieved sftp packet with invalid length"));
+    }
+    if received.len() < 4 + len {
+        return Ok(None);
+    }
+
+    let rest = received.split_off(4 + len);
+    let packet = std::mem::replace(received, rest);
+    Ok(Some((packet[4], packet[5..].to_vec())))
+}
+
+/// Results in the status code of an SSH_FXP_STATUS reply
+fn status_code(reply: &[u8]) -> Result<u32, Error> {
+    if reply.len() < 4 {
+        return Err(Error::Other(
+            "Recieved corrupt sftp status: Expected length of at least 4 bytes",
+        ));
+    }
+    Ok(u32::from_be_bytes(reply[0..4].try_into()?))
+}
+
+/// Turns a failing status code into an error
+fn status_error(code: u32) -> Error {
+    match code {
+        SSH_FX_EOF => Error::Other("Unexpected end of file"),
+        SSH_FX_NO_SUCH_FILE => Error::Other("No such file"),
+        SSH_FX_PERMISSION_DENIED => Error::Other("Permission denied"),
+        _ => Error::Other("Sftp request failed"),
+    }
+}
+
+/// Parses file attributes, keeping only the permissions. Results in the attributes and the
+/// rest of the data.
+fn parse_attrs(data: &[u8]) -> Result<(Attrs, &[u8]), Error> {
+    let (flags, mut rest) = extract_u32(data)?;
+    let mut attrs = Attrs::default();
+    if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+        if rest.len() < 8 {
+            return Err(Error::Other("Recieved corrupt sftp attributes"));
+        }
+        rest = &rest[8..];
+    }
+    if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+        rest = extract_u32(extract_u32(rest)?.1)?.1;
+    }
+    if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+        let (permissions, after) = extract_u32(rest)?;
+        attrs.permissions = Some(permissions);
+        rest = after;
+    }
+    if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+        rest = extract_u32(extract_u32(rest)?.1)?.1;
+    }
+    if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+        let (count, after) = extract_u32(rest)?;
+        rest = after;
+        for _ in 0..count {
+            rest = SshStream::extract_string(rest)?.1;
+            rest = SshStream::extract_string(rest)?.1;
+        }
+    }
+    Ok((attrs, rest))
+}
+
+/// Results in the long names of every entry in an SSH_FXP_NAME reply
+fn parse_long_names(reply: &[u8]) -> Result<Vec<String>, Error> {
+    let (count, mut rest) = extract_u32(reply)?;
+    let mut names = Vec::new();
+    for _ in 0..count {
+        let (_, after) = SshStream::extract_string(rest)?;
+        let (long_name, after) = SshStream::extract_string(after)?;
+        rest = parse_attrs(after)?.1;
+        names.push(String::from_utf8_lossy(&long_name).to_string());
+    }
+    Ok(names)
+}
+
+/// Extracts a big endian u32 and results in it and the rest of the data
+fn extract_u32(data: &[u8]) -> Result<(u32, &[u8]), Error> {
+    if data.len() < 4 {
+        return Err(Error::Other("Recieved corrupt sftp packet"));
+    }
+    Ok((u32::from_be_bytes(data[0..4].try_into()?), &data[4..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_packet_waits_for_whole_packets() {
+        let mut received = vec![0, 0, 0, 5, SSH_FXP_VERSION, 0, 0];
+        assert_eq!(split_packet(&mut received).unwrap(), None);
+
+        received.extend([0, 3, 0, 0]);
+        assert_eq!(
+            split_packet(&mut received).unwrap(),
+            Some((SSH_FXP_VERSION, vec![0, 0, 0, 3]))
+        );
+        // Bytes of the next packet are kept for later
+        assert_eq!(received, vec![0, 0]);
+
+        let mut oversized = ((SFTP_PACKET_MAX + 1) as u32).to_be_bytes().to_vec();
+        assert!(split_packet(&mut oversized).is_err());
+    }
+
+    #[test]
+    fn parse_long_names_skips_attributes() {
+        let mut reply = 2u32.to_be_bytes().to_vec();
+        for (name, long_name) in [("docs", "drwxr-xr-x docs"), ("a.txt", "-rw-r--r-- a.txt")] {
+            SshStream::append_string(&mut reply, name.as_bytes());
+            SshStream::append_string(&mut reply, long_name.as_bytes());
+            let flags =
+                SSH_FILEXFER_ATTR_SIZE | SSH_FILEXFER_ATTR_PERMISSIONS | SSH_FILEXFER_ATTR_EXTENDED;
+            reply.extend(flags.to_be_bytes());
+            reply.extend(4096u64.to_be_bytes());
+            reply.extend(0o040755u32.to_be_bytes());
+            reply.extend(1u32.to_be_bytes());
+            SshStream::append_string(&mut reply, b"name@example.com");
+            SshStream::append_string(&mut reply, b"value");
+        }
+
+        let names = parse_long_names(&reply).unwrap();
+        assert_eq!(names, vec!["drwxr-xr-x docs", "-rw-r--r-- a.txt"]);
+    }
+
+    #[test]
+    fn attrs_recognise_directories() {
+        let mut data = (SSH_FILEXFER_ATTR_UIDGID | SSH_FILEXFER_ATTR_PERMISSIONS)
+            .to_be_bytes()
+            .to_vec();
+        data.extend([0; 8]);
+        data.extend(0o040700u32.to_be_bytes());
+        let (attrs, rest) = parse_attrs(&data).unwrap();
+        assert!(attrs.is_dir());
+        assert!(rest.is_empty());
+
+        let (attrs, _) = parse_attrs(&0u32.to_be_bytes()).unwrap();
+        assert!(!attrs.is_dir());
+    }
+}
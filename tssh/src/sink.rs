@@ -0,0 +1,163 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::time::Instant;
+
+/// Receives the data a host sends over an interactive session
+pub trait OutputSink {
+    /// Handles data the host sent on the session's standard output
+    fn stdout(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Handles data the host sent on the session's standard error
+    fn stderr(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Writes out anything still buffered. Called once the session ends before tssh returns.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes output straight to the local standard output and standard error
+pub struct StdioSink;
+
+impl OutputSink for StdioSink {
+    fn stdout(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        stdout.write_all(data)?;
+        stdout.flush()
+    }
+
+    fn stderr(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut stderr = io::stderr();
+        stderr.write_all(data)?;
+        stderr.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()?;
+        io::stderr().flush()
+    }
+}
+
+/// Captures output in memory so that it can be inspected once the session ends
+#[derive(Default)]
+pub struct BufferSink {
+    /// Everything the host sent on standard output
+    pub stdout: Vec<u8>,
+    /// Everything the host sent on standard error
+    pub stderr: Vec<u8>,
+}
+
+impl OutputSink for BufferSink {
+    fn stdout(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stdout.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn stderr(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stderr.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Records everything the host sends to a log file as it is passed on to another sink, like
+/// the script command does. The raw bytes are written so escape sequences replay faithfully.
+/// If there is a timing file the delay before each chunk and its length are written to it in
+/// the format of script -t so the log can be replayed with scriptreplay.
+pub struct LogSink<'a> {
+    /// The sink the output is passed on to
+    inner: &'a mut dyn OutputSink,
+    /// The file every chunk of output is written to
+    log: File,
+    /// The file the timing of each chunk is written to if any
+    timing: Option<File>,
+    /// When the last chunk was recorded
+    last: Instant,
+}
+
+impl<'a> LogSink<'a> {
+    /// Wraps a sink so that its output is also written to the log
+    pub fn new(inner: &'a mut dyn OutputSink, log: File, timing: Option<File>) -> LogSink<'a> {
+        LogSink {
+            inner,
+            log,
+            timing,
+            last: Instant::now(),
+        }
+    }
+
+    /// Writes a chunk of output to the log and its timing to the timing file
+    fn record(&mut self, data: &[u8]) -> io::Result<()> {
+        self.log.write_all(data)?;
+        if let Some(timing) = &mut self.timing {
+            let now = Instant::now();
+            writeln!(
+                timing,
+                "{:.6} {}",
+                now.duration_since(self.last).as_secs_f64(),
+                data.len()
+            )?;
+            self.last = now;
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for LogSink<'_> {
+    fn stdout(&mut self, data: &[u8]) -> io::Result<()> {
+        self.record(data)?;
+        self.inner.stdout(data)
+    }
+
+    fn stderr(&mut self, data: &[u8]) -> io::Result<()> {
+        self.record(data)?;
+        self.inner.stderr(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.log.flush()?;
+        if let Some(timing) = &mut self.timing {
+            timing.flush()?;
+        }
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn log_sink_records_output_and_timing() {
+        let dir = env::temp_dir();
+        let log_path = dir.join(format!("tssh-log-test-{}", process::id()));
+        let timing_path = dir.join(format!("tssh-timing-test-{}", process::id()));
+
+        let mut buffer = BufferSink::default();
+        let mut sink = LogSink::new(
+            &mut buffer,
+            File::create(&log_path).unwrap(),
+            Some(File::create(&timing_path).unwrap()),
+        );
+        sink.stdout(b"\x1B[1mbold\x1B[0m\r\n").unwrap();
+        sink.stderr(b"oops\r\n").unwrap();
+        sink.flush().unwrap();
+        drop(sink);
+
+        let log = fs::read(&log_path).unwrap();
+        let timing = fs::read_to_string(&timing_path).unwrap();
+        fs::remove_file(&log_path).unwrap();
+        fs::remove_file(&timing_path).unwrap();
+
+        // Output is still passed on and logged byte for byte in the order it arrived
+        assert_eq!(buffer.stdout, b"\x1B[1mbold\x1B[0m\r\n");
+        assert_eq!(buffer.stderr, b"oops\r\n");
+        assert_eq!(log, b"\x1B[1mbold\x1B[0m\r\noops\r\n");
+
+        let lengths: Vec<&str> = timing
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(lengths, ["14", "6"]);
+    }
+}
@@ -0,0 +1,515 @@
+use crate::connection::Connection;
+use crate::encrypter::Encrypter;
+use crate::protocol::{ChannelOutput, Channels, TcpipOrigin};
+use crate::ssh_stream::SshStream;
+use crate::{
+    Error, SSH_MSG_CHANNEL_DATA, SSH_MSG_KEXINIT, connection_reply, debug, key_rexchange,
+    last_sequence_number, print_host_debug, rekey, send,
+};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The only version of the SOCKS protocol that is spoken
+const SOCKS_VERSION: u8 = 5;
+/// The authentication method for clients that don't authenticate
+const NO_AUTHENTICATION: u8 = 0;
+/// Tells the client none of the authentication methods it offered are supported
+const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+/// The command asking for a connection to be made to the target
+const CONNECT: u8 = 1;
+/// The target is given as a 4 byte IPv4 address
+const ADDRESS_IPV4: u8 = 1;
+/// The target is given as a length prefixed domain name
+const ADDRESS_DOMAIN: u8 = 3;
+/// The target is given as a 16 byte IPv6 address
+const ADDRESS_IPV6: u8 = 4;
+
+/// Reply code for a connection that was made
+const SUCCEEDED: u8 = 0;
+/// Reply code for a connection that failed for any other reason
+const GENERAL_FAILURE: u8 = 1;
+/// Reply code for a connection the host would not allow
+const NOT_ALLOWED: u8 = 2;
+/// Reply code for a connection the target refused or could not be reached for
+const CONNECTION_REFUSED: u8 = 5;
+/// Reply code for any command other than CONNECT
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+/// Reply code for an unknown type of target address
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/// The host would not allow the channel to be opened
+const SSH_OPEN_ADMINISTRATIVELY_PROHIBITED: u32 = 1;
+/// The host failed to connect to the target
+const SSH_OPEN_CONNECT_FAILED: u32 = 2;
+
+/// The direct-tcpip channels of every SOCKS client, shared between the thread reading from
+/// the host and the threads serving each client
+#[derive(Default)]
+struct Forwards {
+    /// The open channels and those waiting on the host to confirm them
+    channels: Channels,
+    /// Where the outputs of each channel are passed on to the thread serving its client
+    clients: HashMap<u32, Sender<ChannelOutput>>,
+}
+
+/// Listens on the bind address and port as a SOCKS5 proxy, opening a direct-tcpip channel
+/// for each connection a client asks for and relaying data over it until either side closes.
+/// Runs until the connection to the host ends.
+pub fn run_socks(connection: Connection, bind_address: &str, port: u16) -> Result<(), Error> {
+    let Connection {
+        client,
+        mut stream,
+        encrypter,
+        mut decrypter,
+        hash_prefix,
+    } = connection;
+    let listener = TcpListener::bind((bind_address, port))?;
+    debug(client.verbosity, 1, || {
+        format!("Listening for SOCKS clients on {bind_address}:{port}")
+    });
+
+    let encrypter = Arc::new(Mutex::new(encrypter));
+    let forwards = Arc::new(Mutex::new(Forwards::default()));
+    spawn_listener(
+        listener,
+        stream.try_clone()?,
+        encrypter.clone(),
+        forwards.clone(),
+    );
+
+    let mut deferred = VecDeque::new();
+    loop {
+        // Replace keys that have been used for too long or for too much data
+        let rekey_due = decrypter.needs_rekey() || encrypter.lock().unwrap().needs_rekey();
+        if rekey_due && deferred.is_empty() {
+            let mut enc = encrypter.lock().unwrap();
+            let (new_enc, new_dec, packets) = rekey(
+                &mut stream,
+                hash_prefix.clone(),
+                client,
+                &mut enc,
+                &mut decrypter,
+            )?;
+            *enc = new_enc;
+            decrypter = new_dec;
+            deferred.extend(packets);
+        }
+
+        let from_stream = deferred.is_empty();
+        let (packet_type, data) = match deferred.pop_front() {
+            Some(packet) => packet,
+            None => stream.read(Some(&mut decrypter))?,
+        };
+        let sequence_number = from_stream.then(|| last_sequence_number(&decrypter));
+        if packet_type == SSH_MSG_KEXINIT {
+            // Holding the encrypter pauses every client until the new keys are in use
+            let mut enc = encrypter.lock().unwrap();
+            let (new_enc, new_dec) = key_rexchange(
+                data,
+                &mut stream,
+                hash_prefix.clone(),
+                client,
+                &mut enc,
+                &mut decrypter,
+            )?;
+            *enc = new_enc;
+            decrypter = new_dec;
+            continue;
+        }
+
+        let mut forwards = forwards.lock().unwrap();
+        let (client_channel, outputs) = forwards.channels.handle(packet_type, &data)?;
+        for output in outputs {
+            match output {
+                ChannelOutput::Send(payload) => send(&mut stream, &encrypter, &payload)?,
+                output @ (ChannelOutput::GlobalRequest(..) | ChannelOutput::Unknown(_)) => {
+                    if let Some(reply) =
+                        connection_reply(&output, sequence_number, client.verbosity)
+                    {
+                        send(&mut stream, &encrypter, &reply)?;
+                    }
+                }
+                ChannelOutput::Warning(message) => eprintln!("{message}"),
+                ChannelOutput::Debug(message, always_display) => {
+                    print_host_debug(&message, always_display, client.verbosity)
+                }
+                output => {
+                    // The client may have gone away already
+                    if let Some(sender) = client_channel.and_then(|c| forwards.clients.get(&c)) {
+                        let _ = sender.send(output);
+                    }
+                }
+            }
+        }
+
+        // Dropping the sender lets the client's thread know there is nothing more to come
+        let closed = client_channel.filter(|c| forwards.channels.get(*c).is_err());
+        if let Some(client_channel) = closed {
+            forwards.clients.remove(&client_channel);
+        }
+    }
+}
+
+/// Starts the thread that accepts SOCKS clients, each of which is served on a thread of its own
+fn spawn_listener(
+    listener: TcpListener,
+    stream: SshStream,
+    encrypter: Arc<Mutex<Encrypter>>,
+    forwards: Arc<Mutex<Forwards>>,
+) {
+    thread::spawn(move || {
+        for socks_client in listener.incoming() {
+            let (socks_client, stream) = match (socks_client, stream.try_clone()) {
+                (Ok(socks_client), Ok(stream)) => (socks_client, stream),
+                _ => continue,
+            };
+            let encrypter = encrypter.clone();
+            let forwards = forwards.clone();
+            thread::spawn(move || {
+                if let Err(err) = forward(socks_client, stream, encrypter, forwards) {
+                    eprintln!("SOCKS connection failed: {err}");
+                }
+            });
+        }
+    });
+}
+
+/// Serves a single SOCKS client by having the host connect to the target it asks for and
+/// relaying data both ways until the channel closes
+fn forward(
+    mut socks_client: TcpStream,
+    mut stream: SshStream,
+    encrypter: Arc<Mutex<Encrypter>>,
+    forwards: Arc<Mutex<Forwards>>,
+) -> Result<(), Error> {
+    let (host, port) = match negotiate(&mut socks_client)? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    let origin = socks_client.peer_addr()?;
+    let target = TcpipOrigin {
+        connected_address: host,
+        connected_port: port as u32,
+        originator_address: origin.ip().to_string(),
+        originator_port: origin.port() as u32,
+    };
+
+    let (sender, outputs) = mpsc::channel();
+    let channel = {
+        let mut forwards = forwards.lock().unwrap();
+        let (channel, payload) = forwards.channels.open_direct_tcpip(&target);
+        forwards.clients.insert(channel, sender);
+        send(&mut stream, &encrypter, &payload)?;
+        channel
+    };
+
+    // Wait for the host to connect to the target before telling the client how it went
+    let window = Arc::new(Mutex::new(0));
+    loop {
+        match outputs.recv() {
+            Ok(ChannelOutput::WindowAdjust(amount)) => *window.lock().unwrap() += amount,
+            Ok(ChannelOutput::Ready) => break,
+            Ok(ChannelOutput::OpenFailed(reason_code, _)) => {
+                socks_client.write_all(&gen_reply(open_failure_reply(reason_code)))?;
+                return Ok(());
+            }
+            Ok(_) => (),
+            Err(_) => {
+                socks_client.write_all(&gen_reply(GENERAL_FAILURE))?;
+                return Ok(());
+            }
+        }
+    }
+    socks_client.write_all(&gen_reply(SUCCEEDED))?;
+
+    // The channel is closed once both sides have sent EOF
+    let ended = Arc::new(AtomicU8::new(0));
+    spawn_relay(
+        socks_client.try_clone()?,
+        stream.try_clone()?,
+        encrypter.clone(),
+        forwards.clone(),
+        window.clone(),
+        channel,
+        ended.clone(),
+    );
+
+    // Outputs stop once the channel is closed and forgotten
+    for output in outputs {
+        match output {
+            // The write has a side effect so it stays out of the match guard
+            #[allow(clippy::collapsible_match)]
+            ChannelOutput::Stdout(data) => {
+                if socks_client.write_all(&data).is_err() {
+                    close(&mut stream, &encrypter, &forwards, channel)?;
+                }
+            }
+            ChannelOutput::WindowAdjust(amount) => *window.lock().unwrap() += amount,
+            ChannelOutput::Eof => {
+                let _ = socks_client.shutdown(Shutdown::Write);
+                if ended.fetch_add(1, Ordering::Relaxed) == 1 {
+                    close(&mut stream, &encrypter, &forwards, channel)?;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Stops the relay if the host closed the channel before the client finished sending
+    let _ = socks_client.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+/// Starts the thread that sends what the SOCKS client writes over the channel, sending EOF
+/// once the client stops writing
+fn spawn_relay(
+    mut socks_client: TcpStream,
+    mut stream: SshStream,
+    encrypter: Arc<Mutex<Encrypter>>,
+    forwards: Arc<Mutex<Forwards>>,
+    window: Arc<Mutex<u64>>,
+    channel: u32,
+    ended: Arc<AtomicU8>,
+) {
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let len = match socks_client.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => len,
+            };
+            let sent = send_data(
+                &mut stream,
+                &encrypter,
+                &forwards,
+                &window,
+                channel,
+                &buffer[..len],
+            );
+            if !matches!(sent, Ok(true)) {
+                return;
+            }
+        }
+
+        if let Ok(open) = forwards.lock().unwrap().channels.get(channel) {
+            let _ = send(&mut stream, &encrypter, &open.eof_message());
+        }
+        if ended.fetch_add(1, Ordering::Relaxed) == 1 {
+            let _ = close(&mut stream, &encrypter, &forwards, channel);
+        }
+    });
+}
+
+/// Sends data over the channel in packets that fit within the host's window and maximum
+/// packet size. Results in false if the channel closed before all of it could be sent.
+fn send_data(
+    stream: &mut SshStream,
+    encrypter: &Arc<Mutex<Encrypter>>,
+    forwards: &Mutex<Forwards>,
+    window: &Mutex<u64>,
+    channel: u32,
+    mut data: &[u8],
+) -> Result<bool, Error> {
+    while !data.is_empty() {
+        // Checking the channel is still open while holding the lock means nothing is sent
+        // after the channel's close message
+        let forwards = forwards.lock().unwrap();
+        let open = match forwards.channels.get(channel) {
+            Ok(open) => open,
+            Err(_) => return Ok(false),
+        };
+
+        let len = {
+            let mut window = window.lock().unwrap();
+            let len = (*window).min(data.len().min(open.packet_max() as usize) as u64);
+            *window -= len;
+            len as usize
+        };
+        if len == 0 {
+            // Wait for the host to grant more window without blocking the other clients
+            drop(forwards);
+            thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+
+        let (chunk, rest) = data.split_at(len);
+        let mut packet = vec![SSH_MSG_CHANNEL_DATA];
+        packet.extend(open.server_channel().to_be_bytes());
+        SshStream::append_string(&mut packet, chunk);
+        send(stream, encrypter, &packet)?;
+        data = rest;
+    }
+    Ok(true)
+}
+
+/// Sends the channel's close message unless it has already been sent or the channel is gone
+fn close(
+    stream: &mut SshStream,
+    encrypter: &Arc<Mutex<Encrypter>>,
+    forwards: &Mutex<Forwards>,
+    channel: u32,
+) -> Result<(), Error> {
+    let mut forwards = forwards.lock().unwrap();
+    let message = match forwards.channels.get_mut(channel) {
+        Ok(open) => open.close_message(),
+        Err(_) => None,
+    };
+    match message {
+        Some(message) => send(stream, encrypter, &message),
+        None => Ok(()),
+    }
+}
+
+/// Runs the SOCKS5 method negotiation and reads the client's request. Results in the host
+/// and port to connect to, or None if the client was sent a failure because it asked for
+/// something that is not supported.
+fn negotiate<S: Read + Write>(socks_client: &mut S) -> Result<Option<(String, u16)>, Error> {
+    let mut header = [0; 2];
+    socks_client.read_exact(&mut header)?;
+    if header[0] != SOCKS_VERSION {
+        return Err(Error::Other("SOCKS client is not using SOCKS5"));
+    }
+    let mut methods = vec![0; header[1] as usize];
+    socks_client.read_exact(&mut methods)?;
+    if !methods.contains(&NO_AUTHENTICATION) {
+        socks_client.write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHODS])?;
+        return Ok(None);
+    }
+    socks_client.write_all(&[SOCKS_VERSION, NO_AUTHENTICATION])?;
+
+    // The request is the version, command, a reserved byte and the address type
+    let mut request = [0; 4];
+    socks_client.read_exact(&mut request)?;
+    if request[1] != CONNECT {
+        socks_client.write_all(&gen_reply(COMMAND_NOT_SUPPORTED))?;
+        return Ok(None);
+    }
+    let host = match request[3] {
+        ADDRESS_IPV4 => {
+            let mut address = [0; 4];
+            socks_client.read_exact(&mut address)?;
+            Ipv4Addr::from(address).to_string()
+        }
+        ADDRESS_DOMAIN => {
+            let mut len = [0; 1];
+            socks_client.read_exact(&mut len)?;
+            let mut domain = vec![0; len[0] as usize];
+            socks_client.read_exact(&mut domain)?;
+            String::from_utf8_lossy(&domain).to_string()
+        }
+        ADDRESS_IPV6 => {
+            let mut address = [0; 16];
+            socks_client.read_exact(&mut address)?;
+            Ipv6Addr::from(address).to_string()
+        }
+        _ => {
+            socks_client.write_all(&gen_reply(ADDRESS_TYPE_NOT_SUPPORTED))?;
+            return Ok(None);
+        }
+    };
+    let mut port = [0; 2];
+    socks_client.read_exact(&mut port)?;
+
+    Ok(Some((host, u16::from_be_bytes(port))))
+}
+
+/// Generates a reply to a SOCKS request with the given reply code. The bound address is left
+/// as all zeros since the connection is made from the host rather than locally.
+fn gen_reply(code: u8) -> [u8; 10] {
+    [SOCKS_VERSION, code, 0, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0]
+}
+
+/// Returns the SOCKS reply code for the reason the host gave for failing to open a channel
+fn open_failure_reply(reason_code: u32) -> u8 {
+    match reason_code {
+        SSH_OPEN_ADMINISTRATIVELY_PROHIBITED => NOT_ALLOWED,
+        SSH_OPEN_CONNECT_FAILED => CONNECTION_REFUSED,
+        _ => GENERAL_FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    /// A SOCKS client that sends the given bytes and records what it is sent back
+    struct FakeClient {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl FakeClient {
+        fn new(input: &[u8]) -> FakeClient {
+            FakeClient {
+                input: io::Cursor::new(input.to_vec()),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for FakeClient {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for FakeClient {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn connect_requests_give_the_target() {
+        let mut domain = vec![5, 2, 2, 0, 5, 1, 0, 3, 11];
+        domain.extend(b"example.com");
+        domain.extend(443u16.to_be_bytes());
+        let mut ipv6 = vec![5, 1, 0, 5, 1, 0, 4];
+        ipv6.extend(Ipv6Addr::LOCALHOST.octets());
+        ipv6.extend(22u16.to_be_bytes());
+
+        for (input, host, port) in [
+            (domain, "example.com", 443),
+            (
+                vec![5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 80],
+                "10.0.0.1",
+                80,
+            ),
+            (ipv6, "::1", 22),
+        ] {
+            let mut client = FakeClient::new(&input);
+            let target = negotiate(&mut client).unwrap();
+            assert_eq!(target, Some((host.to_string(), port)));
+            assert_eq!(client.output, [SOCKS_VERSION, NO_AUTHENTICATION]);
+        }
+    }
+
+    #[test]
+    fn unsupported_requests_are_refused() {
+        // Only username and password authentication is offered
+        let mut client = FakeClient::new(&[5, 1, 2]);
+        assert_eq!(negotiate(&mut client).unwrap(), None);
+        assert_eq!(client.output, [SOCKS_VERSION, NO_ACCEPTABLE_METHODS]);
+
+        // BIND instead of CONNECT
+        let mut client = FakeClient::new(&[5, 1, 0, 5, 2, 0, 1]);
+        assert_eq!(negotiate(&mut client).unwrap(), None);
+        assert_eq!(client.output[2..], gen_reply(COMMAND_NOT_SUPPORTED));
+
+        assert_eq!(
+            open_failure_reply(SSH_OPEN_CONNECT_FAILED),
+            CONNECTION_REFUSED
+        );
+    }
+}
@@ -1,75 +1,270 @@
-use crate::encrypter::{Decrypter, Encrypter};
-use crate::{Error, SSH_MSG_DISCONNECT};
+use crate::encrypter::{Decrypter, Encrypter, hex_lines};
+use crate::protocol::gen_disconnect;
+use crate::terminal::interrupted;
+use crate::{Error, SSH_DISCONNECT_BY_APPLICATION, debug};
+use rand::RngCore;
 use rsa::BigUint;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::Duration;
+
+/// The total packet size every implementation must accept according to RFC 4253 section 6.1,
+/// which leaves room for a full channel data packet with its headers, padding and mac
+const MIN_PACKET_LIMIT: usize = 35000;
+
+/// The largest packet accepted by default, which is the smallest maximum the spec allows
+pub const DEFAULT_MAX_PACKET: usize = MIN_PACKET_LIMIT;
+
+/// The largest packet that can be configured to be accepted
+pub const MAX_PACKET_LIMIT: usize = 262144;
 
 /// Represents an SshStream for the purposes of sending and recieving SSH packets
-pub struct SshStream(TcpStream);
+/// along with the largest packet it accepts and how much of its traffic to log. Reads are
+/// buffered so that a packet usually takes a single read from the connection while writes go
+/// straight to it.
+pub struct SshStream(BufReader<TcpStream>, usize, u8);
 
 impl SshStream {
-    /// Creates a new SshStream across the given TcpStream
+    /// Creates a new SshStream across the given TcpStream. Nothing may have been read from
+    /// the stream through any other buffer since those bytes would be lost.
     pub fn new(stream: TcpStream) -> Self {
-        SshStream(stream)
+        SshStream(BufReader::new(stream), DEFAULT_MAX_PACKET, 0)
     }
 
-    /// Creates a clone of an ssh stream
+    /// Sets how much of the traffic is logged. At 1 the type of every packet is logged and at
+    /// 2 their payloads are as well.
+    pub fn set_verbosity(&mut self, verbosity: u8) {
+        self.2 = verbosity;
+    }
+
+    /// Sets the largest packet that will be accepted. It should be checked with
+    /// check_max_packet first.
+    pub fn set_max_packet(&mut self, max_packet: usize) {
+        self.1 = max_packet;
+    }
+
+    /// Returns an error if a maximum packet size is outside of the sane bounds. It can't be
+    /// below the size the spec requires every implementation to accept.
+    pub fn check_max_packet(max_packet: usize) -> Result<(), Error> {
+        if max_packet < MIN_PACKET_LIMIT {
+            return Err(Error::Other(
+                "Maximum packet size is too small: Expected at least 35000 bytes",
+            ));
+        }
+        if max_packet > MAX_PACKET_LIMIT {
+            return Err(Error::Other(
+                "Maximum packet size is too large: Expected at most 262144 bytes",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Creates a clone of an ssh stream for sending packets. The clone does not share the read
+    /// buffer so only the original should be read from.
     pub fn try_clone(&self) -> Result<Self, Error> {
-        let SshStream(stream) = self;
+        let SshStream(stream, max_packet, verbosity) = self;
 
-        let clone = stream.try_clone();
+        let clone = stream.get_ref().try_clone();
 
         match clone {
-            Ok(clone_stream) => Ok(SshStream(clone_stream)),
+            Ok(clone_stream) => Ok(SshStream(
+                BufReader::new(clone_stream),
+                *max_packet,
+                *verbosity,
+            )),
             Err(_) => Err(Error::Other("Failed to clone SSH stream")),
         }
     }
 
+    /// Shuts down both directions of the connection, which makes any read waiting on it fail
+    pub fn shutdown(&self) -> Result<(), Error> {
+        let SshStream(stream, ..) = self;
+        stream.get_ref().shutdown(Shutdown::Both)?;
+        Ok(())
+    }
+
+    /// Sets how long a read may wait for data before failing. None waits forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        let SshStream(stream, ..) = self;
+        stream.get_ref().set_read_timeout(timeout)?;
+        Ok(())
+    }
+
     /// Returns the payload of the next ssh packet.
     /// Requires that the packet (not just the buffer that contains it) meet
-    /// the minimum length requirement of 16 bytes and the maximum length requirement
-    /// of 35000 bytes.
+    /// the minimum length requirement of 16 bytes and the stream's maximum length
+    /// requirement which is 35000 bytes by default.
     pub fn read(&mut self, mut decrypter: Option<&mut Decrypter>) -> Result<(u8, Vec<u8>), Error> {
-        let SshStream(stream) = self;
+        let SshStream(stream, max_packet, verbosity) = self;
 
         // Get the first block of the packet
-        let block_size = match &decrypter {
+        let mut block: Vec<u8> = vec![0; Self::first_block_size(decrypter.as_deref())];
+        match read_exact(stream, &mut block) {
+            // Nothing of the next packet was read so the host closed the connection cleanly
+            Err(Error::TruncatedPacket(0, _)) => {
+                return Err(Error::Other("Connection closed by host"));
+            }
+            result => result?,
+        }
+        let block_length = block.len();
+        let (packet, rest_length) =
+            Self::open_first_block(block, decrypter.as_deref_mut(), *max_packet)?;
+
+        // Get rest of packet and its mac counting the first block towards how much was read
+        let mut rest: Vec<u8> = vec![0; rest_length];
+        match read_exact(stream, &mut rest) {
+            Err(Error::TruncatedPacket(read, expected)) => {
+                return Err(Error::TruncatedPacket(
+                    block_length + read,
+                    block_length + expected,
+                ));
+            }
+            result => result?,
+        }
+
+        let (packet_type, payload) = Self::open_rest(packet, rest, decrypter)?;
+        debug(*verbosity, 1, || {
+            format!(
+                "Recieved packet of type {packet_type} ({} bytes)",
+                payload.len() + 1
+            )
+        });
+        debug(*verbosity, 2, || hex_lines(&payload));
+        Ok((packet_type, payload))
+    }
+
+    /// Sends a single SSH packet with the given payload
+    pub fn send(&mut self, payload: &[u8], encrypter: Option<&mut Encrypter>) -> Result<(), Error> {
+        let SshStream(stream, _, verbosity) = self;
+        if let Some(packet_type) = payload.first() {
+            debug(*verbosity, 1, || {
+                format!(
+                    "Sending packet of type {packet_type} ({} bytes)",
+                    payload.len()
+                )
+            });
+            debug(*verbosity, 2, || hex_lines(&payload[1..]));
+        }
+        let packet = Self::seal(payload, encrypter)?;
+        stream.get_mut().write_all(&packet)?;
+        Ok(())
+    }
+
+    /// Sends a line of the version exchange, which comes before any packets
+    pub fn send_line(&mut self, line: &[u8]) -> Result<(), Error> {
+        let SshStream(stream, ..) = self;
+        let stream = stream.get_mut();
+        stream.write_all(line)?;
+        stream.write_all(b"\r\n")?;
+        Ok(())
+    }
+
+    /// Reads a line of the version exchange including its line ending, which comes before
+    /// any packets. Results in the number of bytes read, which is 0 once the connection ends.
+    pub fn read_line(&mut self, line: &mut String) -> Result<usize, Error> {
+        let SshStream(stream, ..) = self;
+        Ok(stream.read_line(line)?)
+    }
+
+    /// Tells the host that tssh is closing the connection on purpose so that it does not
+    /// treat the connection ending as an error. When the encrypter is shared with other
+    /// threads its lock must be held for the call so that nothing is sent after it.
+    pub fn disconnect(
+        &mut self,
+        description: &[u8],
+        encrypter: Option<&mut Encrypter>,
+    ) -> Result<(), Error> {
+        let message = gen_disconnect(SSH_DISCONNECT_BY_APPLICATION, description);
+        self.send(&message, encrypter)
+    }
+
+    // Begin packet framing algorithms
+
+    /// Returns the number of bytes that must be read before the length of a packet is known
+    pub fn first_block_size(decrypter: Option<&Decrypter>) -> usize {
+        match decrypter {
+            // AEAD ciphers and encrypt-then-mac do not encrypt the length
+            Some(dec) if dec.is_aead() || dec.is_etm() => 4,
             Some(dec) => dec.block_size() as usize,
             None => 8,
-        };
-        let mut packet: Vec<u8> = vec![0; block_size];
-        stream.read_exact(&mut packet)?;
+        }
+    }
 
-        // Decrypt first block of packet
-        let mut packet = match &mut decrypter {
-            Some(dec) => dec.decrypt(packet)?,
-            None => packet,
+    /// Decrypts and validates the first block of a packet. Returns the decrypted block
+    /// along with the number of bytes (including the mac) that still need to be read
+    /// before the packet can be passed to open_rest. Packets longer than max_packet
+    /// bytes are rejected.
+    pub fn open_first_block(
+        block: Vec<u8>,
+        decrypter: Option<&mut Decrypter>,
+        max_packet: usize,
+    ) -> Result<(Vec<u8>, usize), Error> {
+        let block_size = block.len();
+
+        // Decrypt first block of packet. The unencrypted length of AEAD and encrypt-then-mac
+        // packets is left out of the alignment
+        let (packet, mac_length, unaligned, alignment) = match decrypter {
+            Some(dec) if dec.is_aead() || dec.is_etm() => {
+                (block, dec.verify_length(), 4, dec.block_size() as usize)
+            }
+            Some(dec) => (dec.decrypt(block)?, dec.verify_length(), 0, block_size),
+            None => (block, 0, 0, block_size),
         };
 
         // Extract the packet length
-        let packet_length: usize = u32::from_be_bytes((&packet[0..4]).try_into()?) as usize;
+        let packet_length: usize = u32::from_be_bytes(packet[0..4].try_into()?) as usize;
         if packet_length < 12 {
             return Err(Error::Other(
                 "Packet length is too small: Expected at least 12 bytes",
             ));
         }
-        if packet_length + 4 > 35000 {
+        if packet_length + 4 > max_packet {
             return Err(Error::Other(
-                "Packet length is too largs: Expected at most 35000 bytes",
+                "Packet length is too largs: Expected at most the maximum packet size",
             ));
         }
-        if packet_length + 4 % block_size == 0 {
+        if !(packet_length + 4 - unaligned).is_multiple_of(alignment) {
             return Err(Error::Other(
                 "Packet length is not a multiple of the block size",
             ));
         }
 
-        // Get rest of packet
-        let mut rest: Vec<u8> = vec![0; (packet_length + 4) - block_size];
-        stream.read_exact(&mut rest)?;
+        Ok((packet, (packet_length + 4) - block_size + mac_length))
+    }
+
+    /// Decrypts and verifies the remainder of a packet whose first block was opened by
+    /// open_first_block and returns the packet type and payload
+    pub fn open_rest(
+        mut packet: Vec<u8>,
+        mut rest: Vec<u8>,
+        mut decrypter: Option<&mut Decrypter>,
+    ) -> Result<(u8, Vec<u8>), Error> {
+        let packet_length: usize = u32::from_be_bytes(packet[0..4].try_into()?) as usize;
+
+        // Seperate the mac from the rest of the packet
+        let mac = match &decrypter {
+            Some(dec) if !dec.is_aead() => rest.split_off(rest.len() - dec.verify_length()),
+            _ => Vec::new(),
+        };
+
+        // Encrypt-then-mac packets must be verified before anything is decrypted
+        if let Some(dec) = &mut decrypter
+            && dec.is_etm()
+            && !dec.is_aead()
+        {
+            let mut encrypted = packet.clone();
+            encrypted.extend(&rest);
+            if !dec.verify(&encrypted, &mac) {
+                return Err(Error::Other(
+                    "Invalid mac on recieved packet: Packet Corrupted",
+                ));
+            }
+        }
 
         // Decrypt rest
         let rest = match &mut decrypter {
+            // AEAD ciphers check the tag at the end of the packet while decrypting
+            Some(dec) if dec.is_aead() => dec.decrypt_aead(&packet, rest)?,
             Some(dec) => dec.decrypt(rest)?,
             None => rest,
         };
@@ -82,44 +277,51 @@ impl SshStream {
                 "Packet has too little padding: Expected at least 4",
             ));
         }
-        if packet_length - 2 < padding_length {
+        if packet_length <= padding_length {
             return Err(Error::Other(
-                "Invalid Padding length: Expected payload is less than 1",
+                "Invalid Padding length: Expected padding to fit in the packet",
             ));
         }
+        // Every payload starts with its packet type
+        if packet_length - 1 == padding_length {
+            return Err(Error::Other("Recieved packet with empty payload"));
+        }
 
-        if let Some(dec) = &mut decrypter {
-            // Get mac
-            let mut mac: Vec<u8> = vec![0; dec.verify_length()];
-            stream.read_exact(&mut mac)?;
-
-            // Verify packet
-            if !dec.verify(&packet, &mac) {
-                return Err(Error::Other(
-                    "Invalid mac on recieved packet: Packet Corrupted",
-                ));
-            }
+        // Verify packet
+        if let Some(dec) = &mut decrypter
+            && !dec.is_aead()
+            && !dec.is_etm()
+            && !dec.verify(&packet, &mac)
+        {
+            return Err(Error::Other(
+                "Invalid mac on recieved packet: Packet Corrupted",
+            ));
         }
 
-        let payload_length = (packet_length - padding_length - 2) as usize;
+        let payload_length = packet_length - padding_length - 2;
         let payload = &packet[5..(6 + payload_length)];
         let mut payload = match decrypter {
-            Some(dec) => dec.decompress(payload),
+            Some(dec) => dec.decompress(payload)?,
             None => payload.to_vec(),
         };
+        if payload.is_empty() {
+            return Err(Error::Other("Recieved packet with empty payload"));
+        }
 
         // Get the slice containing the payload and its packet type
         let packet_type = payload.remove(0);
         Ok((packet_type, payload))
     }
 
-    /// Sends a single SSH packet with the given payload
-    pub fn send(&mut self, payload: &[u8], encrypter: Option<&mut Encrypter>) -> Result<(), Error> {
-        let SshStream(stream) = self;
-
+    /// Compresses, pads, encrypts, and macs a payload into the bytes of a single SSH packet
+    pub fn seal(payload: &[u8], mut encrypter: Option<&mut Encrypter>) -> Result<Vec<u8>, Error> {
         // Compress payload
-        let payload = match &encrypter {
-            Some(enc) => &enc.compress(payload),
+        let compressed;
+        let payload = match encrypter.as_deref_mut() {
+            Some(enc) => {
+                compressed = enc.compress(payload)?;
+                &compressed
+            }
             None => payload,
         };
 
@@ -132,14 +334,25 @@ impl SshStream {
         // Get payload length
         let payload_length = payload.len() as u32;
 
+        // The length field is not encrypted by AEAD ciphers or encrypt-then-mac so it is left
+        // out of the alignment
+        let aead = encrypter.as_ref().is_some_and(|enc| enc.is_aead());
+        let etm = encrypter.as_ref().is_some_and(|enc| enc.is_etm());
+        let aligned_length = if aead || etm {
+            payload_length + 1
+        } else {
+            payload_length + 5
+        };
+
         // Calculate the amount of padding required (must be at least 4 bytes)
-        let mut padding_length = (block_size - ((payload_length + 5) % block_size)) as u8;
+        let mut padding_length = (block_size - (aligned_length % block_size)) as u8;
         if padding_length < 4 {
             padding_length += block_size as u8;
         }
 
-        // Fill padding with 0's
-        let padding: Vec<u8> = vec![0u8; padding_length as usize];
+        // Fill padding with random bytes so that CBC packets don't end in predictable blocks
+        let mut padding: Vec<u8> = vec![0u8; padding_length as usize];
+        rand::thread_rng().fill_bytes(&mut padding);
 
         // Calculate the total packet length (excluding this field and the mac field)
         let packet_length = payload_length + (padding_length as u32) + 1;
@@ -152,66 +365,30 @@ impl SshStream {
         packet.extend(padding);
 
         // Create mac and encrypt packet
-        let (packet, mac) = match encrypter {
-            Some(enc) => {
-                let m = enc.mac(&packet);
-                let p = enc.encrypt(packet)?;
-                (p, m)
+        match encrypter {
+            Some(enc) if aead => {
+                // The length is sent in the clear but is still authenticated
+                let rest = packet.split_off(4);
+                let sealed = enc.encrypt_aead(&packet, rest)?;
+                packet.extend(sealed);
+                Ok(packet)
             }
-            None => (packet, vec![]),
-        };
-
-        // Send packet
-        stream.write_all(&packet)?;
-
-        // Send mac
-        stream.write_all(&mac)?;
-
-        Ok(())
-    }
-
-    /// Continuously reads SSH packets until it finds one with an ssh code that matches
-    /// the wait type. If it runs into an SSH_MSG_DISCONNECT packet it returns with an error.
-    /// Returns the number of messages read as this needs to be tracked
-    pub fn read_until_no_decrypter(&mut self, wait_type: u8) -> Result<(Vec<u8>, u32), Error> {
-        let mut num_read = 0;
-        loop {
-            // Read next packet
-            let (packet_type, packet) = self.read(None)?;
-
-            // Update counter
-            num_read += 1;
-
-            // Check if recieved desired packet
-            if packet_type == wait_type {
-                return Ok((packet, num_read));
-            }
-
-            // Check if recieved disconnect
-            if packet_type == SSH_MSG_DISCONNECT {
-                return Err(Error::Other("Host sent ssh disconnect message"));
+            Some(enc) if etm => {
+                // The length is sent in the clear and the mac covers it and the encrypted rest
+                let rest = packet.split_off(4);
+                let encrypted = enc.encrypt(rest)?;
+                packet.extend(encrypted);
+                let mac = enc.mac(&packet);
+                packet.extend(mac);
+                Ok(packet)
             }
-        }
-    }
-
-    pub fn read_until(
-        &mut self,
-        wait_type: u8,
-        decrypter: &mut Decrypter,
-    ) -> Result<Vec<u8>, Error> {
-        loop {
-            // Read next packet
-            let (packet_type, packet) = self.read(Some(decrypter))?;
-
-            // Check if recieved desired packet
-            if packet_type == wait_type {
-                return Ok(packet);
-            }
-
-            // Check if recieved disconnect
-            if packet_type == SSH_MSG_DISCONNECT {
-                return Err(Error::Other("Host sent ssh disconnect message"));
+            Some(enc) => {
+                let mac = enc.mac(&packet);
+                let mut packet = enc.encrypt(packet)?;
+                packet.extend(mac);
+                Ok(packet)
             }
+            None => Ok(packet),
         }
     }
 
@@ -243,13 +420,18 @@ impl SshStream {
 
     /// Parses an SSH name-list field into a vector of the string contents in the list.
     /// What is leftover of the packet the contains the list is returned along with the vector list.
+    /// An empty name-list results in an empty vector.
     pub fn extract_name_list(start: &[u8]) -> Result<(Vec<String>, &[u8]), Error> {
-        // extract list length
-        let list_length = u32::from_be_bytes((&start[0..4]).try_into()?) as usize;
-
-        // Get the rest of the list
-        let new_start = &start[(list_length + 4)..];
-        let list_string = String::from_utf8_lossy(&start[4..(list_length + 4)]).to_string();
+        let (list, new_start) = Self::split_field(
+            start,
+            "Recieved truncated name-list: Expected more bytes than the packet holds",
+        )?;
+        let list_string = String::from_utf8_lossy(list).to_string();
+
+        // An empty list has no names rather than a single empty name
+        if list_string.is_empty() {
+            return Ok((Vec::new(), new_start));
+        }
         let list: Vec<String> = list_string.split(",").map(|s| s.to_string()).collect();
 
         Ok((list, new_start))
@@ -258,14 +440,12 @@ impl SshStream {
     /// Parses an SSH string field into a string.
     /// What is leftover of the packet the contains the string is returned along with the string.
     pub fn extract_string(start: &[u8]) -> Result<(Vec<u8>, &[u8]), Error> {
-        // extract list length
-        let string_length = u32::from_be_bytes((&start[0..4]).try_into()?) as usize;
-
-        // Get the rest of the string
-        let new_start = &start[(string_length + 4)..];
-        let string = start[4..(string_length + 4)].to_vec();
+        let (string, new_start) = Self::split_field(
+            start,
+            "Recieved truncated string: Expected more bytes than the packet holds",
+        )?;
 
-        Ok((string, new_start))
+        Ok((string.to_vec(), new_start))
     }
 
     /// Appends an ssh name_list to a vector from a reference to an array
@@ -282,22 +462,49 @@ impl SshStream {
     /// Returns one unsigned mpint from an ssh packet and the remains of the packet
     /// after the end of the mpint.
     pub fn extract_mpint_unsigned(start: &[u8]) -> Result<(BigUint, &[u8]), Error> {
-        // extract list length
-        let num_length = u32::from_be_bytes((&start[0..4]).try_into()?) as usize;
-
-        // Get the rest of the num
-        let new_start = &start[(num_length + 4)..];
-        let num_string = start[4..(num_length + 4)].to_vec();
-        let num = BigUint::from_bytes_be(&num_string);
+        let (num_string, new_start) = Self::split_field(
+            start,
+            "Recieved truncated mpint: Expected more bytes than the packet holds",
+        )?;
+        let num = BigUint::from_bytes_be(num_string);
 
         Ok((num, new_start))
     }
 
+    /// Fails if anything is left of a packet or field after the last value it should hold was
+    /// extracted, since trailing bytes mean it was malformed
+    pub fn expect_end(rest: &[u8]) -> Result<(), Error> {
+        if !rest.is_empty() {
+            return Err(Error::Other(
+                "Recieved corrupt packet: Expected nothing after the last field",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Splits a length prefixed field off the front of a packet and returns its contents
+    /// along with the rest of the packet. Fails with the given error if the packet is too
+    /// short for the length or the field it declares.
+    fn split_field<'a>(
+        start: &'a [u8],
+        error: &'static str,
+    ) -> Result<(&'a [u8], &'a [u8]), Error> {
+        if start.len() < 4 {
+            return Err(Error::Other(error));
+        }
+        let length = u32::from_be_bytes(start[0..4].try_into()?) as usize;
+        if length > start.len() - 4 {
+            return Err(Error::Other(error));
+        }
+
+        Ok(start[4..].split_at(length))
+    }
+
     /// Converts an integer in the form of an array of bytes into an ssh specified mpint
     /// and appends it to the vector referenced by payload
     pub fn append_mpint(payload: &mut Vec<u8>, num: &[u8], is_pos: bool) {
         // Return 0 in mprint if num is empty
-        if num.len() == 0 {
+        if num.is_empty() {
             payload.extend([0u8; 4]);
             return;
         }
@@ -338,6 +545,7 @@ impl SshStream {
             for (i, n) in num.iter().enumerate() {
                 if *n != 0xFFu8 {
                     start = Some(i);
+                    break;
                 }
             }
 
@@ -368,3 +576,325 @@ impl SshStream {
         payload.extend(mpint);
     }
 }
+
+/// Fills the buffer with bytes from the stream. Fails if the user pressed Ctrl-C while
+/// interrupts are being caught so that a hung connection can be aborted.
+fn read_exact(stream: &mut impl Read, buf: &mut [u8]) -> Result<(), Error> {
+    let mut read = 0;
+    while read < buf.len() {
+        if interrupted() {
+            return Err(Error::Other("Connection aborted by user"));
+        }
+
+        match stream.read(&mut buf[read..]) {
+            // The connection went away before the whole buffer arrived
+            Ok(0) => return Err(Error::TruncatedPacket(read, buf.len())),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+                ) =>
+            {
+                return Err(Error::TruncatedPacket(read, buf.len()));
+            }
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns a connected pair of streams over the loopback interface
+#[cfg(test)]
+pub fn loopback_streams() -> (SshStream, SshStream) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    (SshStream::new(client), SshStream::new(server))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypter::loopback_pair;
+
+    /// Length of the hmac-sha2-256 mac appended to encrypted packets
+    const MAC_LENGTH: usize = 32;
+
+    /// Returns a payload of the given length that starts with a packet type
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|n| (n % 251) as u8 + 1).collect()
+    }
+
+    /// Opens a sealed packet the same way read does once its bytes have arrived
+    fn open(
+        sealed: Vec<u8>,
+        mut decrypter: Option<&mut Decrypter>,
+    ) -> Result<(u8, Vec<u8>), Error> {
+        let mut block = sealed;
+        let rest = block.split_off(SshStream::first_block_size(decrypter.as_deref()));
+        let (packet, rest_length) =
+            SshStream::open_first_block(block, decrypter.as_deref_mut(), DEFAULT_MAX_PACKET)?;
+        assert_eq!(rest.len(), rest_length);
+        SshStream::open_rest(packet, rest, decrypter)
+    }
+
+    /// Checks that a sealed packet is block aligned and has at least 4 bytes of padding
+    fn assert_framing(sealed: &[u8], payload_length: usize, block_size: usize, mac_length: usize) {
+        let packet_length = sealed.len() - mac_length;
+        assert_eq!(packet_length % block_size, 0, "payload of {payload_length}");
+        assert!(packet_length >= 16, "payload of {payload_length}");
+
+        let padding_length = packet_length - 5 - payload_length;
+        assert!(padding_length >= 4, "payload of {payload_length}");
+        assert!(
+            padding_length < 4 + block_size,
+            "payload of {payload_length}"
+        );
+    }
+
+    #[test]
+    fn mpints_match_rfc_4251_examples() {
+        let examples: [(&[u8], bool, &[u8]); 6] = [
+            (&[0, 0], true, &[0, 0, 0, 0]),
+            (
+                &[0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7],
+                true,
+                &[0, 0, 0, 8, 0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7],
+            ),
+            (&[0, 0x80], true, &[0, 0, 0, 2, 0, 0x80]),
+            // Negative numbers are given in two's complement with any amount of sign extension
+            (&[0xff, 0xff, 0xed, 0xcc], false, &[0, 0, 0, 2, 0xed, 0xcc]),
+            (
+                &[0xff, 0xff, 0xff, 0x21, 0x52, 0x41, 0x11],
+                false,
+                &[0, 0, 0, 5, 0xff, 0x21, 0x52, 0x41, 0x11],
+            ),
+            (&[0xff, 0xff], false, &[0, 0, 0, 1, 0xff]),
+        ];
+        for (num, is_pos, expected) in examples {
+            let mut mpint = Vec::new();
+            SshStream::append_mpint(&mut mpint, num, is_pos);
+            assert_eq!(mpint, expected, "{num:02x?}");
+        }
+    }
+
+    #[test]
+    fn unencrypted_packets_round_trip() {
+        for len in 0..=24 {
+            let payload = payload(len);
+            let sealed = SshStream::seal(&payload, None).unwrap();
+            assert_framing(&sealed, len, 8, 0);
+
+            // The padding length is sent in the clear
+            assert_eq!(sealed[4] as usize, sealed.len() - 5 - len);
+            let packet_length = u32::from_be_bytes(sealed[0..4].try_into().unwrap()) as usize;
+            assert_eq!(packet_length + 4, sealed.len());
+
+            if len == 0 {
+                continue;
+            }
+            let (packet_type, rest) = open(sealed, None).unwrap();
+            assert_eq!(packet_type, payload[0]);
+            assert_eq!(rest, &payload[1..]);
+        }
+    }
+
+    #[test]
+    fn encrypted_packets_round_trip() {
+        for cipher in ["aes128-ctr", "aes192-ctr", "aes256-ctr", "aes256-cbc"] {
+            // An empty payload can be framed but not opened, which would leave the decrypter
+            // out of step with the encrypter
+            let (mut encrypter, _) = loopback_pair(cipher, "hmac-sha2-256", "none");
+            let sealed = SshStream::seal(&[], Some(&mut encrypter)).unwrap();
+            assert_framing(&sealed, 0, 16, MAC_LENGTH);
+
+            let (mut encrypter, mut decrypter) = loopback_pair(cipher, "hmac-sha2-256", "none");
+            for len in 1..=48 {
+                let payload = payload(len);
+                let sealed = SshStream::seal(&payload, Some(&mut encrypter)).unwrap();
+                assert_framing(&sealed, len, 16, MAC_LENGTH);
+
+                let (packet_type, rest) = open(sealed, Some(&mut decrypter)).unwrap();
+                assert_eq!(packet_type, payload[0]);
+                assert_eq!(rest, &payload[1..]);
+            }
+        }
+    }
+
+    #[test]
+    fn etm_packets_round_trip() {
+        let (mut encrypter, mut decrypter) =
+            loopback_pair("aes256-ctr", "hmac-sha2-256-etm@openssh.com", "none");
+        for len in 1..=48 {
+            let payload = payload(len);
+            let sealed = SshStream::seal(&payload, Some(&mut encrypter)).unwrap();
+
+            // Only the part after the unencrypted length is aligned
+            let packet_length = sealed.len() - MAC_LENGTH - 4;
+            assert_eq!(packet_length % 16, 0, "payload of {len}");
+            assert!(packet_length - 1 - len >= 4, "payload of {len}");
+
+            let (packet_type, rest) = open(sealed, Some(&mut decrypter)).unwrap();
+            assert_eq!(packet_type, payload[0]);
+            assert_eq!(rest, &payload[1..]);
+        }
+    }
+
+    #[test]
+    fn packets_sent_over_a_stream_are_read_back() {
+        let (mut client, mut server) = loopback_streams();
+        let (mut encrypter, mut decrypter) = loopback_pair("aes128-ctr", "hmac-sha2-256", "none");
+        for len in 1..=48 {
+            let payload = payload(len);
+            client.send(&payload, None).unwrap();
+            assert_eq!(
+                server.read(None).unwrap(),
+                (payload[0], payload[1..].to_vec())
+            );
+
+            client.send(&payload, Some(&mut encrypter)).unwrap();
+            let (packet_type, rest) = server.read(Some(&mut decrypter)).unwrap();
+            assert_eq!(packet_type, payload[0]);
+            assert_eq!(rest, &payload[1..]);
+        }
+    }
+
+    #[test]
+    fn empty_payloads_are_rejected() {
+        // A packet whose padding fills everything after the padding length has no packet type
+        let mut packet = 12u32.to_be_bytes().to_vec();
+        packet.push(11);
+        packet.extend([0; 11]);
+        assert!(matches!(
+            open(packet, None),
+            Err(Error::Other("Recieved packet with empty payload"))
+        ));
+
+        let (mut encrypter, mut decrypter) = loopback_pair("aes128-ctr", "hmac-sha2-256", "none");
+        let sealed = SshStream::seal(&[], Some(&mut encrypter)).unwrap();
+        assert!(matches!(
+            open(sealed, Some(&mut decrypter)),
+            Err(Error::Other("Recieved packet with empty payload"))
+        ));
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let mut packet = Vec::new();
+        SshStream::append_string(&mut packet, b"ssh-userauth");
+        let (_, rest) = SshStream::extract_string(&packet).unwrap();
+        assert!(SshStream::expect_end(rest).is_ok());
+
+        packet.push(0);
+        let (_, rest) = SshStream::extract_string(&packet).unwrap();
+        assert!(SshStream::expect_end(rest).is_err());
+    }
+
+    #[test]
+    fn corrupted_packets_are_rejected() {
+        let (mut encrypter, mut decrypter) = loopback_pair("aes128-ctr", "hmac-sha2-256", "none");
+        let mut sealed = SshStream::seal(&payload(20), Some(&mut encrypter)).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(open(sealed, Some(&mut decrypter)).is_err());
+    }
+
+    #[test]
+    fn unaligned_packet_lengths_are_rejected() {
+        // A length of 22 is aligned to neither the 16 byte cipher block nor the 8 byte
+        // unencrypted block
+        let mut block = 22u32.to_be_bytes().to_vec();
+        block.extend([4; 12]);
+
+        let (mut encrypter, mut decrypter) = loopback_pair("aes128-ctr", "hmac-sha2-256", "none");
+        let encrypted = encrypter.encrypt(block.clone()).unwrap();
+        assert!(matches!(
+            SshStream::open_first_block(encrypted, Some(&mut decrypter), DEFAULT_MAX_PACKET),
+            Err(Error::Other(
+                "Packet length is not a multiple of the block size"
+            ))
+        ));
+
+        // Encrypt-then-mac sends the length in the clear and aligns what follows it
+        let (_, mut decrypter) =
+            loopback_pair("aes128-ctr", "hmac-sha2-256-etm@openssh.com", "none");
+        assert!(matches!(
+            SshStream::open_first_block(
+                block[0..4].to_vec(),
+                Some(&mut decrypter),
+                DEFAULT_MAX_PACKET
+            ),
+            Err(Error::Other(
+                "Packet length is not a multiple of the block size"
+            ))
+        ));
+
+        assert!(matches!(
+            SshStream::open_first_block(block[0..8].to_vec(), None, DEFAULT_MAX_PACKET),
+            Err(Error::Other(
+                "Packet length is not a multiple of the block size"
+            ))
+        ));
+    }
+
+    #[test]
+    fn truncated_fields_are_rejected() {
+        let mut field = Vec::new();
+        SshStream::append_string(&mut field, b"ssh-connection");
+
+        // Every cut short of the full field either loses part of the length or part of
+        // what it declares
+        for len in 0..field.len() {
+            let truncated = &field[..len];
+            assert!(matches!(
+                SshStream::extract_string(truncated),
+                Err(Error::Other(
+                    "Recieved truncated string: Expected more bytes than the packet holds"
+                ))
+            ));
+            assert!(matches!(
+                SshStream::extract_mpint_unsigned(truncated),
+                Err(Error::Other(
+                    "Recieved truncated mpint: Expected more bytes than the packet holds"
+                ))
+            ));
+            assert!(matches!(
+                SshStream::extract_name_list(truncated),
+                Err(Error::Other(
+                    "Recieved truncated name-list: Expected more bytes than the packet holds"
+                ))
+            ));
+        }
+
+        // A length near the top of the range must not overflow while it is checked
+        let huge = [0xff, 0xff, 0xff, 0xff, 0];
+        assert!(SshStream::extract_string(&huge).is_err());
+        assert!(SshStream::extract_mpint_unsigned(&huge).is_err());
+        assert!(SshStream::extract_name_list(&huge).is_err());
+    }
+
+    #[test]
+    fn name_lists_are_split_from_the_rest_of_the_packet() {
+        // An empty name-list has no names rather than one empty name
+        let (names, rest) = SshStream::extract_name_list(&[0, 0, 0, 0]).unwrap();
+        assert!(names.is_empty());
+        assert!(rest.is_empty());
+
+        let mut packet = Vec::new();
+        SshStream::append_name_list(&mut packet, &["aes256-ctr"]);
+        let (names, rest) = SshStream::extract_name_list(&packet).unwrap();
+        assert_eq!(names, ["aes256-ctr"]);
+        assert!(rest.is_empty());
+
+        // Whatever follows the list is left for the next field
+        let mut packet = Vec::new();
+        SshStream::append_name_list(&mut packet, &["aes128-ctr", "aes256-ctr"]);
+        packet.extend([0, 1, 2]);
+        let (names, rest) = SshStream::extract_name_list(&packet).unwrap();
+        assert_eq!(names, ["aes128-ctr", "aes256-ctr"]);
+        assert_eq!(rest, [0, 1, 2]);
+    }
+}
@@ -0,0 +1,427 @@
+use crate::Error;
+use crossterm::{event::DisableBracketedPaste, execute, terminal::disable_raw_mode};
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Terminal mode opcode marking the end of the encoded modes
+const TTY_OP_END: u8 = 0;
+
+/// Set when the user presses Ctrl-C while interrupts are being caught
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether or not tssh has been resumed after being stopped since this was last checked
+static CONTINUED: AtomicBool = AtomicBool::new(false);
+
+/// The terminal settings from before raw mode was enabled, which are put back if tssh is
+/// killed by a signal
+#[cfg(unix)]
+static ORIGINAL_TERMIOS: std::sync::OnceLock<libc::termios> = std::sync::OnceLock::new();
+
+/// Turns bracketed paste back off for when tssh is killed by a signal
+#[cfg(unix)]
+const DISABLE_BRACKETED_PASTE: &[u8] = b"\x1B[?2004l";
+
+/// Terminal modes that leave every setting up to the host
+#[cfg(feature = "async")]
+pub const NO_TERMINAL_MODES: [u8; 1] = [TTY_OP_END];
+
+/// Encodes the settings of the local terminal for a pty request. If stdin is not a real
+/// terminal there are no settings to mirror so none are sent and the host uses its defaults.
+///
+/// Must be called before raw mode is enabled or the raw settings will be sent instead.
+pub fn terminal_modes() -> Vec<u8> {
+    let mut modes = Vec::new();
+    if io::stdin().is_terminal() {
+        #[cfg(unix)]
+        append_local_modes(&mut modes);
+    }
+    modes.push(TTY_OP_END);
+    modes
+}
+
+/// Appends the opcode and value of a single terminal mode
+#[cfg(unix)]
+fn append_mode(modes: &mut Vec<u8>, opcode: u8, value: u32) {
+    modes.push(opcode);
+    modes.extend(value.to_be_bytes());
+}
+
+/// Appends the control characters, flags, and speeds of the terminal on stdin
+#[cfg(unix)]
+fn append_local_modes(modes: &mut Vec<u8>) {
+    let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+    // SAFETY: tcgetattr only writes to the termios struct it is given
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) } != 0 {
+        return;
+    }
+    // SAFETY: tcgetattr succeeded so the struct has been initialized
+    let termios = unsafe { termios.assume_init() };
+
+    // Control characters
+    let characters = [
+        (1, libc::VINTR),
+        (2, libc::VQUIT),
+        (3, libc::VERASE),
+        (4, libc::VKILL),
+        (5, libc::VEOF),
+        (6, libc::VEOL),
+        (7, libc::VEOL2),
+        (8, libc::VSTART),
+        (9, libc::VSTOP),
+        (10, libc::VSUSP),
+        (12, libc::VREPRINT),
+        (13, libc::VWERASE),
+        (14, libc::VLNEXT),
+        (18, libc::VDISCARD),
+    ];
+    for (opcode, index) in characters {
+        append_mode(modes, opcode, termios.c_cc[index] as u32);
+    }
+
+    // Input flags
+    let mut input_flags = vec![
+        (30, libc::IGNPAR),
+        (31, libc::PARMRK),
+        (32, libc::INPCK),
+        (33, libc::ISTRIP),
+        (34, libc::INLCR),
+        (35, libc::IGNCR),
+        (36, libc::ICRNL),
+        (38, libc::IXON),
+        (39, libc::IXANY),
+        (40, libc::IXOFF),
+        (41, libc::IMAXBEL),
+    ];
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+    input_flags.push((42, libc::IUTF8));
+    for (opcode, flag) in input_flags {
+        append_mode(modes, opcode, (termios.c_iflag & flag != 0) as u32);
+    }
+
+    // Local flags
+    let local_flags = [
+        (50, libc::ISIG),
+        (51, libc::ICANON),
+        (53, libc::ECHO),
+        (54, libc::ECHOE),
+        (55, libc::ECHOK),
+        (56, libc::ECHONL),
+        (57, libc::NOFLSH),
+        (58, libc::TOSTOP),
+        (59, libc::IEXTEN),
+        (60, libc::ECHOCTL),
+        (61, libc::ECHOKE),
+        (62, libc::PENDIN),
+    ];
+    for (opcode, flag) in local_flags {
+        append_mode(modes, opcode, (termios.c_lflag & flag != 0) as u32);
+    }
+
+    // Output flags
+    let output_flags = [
+        (70, libc::OPOST),
+        (72, libc::ONLCR),
+        (73, libc::OCRNL),
+        (74, libc::ONOCR),
+        (75, libc::ONLRET),
+    ];
+    for (opcode, flag) in output_flags {
+        append_mode(modes, opcode, (termios.c_oflag & flag != 0) as u32);
+    }
+
+    // Control flags
+    append_mode(
+        modes,
+        90,
+        (termios.c_cflag & libc::CSIZE == libc::CS7) as u32,
+    );
+    append_mode(
+        modes,
+        91,
+        (termios.c_cflag & libc::CSIZE == libc::CS8) as u32,
+    );
+    append_mode(modes, 92, (termios.c_cflag & libc::PARENB != 0) as u32);
+    append_mode(modes, 93, (termios.c_cflag & libc::PARODD != 0) as u32);
+
+    // Terminal speeds
+    // SAFETY: the termios struct was initialized by tcgetattr
+    let (input_speed, output_speed) =
+        unsafe { (libc::cfgetispeed(&termios), libc::cfgetospeed(&termios)) };
+    append_mode(modes, 128, baud_rate(input_speed));
+    append_mode(modes, 129, baud_rate(output_speed));
+}
+
+/// Converts a termios speed into its baud rate
+#[cfg(unix)]
+fn baud_rate(speed: libc::speed_t) -> u32 {
+    match speed {
+        libc::B0 => 0,
+        libc::B50 => 50,
+        libc::B75 => 75,
+        libc::B110 => 110,
+        libc::B134 => 134,
+        libc::B150 => 150,
+        libc::B200 => 200,
+        libc::B300 => 300,
+        libc::B600 => 600,
+        libc::B1200 => 1200,
+        libc::B1800 => 1800,
+        libc::B2400 => 2400,
+        libc::B4800 => 4800,
+        libc::B9600 => 9600,
+        libc::B19200 => 19200,
+        libc::B57600 => 57600,
+        libc::B115200 => 115200,
+        libc::B230400 => 230400,
+        _ => 38400,
+    }
+}
+
+/// Catches Ctrl-C until release_interrupt is called so that a hung handshake can be aborted
+/// by failing the read it is blocked on rather than by killing the process. The terminal
+/// settings are saved first so that SIGTERM and SIGHUP can put them back before tssh exits.
+pub fn catch_interrupt() {
+    #[cfg(unix)]
+    {
+        extern "C" fn handle(_: libc::c_int) {
+            INTERRUPTED.store(true, Ordering::Relaxed);
+        }
+
+        save_terminal();
+
+        // SAFETY: the handler only stores to an atomic which is async-signal-safe. SA_RESTART
+        // is left out so that blocked reads return and can check whether they were interrupted.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+        }
+        restore_on_signal(libc::SIGTERM);
+        restore_on_signal(libc::SIGHUP);
+    }
+}
+
+/// Lets Ctrl-C end tssh once the session has started, putting the terminal back first. In
+/// raw mode a typed Ctrl-C is read as 0x03 and sent to the host instead of raising SIGINT,
+/// so this only happens before raw mode is on or when SIGINT is sent from elsewhere.
+pub fn release_interrupt() {
+    #[cfg(unix)]
+    restore_on_signal(libc::SIGINT);
+}
+
+/// Saves the settings of the terminal on stdin if there is one, unless they already were
+#[cfg(unix)]
+fn save_terminal() {
+    if ORIGINAL_TERMIOS.get().is_some() || !io::stdin().is_terminal() {
+        return;
+    }
+
+    let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+    // SAFETY: tcgetattr only writes to the termios struct it is given and it is only read
+    // once tcgetattr succeeds
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) } == 0 {
+        let _ = ORIGINAL_TERMIOS.set(unsafe { termios.assume_init() });
+    }
+}
+
+/// Makes a signal put the terminal back the way it was before ending tssh like it would have
+#[cfg(unix)]
+fn restore_on_signal(signal: libc::c_int) {
+    extern "C" fn handle(signal: libc::c_int) {
+        // SAFETY: write, tcsetattr, signal and raise are all async-signal-safe and the saved
+        // settings are never changed once set
+        unsafe {
+            if let Some(termios) = ORIGINAL_TERMIOS.get() {
+                libc::write(
+                    libc::STDOUT_FILENO,
+                    DISABLE_BRACKETED_PASTE.as_ptr().cast(),
+                    DISABLE_BRACKETED_PASTE.len(),
+                );
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+            }
+
+            // Die from the signal itself so the parent sees why tssh ended
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+
+    // SAFETY: the handler only makes async-signal-safe calls
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+}
+
+/// Leaves raw mode and turns bracketed paste back off. The writing thread does this itself
+/// once it stops, but tssh can exit before it gets the chance.
+pub fn restore_terminal() {
+    if io::stdin().is_terminal() {
+        let _ = execute!(io::stdout(), DisableBracketedPaste);
+    }
+    let _ = disable_raw_mode();
+}
+
+/// Returns whether or not the user has pressed Ctrl-C while interrupts were being caught
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Stops the whole process group like Ctrl-Z does in a normal terminal. Returns once the
+/// process is resumed, such as with fg.
+#[cfg(unix)]
+pub fn suspend() {
+    // SAFETY: sending a signal to our own process group has no preconditions
+    unsafe {
+        libc::kill(0, libc::SIGTSTP);
+    }
+}
+
+/// Notes whenever tssh is resumed after being stopped so that the terminal can be put back
+/// the way the session needs it
+pub fn catch_continue() {
+    #[cfg(unix)]
+    {
+        extern "C" fn handle(_: libc::c_int) {
+            CONTINUED.store(true, Ordering::Relaxed);
+        }
+
+        // SAFETY: the handler only stores to an atomic which is async-signal-safe
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGCONT, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+/// Returns whether or not tssh has been resumed since this was last called
+pub fn take_continued() -> bool {
+    CONTINUED.swap(false, Ordering::Relaxed)
+}
+
+/// Reads a password from the first line of an open file descriptor, which is closed after.
+/// Used to take a password from a credential manager without it showing up in the arguments.
+#[cfg(unix)]
+pub fn read_password_fd(fd: i32) -> Result<String, Error> {
+    use std::io::{BufRead, BufReader};
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: fcntl only reads the flags of the descriptor and fails if it is not open
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if fd < 0 || flags == -1 {
+        return Err(Error::Other("Password file descriptor is not open"));
+    }
+    if flags & libc::O_ACCMODE == libc::O_WRONLY {
+        return Err(Error::Other(
+            "Password file descriptor is not open for reading",
+        ));
+    }
+
+    // SAFETY: the descriptor is open and nothing else in tssh uses it
+    let file = unsafe { std::fs::File::from_raw_fd(fd) };
+    let mut password = String::new();
+    if BufReader::new(file).read_line(&mut password)? == 0 {
+        return Err(Error::Other(
+            "No password was read from the password file descriptor",
+        ));
+    }
+
+    let password = password.strip_suffix('\n').unwrap_or(&password);
+    let password = password.strip_suffix('\r').unwrap_or(password);
+    Ok(password.to_string())
+}
+
+/// Reading from file descriptors is only supported on unix
+#[cfg(not(unix))]
+pub fn read_password_fd(_fd: i32) -> Result<String, Error> {
+    Err(Error::Other(
+        "Reading a password from a file descriptor is only supported on unix",
+    ))
+}
+
+/// Returns the fully qualified name of this machine with a trailing dot as hostbased
+/// authentication expects. Falls back to the plain hostname if it has no domain.
+#[cfg(unix)]
+pub fn local_hostname() -> Result<String, Error> {
+    let mut name = [0u8; 256];
+    // SAFETY: gethostname writes at most the given length into the buffer
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return Err(Error::Other("Unable to get the local hostname"));
+    }
+    let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    let hostname = String::from_utf8_lossy(&name[..end]).into_owned();
+
+    // Use the canonical name from the resolver when there is one
+    let mut fqdn = canonical_name(&hostname).unwrap_or(hostname);
+    if !fqdn.ends_with('.') {
+        fqdn.push('.');
+    }
+    Ok(fqdn)
+}
+
+/// Looks up the canonical name of a host with getaddrinfo
+#[cfg(unix)]
+fn canonical_name(hostname: &str) -> Option<String> {
+    let node = std::ffi::CString::new(hostname).ok()?;
+    // SAFETY: a zeroed addrinfo is a valid set of hints with no restrictions
+    let mut hints: libc::addrinfo = unsafe { std::mem::zeroed() };
+    hints.ai_flags = libc::AI_CANONNAME;
+    let mut info = std::ptr::null_mut();
+    // SAFETY: the node and hints outlive the call and info is freed below
+    if unsafe { libc::getaddrinfo(node.as_ptr(), std::ptr::null(), &hints, &mut info) } != 0 {
+        return None;
+    }
+
+    // SAFETY: getaddrinfo succeeded so info points to at least one entry, and ai_canonname
+    // is either null or a C string that lives until freeaddrinfo
+    let name = unsafe {
+        let canonname = (*info).ai_canonname;
+        let name = (!canonname.is_null()).then(|| {
+            std::ffi::CStr::from_ptr(canonname)
+                .to_string_lossy()
+                .into_owned()
+        });
+        libc::freeaddrinfo(info);
+        name
+    };
+    name.filter(|name| !name.is_empty())
+}
+
+/// Getting the hostname is only supported on unix
+#[cfg(not(unix))]
+pub fn local_hostname() -> Result<String, Error> {
+    Err(Error::Other(
+        "Hostbased authentication is only supported on unix",
+    ))
+}
+
+/// Returns the name of the local user running tssh from the password database, so that it
+/// can't be changed by setting USER
+#[cfg(unix)]
+pub fn local_username() -> Result<String, Error> {
+    // SAFETY: getpwuid returns null or a pointer to a static entry that is only read here
+    // before anything else could call it
+    unsafe {
+        let entry = libc::getpwuid(libc::getuid());
+        if entry.is_null() || (*entry).pw_name.is_null() {
+            return Err(Error::Other("Unable to find the local username"));
+        }
+        Ok(std::ffi::CStr::from_ptr((*entry).pw_name)
+            .to_string_lossy()
+            .into_owned())
+    }
+}
+
+/// Looking up the local user is only supported on unix
+#[cfg(not(unix))]
+pub fn local_username() -> Result<String, Error> {
+    Err(Error::Other(
+        "Hostbased authentication is only supported on unix",
+    ))
+}
@@ -1,29 +1,192 @@
 use crate::{
-    Error, SSH_MSG_CHANNEL_DATA, SSH_MSG_CHANNEL_REQUEST, encrypter::Encrypter,
+    Error, SSH_MSG_CHANNEL_DATA, SSH_MSG_CHANNEL_EOF,
+    encrypter::Encrypter,
+    protocol::{Channel, gen_signal, gen_window_change},
     ssh_stream::SshStream,
+    terminal::{catch_continue, take_continued},
 };
 use crossterm::{
-    event::{Event, KeyCode, KeyEventKind, KeyModifiers, poll, read},
-    terminal::{disable_raw_mode, enable_raw_mode},
+    event::{
+        DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEventKind, KeyModifiers,
+        poll, read,
+    },
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, size},
 };
 use std::{
+    fs::File,
+    io::{self, IsTerminal, Read, Write},
     panic, process,
     sync::{
         Arc, Mutex,
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
     },
     thread,
     time::Duration,
 };
 
+/// What to do once local input ends when it is piped in instead of typed in a terminal
+#[derive(Clone, Copy, Default)]
+pub enum StdinEofAction {
+    /// Sends an end of transmission character so the shell sees the end of input like Ctrl-D,
+    /// or ends the channel's input when there is no pseudo-terminal to read the character
+    #[default]
+    ForwardEof,
+    /// Ends the connection
+    Disconnect,
+    /// Leaves the session running until the host ends it
+    Ignore,
+}
+
+/// What the writing thread does with local input besides sending it to the host
+pub struct InputOptions {
+    /// What to do once piped input ends
+    pub eof_action: StdinEofAction,
+    /// The file everything sent to the host is recorded in if any
+    pub log: Option<File>,
+    /// Whether or not the host gave the session a pseudo-terminal. Without one input is always
+    /// sent as it is piped in since there is nothing on the host to handle raw keys.
+    pub pty: bool,
+    /// The character that starts escape sequences, or None to send every key as typed
+    pub escape_char: Option<u8>,
+}
+
+/// Checks typed keys for escape sequences, which are only recognized as the escape character
+/// at the start of a line
+struct Escape {
+    escape_char: Option<u8>,
+    state: EscapeState,
+}
+
+/// Where the typed keys are relative to an escape sequence. After ~K the signal name being
+/// typed is collected locally.
+enum EscapeState {
+    LineStart,
+    EscapeChar,
+    MidLine,
+    Signal(String),
+}
+
+/// What to do with a key once escape sequences have been checked for
+#[cfg_attr(test, derive(Debug, PartialEq))]
+enum Typed {
+    Send(Vec<u8>),
+    Hold,
+    Suspend,
+    ReportWindows,
+    Disconnect,
+    Help,
+    /// Shows the bytes on the local terminal without sending them
+    Echo(Vec<u8>),
+    Signal(String),
+}
+
+impl Escape {
+    /// Starts checking for escape sequences at the start of a line
+    fn new(escape_char: Option<u8>) -> Escape {
+        Escape {
+            escape_char,
+            state: EscapeState::LineStart,
+        }
+    }
+
+    /// Updates the escape state with the bytes of a key and returns what to do with them
+    fn process(&mut self, data: Vec<u8>) -> Typed {
+        let escape_char = match self.escape_char {
+            Some(escape_char) => escape_char,
+            None => return Typed::Send(data),
+        };
+        let state = &mut self.state;
+
+        if let EscapeState::Signal(name) = state {
+            return match data.as_slice() {
+                // The remote line is untouched so another escape may follow
+                b"\n" | b"\r" => {
+                    let name = std::mem::take(name);
+                    *state = EscapeState::LineStart;
+                    Typed::Signal(name)
+                }
+                // Esc or Ctrl-C gives up on sending a signal
+                [0x1B] | [0x03] => {
+                    *state = EscapeState::LineStart;
+                    Typed::Echo(b"\r\n".to_vec())
+                }
+                [0x7F] => match name.pop() {
+                    Some(_) => Typed::Echo(b"\x08 \x08".to_vec()),
+                    None => Typed::Hold,
+                },
+                [c] if c.is_ascii_alphanumeric() => {
+                    name.push(*c as char);
+                    Typed::Echo(vec![*c])
+                }
+                _ => Typed::Hold,
+            };
+        }
+
+        match state {
+            EscapeState::LineStart if data == [escape_char] => {
+                *state = EscapeState::EscapeChar;
+                return Typed::Hold;
+            }
+            EscapeState::EscapeChar if cfg!(unix) && data == [0x1A] => {
+                *state = EscapeState::MidLine;
+                return Typed::Suspend;
+            }
+            EscapeState::EscapeChar if data == b"W" => {
+                *state = EscapeState::MidLine;
+                return Typed::ReportWindows;
+            }
+            EscapeState::EscapeChar if data == b"." => {
+                *state = EscapeState::MidLine;
+                return Typed::Disconnect;
+            }
+            EscapeState::EscapeChar if data == b"K" => {
+                *state = EscapeState::Signal(String::new());
+                return Typed::Echo(b"\r\nSignal: ".to_vec());
+            }
+            // Help is printed locally and a new escape may follow on the same line
+            EscapeState::EscapeChar if data == b"?" => {
+                *state = EscapeState::LineStart;
+                return Typed::Help;
+            }
+            // A second escape character sends just one
+            EscapeState::EscapeChar if data == [escape_char] => {
+                *state = EscapeState::MidLine;
+                return Typed::Send(data);
+            }
+            _ => (),
+        }
+
+        // The held escape character was not part of an escape so it is sent along with the key
+        let mut typed = match state {
+            EscapeState::EscapeChar => vec![escape_char],
+            _ => Vec::new(),
+        };
+        *state = match data.last() {
+            Some(b'\n' | b'\r') => EscapeState::LineStart,
+            _ => EscapeState::MidLine,
+        };
+        typed.extend(data);
+        Typed::Send(typed)
+    }
+}
+
+/// Starts the thread that sends local input to the host. Every packet it sends is encrypted
+/// while holding the encrypter lock, which the reading thread holds for the whole of a key
+/// re-exchange, so no input can be sent with keys that are about to be replaced. Input piped
+/// in instead of typed is sent as is and the input options decide what happens once it ends.
 pub fn spawn(
     mut stream: SshStream,
     encrypter: Arc<Mutex<Encrypter>>,
     window: Arc<Mutex<u64>>,
-    packet_max: u32,
-    channel: u32,
+    local_window: Arc<AtomicU64>,
+    channel: &Channel,
     stop_flag: Arc<AtomicBool>,
+    mut input: InputOptions,
 ) -> Result<(), Error> {
+    let packet_max = channel.packet_max();
+    let channel = channel.server_channel();
+
     // Check that packet max is acceptible
     if packet_max < 16 {
         return Err(Error::Other("Server maximum packet size is too small"));
@@ -31,15 +194,38 @@ pub fn spawn(
 
     // Exit the process if this thread panics
     panic::set_hook(Box::new(|info| {
+        let _ = execute!(io::stdout(), DisableBracketedPaste);
         let _ = disable_raw_mode();
         eprintln!("Writing thread panicked: {}", info);
         process::exit(1);
     }));
 
     thread::spawn(move || {
+        if !io::stdin().is_terminal() || !input.pty {
+            forward_piped_input(
+                &mut stream,
+                &encrypter,
+                &window,
+                channel,
+                packet_max,
+                &stop_flag,
+                &mut input,
+            )
+            .unwrap();
+            return;
+        }
+
         enable_raw_mode().unwrap();
+        execute!(io::stdout(), EnableBracketedPaste).unwrap();
+        let mut escape = Escape::new(input.escape_char);
+        catch_continue();
 
         while !stop_flag.load(Ordering::Relaxed) {
+            // The terminal may have been reset while tssh was stopped
+            if take_continued() {
+                reassert_terminal(&mut stream, &encrypter, channel).unwrap();
+            }
+
             if poll(Duration::from_millis(100)).unwrap() {
                 // Capture key pressed
                 match read().unwrap() {
@@ -54,7 +240,7 @@ pub fn spawn(
                             KeyCode::Char(c) => {
                                 if event.modifiers.contains(KeyModifiers::CONTROL) {
                                     let upper = c.to_ascii_uppercase();
-                                    if upper >= 'A' && upper <= '_' {
+                                    if ('A'..='_').contains(&upper) {
                                         data.push((upper as u8) & 0x1F);
                                     } else {
                                         data.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
@@ -80,29 +266,56 @@ pub fn spawn(
                             _ => (),
                         }
 
-                        for byte in data {
-                            // Wait until we can send data
-                            wait_for_window(&window);
-
-                            // Assemble data packet
-                            let mut packet = vec![SSH_MSG_CHANNEL_DATA];
-                            packet.extend(channel.to_be_bytes());
-                            packet.extend(b"\x00\x00\x00\x01");
-                            packet.push(byte);
-
-                            // Send packet
-                            let mut enc = encrypter.lock().unwrap();
-                            stream.send(&packet, Some(&mut enc)).unwrap();
+                        match escape.process(data) {
+                            Typed::Send(data) => send_channel_data(
+                                &mut stream,
+                                &encrypter,
+                                &window,
+                                channel,
+                                packet_max,
+                                &mut input.log,
+                                &data,
+                            )
+                            .unwrap(),
+                            Typed::Hold => (),
+                            Typed::Suspend => {
+                                #[cfg(unix)]
+                                suspend(&mut stream, &encrypter, channel).unwrap();
+                            }
+                            Typed::ReportWindows => report_windows(&window, &local_window).unwrap(),
+                            Typed::Disconnect => {
+                                // The reading thread sees the stop flag once the connection
+                                // ends and returns as if the session ended normally
+                                stop_flag.store(true, Ordering::Relaxed);
+                                let mut enc = encrypter.lock().unwrap();
+                                stream
+                                    .disconnect(b"Disconnected by user", Some(&mut enc))
+                                    .unwrap();
+                                stream.shutdown().unwrap();
+                            }
+                            Typed::Help => print_escape_help(input.escape_char).unwrap(),
+                            Typed::Echo(data) => {
+                                let mut stderr = io::stderr();
+                                stderr.write_all(&data).unwrap();
+                                stderr.flush().unwrap();
+                            }
+                            Typed::Signal(name) => {
+                                send_signal(&mut stream, &encrypter, channel, &name).unwrap()
+                            }
                         }
                     }
+                    Event::Paste(text) => send_channel_data(
+                        &mut stream,
+                        &encrypter,
+                        &window,
+                        channel,
+                        packet_max,
+                        &mut input.log,
+                        text.as_bytes(),
+                    )
+                    .unwrap(),
                     Event::Resize(width, height) => {
-                        let mut request = vec![SSH_MSG_CHANNEL_REQUEST];
-                        request.extend(channel.to_be_bytes());
-                        SshStream::append_string(&mut request, b"window-change");
-                        request.push(0);
-                        request.extend((width as u32).to_be_bytes());
-                        request.extend((height as u32).to_be_bytes());
-                        request.extend([0, 0, 0, 0, 0, 0, 0, 0]);
+                        let request = gen_window_change(channel, width as u32, height as u32);
 
                         let mut enc = encrypter.lock().unwrap();
                         stream.send(&request, Some(&mut enc)).unwrap();
@@ -112,19 +325,379 @@ pub fn spawn(
             }
         }
 
+        execute!(io::stdout(), DisableBracketedPaste).unwrap();
         disable_raw_mode().unwrap();
     });
 
     Ok(())
 }
 
-/// Thjs function blocks the thread until the window is non-zero and decriments it by one
-fn wait_for_window(window: &Arc<Mutex<u64>>) {
+/// Sends piped input to the host until it ends and then handles the end as the eof action says.
+/// Disconnecting sets the stop flag before the connection is shut down so that the reading
+/// thread knows the session ended on purpose.
+fn forward_piped_input(
+    stream: &mut SshStream,
+    encrypter: &Arc<Mutex<Encrypter>>,
+    window: &Arc<Mutex<u64>>,
+    channel: u32,
+    packet_max: u32,
+    stop_flag: &AtomicBool,
+    input: &mut InputOptions,
+) -> Result<(), Error> {
+    let mut buffer = [0; 4096];
+    loop {
+        let len = io::stdin().read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        send_channel_data(
+            stream,
+            encrypter,
+            window,
+            channel,
+            packet_max,
+            &mut input.log,
+            &buffer[..len],
+        )?;
+    }
+
+    match input.eof_action {
+        StdinEofAction::ForwardEof if !input.pty => {
+            let mut eof = vec![SSH_MSG_CHANNEL_EOF];
+            eof.extend(channel.to_be_bytes());
+            let mut enc = encrypter.lock().unwrap();
+            stream.send(&eof, Some(&mut enc))
+        }
+        StdinEofAction::ForwardEof => send_channel_data(
+            stream,
+            encrypter,
+            window,
+            channel,
+            packet_max,
+            &mut input.log,
+            &[0x04],
+        ),
+        StdinEofAction::Disconnect => {
+            stop_flag.store(true, Ordering::Relaxed);
+            let mut enc = encrypter.lock().unwrap();
+            stream.disconnect(b"Local input ended", Some(&mut enc))?;
+            stream.shutdown()
+        }
+        StdinEofAction::Ignore => Ok(()),
+    }
+}
+
+/// Restores the local terminal and suspends tssh for ~^Z. Once resumed the terminal is put
+/// back the way the session needs it.
+#[cfg(unix)]
+fn suspend(
+    stream: &mut SshStream,
+    encrypter: &Arc<Mutex<Encrypter>>,
+    channel: u32,
+) -> Result<(), Error> {
+    execute!(io::stdout(), DisableBracketedPaste)?;
+    disable_raw_mode()?;
+
+    crate::terminal::suspend();
+
+    // The terminal is reasserted here even if the stop signal was ignored, so the resume
+    // itself does not need to be handled again
+    take_continued();
+    reassert_terminal(stream, encrypter, channel)
+}
+
+/// Prints the channel windows for ~W. The send window is how much more the host will accept
+/// and the receive window is how much more it may send before it is granted more.
+fn report_windows(window: &Arc<Mutex<u64>>, local_window: &AtomicU64) -> Result<(), Error> {
+    let send_window = *window.lock().unwrap();
+    let receive_window = local_window.load(Ordering::Relaxed);
+
+    // The terminal is in raw mode so lines need a carriage return
+    let mut stderr = io::stderr();
+    write!(
+        stderr,
+        "\r\nSend window: {send_window} bytes, receive window: {receive_window} bytes\r\n"
+    )?;
+    stderr.flush()?;
+    Ok(())
+}
+
+/// Sends the named signal to the remote process for ~K, or tells the user it is not one SSH
+/// can send
+fn send_signal(
+    stream: &mut SshStream,
+    encrypter: &Arc<Mutex<Encrypter>>,
+    channel: u32,
+    name: &str,
+) -> Result<(), Error> {
+    // The terminal is in raw mode so lines need a carriage return
+    let mut stderr = io::stderr();
+    match gen_signal(channel, name) {
+        Some(request) => {
+            write!(stderr, "\r\n")?;
+            let mut enc = encrypter.lock().unwrap();
+            stream.send(&request, Some(&mut enc))?;
+        }
+        None => write!(
+            stderr,
+            "\r\nUnknown signal {name}: Expected ABRT, ALRM, FPE, HUP, ILL, INT, KILL, PIPE, \
+             QUIT, SEGV, TERM, USR1, or USR2\r\n"
+        )?,
+    }
+    stderr.flush()?;
+    Ok(())
+}
+
+/// Prints the escape sequences that are recognized at the start of a line for ~?
+fn print_escape_help(escape_char: Option<u8>) -> Result<(), Error> {
+    // Control characters are shown the way they are typed
+    let e = match escape_char {
+        Some(c) if c < 0x20 => format!("^{}", (c | 0x40) as char),
+        Some(c) => (c as char).to_string(),
+        None => return Ok(()),
+    };
+
+    // The terminal is in raw mode so lines need a carriage return
+    let mut stderr = io::stderr();
+    write!(stderr, "\r\nSupported escape sequences:\r\n")?;
+    write!(stderr, " {:<4}- terminate connection\r\n", format!("{e}."))?;
+    #[cfg(unix)]
+    write!(stderr, " {:<4}- suspend tssh\r\n", format!("{e}^Z"))?;
+    write!(
+        stderr,
+        " {:<4}- report channel window sizes\r\n",
+        format!("{e}W")
+    )?;
+    write!(
+        stderr,
+        " {:<4}- send a signal such as TERM to the remote process\r\n",
+        format!("{e}K")
+    )?;
+    write!(stderr, " {:<4}- this message\r\n", format!("{e}?"))?;
+    write!(stderr, " {:<4}- send a single {e}\r\n", format!("{e}{e}"))?;
+    write!(
+        stderr,
+        "(Escapes are only recognized immediately after a newline)\r\n"
+    )?;
+    stderr.flush()?;
+    Ok(())
+}
+
+/// Puts the terminal back into raw mode with bracketed paste and tells the host its size in
+/// case it changed, for whenever something else may have reconfigured the terminal
+fn reassert_terminal(
+    stream: &mut SshStream,
+    encrypter: &Arc<Mutex<Encrypter>>,
+    channel: u32,
+) -> Result<(), Error> {
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnableBracketedPaste)?;
+    let (width, height) = size()?;
+    let request = gen_window_change(channel, width as u32, height as u32);
+
+    let mut enc = encrypter.lock().unwrap();
+    stream.send(&request, Some(&mut enc))
+}
+
+/// Sends data over a channel split into packets that fit within both the host's window and
+/// its maximum packet size, waiting for the window to grow whenever it is used up. The data
+/// is recorded in the input log first if there is one.
+fn send_channel_data(
+    stream: &mut SshStream,
+    encrypter: &Arc<Mutex<Encrypter>>,
+    window: &Arc<Mutex<u64>>,
+    channel: u32,
+    packet_max: u32,
+    log: &mut Option<File>,
+    mut data: &[u8],
+) -> Result<(), Error> {
+    if let Some(log) = log {
+        log.write_all(data)?;
+    }
+
+    while !data.is_empty() {
+        // Wait until we can send data
+        let len = wait_for_window(window, data.len().min(packet_max as usize));
+        let (chunk, rest) = data.split_at(len);
+
+        // Assemble data packet
+        let mut packet = vec![SSH_MSG_CHANNEL_DATA];
+        packet.extend(channel.to_be_bytes());
+        SshStream::append_string(&mut packet, chunk);
+
+        // Send packet
+        let mut enc = encrypter.lock().unwrap();
+        stream.send(&packet, Some(&mut enc))?;
+        data = rest;
+    }
+
+    Ok(())
+}
+
+/// Blocks the thread until the window is non-zero and then reserves as much of it as is
+/// available up to max bytes. Returns the number of bytes reserved.
+fn wait_for_window(window: &Arc<Mutex<u64>>, max: usize) -> usize {
     loop {
         let mut window = window.lock().unwrap();
         if *window != 0 {
-            *window -= 1;
-            return;
+            let amount = (*window).min(max as u64);
+            *window -= amount;
+            return amount as usize;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypter::{SSH_MSG_NEWKEYS, loopback_pair};
+    use crate::ssh_stream::loopback_streams;
+
+    /// The ciphers the rekey stress test cycles through so each new key differs from the last
+    const REKEY_CIPHERS: [&str; 3] = ["aes128-ctr", "aes192-ctr", "aes256-ctr"];
+
+    /// Feeds each key to the escape state and returns what was done with the last one
+    fn type_keys(escape: &mut Escape, keys: &[&[u8]]) -> Typed {
+        let mut typed = Typed::Hold;
+        for key in keys {
+            typed = escape.process(key.to_vec());
+        }
+        typed
+    }
+
+    #[test]
+    fn signal_escape_collects_a_name() {
+        let mut escape = Escape::new(Some(b'~'));
+        assert_eq!(
+            type_keys(&mut escape, &[b"~", b"K"]),
+            Typed::Echo(b"\r\nSignal: ".to_vec())
+        );
+        assert_eq!(
+            type_keys(&mut escape, &[b"T", b"E", b"X", b"\x7F", b"R", b"M"]),
+            Typed::Echo(b"M".to_vec())
+        );
+        assert_eq!(
+            type_keys(&mut escape, &[b"\r"]),
+            Typed::Signal("TERM".to_string())
+        );
+
+        // Another escape can follow straight away
+        assert_eq!(type_keys(&mut escape, &[b"~", b"."]), Typed::Disconnect);
+    }
+
+    #[test]
+    fn signal_escape_can_be_cancelled() {
+        let mut escape = Escape::new(Some(b'~'));
+        type_keys(&mut escape, &[b"~", b"K", b"H", b"U"]);
+        assert_eq!(
+            type_keys(&mut escape, &[b"\x1B"]),
+            Typed::Echo(b"\r\n".to_vec())
+        );
+        assert_eq!(type_keys(&mut escape, &[b"l"]), Typed::Send(b"l".to_vec()));
+    }
+
+    #[test]
+    fn no_escape_char_forwards_every_key() {
+        let mut escape = Escape::new(None);
+        assert_eq!(escape.process(b"~".to_vec()), Typed::Send(b"~".to_vec()));
+        assert_eq!(escape.process(b".".to_vec()), Typed::Send(b".".to_vec()));
+    }
+
+    #[test]
+    fn escape_char_can_be_changed() {
+        let mut escape = Escape::new(Some(b'#'));
+        assert_eq!(
+            type_keys(&mut escape, &[b"~", b"."]),
+            Typed::Send(b".".to_vec())
+        );
+        assert_eq!(
+            type_keys(&mut escape, &[b"\r", b"#", b"x"]),
+            Typed::Send(b"#x".to_vec())
+        );
+        assert_eq!(
+            type_keys(&mut escape, &[b"\r", b"#", b"."]),
+            Typed::Disconnect
+        );
+    }
+
+    #[test]
+    fn rekeys_under_the_encrypter_lock_never_split_a_write() {
+        let (mut stream, mut host) = loopback_streams();
+        let (encrypter, mut decrypter) = loopback_pair(REKEY_CIPHERS[0], "hmac-sha2-256", "none");
+        let encrypter = Arc::new(Mutex::new(encrypter));
+        let window = Arc::new(Mutex::new(u64::MAX));
+
+        // Send numbered chunks of input while the keys keep changing underneath
+        let writer = {
+            let mut stream = stream.try_clone().unwrap();
+            let encrypter = encrypter.clone();
+            thread::spawn(move || {
+                for n in 0..5000u32 {
+                    let data = n.to_be_bytes().repeat(64);
+                    send_channel_data(&mut stream, &encrypter, &window, 0, 100, &mut None, &data)
+                        .unwrap();
+                }
+            })
+        };
+
+        // Swap in new keys the way the reading thread does, announcing each with NEWKEYS
+        // under the old keys, until the writer is done
+        let rekeyer = thread::spawn(move || {
+            let mut rekeys = 0;
+            while !writer.is_finished() {
+                let mut enc = encrypter.lock().unwrap();
+                stream.send(&[SSH_MSG_NEWKEYS], Some(&mut enc)).unwrap();
+                rekeys += 1;
+                let cipher = REKEY_CIPHERS[rekeys % REKEY_CIPHERS.len()];
+                let (new_enc, _) = loopback_pair(cipher, "hmac-sha2-256", "none");
+                *enc = new_enc;
+                drop(enc);
+
+                // Give the writer a chance at the lock between rekeys
+                thread::sleep(Duration::from_micros(100));
+            }
+            writer.join().unwrap();
+            let eof = [SSH_MSG_CHANNEL_EOF, 0, 0, 0, 0];
+            stream
+                .send(&eof, Some(&mut encrypter.lock().unwrap()))
+                .unwrap();
+            rekeys
+        });
+
+        let mut received = Vec::new();
+        let mut rekeys = 0;
+        loop {
+            let (packet_type, data) = host.read(Some(&mut decrypter)).unwrap();
+            match packet_type {
+                SSH_MSG_NEWKEYS => {
+                    rekeys += 1;
+                    let cipher = REKEY_CIPHERS[rekeys % REKEY_CIPHERS.len()];
+                    let (_, new_dec) = loopback_pair(cipher, "hmac-sha2-256", "none");
+                    decrypter = new_dec;
+                }
+                SSH_MSG_CHANNEL_DATA => {
+                    let (chunk, _) = SshStream::extract_string(&data[4..]).unwrap();
+                    received.extend(chunk);
+                }
+                SSH_MSG_CHANNEL_EOF => break,
+                _ => panic!("Unexpected packet type {packet_type}"),
+            }
         }
+
+        assert_eq!(rekeyer.join().unwrap(), rekeys);
+        let expected: Vec<u8> = (0..5000u32)
+            .flat_map(|n| n.to_be_bytes().repeat(64))
+            .collect();
+        assert!(received == expected);
+    }
+
+    #[test]
+    fn signal_names_are_checked() {
+        assert!(gen_signal(0, "TERM").is_some());
+        assert!(gen_signal(0, "sigkill").is_some());
+        assert!(gen_signal(0, "WINCH").is_none());
+
+        let request = gen_signal(3, "SIGHUP").unwrap();
+        assert!(request.ends_with(b"\x00\x00\x00\x03HUP"));
     }
 }